@@ -23,24 +23,44 @@ function getUserRole() {
   return localStorage.getItem('userRole');
 }
 
-// Function to check if user is authenticated
+// Permissions that came with the role at login, e.g. "catalog.edit"
+function getPermissions() {
+  try {
+    return JSON.parse(localStorage.getItem('permissions')) || [];
+  } catch (error) {
+    return [];
+  }
+}
+
+function hasPermission(permission) {
+  return getPermissions().includes(permission);
+}
+
+// Librarians and admins both use the staff pages; the server checks each action.
+function isStaff() {
+  return getPermissions().length > 0;
+}
+
+// Function to check if user is authenticated. An expired access token is fine
+// as long as there is a refresh token to trade for a new one.
 function isAuthenticated() {
-  return isJWTValid() && getUserRole();
+  return (isJWTValid() || localStorage.getItem('refreshToken')) && getUserRole();
 }
 
 // Function to clear auth data
 function clearAuthData() {
   localStorage.removeItem('jwt');
+  localStorage.removeItem('refreshToken');
   localStorage.removeItem('userRole');
   localStorage.removeItem('userName');
   localStorage.removeItem('userId');
+  localStorage.removeItem('permissions');
 }
 
 // Function to redirect based on authentication status
 function redirectIfAuthenticated() {
   if (isAuthenticated()) {
-    const role = getUserRole();
-    if (role === 'admin') {
+    if (isStaff()) {
       window.location.href = '/admin_dashboard.html';
     } else {
       window.location.href = '/dashboard.html';
@@ -57,12 +77,18 @@ function protectRoute(requiredRole = null) {
     return;
   }
 
-  // If specific role is required, check it
+  // The access token ran out while the page was closed: get a new one first
+  if (!isJWTValid()) {
+    refreshSession().then(() => window.location.reload());
+    return;
+  }
+
+  // Staff pages need a role with permissions; patron pages need one without
   if (requiredRole) {
-    const userRole = getUserRole();
-    if (userRole !== requiredRole) {
+    const staff = isStaff();
+    if ((requiredRole === 'staff') !== staff) {
       // User doesn't have required role
-      if (userRole === 'admin') {
+      if (staff) {
         window.location.href = '/admin_dashboard.html';
       } else {
         window.location.href = '/dashboard.html';
@@ -72,9 +98,9 @@ function protectRoute(requiredRole = null) {
   }
 }
 
-// Function to protect admin-only routes
+// Function to protect staff-only routes
 function protectAdminRoute() {
-  protectRoute('admin');
+  protectRoute('staff');
 }
 
 // Function to protect regular user routes
@@ -82,10 +108,72 @@ function protectUserRoute() {
   protectRoute('user');
 }
 
-function logout() {
+// Trades the refresh token for a new access token and refresh token. The old
+// refresh token stops working, so a copied one is detected by the server.
+async function refreshSession() {
+  const refreshToken = localStorage.getItem('refreshToken');
+  try {
+    const response = await fetch('/api/token/refresh', {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ refresh_token: refreshToken })
+    });
+    if (!response.ok) throw new Error('refresh rejected');
+
+    const data = await response.json();
+    localStorage.setItem('jwt', data.jwt);
+    localStorage.setItem('refreshToken', data.refresh_token);
+    scheduleRefresh();
+  } catch (error) {
+    clearAuthData();
+    window.location.href = '/login.html';
+  }
+}
+
+// Refreshes a minute before the access token expires
+let refreshTimer = null;
+function scheduleRefresh() {
+  const jwt = localStorage.getItem('jwt');
+  if (!jwt || !localStorage.getItem('refreshToken')) return;
+
+  try {
+    const payload = JSON.parse(atob(jwt.split('.')[1]));
+    const delay = Math.max(payload.exp * 1000 - Date.now() - 60000, 0);
+    clearTimeout(refreshTimer);
+    refreshTimer = setTimeout(refreshSession, delay);
+  } catch (error) {
+    console.error('Invalid JWT format:', error);
+  }
+}
+
+if (isJWTValid()) {
+  scheduleRefresh();
+}
+
+// Ends the session on the server too, so the tokens can't be reused
+async function endSession(path) {
+  const jwt = localStorage.getItem('jwt');
+  try {
+    await fetch(path, {
+      method: 'POST',
+      headers: { 'Authorization': `Bearer ${jwt}` }
+    });
+  } catch (error) {
+    console.error('Logout request failed:', error);
+  }
+
   // Clear all authentication data
   clearAuthData();
 
   // Redirect to login page
   window.location.href = '/login.html';
+}
+
+function logout() {
+  endSession('/api/logout');
+}
+
+// Signs out every browser and device the user is logged in on
+function logoutEverywhere() {
+  endSession('/api/logout/all');
 }
\ No newline at end of file
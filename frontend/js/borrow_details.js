@@ -9,7 +9,9 @@ document.addEventListener('DOMContentLoaded', async () => {
   }
 
   try {
-    const response = await fetch(`/api/borrow/${user_id}`);
+    const response = await fetch(`/api/borrow/${user_id}`, {
+      headers: { 'Authorization': `Bearer ${localStorage.getItem('jwt')}` }
+    });
     if (!response.ok) {
       throw new Error("Failed to fetch books");
     }
@@ -56,7 +58,10 @@ document.addEventListener('DOMContentLoaded', async () => {
         const borrowed_id = button.getAttribute('data-borrowed-id');
         const book_id = button.getAttribute('data-book-id');
         try {
-          const res = await fetch(`/api/borrow/${borrowed_id}/${book_id}`, { method: 'DELETE' });
+          const res = await fetch(`/api/borrow/${borrowed_id}/${book_id}`, {
+            method: 'DELETE',
+            headers: { 'Authorization': `Bearer ${localStorage.getItem('jwt')}` }
+          });
           if (res.ok) {
             button.parentElement.remove(); // Remove book card on success
           } else {
@@ -2,7 +2,9 @@ document.addEventListener('DOMContentLoaded', async () => {
   const container = document.getElementById("books-container");
 
   try {
-    const response = await fetch("/api/borrow");
+    const response = await fetch("/api/borrow", {
+      headers: { 'Authorization': `Bearer ${localStorage.getItem('jwt')}` }
+    });
     if (!response.ok) {
       throw new Error("Failed to fetch borrowed book details");
     }
@@ -1,3 +1,116 @@
+// Cards by book id, so live events can update them in place
+const bookCards = new Map();
+
+function renderBookDetails(bookDiv, book) {
+  bookDiv.querySelector(".book-details").innerHTML = `
+    <span style="font-size:20px;"><strong>${book.title}</strong><br></span>
+    Author: ${book.author}<br><br>
+    Year: ${book.publication_year}&nbsp;&nbsp;
+    Genre: ${book.genre}&nbsp;&nbsp;
+    ISBN: ${book.isbn}<br><br>
+  `;
+}
+
+function renderAvailability(bookDiv, available) {
+  bookDiv.querySelector(".book-availability").innerHTML = `
+    Copies: ${available}<br>
+    <span style="color: ${available < 1 ? 'red' : 'green'};">
+    ${available < 1 ? 'Checked Out' : 'Available'}
+    </span><br><br>
+  `;
+  bookDiv.querySelector("button").disabled = available < 1;
+}
+
+function createBookCard(book) {
+  const bookDiv = document.createElement("div");
+  bookDiv.style.border = "1px solid #ccc";
+  bookDiv.style.padding = "10px 30px";
+  bookDiv.style.marginBottom = "10px";
+  bookDiv.style.borderRadius = "5px";
+  bookDiv.innerHTML = `<div class="book-details"></div><div class="book-availability"></div>`;
+
+  // Create the borrow button
+  const borrowButton = document.createElement("button");
+  borrowButton.textContent = "Borrow Book";
+  borrowButton.style.marginTop = "10px";
+
+  // Borrow click handler
+  borrowButton.addEventListener("click", async () => {
+    const userId = localStorage.getItem("userId");
+
+    if (!userId) {
+      alert("User not logged in.");
+      return;
+    }
+
+    try {
+      const res = await fetch("/api/borrow", {
+        method: "POST",
+        headers: {
+          "Content-Type": "application/json",
+          'Authorization': `Bearer ${localStorage.getItem('jwt')}`,
+        },
+        body: JSON.stringify({
+          user_id: parseInt(userId),
+          book_id: book.id,
+        }),
+      });
+
+      const result = await res.json();
+
+      // The new copy count arrives as a live event, no reload needed
+      if (res.ok) {
+        alert(result.message || "Book borrowed successfully.");
+      } else {
+        alert(result.message || "Could not borrow book.");
+      }
+    } catch (err) {
+      console.error("Error borrowing book:", err);
+      alert("Something went wrong.");
+    }
+  });
+
+  bookDiv.appendChild(borrowButton);
+  renderBookDetails(bookDiv, book);
+  renderAvailability(bookDiv, book.number_of_copies);
+  bookCards.set(book.id, bookDiv);
+  return bookDiv;
+}
+
+function listenForCatalogChanges(container) {
+  const token = localStorage.getItem("jwt");
+  const events = new EventSource(token ? `/api/events?token=${token}` : "/api/events");
+
+  events.addEventListener("copies_changed", (e) => {
+    const data = JSON.parse(e.data);
+    const bookDiv = bookCards.get(data.book_id);
+    if (bookDiv) renderAvailability(bookDiv, data.available);
+  });
+
+  events.addEventListener("book_edited", (e) => {
+    const data = JSON.parse(e.data);
+    const bookDiv = bookCards.get(data.book_id);
+    if (bookDiv) renderBookDetails(bookDiv, data);
+  });
+
+  events.addEventListener("book_deleted", (e) => {
+    const data = JSON.parse(e.data);
+    const bookDiv = bookCards.get(data.book_id);
+    if (bookDiv) {
+      bookDiv.remove();
+      bookCards.delete(data.book_id);
+    }
+  });
+
+  events.addEventListener("book_added", async (e) => {
+    const data = JSON.parse(e.data);
+    const res = await fetch(`/api/books/${data.book_id}`);
+    if (res.ok && !bookCards.has(data.book_id)) {
+      container.appendChild(createBookCard(await res.json()));
+    }
+  });
+}
+
 document.addEventListener("DOMContentLoaded", async () => {
   const container = document.getElementById("books-container");
 
@@ -11,75 +124,15 @@ document.addEventListener("DOMContentLoaded", async () => {
 
     if (books.length === 0) {
       container.textContent = "No books available.";
-      return;
     }
 
     for (const book of books) {
-      const bookDiv = document.createElement("div");
-      bookDiv.style.border = "1px solid #ccc";
-      bookDiv.style.padding = "10px 30px";
-      bookDiv.style.marginBottom = "10px";
-      bookDiv.style.borderRadius = "5px";
-
-      // Create the borrow button
-      const borrowButton = document.createElement("button");
-      borrowButton.textContent = "Borrow Book";
-      borrowButton.style.marginTop = "10px";
-      borrowButton.disabled = book.number_of_copies < 1;
-
-      // Borrow click handler
-      borrowButton.addEventListener("click", async () => {
-        const userId = localStorage.getItem("userId");
-
-        if (!userId) {
-          alert("User not logged in.");
-          return;
-        }
-
-        try {
-          const res = await fetch("/api/borrow", {
-            method: "POST",
-            headers: {
-              "Content-Type": "application/json",
-            },
-            body: JSON.stringify({
-              user_id: parseInt(userId),
-              book_id: book.id,
-            }),
-          });
-
-          const result = await res.json();
-
-          if (res.ok) {
-            alert(result.message || "Book borrowed successfully.");
-            location.reload();
-          } else {
-            alert(result.message || "Could not borrow book.");
-          }
-        } catch (err) {
-          console.error("Error borrowing book:", err);
-          alert("Something went wrong.");
-        }
-      });
-
-
-      bookDiv.innerHTML = `
-        <span style="font-size:20px;"><strong>${book.title}</strong><br></span>
-        Author: ${book.author}<br><br>
-        Year: ${book.publication_year}&nbsp;&nbsp;
-        Genre: ${book.genre}&nbsp;&nbsp;
-        ISBN: ${book.isbn}<br><br>
-        Copies: ${book.number_of_copies}<br>
-        <span style="color: ${book.number_of_copies < 1 ? 'red' : 'green'};">
-        ${book.number_of_copies < 1 ? 'Checked Out' : 'Available'}
-        </span><br><br>
-      `;
-
-      bookDiv.appendChild(borrowButton);
-      container.appendChild(bookDiv);
+      container.appendChild(createBookCard(book));
     }
+
+    listenForCatalogChanges(container);
   } catch (error) {
     container.textContent = "Error loading books.";
     console.error(error);
   }
-});
\ No newline at end of file
+});
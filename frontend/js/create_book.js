@@ -15,7 +15,8 @@ document.addEventListener('DOMContentLoaded', () => {
       const response = await fetch("/api/books", {
         method: "POST",
         headers: {
-          "Content-type": "application/json"
+          "Content-type": "application/json",
+          'Authorization': `Bearer ${localStorage.getItem('jwt')}`
         },
         body: JSON.stringify({
           title: title,
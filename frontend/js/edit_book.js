@@ -31,6 +31,7 @@ document.addEventListener('DOMContentLoaded', async () => {
         method: "PATCH",
         headers: {
           "Content-Type": "application/json",
+          'Authorization': `Bearer ${localStorage.getItem('jwt')}`,
         },
         body: JSON.stringify(updatedBook),
       });
@@ -1,3 +1,41 @@
+// Second login step for accounts with two-factor authentication. Returns the
+// final login response, or null if the user gave up.
+async function completeTwoFactor(data) {
+  if (data.enrolment_required) {
+    const response = await fetch('/api/login/totp/enroll', {
+      method: "POST",
+      headers: { "Content-type": "application/json" },
+      body: JSON.stringify({ mfa_token: data.mfa_token })
+    });
+    const enrolment = await response.json();
+    if (!response.ok) {
+      alert(enrolment.message);
+      return null;
+    }
+    alert(`${data.message}. Add this key to your authenticator app:\n\n${enrolment.secret}\n\n${enrolment.otpauth_uri}`);
+  }
+
+  const code = prompt("Enter the 6-digit code from your authenticator app, or a recovery code");
+  if (!code) {
+    return null;
+  }
+
+  const response = await fetch('/api/login/totp', {
+    method: "POST",
+    headers: { "Content-type": "application/json" },
+    body: JSON.stringify({ mfa_token: data.mfa_token, code: code })
+  });
+  const result = await response.json();
+  if (!response.ok) {
+    alert(result.message);
+    return null;
+  }
+  if (result.recovery_codes) {
+    alert(`Save these recovery codes somewhere safe. Each one can be used once if you lose your authenticator:\n\n${result.recovery_codes.join('\n')}`);
+  }
+  return result;
+}
+
 document.addEventListener('DOMContentLoaded', function () {
   const form = document.querySelector('form');
   document.addEventListener('submit', async function (event) {
@@ -17,9 +55,15 @@ document.addEventListener('DOMContentLoaded', function () {
           password: password
         })
       });
-      const data = await response.json();
+      let data = await response.json();
+
+      if (response.ok && data.mfa_required) {
+        data = await completeTwoFactor(data);
+        if (!data) {
+          return;
+        }
+      }
 
-      console.log(data);
       if (response.ok) {
         const userRole = data.role;
         const userName = data.username;
@@ -29,8 +73,10 @@ document.addEventListener('DOMContentLoaded', function () {
         localStorage.setItem('userName', userName);
         localStorage.setItem('userId', userId);
         localStorage.setItem('jwt', jwt);
+        localStorage.setItem('refreshToken', data.refresh_token);
+        localStorage.setItem('permissions', JSON.stringify(data.permissions || []));
 
-        if (userRole == "admin") {
+        if (isStaff()) {
           window.location.href = 'admin_dashboard.html';
         }
         else {
@@ -40,7 +86,7 @@ document.addEventListener('DOMContentLoaded', function () {
       }
       else {
         console.error("Login failed: ", data);
-        alert('Login failed: ', data.error);
+        alert(data.message || 'Login failed');
       }
     }
     catch (error) {
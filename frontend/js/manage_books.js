@@ -9,12 +9,12 @@ async function deleteBook(bookId) {
   try {
     const response = await fetch(`/api/books/${bookId}`, {
       method: "DELETE",
+      headers: { 'Authorization': `Bearer ${localStorage.getItem('jwt')}` },
     });
 
     if (!response.ok) throw new Error("Failed to delete book");
 
-    // Refresh the page after deletion
-    location.reload();
+    // The card is removed when the book_deleted event arrives
   } catch (error) {
     console.error("Delete failed:", error);
     alert("Failed to delete the book.");
@@ -50,18 +50,40 @@ document.addEventListener("DOMContentLoaded", async () => {
         Year: ${book.publication_year}&nbsp;&nbsp;
         Genre: ${book.genre}&nbsp;&nbsp;
         ISBN: ${book.isbn}<br><br>
-        Copies: ${book.number_of_copies}<br>
+        <div class="availability">Copies: ${book.number_of_copies}<br>
         <span style="color: ${book.number_of_copies < 1 ? 'red' : 'green'};">
         ${book.number_of_copies < 1 ? 'Checked Out' : 'Available'}
-        </span><br><br>
+        </span></div><br>
         <div style="display: flex; gap: 10px;">
           <button onclick="editBook('${encodeURIComponent(JSON.stringify(book))}')">Edit</button>
           <button onclick="deleteBook(${book.id})" style="background-color: rgb(255, 182, 182);">Delete</button>
         </div>
       `;
 
+      bookDiv.dataset.bookId = book.id;
       container.appendChild(bookDiv);
     }
+
+    // Keep copy counts and deletions live; edits and additions happen on other
+    // pages, so those just reload the list.
+    const events = new EventSource(`/api/events?token=${localStorage.getItem("jwt")}`);
+    const cardFor = (bookId) => container.querySelector(`[data-book-id="${bookId}"]`);
+
+    events.addEventListener("copies_changed", (e) => {
+      const data = JSON.parse(e.data);
+      const availability = cardFor(data.book_id)?.querySelector(".availability");
+      if (availability) {
+        availability.innerHTML = `Copies: ${data.available}<br>
+        <span style="color: ${data.available < 1 ? 'red' : 'green'};">
+        ${data.available < 1 ? 'Checked Out' : 'Available'}
+        </span>`;
+      }
+    });
+    events.addEventListener("book_deleted", (e) => {
+      cardFor(JSON.parse(e.data).book_id)?.remove();
+    });
+    events.addEventListener("book_added", () => location.reload());
+    events.addEventListener("book_edited", () => location.reload());
   } catch (error) {
     container.textContent = "Error loading books.";
     console.error(error);
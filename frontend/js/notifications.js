@@ -0,0 +1,73 @@
+document.addEventListener('DOMContentLoaded', async () => {
+  const container = document.getElementById("notifications-container");
+  const headers = { 'Authorization': `Bearer ${localStorage.getItem('jwt')}` };
+
+  async function loadNotifications() {
+    container.innerHTML = "";
+    try {
+      const response = await fetch('/api/notifications', { headers });
+      if (!response.ok) {
+        throw new Error("Failed to fetch notifications");
+      }
+
+      const data = await response.json();
+
+      if (data.notifications.length === 0) {
+        container.textContent = "No notifications";
+        return;
+      }
+
+      for (const notification of data.notifications) {
+        const div = document.createElement("div");
+        div.style.border = "1px solid #ccc";
+        div.style.padding = "10px 30px";
+        div.style.marginBottom = "10px";
+        div.style.borderRadius = "5px";
+        div.style.fontWeight = notification.read ? "normal" : "bold";
+
+        const title = document.createElement("span");
+        title.style.fontSize = "18px";
+        title.textContent = notification.title;
+        const message = document.createElement("p");
+        message.textContent = `${notification.message} (${notification.created_at})`;
+
+        div.appendChild(title);
+        div.appendChild(message);
+
+        if (!notification.read) {
+          const readButton = document.createElement("button");
+          readButton.textContent = "Mark as read";
+          readButton.addEventListener('click', async () => {
+            await fetch(`/api/notifications/${notification.id}/read`, { method: 'POST', headers });
+            loadNotifications();
+          });
+          div.appendChild(readButton);
+        }
+
+        const deleteButton = document.createElement("button");
+        deleteButton.textContent = "Delete";
+        deleteButton.addEventListener('click', async () => {
+          const res = await fetch(`/api/notifications/${notification.id}`, { method: 'DELETE', headers });
+          if (res.ok) {
+            div.remove();
+          } else {
+            alert("Failed to delete notification");
+          }
+        });
+        div.appendChild(deleteButton);
+
+        container.appendChild(div);
+      }
+    } catch (error) {
+      container.textContent = "Error loading notifications.";
+      console.error(error);
+    }
+  }
+
+  document.getElementById("mark-all-read").addEventListener('click', async () => {
+    await fetch('/api/notifications/read', { method: 'POST', headers });
+    loadNotifications();
+  });
+
+  loadNotifications();
+});
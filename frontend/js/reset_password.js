@@ -0,0 +1,55 @@
+document.addEventListener('DOMContentLoaded', function () {
+  const forgotForm = document.getElementById('forgot-form');
+  const resetForm = document.getElementById('reset-form');
+
+  // Links in reset emails carry the code as #token=..., which never reaches the server
+  const token = new URLSearchParams(window.location.hash.slice(1)).get('token');
+  if (token) {
+    resetForm.querySelector('input[name="token"]').value = token;
+    forgotForm.style.display = 'none';
+  }
+
+  forgotForm.addEventListener('submit', async function (event) {
+    event.preventDefault();
+
+    try {
+      const response = await fetch('/api/password/forgot', {
+        method: "POST",
+        headers: { "Content-type": "application/json" },
+        body: JSON.stringify({
+          username: forgotForm.querySelector('input[name="username"]').value
+        })
+      });
+      const data = await response.json();
+      alert(data.message);
+    }
+    catch (error) {
+      console.error("Error: ", error);
+      alert("Network error occured");
+    }
+  });
+
+  resetForm.addEventListener('submit', async function (event) {
+    event.preventDefault();
+
+    try {
+      const response = await fetch('/api/password/reset', {
+        method: "POST",
+        headers: { "Content-type": "application/json" },
+        body: JSON.stringify({
+          token: resetForm.querySelector('input[name="token"]').value,
+          new_password: resetForm.querySelector('input[name="new_password"]').value
+        })
+      });
+      const data = await response.json();
+      alert(data.message);
+      if (response.ok) {
+        window.location.href = 'login.html';
+      }
+    }
+    catch (error) {
+      console.error("Error: ", error);
+      alert("Network error occured");
+    }
+  });
+});
@@ -0,0 +1,57 @@
+document.addEventListener('DOMContentLoaded', async () => {
+  const container = document.getElementById("sessions-container");
+  const headers = { 'Authorization': `Bearer ${localStorage.getItem('jwt')}` };
+
+  async function loadSessions() {
+    container.innerHTML = "";
+    try {
+      const response = await fetch('/api/account/sessions', { headers });
+      if (!response.ok) {
+        throw new Error("Failed to fetch sessions");
+      }
+
+      const sessions = await response.json();
+
+      for (const session of sessions) {
+        const div = document.createElement("div");
+        div.style.border = "1px solid #ccc";
+        div.style.padding = "10px 30px";
+        div.style.marginBottom = "10px";
+        div.style.borderRadius = "5px";
+
+        const device = document.createElement("span");
+        device.style.fontSize = "18px";
+        device.textContent = session.user_agent || "Unknown device";
+        if (session.current) {
+          device.textContent += " (this device)";
+        }
+        const details = document.createElement("p");
+        details.textContent = `${session.ip} - signed in ${session.created_at}, last active ${session.last_seen_at}`;
+
+        div.appendChild(device);
+        div.appendChild(details);
+
+        if (!session.current) {
+          const revokeButton = document.createElement("button");
+          revokeButton.textContent = "Sign out";
+          revokeButton.addEventListener('click', async () => {
+            const res = await fetch(`/api/account/sessions/${session.id}`, { method: 'DELETE', headers });
+            if (res.ok) {
+              div.remove();
+            } else {
+              alert("Failed to sign out device");
+            }
+          });
+          div.appendChild(revokeButton);
+        }
+
+        container.appendChild(div);
+      }
+    } catch (error) {
+      container.textContent = "Error loading sessions.";
+      console.error(error);
+    }
+  }
+
+  loadSessions();
+});
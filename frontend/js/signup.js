@@ -1,11 +1,27 @@
 document.addEventListener('DOMContentLoaded', function () {
   const form = document.querySelector('form');
+  const emailHint = document.getElementById('email-hint');
+
+  // The library may require an email, or one at particular domains
+  fetch('/api/signup/settings')
+    .then(response => response.json())
+    .then(settings => {
+      if (settings.email_domains.length > 0) {
+        emailHint.textContent = `Use your address at ${settings.email_domains.join(', ')}`;
+      } else if (settings.mode === 'email_verified') {
+        emailHint.textContent = 'We will send you a link to confirm it';
+      } else {
+        emailHint.textContent = 'Optional';
+      }
+    })
+    .catch(error => console.error('Error:', error));
 
   form.addEventListener('submit', async function (event) {
     event.preventDefault();
 
     const username = document.querySelector('input[name="username"]').value;
     const password = document.querySelector('input[name="password"]').value;
+    const email = document.querySelector('input[name="email"]').value;
 
     try {
       const response = await fetch('/api/signup', {
@@ -15,19 +31,23 @@ document.addEventListener('DOMContentLoaded', function () {
         },
         body: JSON.stringify({
           username: username,
-          password: password
+          password: password,
+          email: email
         })
       });
 
       const data = await response.json();
       console.log(data);
       if (response.ok) {
-        // Success - redirect to login page
+        // Pending accounts are told what happens next before going to the login page
+        if (data.registration_status !== 'approved') {
+          alert(data.message);
+        }
         window.location.href = 'login.html';
       } else {
         // Handle error
         console.error('Signup failed:', data);
-        alert('Signup failed: ' + data.error);
+        alert('Signup failed: ' + data.message);
       }
 
     } catch (error) {
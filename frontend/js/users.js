@@ -1,8 +1,13 @@
 document.addEventListener('DOMContentLoaded', async () => {
   const container = document.getElementById("users-list");
+  const headers = { 'Authorization': `Bearer ${localStorage.getItem('jwt')}` };
+
+  if (hasPermission('users.manage')) {
+    loadRegistrations(headers);
+  }
 
   try {
-    const response = await fetch("/api/users");
+    const response = await fetch("/api/users", { headers });
     if (!response.ok) {
       throw new Error("Failed to fetch user details");
     }
@@ -10,7 +15,7 @@ document.addEventListener('DOMContentLoaded', async () => {
     const users = await response.json();
 
     if (users.length === 0) {
-      container.textContent = "No logged in.";
+      container.textContent = "No users found.";
       return;
     }
 
@@ -20,14 +25,120 @@ document.addEventListener('DOMContentLoaded', async () => {
       userDiv.style.padding = "8px";
       userDiv.style.marginBottom = "6px";
 
+      const balance = (user.balance_cents / 100).toFixed(2);
       userDiv.innerHTML = `
-        <strong>User:</strong> ${user.username}
+        <strong>User:</strong> ${user.username} (${user.role})${user.full_name ? ` &ndash; ${user.full_name}` : ''}<br>
+        <strong>Card:</strong> ${user.card_number || '-'}<br>
+        <strong>Status:</strong> ${user.active ? 'Active' : 'Deactivated'}<br>
+        <strong>Membership:</strong> ${user.membership_expires_at
+          ? `${user.membership_expired ? 'Expired' : 'Valid until'} ${user.membership_expires_at}`
+          : 'No expiry'}<br>
+        <strong>Loans:</strong> ${user.active_loans} &nbsp; <strong>Balance:</strong> $${balance}<br>
       `;
 
+      // Deactivating blocks login and borrowing; it can be undone
+      if (hasPermission('users.manage')) {
+        const button = document.createElement("button");
+        button.textContent = user.active ? "Deactivate" : "Reactivate";
+        button.addEventListener('click', async () => {
+          const action = user.active ? 'deactivate' : 'reactivate';
+          const reason = user.active ? prompt("Reason for deactivating (optional):") : '';
+          if (reason === null) {
+            return;
+          }
+          const res = await fetch(`/api/users/${user.id}/${action}`, {
+            method: 'POST',
+            headers,
+            body: JSON.stringify({ reason })
+          });
+          const data = await res.json();
+          if (res.ok) {
+            window.location.reload();
+          } else {
+            alert(data.message || 'Could not update user');
+          }
+        });
+        userDiv.appendChild(button);
+
+        const renew = document.createElement("button");
+        renew.textContent = "Renew Membership";
+        renew.addEventListener('click', async () => {
+          const res = await fetch(`/api/users/${user.id}/membership/renew`, {
+            method: 'POST',
+            headers
+          });
+          const data = await res.json();
+          if (res.ok) {
+            window.location.reload();
+          } else {
+            alert(data.message || 'Could not renew membership');
+          }
+        });
+        userDiv.appendChild(renew);
+      }
+
       container.appendChild(userDiv);
     }
   } catch (error) {
     container.textContent = "Error loading users.";
     console.error(error);
   }
-})
\ No newline at end of file
+})
+
+// Registrations waiting for staff when signups need approval
+async function loadRegistrations(headers) {
+  const section = document.getElementById("registrations");
+  const list = document.getElementById("registrations-list");
+
+  try {
+    const response = await fetch("/api/registrations", { headers });
+    if (!response.ok) {
+      throw new Error("Failed to fetch registrations");
+    }
+
+    const registrations = await response.json();
+    if (registrations.length === 0) {
+      return;
+    }
+    section.style.display = "block";
+
+    for (const registration of registrations) {
+      const div = document.createElement("div");
+      div.style.border = "1px solid #ddd";
+      div.style.padding = "8px";
+      div.style.marginBottom = "6px";
+      div.innerHTML = `
+        <strong>User:</strong> ${registration.username}<br>
+        <strong>Email:</strong> ${registration.email || '-'}<br>
+        <strong>Registered:</strong> ${registration.created_at}<br>
+      `;
+
+      for (const action of ['approve', 'reject']) {
+        const button = document.createElement("button");
+        button.textContent = action === 'approve' ? "Approve" : "Reject";
+        button.addEventListener('click', async () => {
+          const reason = action === 'reject' ? prompt("Reason for rejecting:") : '';
+          if (reason === null) {
+            return;
+          }
+          const res = await fetch(`/api/registrations/${registration.id}/${action}`, {
+            method: 'POST',
+            headers,
+            body: JSON.stringify({ reason })
+          });
+          const data = await res.json();
+          if (res.ok) {
+            window.location.reload();
+          } else {
+            alert(data.message || 'Could not review registration');
+          }
+        });
+        div.appendChild(button);
+      }
+
+      list.appendChild(div);
+    }
+  } catch (error) {
+    console.error(error);
+  }
+}
@@ -0,0 +1,48 @@
+document.addEventListener('DOMContentLoaded', async function () {
+  const status = document.getElementById('status');
+  const resendForm = document.getElementById('resend-form');
+
+  resendForm.addEventListener('submit', async function (event) {
+    event.preventDefault();
+
+    try {
+      const response = await fetch('/api/signup/resend', {
+        method: "POST",
+        headers: { "Content-type": "application/json" },
+        body: JSON.stringify({
+          username: resendForm.querySelector('input[name="username"]').value
+        })
+      });
+      const data = await response.json();
+      alert(data.message);
+    }
+    catch (error) {
+      console.error("Error: ", error);
+      alert("Network error occured");
+    }
+  });
+
+  // Links in verification emails carry the token as #token=..., which never reaches the server
+  const token = new URLSearchParams(window.location.hash.slice(1)).get('token');
+  if (!token) {
+    status.textContent = 'Open the link from your email, or ask for a new one below.';
+    return;
+  }
+
+  try {
+    const response = await fetch('/api/signup/verify', {
+      method: "POST",
+      headers: { "Content-type": "application/json" },
+      body: JSON.stringify({ token })
+    });
+    const data = await response.json();
+    status.textContent = data.message;
+    if (response.ok) {
+      resendForm.style.display = 'none';
+    }
+  }
+  catch (error) {
+    console.error("Error: ", error);
+    status.textContent = "Network error occured";
+  }
+});
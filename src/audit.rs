@@ -0,0 +1,82 @@
+use crate::auth;
+use rusqlite::{Connection, Result as SqliteResult, params};
+use serde_json::{Map, Value};
+use std::env;
+
+// Who made a change and from where. Requests without a valid token are recorded
+// with no actor, kiosk actions as "kiosk:<id>".
+#[derive(Debug, Clone)]
+pub struct Actor {
+    pub username: Option<String>,
+    pub source_ip: String,
+}
+
+impl Actor {
+    pub fn from_request(authorization: Option<&str>, source_ip: &str) -> Self {
+        Actor {
+            username: auth::decode_claims(authorization).map(|claims| claims.sub),
+            source_ip: source_ip.to_string(),
+        }
+    }
+
+    // Commands run on the server itself are recorded as "cli:<os user>".
+    pub fn cli() -> Self {
+        let user = env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+        Actor {
+            username: Some(format!("cli:{user}")),
+            source_ip: "local".to_string(),
+        }
+    }
+
+    pub fn kiosk(&self, kiosk_id: i64) -> Self {
+        Actor {
+            username: Some(format!("kiosk:{kiosk_id}")),
+            source_ip: self.source_ip.clone(),
+        }
+    }
+}
+
+// Appends an entry to the audit log. Call it on the transaction that makes the
+// change, with the affected fields as they were before and after it.
+pub fn record(
+    conn: &Connection,
+    actor: &Actor,
+    action: &str,
+    target_type: &str,
+    target_id: i64,
+    before: Option<&Value>,
+    after: Option<&Value>,
+) -> SqliteResult<()> {
+    conn.execute(
+        "INSERT INTO audit_log (actor, actor_id, source_ip, action, target_type, target_id, before, after)
+         VALUES (?1, (SELECT id FROM users WHERE username = ?1), ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            actor.username,
+            actor.source_ip,
+            action,
+            target_type,
+            target_id,
+            before.map(Value::to_string),
+            after.map(Value::to_string),
+        ],
+    )?;
+    Ok(())
+}
+
+// Narrows two snapshots of a record down to the fields that differ.
+pub fn changes(before: &Value, after: &Value) -> (Value, Value) {
+    let mut old = Map::new();
+    let mut new = Map::new();
+
+    if let (Some(before), Some(after)) = (before.as_object(), after.as_object()) {
+        for (field, value) in after {
+            let previous = before.get(field).unwrap_or(&Value::Null);
+            if previous != value {
+                old.insert(field.clone(), previous.clone());
+                new.insert(field.clone(), value.clone());
+            }
+        }
+    }
+
+    (Value::Object(old), Value::Object(new))
+}
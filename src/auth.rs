@@ -0,0 +1,111 @@
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Access tokens are short-lived; clients stay signed in by trading their refresh
+// token for a new pair at POST /api/token/refresh.
+pub const ACCESS_TOKEN_SECONDS: u64 = 15 * 60;
+// A session that hasn't been refreshed for this long has to log in again.
+pub const REFRESH_TOKEN_DAYS: i64 = 30;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String, // subject, like username or user ID
+    pub role: String,
+    pub exp: usize, // expiration timestamp
+    pub sid: i64,   // server-side session, checked on every request
+}
+
+// Signs an access token for a session. None when JWT_SECRET isn't set.
+pub fn issue_access_token(username: &str, role: &str, session_id: i64) -> Option<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let claims = Claims {
+        sub: username.to_string(),
+        role: role.to_string(),
+        exp: (now + ACCESS_TOKEN_SECONDS) as usize,
+        sid: session_id,
+    };
+    let jwt_secret = env::var("JWT_SECRET").ok()?;
+
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(jwt_secret.as_bytes()),
+    )
+    .ok()
+}
+
+// Reads the claims from an `Authorization: Bearer <jwt>` header value, rejecting
+// tokens that are malformed, expired or signed with another secret.
+pub fn decode_claims(authorization: Option<&str>) -> Option<Claims> {
+    let token = authorization?.strip_prefix("Bearer ")?.trim();
+    let jwt_secret = env::var("JWT_SECRET").ok()?;
+
+    decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(jwt_secret.as_bytes()),
+        &Validation::default(),
+    )
+    .ok()
+    .map(|data| data.claims)
+}
+
+// Proves the holder received mail at `email`. Tied to the address, so it stops
+// working if the email is changed before it's used.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerificationClaims {
+    pub sub: i64, // user ID
+    pub email: String,
+    pub purpose: String,
+    pub exp: usize,
+}
+
+const EMAIL_VERIFICATION_PURPOSE: &str = "verify_email";
+
+// None when JWT_SECRET isn't set.
+pub fn issue_verification_token(user_id: i64, email: &str, valid_seconds: u64) -> Option<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let claims = VerificationClaims {
+        sub: user_id,
+        email: email.to_string(),
+        purpose: EMAIL_VERIFICATION_PURPOSE.to_string(),
+        exp: (now + valid_seconds) as usize,
+    };
+    let jwt_secret = env::var("JWT_SECRET").ok()?;
+
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(jwt_secret.as_bytes()),
+    )
+    .ok()
+}
+
+// Access tokens are signed with the same secret, so the purpose is checked too.
+pub fn decode_verification_token(token: &str) -> Option<VerificationClaims> {
+    let jwt_secret = env::var("JWT_SECRET").ok()?;
+
+    decode::<VerificationClaims>(
+        token.trim(),
+        &DecodingKey::from_secret(jwt_secret.as_bytes()),
+        &Validation::default(),
+    )
+    .ok()
+    .map(|data| data.claims)
+    .filter(|claims| claims.purpose == EMAIL_VERIFICATION_PURPOSE)
+}
+
+// Opaque random credential, handed out once and only ever stored as `hash_token`.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// Tokens are long and random, so a fast hash is enough; passwords and PINs use bcrypt.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
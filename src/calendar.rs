@@ -0,0 +1,206 @@
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime};
+use serde::Serialize;
+use std::collections::HashSet;
+
+pub const DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+pub const DATE_FORMAT: &str = "%Y-%m-%d";
+
+#[derive(Debug, Serialize)]
+pub struct OpeningHours {
+    pub weekday: u32, // 0 = Monday .. 6 = Sunday
+    pub opens: String,
+    pub closes: String,
+    pub closed: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Closure {
+    pub date: String,
+    pub reason: String,
+}
+
+pub struct Calendar {
+    closed_weekdays: [bool; 7],
+    closures: HashSet<NaiveDate>,
+}
+
+impl Calendar {
+    pub fn new(hours: &[OpeningHours], closures: &[Closure]) -> Calendar {
+        let mut closed_weekdays = [false; 7];
+        for day in hours {
+            if let Some(slot) = closed_weekdays.get_mut(day.weekday as usize) {
+                *slot = day.closed;
+            }
+        }
+
+        let closures = closures
+            .iter()
+            .filter_map(|c| NaiveDate::parse_from_str(&c.date, DATE_FORMAT).ok())
+            .collect();
+
+        Calendar {
+            closed_weekdays,
+            closures,
+        }
+    }
+
+    pub fn is_open(&self, date: NaiveDate) -> bool {
+        !self.closed_weekdays[date.weekday().num_days_from_monday() as usize]
+            && !self.closures.contains(&date)
+    }
+
+    // Moves a due date to the next day the library is open, keeping the time of day.
+    // Gives up after a year so a calendar with every day closed can't loop forever.
+    pub fn roll_forward(&self, due: NaiveDateTime) -> NaiveDateTime {
+        let mut rolled = due;
+        for _ in 0..366 {
+            if self.is_open(rolled.date()) {
+                return rolled;
+            }
+            rolled += Duration::days(1);
+        }
+        due
+    }
+
+    // Counts the open days after `from` up to and including `to`.
+    pub fn open_days_between(&self, from: NaiveDate, to: NaiveDate) -> i64 {
+        from.iter_days()
+            .skip(1)
+            .take_while(|date| *date <= to)
+            .filter(|date| self.is_open(*date))
+            .count() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(text: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(text, DATE_FORMAT).unwrap()
+    }
+
+    fn datetime(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, DATETIME_FORMAT).unwrap()
+    }
+
+    fn hours(closed_weekdays: &[u32]) -> Vec<OpeningHours> {
+        (0..7)
+            .map(|weekday| OpeningHours {
+                weekday,
+                opens: "09:00".to_string(),
+                closes: "17:00".to_string(),
+                closed: closed_weekdays.contains(&weekday),
+            })
+            .collect()
+    }
+
+    fn closures(dates: &[&str]) -> Vec<Closure> {
+        dates
+            .iter()
+            .map(|date| Closure {
+                date: date.to_string(),
+                reason: "Holiday".to_string(),
+            })
+            .collect()
+    }
+
+    // Closed on Sundays, plus Christmas Day and Boxing Day 2026 (a Friday and
+    // Saturday), so 25-27 December is a run of three closed days.
+    fn christmas() -> Calendar {
+        Calendar::new(&hours(&[6]), &closures(&["2026-12-25", "2026-12-26"]))
+    }
+
+    #[test]
+    fn is_open_checks_weekly_hours_and_closures() {
+        let calendar = christmas();
+        assert!(calendar.is_open(date("2026-12-24")));
+        assert!(!calendar.is_open(date("2026-12-25")));
+        assert!(!calendar.is_open(date("2026-12-20")));
+        assert!(calendar.is_open(date("2026-12-21")));
+    }
+
+    #[test]
+    fn roll_forward_leaves_open_days_alone() {
+        let due = datetime("2026-12-24 14:30:00");
+        assert_eq!(christmas().roll_forward(due), due);
+    }
+
+    #[test]
+    fn roll_forward_skips_weekly_closed_day() {
+        assert_eq!(
+            christmas().roll_forward(datetime("2026-12-20 10:00:00")),
+            datetime("2026-12-21 10:00:00")
+        );
+    }
+
+    #[test]
+    fn roll_forward_skips_consecutive_closed_days_keeping_time() {
+        let calendar = christmas();
+        for closed in ["2026-12-25", "2026-12-26", "2026-12-27"] {
+            assert_eq!(
+                calendar.roll_forward(datetime(&format!("{closed} 14:30:00"))),
+                datetime("2026-12-28 14:30:00"),
+                "{closed}"
+            );
+        }
+    }
+
+    #[test]
+    fn roll_forward_gives_up_when_never_open() {
+        let calendar = Calendar::new(&hours(&[0, 1, 2, 3, 4, 5, 6]), &[]);
+        let due = datetime("2026-12-24 14:30:00");
+        assert_eq!(calendar.roll_forward(due), due);
+    }
+
+    #[test]
+    fn no_hours_or_closures_means_always_open() {
+        let calendar = Calendar::new(&[], &[]);
+        let due = datetime("2026-12-27 09:00:00");
+        assert_eq!(calendar.roll_forward(due), due);
+        assert_eq!(
+            calendar.open_days_between(date("2026-12-24"), date("2026-12-31")),
+            7
+        );
+    }
+
+    #[test]
+    fn unparseable_closures_are_ignored() {
+        let calendar = Calendar::new(&[], &closures(&["25/12/2026", "2026-12-26"]));
+        assert!(calendar.is_open(date("2026-12-25")));
+        assert!(!calendar.is_open(date("2026-12-26")));
+    }
+
+    #[test]
+    fn open_days_between_excludes_start_and_closed_days() {
+        let calendar = christmas();
+        // 25, 26 and 27 are closed, leaving the 28th and 29th
+        assert_eq!(
+            calendar.open_days_between(date("2026-12-24"), date("2026-12-29")),
+            2
+        );
+        // Over the closed run alone there is nothing to count
+        assert_eq!(
+            calendar.open_days_between(date("2026-12-24"), date("2026-12-27")),
+            0
+        );
+        // A whole week with one weekly closure
+        assert_eq!(
+            calendar.open_days_between(date("2026-11-01"), date("2026-11-08")),
+            6
+        );
+    }
+
+    #[test]
+    fn open_days_between_is_zero_for_empty_ranges() {
+        let calendar = christmas();
+        assert_eq!(
+            calendar.open_days_between(date("2026-12-24"), date("2026-12-24")),
+            0
+        );
+        assert_eq!(
+            calendar.open_days_between(date("2026-12-29"), date("2026-12-24")),
+            0
+        );
+    }
+}
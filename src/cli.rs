@@ -0,0 +1,489 @@
+use crate::audit::Actor;
+use crate::db::{self, AdminBootstrap, Database, NewBook, UserChange};
+use crate::roles;
+use serde_json::{Value, json};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Read};
+
+const USAGE: &str = "Usage: project [--db <file>] [command]
+
+With no command the server is started, on the --db file if one is given.
+Commands print JSON on stdout and exit with 0 on success, 1 when the command
+fails and 2 on a usage error.
+
+Options:
+  --db <file>
+      The database to use (default project.db). It is migrated when opened.
+
+Commands:
+  create-admin <username> [--password-stdin]
+      Create the first admin account. Only works while the library has no
+      active admin.
+  create-user <username> [--role <role>] [--password-stdin]
+      Create an account, as a patron unless a role is given.
+  reset-password <username> [--password-stdin]
+      Set a new password and sign the user out everywhere.
+  set-role <username> <role>
+      Move a user to another role and sign them out everywhere.
+  list-overdue
+      Loans past their due date, most overdue first.
+  export-catalog [--output <file>]
+      Write the catalog as JSON, to stdout unless a file is given.
+  import-catalog <file>
+      Add the books in a JSON file written by export-catalog (- reads stdin).
+      Books whose ISBN is already in the catalog are skipped.
+  migrate
+      Bring the database schema up to date.
+  backup <file>
+      Copy the database to a new file. Safe while the server is running.
+  help
+      Show this message.
+
+Passwords are prompted for twice and never echoed. With --password-stdin the
+first line of stdin is used instead.";
+
+const DEFAULT_DB_PATH: &str = "project.db";
+
+// Options that take a value; any other --option is a flag.
+const VALUE_OPTIONS: [&str; 3] = ["--db", "--role", "--output"];
+
+struct Args<'a> {
+    positional: Vec<&'a str>,
+    options: HashMap<&'a str, &'a str>,
+    flags: Vec<&'a str>,
+}
+
+impl<'a> Args<'a> {
+    fn parse(args: &'a [String]) -> Result<Self, String> {
+        let mut parsed = Args {
+            positional: Vec::new(),
+            options: HashMap::new(),
+            flags: Vec::new(),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if VALUE_OPTIONS.contains(&arg.as_str()) {
+                let value = args.next().ok_or_else(|| format!("{arg} needs a value"))?;
+                parsed.options.insert(arg, value);
+            } else if arg.starts_with("--") && arg.len() > 2 {
+                parsed.flags.push(arg);
+            } else {
+                parsed.positional.push(arg);
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.contains(&name)
+    }
+}
+
+// What the binary should do once the command line has been read.
+pub enum Invocation {
+    // No command was given, so the server starts on this database.
+    Serve(String),
+    // A command ran (or the arguments were wrong); exit with this code.
+    Exit(i32),
+}
+
+// Reads the global options, then runs the command if there is one.
+pub fn run(args: &[String]) -> Invocation {
+    let args = match Args::parse(args) {
+        Ok(args) => args,
+        Err(message) => return Invocation::Exit(usage_error(&message)),
+    };
+    if args.flag("--help") {
+        println!("{USAGE}");
+        return Invocation::Exit(0);
+    }
+    let db_path = args.options.get("--db").copied().unwrap_or(DEFAULT_DB_PATH);
+    let Some((&command, operands)) = args.positional.split_first() else {
+        if let Some(option) = args
+            .flags
+            .first()
+            .or(args.options.keys().find(|o| **o != "--db"))
+        {
+            return Invocation::Exit(usage_error(&format!("{option} only applies to a command")));
+        }
+        return Invocation::Serve(db_path.to_string());
+    };
+    Invocation::Exit(run_command(command, operands, db_path, &args))
+}
+
+// Runs a command and returns the process exit code.
+fn run_command(command: &str, operands: &[&str], db_path: &str, args: &Args) -> i32 {
+    if matches!(command, "help" | "-h") {
+        println!("{USAGE}");
+        return 0;
+    }
+
+    let known_flags: &[&str] = match command {
+        "create-admin" | "create-user" | "reset-password" => &["--password-stdin"],
+        _ => &[],
+    };
+    if let Some(flag) = args.flags.iter().find(|flag| !known_flags.contains(flag)) {
+        return usage_error(&format!("Unknown option '{flag}' for {command}"));
+    }
+
+    if command == "migrate" {
+        return migrate(db_path);
+    }
+    let db = match Database::new(db_path) {
+        Ok(db) => db,
+        Err(e) => return fail(&format!("Could not open {db_path}: {e}")),
+    };
+
+    match (command, operands) {
+        ("create-admin", [username]) => create_admin(username, args, &db),
+        ("create-user", [username]) => create_user(username, args, &db),
+        ("reset-password", [username]) => reset_password(username, args, &db),
+        ("set-role", [username, role]) => set_role(username, role, &db),
+        ("list-overdue", []) => list_overdue(&db),
+        ("export-catalog", []) => export_catalog(args, &db),
+        ("import-catalog", [path]) => import_catalog(path, &db),
+        ("backup", [path]) => backup(path, &db),
+        (
+            "create-admin" | "create-user" | "reset-password" | "set-role" | "list-overdue"
+            | "export-catalog" | "import-catalog" | "backup",
+            _,
+        ) => usage_error(&format!("Wrong number of arguments for {command}")),
+        _ => usage_error(&format!("Unknown command '{command}'")),
+    }
+}
+
+fn print(value: &Value) {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).unwrap_or_default()
+    );
+}
+
+fn fail(message: &str) -> i32 {
+    print(&json!({ "success": false, "message": message }));
+    1
+}
+
+fn usage_error(message: &str) -> i32 {
+    eprintln!("{message}\n\n{USAGE}");
+    2
+}
+
+fn create_admin(username: &str, args: &Args, db: &Database) -> i32 {
+    match db.admin_exists() {
+        Ok(false) => {}
+        Ok(true) => return fail("An admin account already exists; use it to assign roles instead"),
+        Err(e) => return fail(&format!("Database error: {e}")),
+    }
+    if let Err(message) = check_username(username) {
+        return fail(&message);
+    }
+    let password = match new_password(args) {
+        Ok(password) => password,
+        Err(message) => return fail(&message),
+    };
+
+    match db.create_first_admin(username, &password, &Actor::cli()) {
+        Ok(AdminBootstrap::Created(user_id)) => {
+            print(
+                &json!({ "success": true, "id": user_id, "username": username, "role": "admin" }),
+            );
+            0
+        }
+        Ok(AdminBootstrap::AdminExists) => {
+            fail("An admin account already exists; use it to assign roles instead")
+        }
+        Ok(AdminBootstrap::UsernameTaken) => fail(&format!("Username '{username}' already exists")),
+        Err(e) => fail(&format!("Database error: {e}")),
+    }
+}
+
+fn create_user(username: &str, args: &Args, db: &Database) -> i32 {
+    let role = args.options.get("--role").copied().unwrap_or("user");
+    let Some(role) = roles::find(role) else {
+        return fail(&unknown_role(role));
+    };
+    if let Err(message) = check_username(username) {
+        return fail(&message);
+    }
+    let password = match new_password(args) {
+        Ok(password) => password,
+        Err(message) => return fail(&message),
+    };
+
+    match db.create_user_with_role(username, &password, role.name, &Actor::cli()) {
+        Ok(Some(user_id)) => {
+            print(
+                &json!({ "success": true, "id": user_id, "username": username, "role": role.name }),
+            );
+            0
+        }
+        Ok(None) => fail(&format!("Username '{username}' already exists")),
+        Err(e) => fail(&format!("Database error: {e}")),
+    }
+}
+
+fn reset_password(username: &str, args: &Args, db: &Database) -> i32 {
+    let patron = match db.fetch_patron_by_username(username) {
+        Ok(Some(patron)) => patron,
+        Ok(None) => return fail(&format!("No user named '{username}'")),
+        Err(e) => return fail(&format!("Database error: {e}")),
+    };
+    let password = match new_password(args) {
+        Ok(password) => password,
+        Err(message) => return fail(&message),
+    };
+
+    match db.set_password(patron.id, &password, &Actor::cli()) {
+        Ok(true) => {
+            print(&json!({ "success": true, "id": patron.id, "username": username }));
+            0
+        }
+        Ok(false) => fail("Failed to set password"),
+        Err(e) => fail(&format!("Database error: {e}")),
+    }
+}
+
+fn set_role(username: &str, role: &str, db: &Database) -> i32 {
+    let Some(role) = roles::find(role) else {
+        return fail(&unknown_role(role));
+    };
+    let patron = match db.fetch_patron_by_username(username) {
+        Ok(Some(patron)) => patron,
+        Ok(None) => return fail(&format!("No user named '{username}'")),
+        Err(e) => return fail(&format!("Database error: {e}")),
+    };
+
+    let changed = match db.set_user_role(patron.id, role.name, &Actor::cli()) {
+        Ok(UserChange::Changed) => true,
+        Ok(UserChange::Unchanged) => false,
+        Ok(UserChange::NotFound) => return fail(&format!("No user named '{username}'")),
+        Ok(UserChange::LastAdmin) => {
+            return fail("The last active admin can't be given another role");
+        }
+        Err(e) => return fail(&format!("Database error: {e}")),
+    };
+    print(&json!({
+        "success": true,
+        "id": patron.id,
+        "username": username,
+        "role": role.name,
+        "changed": changed,
+    }));
+    0
+}
+
+fn unknown_role(role: &str) -> String {
+    let names: Vec<&str> = roles::ROLES.iter().map(|role| role.name).collect();
+    format!(
+        "Unknown role '{role}'; expected one of {}",
+        names.join(", ")
+    )
+}
+
+fn list_overdue(db: &Database) -> i32 {
+    let mut loans = match db.fetch_all_borrowed_books() {
+        Ok(loans) => loans,
+        Err(e) => return fail(&format!("Database error: {e}")),
+    };
+    loans.retain(|loan| loan.overdue_days > 0);
+    loans.sort_by_key(|loan| Reverse(loan.overdue_days));
+
+    print(&json!({ "success": true, "count": loans.len(), "loans": loans }));
+    0
+}
+
+// The export is a list of books in the shape POST /api/books takes, so it can be
+// imported again or edited by hand.
+fn export_catalog(args: &Args, db: &Database) -> i32 {
+    let books = match db.fetch_books() {
+        Ok(books) => books,
+        Err(message) => return fail(&message),
+    };
+    let catalog: Vec<Value> = books
+        .iter()
+        .map(|book| {
+            json!({
+                "title": book.title,
+                "author": book.author,
+                "isbn": book.isbn,
+                "publication_year": book.publication_year,
+                "genre": book.genre,
+                "number_of_copies": book.total_copies,
+            })
+        })
+        .collect();
+
+    match args.options.get("--output") {
+        Some(path) => {
+            let contents = serde_json::to_string_pretty(&catalog).unwrap_or_default();
+            if let Err(e) = fs::write(path, contents + "\n") {
+                return fail(&format!("Could not write {path}: {e}"));
+            }
+            print(&json!({ "success": true, "exported": catalog.len(), "output": path }));
+        }
+        None => print(&Value::Array(catalog)),
+    }
+    0
+}
+
+fn import_catalog(path: &str, db: &Database) -> i32 {
+    let contents = if path == "-" {
+        let mut contents = String::new();
+        io::stdin().read_to_string(&mut contents).map(|_| contents)
+    } else {
+        fs::read_to_string(path)
+    };
+    let contents = match contents {
+        Ok(contents) => contents,
+        Err(e) => return fail(&format!("Could not read {path}: {e}")),
+    };
+    let entries = match serde_json::from_str::<Value>(&contents) {
+        Ok(Value::Array(entries)) => entries,
+        _ => return fail("Expected a JSON list of books"),
+    };
+
+    let actor = Actor::cli();
+    let mut imported = 0;
+    let mut skipped = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let book = match book_from_entry(entry) {
+            Ok(book) => book,
+            Err(reason) => {
+                skipped.push(json!({ "index": index, "isbn": entry["isbn"], "reason": reason }));
+                continue;
+            }
+        };
+        match db.add_book(&book, &actor) {
+            Ok(true) => imported += 1,
+            Ok(false) => skipped.push(json!({
+                "index": index,
+                "isbn": book.isbn,
+                "reason": "A book with this ISBN already exists",
+            })),
+            Err(e) => return fail(&format!("Database error after {imported} books: {e}")),
+        }
+    }
+
+    print(&json!({ "success": true, "imported": imported, "skipped": skipped }));
+    0
+}
+
+// Same rules as adding a book through the API.
+fn book_from_entry(entry: &Value) -> Result<NewBook<'_>, &'static str> {
+    let title = entry["title"].as_str().unwrap_or("");
+    let author = entry["author"].as_str().unwrap_or("");
+    let isbn = entry["isbn"].as_str().unwrap_or("");
+    let publication_year = entry["publication_year"].as_str().unwrap_or("");
+    let genre = entry["genre"].as_str().unwrap_or("");
+    let number_of_copies = entry["number_of_copies"].as_i64().unwrap_or(-1);
+
+    if title.trim().is_empty()
+        || author.trim().is_empty()
+        || isbn.trim().is_empty()
+        || publication_year.trim().is_empty()
+        || genre.trim().is_empty()
+        || number_of_copies <= 0
+    {
+        return Err("Please provide all the fields");
+    }
+    if isbn.len() != 13 || !isbn.chars().all(|c| c.is_ascii_digit()) {
+        return Err("ISBN must be exactly 13 digits");
+    }
+    match publication_year.parse::<u16>() {
+        Ok(year) if (1500..=2024).contains(&year) => {}
+        _ => return Err("Publication year must be a valid year between 1500 and 2024"),
+    }
+    if number_of_copies > i32::MAX as i64 {
+        return Err("Number of copies must be a positive integer");
+    }
+
+    Ok(NewBook {
+        title,
+        author,
+        isbn,
+        publication_year,
+        genre,
+        number_of_copies: number_of_copies as i32,
+    })
+}
+
+fn migrate(db_path: &str) -> i32 {
+    let from = match Database::schema_version(db_path) {
+        Ok(version) => version,
+        Err(e) => return fail(&format!("Could not open {db_path}: {e}")),
+    };
+    if let Err(e) = Database::new(db_path) {
+        return fail(&format!("Migration failed: {e}"));
+    }
+    let to = match Database::schema_version(db_path) {
+        Ok(version) => version,
+        Err(e) => return fail(&format!("Could not open {db_path}: {e}")),
+    };
+
+    print(&json!({
+        "success": true,
+        "from_version": from,
+        "to_version": to,
+        "applied": to.saturating_sub(from),
+        "latest_version": Database::latest_schema_version(),
+    }));
+    0
+}
+
+fn backup(path: &str, db: &Database) -> i32 {
+    if fs::metadata(path).is_ok() {
+        return fail(&format!("{path} already exists"));
+    }
+    match db.backup(path) {
+        Ok(()) => {
+            let bytes = fs::metadata(path).map(|meta| meta.len()).unwrap_or(0);
+            print(&json!({ "success": true, "output": path, "bytes": bytes }));
+            0
+        }
+        Err(e) => fail(&format!("Backup failed: {e}")),
+    }
+}
+
+// The same username rules as signing up through the API.
+fn check_username(username: &str) -> Result<(), String> {
+    if username.len() < 3 || username.len() > 50 {
+        return Err("Username must be between 3 and 50 characters".to_string());
+    }
+    if db::looks_like_card_number(username) {
+        return Err(
+            "Usernames can't be all digits, so they aren't mistaken for library card numbers"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+// Asks for the password twice, or reads it from stdin, and checks it against
+// the same rules as signing up through the API.
+fn new_password(args: &Args) -> Result<String, String> {
+    let password = if args.flag("--password-stdin") {
+        let mut line = String::new();
+        io::stdin()
+            .lock()
+            .read_line(&mut line)
+            .map_err(|e| format!("Could not read password: {e}"))?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    } else {
+        let password = rpassword::prompt_password("Password: ")
+            .map_err(|e| format!("Could not read password: {e}"))?;
+        let confirmation = rpassword::prompt_password("Confirm password: ")
+            .map_err(|e| format!("Could not read password: {e}"))?;
+        if password != confirmation {
+            return Err("Passwords do not match".to_string());
+        }
+        password
+    };
+
+    if password.len() < 6 {
+        return Err("Password must be at least 6 characters long".to_string());
+    }
+    Ok(password)
+}
@@ -1,12 +1,19 @@
+use crate::audit::{self, Actor};
+use crate::auth::{REFRESH_TOKEN_DAYS, generate_token, hash_token};
+use crate::calendar::{Calendar, Closure, DATETIME_FORMAT, OpeningHours};
+use crate::events::{self, DomainEvent};
+use crate::mail;
+use crate::registration;
+use crate::totp;
 use bcrypt::{DEFAULT_COST, hash, verify};
-use jsonwebtoken::{EncodingKey, Header, encode};
-use rusqlite::Error as RusqliteError;
+use chrono::{Duration, NaiveDateTime, Utc};
+use rand::Rng;
 use rusqlite::Result;
-use rusqlite::params;
-use rusqlite::{Connection, Result as SqliteResult};
-use serde::{Deserialize, Serialize};
+use rusqlite::{Connection, OptionalExtension, Result as SqliteResult};
+use rusqlite::{Row, params};
+use serde::Serialize;
 use serde_json::{Value, json};
-use std::env;
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
 
 #[derive(Clone)]
@@ -23,6 +30,479 @@ pub struct Book {
     pub publication_year: String,
     pub genre: String,
     pub number_of_copies: i32,
+    pub total_copies: i32,
+}
+
+pub struct NewBook<'a> {
+    pub title: &'a str,
+    pub author: &'a str,
+    pub isbn: &'a str,
+    pub publication_year: &'a str,
+    pub genre: &'a str,
+    pub number_of_copies: i32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Item {
+    pub id: i64,
+    pub book_id: i64,
+    pub barcode: String,
+    pub shelf_location: String,
+    pub condition: String,
+    pub status: String,
+    pub branch_id: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Branch {
+    pub id: i64,
+    pub name: String,
+    pub address: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BranchAvailability {
+    pub branch_id: i64,
+    pub name: String,
+    pub available: i64,
+    pub total: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BookDetail {
+    #[serde(flatten)]
+    pub book: Book,
+    pub branches: Vec<BranchAvailability>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Hold {
+    pub id: i64,
+    pub user_id: i64,
+    pub username: String,
+    pub book_id: i64,
+    pub title: String,
+    pub pickup_branch_id: i64,
+    pub pickup_branch: String,
+    pub item_id: Option<i64>,
+    pub status: String,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Patron {
+    pub id: i64,
+    pub username: String,
+    pub role: String,
+    pub card_number: String,
+    pub active: bool,
+    pub membership_expires_at: Option<String>,
+    pub membership_expired: bool,
+}
+
+const PATRON_COLUMNS: &str = "id, username, role, card_number, active, membership_expires_at,
+    COALESCE(membership_expires_at < date('now'), 0)";
+
+// Contact details patrons keep up to date themselves. For each field, None
+// leaves it alone and Some(None) clears it.
+pub struct ProfileUpdate<'a> {
+    pub full_name: Option<Option<&'a str>>,
+    pub phone: Option<Option<&'a str>>,
+    pub address: Option<Option<&'a str>>,
+    pub date_of_birth: Option<Option<&'a str>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Checkout {
+    pub borrowed_id: i64,
+    pub barcode: String,
+    pub title: String,
+    pub due_date: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckIn {
+    pub borrowed_id: i64,
+    pub user_id: i64,
+    pub username: String,
+    pub barcode: String,
+    pub title: String,
+    pub item_status: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Kiosk {
+    pub id: i64,
+    pub name: String,
+    pub branch_id: Option<i64>,
+    pub active: bool,
+    pub created_at: String,
+}
+
+#[derive(Debug)]
+pub struct KioskSession {
+    pub session_id: i64,
+    pub kiosk_id: i64,
+    pub user_id: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Notice {
+    pub id: i64,
+    pub user_id: i64,
+    pub username: String,
+    pub borrowed_id: i64,
+    pub title: String,
+    pub kind: String,
+    pub level: i64,
+    pub due_date: String,
+    pub status: String,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobRun {
+    pub job: String,
+    pub run_key: String,
+    pub processed: i64,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Notification {
+    pub id: i64,
+    pub kind: String,
+    pub title: String,
+    pub message: String,
+    pub read: bool,
+    pub created_at: String,
+}
+
+// A message on the live feeds. Catalog events have no user_id and go to everyone.
+#[derive(Debug)]
+pub struct Event {
+    pub id: i64,
+    pub user_id: Option<i64>,
+    pub kind: String,
+    pub data: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Session {
+    pub id: i64,
+    pub user_agent: String,
+    pub ip: String,
+    pub created_at: String,
+    pub last_seen_at: String,
+    pub expires_at: String,
+    // Whether this is the session making the request
+    pub current: bool,
+}
+
+// How long a password reset code stays usable.
+pub const PASSWORD_RESET_MINUTES: i64 = 30;
+
+// How long the partial token from a password login waits for the second factor.
+pub const LOGIN_CHALLENGE_MINUTES: i64 = 5;
+
+// A login that has passed the password check and is waiting for a TOTP code.
+pub struct LoginChallenge {
+    pub user_id: i64,
+    pub username: String,
+    pub role: String,
+    pub user_agent: String,
+    pub ip: String,
+    pub totp_enabled: bool,
+}
+
+// bcrypt hash (at DEFAULT_COST) of a throwaway string, checked when a login names
+// a user that doesn't exist.
+const DUMMY_PASSWORD_HASH: &str = "$2b$12$niw6SlhY0KSxknNFSba9iuaSmGBXINTtrapF.cWhSBvb3LXOZopfe";
+
+// Failed logins are forgotten once none have happened for this long.
+pub const LOGIN_FAILURE_WINDOW_MINUTES: i64 = 15;
+pub const LOGIN_LOCKOUT_MINUTES: i64 = 15;
+// Cap on the wait between attempts before a lockout kicks in.
+pub const MAX_LOGIN_DELAY_SECONDS: i64 = 60;
+
+pub struct LoginLimit {
+    pub scope: &'static str,
+    // Failures allowed before each further attempt has to wait 1, 2, 4... seconds
+    pub free_attempts: i64,
+    pub lockout_after: i64,
+}
+
+// Accounts are tracked by the username that was tried, whether or not it exists,
+// so throttling looks the same either way. An IP can make more attempts, since a
+// branch's public machines share one.
+pub const LOGIN_LIMITS: [LoginLimit; 2] = [
+    LoginLimit {
+        scope: "account",
+        free_attempts: 3,
+        lockout_after: 10,
+    },
+    LoginLimit {
+        scope: "ip",
+        free_attempts: 10,
+        lockout_after: 50,
+    },
+];
+
+// Kiosk PIN logins are tracked by the card number that was tried and by the
+// kiosk. A kiosk is shared by every patron at its branch, like an IP.
+pub const KIOSK_LOGIN_LIMITS: [LoginLimit; 2] = [
+    LoginLimit {
+        scope: "card",
+        free_attempts: 3,
+        lockout_after: 10,
+    },
+    LoginLimit {
+        scope: "kiosk",
+        free_attempts: 10,
+        lockout_after: 50,
+    },
+];
+
+pub enum KioskLogin {
+    Started { token: String, expires_at: String },
+    // Bad device credential, unknown card or wrong PIN
+    Invalid,
+    // Seconds until the card can be tried on this kiosk again
+    RetryAfter(i64),
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoginLockout {
+    pub scope: String,
+    pub key: String,
+    pub failures: i64,
+    pub last_failure_at: String,
+    pub locked_until: Option<String>,
+}
+
+// last_seen_at is only written when it is older than this, so busy sessions
+// don't cost a write per request.
+const SESSION_TOUCH_SECONDS: i64 = 60;
+
+pub enum RefreshOutcome {
+    Rotated {
+        session_id: i64,
+        username: String,
+        role: String,
+        refresh_token: String,
+    },
+    // The token had already been exchanged; its session is now revoked.
+    Reused,
+    Invalid,
+}
+
+// Outcome of changing a user's role or deactivating them.
+pub enum UserChange {
+    Changed,
+    Unchanged,
+    NotFound,
+    // Demoting or deactivating the only active admin would leave nobody able to
+    // assign roles.
+    LastAdmin,
+}
+
+pub enum EmailVerification {
+    Verified,
+    AlreadyVerified,
+    // Unknown user, or the email has changed since the token was sent
+    Invalid,
+}
+
+pub enum RegistrationReview {
+    Reviewed,
+    NotFound,
+    NotPending,
+}
+
+pub enum AdminBootstrap {
+    Created(i64),
+    AdminExists,
+    UsernameTaken,
+}
+
+pub enum UserUpdate {
+    Updated,
+    NotFound,
+    UsernameTaken,
+    UnknownBranch,
+}
+
+pub enum Renewal {
+    Renewed(String),
+    // Unknown loan, overdue, or out of renewals
+    Refused,
+    // Someone has a hold on the book, so the copy has to come back
+    HoldWaiting,
+    // The borrower is deactivated, not yet approved, or their membership expired
+    CannotBorrow,
+}
+
+pub enum ItemUpdate {
+    Updated,
+    BarcodeTaken,
+    // Unknown item, nothing to change, or a status only circulation may set
+    Refused,
+}
+
+pub enum UserDeletion {
+    Deleted,
+    NotFound,
+    LastAdmin,
+    // Loans still out or money owed; these have to be settled first, since the
+    // loans and ledger go with the account.
+    Outstanding {
+        active_loans: i64,
+        balance_cents: i64,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserSummary {
+    pub id: i64,
+    pub username: String,
+    pub role: String,
+    pub email: Option<String>,
+    pub card_number: Option<String>,
+    pub branch_id: Option<i64>,
+    pub created_at: Option<String>,
+    pub active: bool,
+    pub deactivated_at: Option<String>,
+    pub deactivated_reason: Option<String>,
+    pub active_loans: i64,
+    pub balance_cents: i64,
+    pub full_name: Option<String>,
+    pub phone: Option<String>,
+    pub address: Option<String>,
+    pub date_of_birth: Option<String>,
+    pub membership_expires_at: Option<String>,
+    pub membership_expired: bool,
+    pub registration_status: String,
+    pub registration_reason: Option<String>,
+    pub email_verified_at: Option<String>,
+}
+
+const USER_SUMMARY_COLUMNS: &str = "u.id, u.username, u.role, u.email, u.card_number,
+    u.branch_id, u.created_at, u.active, u.deactivated_at, u.deactivated_reason,
+    (SELECT COUNT(*) FROM borrowed WHERE user_id = u.id),
+    (SELECT COALESCE(SUM(amount_cents), 0) FROM ledger WHERE user_id = u.id),
+    u.full_name, u.phone, u.address, u.date_of_birth, u.membership_expires_at,
+    COALESCE(u.membership_expires_at < date('now'), 0),
+    u.registration_status, u.registration_reason, u.email_verified_at";
+
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor: Option<String>,
+    pub actor_id: Option<i64>,
+    pub source_ip: String,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: i64,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub created_at: String,
+}
+
+// Every field is optional; `from` and `to` are inclusive YYYY-MM-DD dates.
+#[derive(Debug)]
+pub struct AuditFilter<'a> {
+    pub actor: Option<&'a str>,
+    pub action: Option<&'a str>,
+    pub target_type: Option<&'a str>,
+    pub target_id: Option<i64>,
+    pub from: Option<&'a str>,
+    pub to: Option<&'a str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EventSubscriber {
+    pub subscriber: String,
+    pub last_event_id: i64,
+    pub pending: i64,
+    pub failures: i64,
+    pub last_error: Option<String>,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    pub events: Vec<String>,
+    pub active: bool,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    #[serde(skip)]
+    pub url: String,
+    #[serde(skip)]
+    pub secret: String,
+    pub event_id: i64,
+    pub event_type: String,
+    pub payload: String,
+    pub status: String,
+    pub attempts: i64,
+    pub next_attempt_at: String,
+    pub response_status: Option<i64>,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub delivered_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EmailPreferences {
+    pub email: Option<String>,
+    pub categories: BTreeMap<&'static str, bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutboxEmail {
+    pub id: i64,
+    pub user_id: i64,
+    pub to_address: String,
+    pub category: String,
+    pub subject: String,
+    pub body: String,
+    pub status: String,
+    pub attempts: i64,
+    pub next_attempt_at: String,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub sent_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LedgerEntry {
+    pub id: i64,
+    pub user_id: i64,
+    pub item_id: Option<i64>,
+    pub kind: String,
+    pub amount_cents: i64,
+    pub note: String,
+    pub reverses_id: Option<i64>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Transfer {
+    pub id: i64,
+    pub item_id: i64,
+    pub barcode: String,
+    pub from_branch_id: i64,
+    pub to_branch_id: i64,
+    pub hold_id: Option<i64>,
+    pub sent_at: String,
+    pub received_at: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -31,15 +511,583 @@ pub struct BorrowedBook {
     pub user_id: i64,
     pub username: String,
     pub due_date: String,
+    pub renewals: i64,
+    pub overdue_days: i64,
+    pub fine_cents: i64,
+    pub item_id: i64,
+    pub barcode: String,
     pub book: Book,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: String, // subject, like username or user ID
-    role: String,
-    exp: usize, // expiration timestamp
-}
+pub const LOAN_PERIOD_DAYS: i64 = 7;
+// New patrons and renewals get this many days of membership. Staff accounts
+// don't expire unless a membership is set for them.
+pub const MEMBERSHIP_DAYS: i64 = 365;
+// Library cards are 14 digits: this prefix, 11 random digits and a Luhn check
+// digit, so scanners and the desk can catch misreads.
+pub const CARD_NUMBER_PREFIX: &str = "29";
+pub const MAX_RENEWALS: i64 = 2;
+// Fines accrue per open day a loan is overdue; closed days are free.
+pub const DAILY_FINE_CENTS: i64 = 25;
+pub const DEFAULT_REPLACEMENT_FEE_CENTS: i64 = 2500;
+// Overdue notices escalate to level 1, 2 and 3 once a loan is this many days late.
+pub const OVERDUE_ESCALATION_DAYS: [i64; 3] = [1, 7, 14];
+pub const EMAIL_CATEGORIES: [&str; 6] = [
+    "hold_ready",
+    "due_soon",
+    "overdue",
+    "account",
+    "password_reset",
+    "verify_email",
+];
+// Account emails (password resets and the like) can't be switched off.
+pub const OPTIONAL_EMAIL_CATEGORIES: [&str; 3] = ["hold_ready", "due_soon", "overdue"];
+// Failed emails are retried after 1, 2, 4, 8... minutes and given up on after this many attempts.
+pub const MAX_EMAIL_ATTEMPTS: i64 = 6;
+// Webhook deliveries back off like emails and are given up on after this many attempts.
+pub const MAX_WEBHOOK_ATTEMPTS: i64 = 6;
+// Kiosk sessions end after this many idle minutes; each action extends them.
+pub const KIOSK_SESSION_MINUTES: i64 = 5;
+
+pub const ITEM_STATUSES: [&str; 7] = [
+    "available",
+    "on_loan",
+    "on_hold",
+    "in_transit",
+    "lost",
+    "in_repair",
+    "withdrawn",
+];
+// Statuses owned by loans, holds and transfers; items only enter or leave them there.
+const CIRCULATION_STATUSES: [&str; 3] = ["on_loan", "on_hold", "in_transit"];
+
+const ITEM_COLUMNS: &str = "id, book_id, barcode, shelf_location, condition, status, branch_id";
+
+// Availability is derived from the copies in `items`; `number_of_copies` is the
+// count currently on the shelf and `total_copies` excludes lost and withdrawn ones.
+const BOOK_COLUMNS: &str = "b.id, b.title, b.author, b.isbn, b.publication_year, b.genre,
+    (SELECT COUNT(*) FROM items i WHERE i.book_id = b.id AND i.status = 'available'),
+    (SELECT COUNT(*) FROM items i WHERE i.book_id = b.id AND i.status NOT IN ('lost', 'withdrawn'))";
+
+// Schema changes applied on top of the base tables, tracked with PRAGMA user_version.
+// Append new entries; never edit one that has already shipped.
+const MIGRATIONS: &[&str] = &[
+    // 1: per-copy inventory. Every available copy and every open loan of the old
+    // `number_of_copies` counter becomes an item with a generated barcode.
+    "CREATE TABLE items (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        book_id INTEGER NOT NULL,
+        barcode TEXT UNIQUE NOT NULL,
+        shelf_location TEXT NOT NULL DEFAULT '',
+        condition TEXT NOT NULL DEFAULT 'good',
+        status TEXT NOT NULL DEFAULT 'available',
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        FOREIGN KEY(book_id) REFERENCES books(id) ON DELETE CASCADE
+    );
+    WITH RECURSIVE copies(book_id, n) AS (
+        SELECT id, 1 FROM books WHERE number_of_copies >= 1
+        UNION ALL
+        SELECT c.book_id, c.n + 1 FROM copies c JOIN books b ON b.id = c.book_id
+        WHERE c.n < b.number_of_copies
+    )
+    INSERT INTO items (book_id, barcode) SELECT book_id, 'migrated-' || book_id || '-' || n FROM copies;
+    INSERT INTO items (book_id, barcode, status)
+        SELECT book_id, 'migrated-loan-' || id, 'on_loan' FROM borrowed;
+    ALTER TABLE borrowed ADD COLUMN item_id INTEGER REFERENCES items(id);
+    UPDATE borrowed SET item_id = (SELECT id FROM items WHERE barcode = 'migrated-loan-' || borrowed.id);
+    UPDATE items SET barcode = printf('%08d', id);
+    ALTER TABLE books DROP COLUMN number_of_copies;",
+    // 2: library calendar. `due_date` stops being generated so borrowing and renewing
+    // can roll it past closed days; the AUTOINCREMENT counter is carried over.
+    "CREATE TABLE borrowed_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        book_id INTEGER NOT NULL,
+        item_id INTEGER REFERENCES items(id),
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        due_date DATETIME NOT NULL,
+        renewals INTEGER NOT NULL DEFAULT 0,
+        FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE,
+        FOREIGN KEY(book_id) REFERENCES books(id) ON DELETE CASCADE
+    );
+    INSERT INTO borrowed_new (id, user_id, book_id, item_id, created_at, due_date)
+        SELECT id, user_id, book_id, item_id, created_at, due_date FROM borrowed;
+    DELETE FROM sqlite_sequence WHERE name = 'borrowed_new';
+    UPDATE sqlite_sequence SET name = 'borrowed_new' WHERE name = 'borrowed';
+    DROP TABLE borrowed;
+    ALTER TABLE borrowed_new RENAME TO borrowed;
+    CREATE TABLE opening_hours (
+        weekday INTEGER PRIMARY KEY CHECK (weekday BETWEEN 0 AND 6),
+        opens TEXT NOT NULL DEFAULT '09:00',
+        closes TEXT NOT NULL DEFAULT '17:00',
+        closed INTEGER NOT NULL DEFAULT 0
+    );
+    INSERT INTO opening_hours (weekday, closed) VALUES (0, 0), (1, 0), (2, 0), (3, 0), (4, 0), (5, 0), (6, 1);
+    CREATE TABLE closures (
+        date TEXT PRIMARY KEY,
+        reason TEXT NOT NULL DEFAULT ''
+    );",
+    // 3: branches. Existing copies and staff belong to the first branch; holds pick
+    // a branch to collect from and transfers move copies between branches.
+    "CREATE TABLE branches (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT UNIQUE NOT NULL,
+        address TEXT NOT NULL DEFAULT '',
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+    );
+    INSERT INTO branches (name) VALUES ('Main Library');
+    ALTER TABLE items ADD COLUMN branch_id INTEGER REFERENCES branches(id);
+    UPDATE items SET branch_id = (SELECT MIN(id) FROM branches);
+    ALTER TABLE users ADD COLUMN branch_id INTEGER REFERENCES branches(id);
+    UPDATE users SET branch_id = (SELECT MIN(id) FROM branches) WHERE role = 'admin';
+    CREATE TABLE holds (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        book_id INTEGER NOT NULL,
+        pickup_branch_id INTEGER NOT NULL,
+        item_id INTEGER,
+        status TEXT NOT NULL DEFAULT 'waiting',
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE,
+        FOREIGN KEY(book_id) REFERENCES books(id) ON DELETE CASCADE,
+        FOREIGN KEY(pickup_branch_id) REFERENCES branches(id),
+        FOREIGN KEY(item_id) REFERENCES items(id) ON DELETE SET NULL
+    );
+    CREATE TABLE transfers (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        item_id INTEGER NOT NULL,
+        from_branch_id INTEGER NOT NULL,
+        to_branch_id INTEGER NOT NULL,
+        hold_id INTEGER,
+        sent_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        received_at DATETIME,
+        FOREIGN KEY(item_id) REFERENCES items(id) ON DELETE CASCADE,
+        FOREIGN KEY(from_branch_id) REFERENCES branches(id),
+        FOREIGN KEY(to_branch_id) REFERENCES branches(id),
+        FOREIGN KEY(hold_id) REFERENCES holds(id) ON DELETE SET NULL
+    );",
+    // 4: patron ledger. Charges are positive, credits negative; a reversal points at
+    // the entry it cancels so a found item can't be refunded twice.
+    "CREATE TABLE ledger (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        item_id INTEGER,
+        kind TEXT NOT NULL,
+        amount_cents INTEGER NOT NULL,
+        note TEXT NOT NULL DEFAULT '',
+        reverses_id INTEGER,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE,
+        FOREIGN KEY(item_id) REFERENCES items(id) ON DELETE SET NULL,
+        FOREIGN KEY(reverses_id) REFERENCES ledger(id)
+    );",
+    // 5: library card numbers, 14 digits so they print as scannable patron barcodes.
+    "ALTER TABLE users ADD COLUMN card_number TEXT;
+    UPDATE users SET card_number = printf('29%012d', id);
+    CREATE UNIQUE INDEX idx_users_card_number ON users(card_number);",
+    // 6: self-checkout kiosks. Devices hold a hashed secret; patrons sign in with
+    // card number and PIN, and every kiosk action records both.
+    "ALTER TABLE users ADD COLUMN pin_hash TEXT;
+    CREATE TABLE kiosks (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL,
+        secret_hash TEXT NOT NULL,
+        branch_id INTEGER,
+        active INTEGER NOT NULL DEFAULT 1,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        FOREIGN KEY(branch_id) REFERENCES branches(id)
+    );
+    CREATE TABLE kiosk_sessions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        kiosk_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        token_hash TEXT UNIQUE NOT NULL,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        expires_at DATETIME NOT NULL,
+        ended_at DATETIME,
+        FOREIGN KEY(kiosk_id) REFERENCES kiosks(id) ON DELETE CASCADE,
+        FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+    );
+    CREATE TABLE kiosk_activity (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        kiosk_id INTEGER NOT NULL,
+        session_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        action TEXT NOT NULL,
+        barcode TEXT NOT NULL,
+        borrowed_id INTEGER,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        FOREIGN KEY(kiosk_id) REFERENCES kiosks(id) ON DELETE CASCADE,
+        FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+    );",
+    // 7: scheduled jobs and loan notices. A job claims its run key in the same
+    // transaction as its work, and notices are unique per loan, level and due
+    // date, so a rerun or restart never enqueues the same notice twice.
+    "CREATE TABLE job_runs (
+        job TEXT NOT NULL,
+        run_key TEXT NOT NULL,
+        processed INTEGER NOT NULL DEFAULT 0,
+        started_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        finished_at DATETIME,
+        PRIMARY KEY(job, run_key)
+    );
+    CREATE TABLE notices (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        borrowed_id INTEGER NOT NULL,
+        book_id INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        level INTEGER NOT NULL,
+        due_date DATETIME NOT NULL,
+        status TEXT NOT NULL DEFAULT 'pending',
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        UNIQUE(borrowed_id, kind, level, due_date),
+        FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+    );",
+    // 8: email. Patrons may give an address and opt out of categories; messages
+    // are rendered into a persistent outbox that the scheduler delivers over SMTP.
+    "ALTER TABLE users ADD COLUMN email TEXT;
+    CREATE TABLE email_opt_outs (
+        user_id INTEGER NOT NULL,
+        category TEXT NOT NULL,
+        PRIMARY KEY(user_id, category),
+        FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+    );
+    CREATE TABLE outbox (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        to_address TEXT NOT NULL,
+        category TEXT NOT NULL,
+        subject TEXT NOT NULL,
+        body TEXT NOT NULL,
+        status TEXT NOT NULL DEFAULT 'pending',
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        last_error TEXT,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        sent_at DATETIME,
+        FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+    );
+    CREATE INDEX idx_outbox_due ON outbox(status, next_attempt_at);",
+    // 9: in-app notification inbox shown on the patron dashboard.
+    "CREATE TABLE notifications (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        title TEXT NOT NULL,
+        message TEXT NOT NULL,
+        read_at DATETIME,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+    );
+    CREATE INDEX idx_notifications_user ON notifications(user_id, read_at);",
+    // 10: change events for the live stream. Triggers record them in the same
+    // transaction as the change, so no code path can forget to. Catalog events
+    // have no user_id and go to everyone; the rest only to that user.
+    "CREATE TABLE events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER,
+        kind TEXT NOT NULL,
+        data TEXT NOT NULL,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TRIGGER events_book_added AFTER INSERT ON books BEGIN
+        INSERT INTO events (kind, data)
+        VALUES ('book_added', json_object('book_id', NEW.id, 'title', NEW.title));
+    END;
+    CREATE TRIGGER events_book_edited AFTER UPDATE ON books BEGIN
+        INSERT INTO events (kind, data)
+        VALUES ('book_edited', json_object('book_id', NEW.id, 'title', NEW.title,
+            'author', NEW.author, 'isbn', NEW.isbn,
+            'publication_year', NEW.publication_year, 'genre', NEW.genre));
+    END;
+    CREATE TRIGGER events_book_deleted AFTER DELETE ON books BEGIN
+        INSERT INTO events (kind, data) VALUES ('book_deleted', json_object('book_id', OLD.id));
+    END;
+    CREATE VIEW book_copy_counts AS
+        SELECT b.id AS book_id,
+            (SELECT COUNT(*) FROM items i WHERE i.book_id = b.id AND i.status = 'available') AS available,
+            (SELECT COUNT(*) FROM items i WHERE i.book_id = b.id AND i.status NOT IN ('lost', 'withdrawn')) AS total
+        FROM books b;
+    CREATE TRIGGER events_item_added AFTER INSERT ON items BEGIN
+        INSERT INTO events (kind, data)
+        SELECT 'copies_changed', json_object('book_id', book_id, 'available', available, 'total', total)
+        FROM book_copy_counts WHERE book_id = NEW.book_id;
+    END;
+    CREATE TRIGGER events_item_status AFTER UPDATE OF status ON items
+    WHEN OLD.status IS NOT NEW.status BEGIN
+        INSERT INTO events (kind, data)
+        SELECT 'copies_changed', json_object('book_id', book_id, 'available', available, 'total', total)
+        FROM book_copy_counts WHERE book_id = NEW.book_id;
+    END;
+    CREATE TRIGGER events_loan_opened AFTER INSERT ON borrowed BEGIN
+        INSERT INTO events (user_id, kind, data)
+        VALUES (NEW.user_id, 'loans_changed',
+            json_object('action', 'borrowed', 'borrowed_id', NEW.id, 'book_id', NEW.book_id, 'due_date', NEW.due_date));
+    END;
+    CREATE TRIGGER events_loan_renewed AFTER UPDATE OF due_date ON borrowed BEGIN
+        INSERT INTO events (user_id, kind, data)
+        VALUES (NEW.user_id, 'loans_changed',
+            json_object('action', 'renewed', 'borrowed_id', NEW.id, 'book_id', NEW.book_id, 'due_date', NEW.due_date));
+    END;
+    CREATE TRIGGER events_loan_closed AFTER DELETE ON borrowed BEGIN
+        INSERT INTO events (user_id, kind, data)
+        VALUES (OLD.user_id, 'loans_changed',
+            json_object('action', 'returned', 'borrowed_id', OLD.id, 'book_id', OLD.book_id));
+    END;
+    CREATE TRIGGER events_hold_status AFTER UPDATE OF status ON holds
+    WHEN OLD.status IS NOT NEW.status BEGIN
+        INSERT INTO events (user_id, kind, data)
+        VALUES (NEW.user_id, 'hold_updated',
+            json_object('hold_id', NEW.id, 'book_id', NEW.book_id, 'status', NEW.status));
+    END;
+    CREATE TRIGGER events_notification AFTER INSERT ON notifications BEGIN
+        INSERT INTO events (user_id, kind, data)
+        VALUES (NEW.user_id, 'notification',
+            json_object('id', NEW.id, 'kind', NEW.kind, 'title', NEW.title, 'message', NEW.message));
+    END;",
+    // 11: outbound webhooks. Each subscription keeps a cursor into events; new
+    // events become deliveries, unique per webhook and event.
+    "CREATE TABLE webhooks (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        url TEXT NOT NULL,
+        secret TEXT NOT NULL,
+        events TEXT NOT NULL,
+        active INTEGER NOT NULL DEFAULT 1,
+        last_event_id INTEGER NOT NULL DEFAULT 0,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE webhook_deliveries (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        webhook_id INTEGER NOT NULL,
+        event_id INTEGER NOT NULL,
+        event_type TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        status TEXT NOT NULL DEFAULT 'pending',
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        response_status INTEGER,
+        last_error TEXT,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        delivered_at DATETIME,
+        UNIQUE(webhook_id, event_id),
+        FOREIGN KEY(webhook_id) REFERENCES webhooks(id) ON DELETE CASCADE
+    );
+    CREATE INDEX idx_webhook_deliveries_due ON webhook_deliveries(status, next_attempt_at);",
+    // 12: domain event outbox, one cursor per subscriber, and the full-text catalog
+    // index the search_index subscriber maintains (seeded from existing books).
+    "CREATE TABLE domain_events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        event_type TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE event_cursors (
+        subscriber TEXT PRIMARY KEY,
+        last_event_id INTEGER NOT NULL DEFAULT 0,
+        failures INTEGER NOT NULL DEFAULT 0,
+        last_error TEXT,
+        updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE VIRTUAL TABLE book_search USING fts5(title, author, isbn, genre);
+    INSERT INTO book_search (rowid, title, author, isbn, genre)
+        SELECT id, title, author, isbn, genre FROM books;",
+    // 13: append-only audit log. `before` and `after` hold the changed fields as JSON.
+    "CREATE TABLE audit_log (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        actor TEXT,
+        actor_id INTEGER,
+        source_ip TEXT NOT NULL,
+        action TEXT NOT NULL,
+        target_type TEXT NOT NULL,
+        target_id INTEGER NOT NULL,
+        before TEXT,
+        after TEXT,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE INDEX idx_audit_log_actor ON audit_log(actor, created_at);
+    CREATE INDEX idx_audit_log_target ON audit_log(target_type, target_id, created_at);
+    CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log BEGIN
+        SELECT RAISE(ABORT, 'audit log is append-only');
+    END;
+    CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log BEGIN
+        SELECT RAISE(ABORT, 'audit log is append-only');
+    END;",
+    // 14: login sessions and their rotating refresh tokens. Used tokens are kept so
+    // a replayed one can be spotted.
+    "CREATE TABLE sessions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        last_seen_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        expires_at DATETIME NOT NULL,
+        revoked_at DATETIME,
+        revoked_reason TEXT,
+        FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+    );
+    CREATE TABLE refresh_tokens (
+        token_hash TEXT PRIMARY KEY,
+        session_id INTEGER NOT NULL,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        used_at DATETIME,
+        FOREIGN KEY(session_id) REFERENCES sessions(id) ON DELETE CASCADE
+    );
+    CREATE INDEX idx_sessions_user ON sessions(user_id);",
+    // 15: the device each session was opened from
+    "ALTER TABLE sessions ADD COLUMN user_agent TEXT NOT NULL DEFAULT '';
+    ALTER TABLE sessions ADD COLUMN ip TEXT NOT NULL DEFAULT '';",
+    // 16: forgotten-password tokens, stored hashed and usable once.
+    "CREATE TABLE password_resets (
+        token_hash TEXT PRIMARY KEY,
+        user_id INTEGER NOT NULL,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        expires_at DATETIME NOT NULL,
+        used_at DATETIME,
+        FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+    );
+    CREATE INDEX idx_password_resets_user ON password_resets(user_id);",
+    // 17: failed logins, counted per submitted username and per source IP.
+    "CREATE TABLE login_failures (
+        scope TEXT NOT NULL,
+        key TEXT NOT NULL,
+        failures INTEGER NOT NULL DEFAULT 0,
+        last_failure_at DATETIME NOT NULL,
+        locked_until DATETIME,
+        PRIMARY KEY(scope, key)
+    );",
+    // 18: TOTP two-factor login. The secret is set when enrolment starts and only
+    // enforced once a first code confirms it. Recovery codes are stored hashed;
+    // login_challenges hold the partial token given out between password and code.
+    "ALTER TABLE users ADD COLUMN totp_secret TEXT;
+    ALTER TABLE users ADD COLUMN totp_enabled INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE users ADD COLUMN totp_last_step INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE recovery_codes (
+        user_id INTEGER NOT NULL,
+        code_hash TEXT NOT NULL,
+        used_at DATETIME,
+        PRIMARY KEY(user_id, code_hash),
+        FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+    );
+    CREATE TABLE login_challenges (
+        token_hash TEXT PRIMARY KEY,
+        user_id INTEGER NOT NULL,
+        user_agent TEXT NOT NULL DEFAULT '',
+        ip TEXT NOT NULL DEFAULT '',
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        expires_at DATETIME NOT NULL,
+        FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+    );",
+    // 19: deactivated accounts keep their loans and history but can't sign in,
+    // borrow or place holds.
+    "ALTER TABLE users ADD COLUMN active INTEGER NOT NULL DEFAULT 1;
+    ALTER TABLE users ADD COLUMN deactivated_at DATETIME;
+    ALTER TABLE users ADD COLUMN deactivated_reason TEXT;",
+    // 20: patron profiles. Existing patrons get a year's membership from the
+    // upgrade; staff accounts don't expire.
+    "ALTER TABLE users ADD COLUMN full_name TEXT;
+    ALTER TABLE users ADD COLUMN phone TEXT;
+    ALTER TABLE users ADD COLUMN address TEXT;
+    ALTER TABLE users ADD COLUMN date_of_birth DATE;
+    ALTER TABLE users ADD COLUMN membership_expires_at DATE;
+    UPDATE users SET membership_expires_at = date('now', '+365 days') WHERE role = 'user';",
+    // 21: self-registration can need a confirmed email or staff approval before
+    // the first login. Existing accounts are approved.
+    "ALTER TABLE users ADD COLUMN registration_status TEXT NOT NULL DEFAULT 'approved';
+    ALTER TABLE users ADD COLUMN registration_reason TEXT;
+    ALTER TABLE users ADD COLUMN registration_reviewed_at DATETIME;
+    ALTER TABLE users ADD COLUMN email_verified_at DATETIME;",
+    // 22: webhooks are fed by the `webhooks` domain event subscriber instead of
+    // scanning `events`. Its cursor starts at the newest event, and domain event
+    // ids skip past the change-event ids earlier deliveries were keyed on.
+    // hold.updated becomes hold.ready; copies.changed has no domain event.
+    "INSERT OR IGNORE INTO event_cursors (subscriber, last_event_id)
+        SELECT 'webhooks', IFNULL(MAX(id), 0) FROM domain_events;
+    INSERT INTO sqlite_sequence (name, seq)
+        SELECT 'domain_events', 0
+        WHERE NOT EXISTS(SELECT 1 FROM sqlite_sequence WHERE name = 'domain_events');
+    UPDATE sqlite_sequence
+        SET seq = MAX(seq, (SELECT IFNULL(MAX(event_id), 0) FROM webhook_deliveries))
+        WHERE name = 'domain_events';
+    UPDATE webhooks SET events = trim(
+        replace(
+            replace(',' || events || ',', ',copies.changed,', ','),
+            ',hold.updated,', ',hold.ready,'
+        ), ',');",
+    // 23: cards numbered by migration 5 fail the Luhn check, so they are reissued
+    // in the same shape as new cards: "29", 11 digits and a check digit. The 11
+    // digits are the user id plus a multiple of 10^10, so no two users share a
+    // candidate; the first one nobody holds yet is used. Kiosk sessions on the
+    // old cards end, and each change is audited like a staff reissue.
+    "CREATE TEMP TABLE card_reissues AS
+    WITH RECURSIVE
+        attempts(n) AS (SELECT 0 UNION ALL SELECT n + 1 FROM attempts WHERE n < 9),
+        positions(n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM positions WHERE n < 13),
+        bodies AS (
+            SELECT users.id, attempts.n AS attempt,
+                '29' || printf('%011d', attempts.n * 10000000000 + users.id) AS body
+            FROM users, attempts
+            WHERE users.card_number = printf('29%012d', users.id)
+        ),
+        candidates AS (
+            SELECT bodies.id, bodies.attempt, bodies.body || ((10 - SUM(
+                CASE WHEN positions.n % 2 = 1
+                    THEN CAST(substr(bodies.body, positions.n, 1) AS INTEGER) * 2
+                        - 9 * (CAST(substr(bodies.body, positions.n, 1) AS INTEGER) >= 5)
+                    ELSE CAST(substr(bodies.body, positions.n, 1) AS INTEGER)
+                END
+            ) % 10) % 10) AS card_number
+            FROM bodies, positions
+            GROUP BY bodies.id, bodies.attempt
+        ),
+        free AS (
+            SELECT id, card_number,
+                ROW_NUMBER() OVER (PARTITION BY id ORDER BY attempt) AS rank
+            FROM candidates
+            WHERE card_number NOT IN (
+                SELECT card_number FROM users WHERE card_number IS NOT NULL
+            )
+        )
+    SELECT users.id, users.card_number AS old_card_number, free.card_number
+    FROM free JOIN users ON users.id = free.id
+    WHERE free.rank = 1;
+    UPDATE users SET card_number = (
+        SELECT card_number FROM card_reissues WHERE card_reissues.id = users.id
+    ) WHERE id IN (SELECT id FROM card_reissues);
+    UPDATE kiosk_sessions SET ended_at = CURRENT_TIMESTAMP
+        WHERE user_id IN (SELECT id FROM card_reissues) AND ended_at IS NULL;
+    INSERT INTO audit_log (actor, source_ip, action, target_type, target_id, before, after)
+        SELECT 'migration', 'local', 'user.reissue_card', 'user', id,
+            json_object('card_number', old_card_number),
+            json_object('card_number', card_number)
+        FROM card_reissues;
+    DROP TABLE card_reissues;",
+    // 24: reset and verification emails that gave up still held their link.
+    "UPDATE outbox SET body = '[redacted]'
+        WHERE status = 'failed' AND category IN ('password_reset', 'verify_email');",
+    // 25: the live stream and desk feed read domain_events, so the trigger-fed
+    // events table goes. Domain event ids skip past its ids, so a client resuming
+    // with an old Last-Event-ID doesn't miss anything new.
+    "INSERT INTO sqlite_sequence (name, seq)
+        SELECT 'domain_events', 0
+        WHERE NOT EXISTS(SELECT 1 FROM sqlite_sequence WHERE name = 'domain_events');
+    UPDATE sqlite_sequence
+        SET seq = MAX(seq, (SELECT IFNULL(MAX(id), 0) FROM events))
+        WHERE name = 'domain_events';
+    DROP TRIGGER events_book_added;
+    DROP TRIGGER events_book_edited;
+    DROP TRIGGER events_book_deleted;
+    DROP TRIGGER events_item_added;
+    DROP TRIGGER events_item_status;
+    DROP TRIGGER events_loan_opened;
+    DROP TRIGGER events_loan_renewed;
+    DROP TRIGGER events_loan_closed;
+    DROP TRIGGER events_hold_status;
+    DROP TRIGGER events_notification;
+    DROP TABLE events;",
+    // 26: each webhook's cursor into `events` has been unused since the webhooks
+    // subscriber took over; event_cursors holds the only one.
+    "ALTER TABLE webhooks DROP COLUMN last_event_id;",
+];
 
 impl Database {
     pub fn new(db_path: &str) -> SqliteResult<Self> {
@@ -87,421 +1135,5480 @@ impl Database {
             [],
         )?;
 
+        Self::migrate(&conn)?;
+
         Ok(Database {
             connection: Arc::new(Mutex::new(conn)),
         })
     }
 
-    pub fn create_user(&self, username: &str, password: &str) -> SqliteResult<bool> {
+    fn migrate(conn: &Connection) -> SqliteResult<()> {
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.unchecked_transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", index + 1)?;
+            tx.commit()?;
+        }
+
+        Ok(())
+    }
+
+    // The schema version of the database file, without migrating it. New files are 0.
+    pub fn schema_version(db_path: &str) -> SqliteResult<usize> {
+        let conn = Connection::open(db_path)?;
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+    }
+
+    pub fn latest_schema_version() -> usize {
+        MIGRATIONS.len()
+    }
+
+    // A consistent copy of the whole database, taken while the server may be running.
+    pub fn backup(&self, path: &str) -> SqliteResult<()> {
+        let conn = self.connection.lock().unwrap();
+        conn.execute("VACUUM INTO ?1", [path])?;
+        Ok(())
+    }
+
+    fn book_from_row(row: &Row, offset: usize) -> SqliteResult<Book> {
+        Ok(Book {
+            id: row.get(offset)?,
+            title: row.get(offset + 1)?,
+            author: row.get(offset + 2)?,
+            isbn: row.get(offset + 3)?,
+            publication_year: row.get(offset + 4)?,
+            genre: row.get(offset + 5)?,
+            number_of_copies: row.get(offset + 6)?,
+            total_copies: row.get(offset + 7)?,
+        })
+    }
+
+    fn item_from_row(row: &Row) -> SqliteResult<Item> {
+        Ok(Item {
+            id: row.get(0)?,
+            book_id: row.get(1)?,
+            barcode: row.get(2)?,
+            shelf_location: row.get(3)?,
+            condition: row.get(4)?,
+            status: row.get(5)?,
+            branch_id: row.get(6)?,
+        })
+    }
+
+    // Snapshots of the records the audit log tracks, as they stand inside `conn`.
+    fn book_snapshot(conn: &Connection, book_id: i64) -> SqliteResult<Option<Value>> {
+        conn.query_row(
+            &format!("SELECT {BOOK_COLUMNS} FROM books b WHERE b.id = ?1"),
+            [book_id],
+            |row| Self::book_from_row(row, 0).map(|book| json!(book)),
+        )
+        .optional()
+    }
+
+    fn item_snapshot(conn: &Connection, item_id: i64) -> SqliteResult<Value> {
+        conn.query_row(
+            &format!("SELECT {ITEM_COLUMNS} FROM items WHERE id = ?1"),
+            [item_id],
+            |row| Self::item_from_row(row).map(|item| json!(item)),
+        )
+    }
+
+    fn hold_snapshot(conn: &Connection, hold_id: i64) -> SqliteResult<Option<Value>> {
+        conn.query_row(
+            "SELECT user_id, book_id, pickup_branch_id, item_id, status FROM holds WHERE id = ?1",
+            [hold_id],
+            |row| {
+                Ok(json!({
+                    "user_id": row.get::<_, i64>(0)?,
+                    "book_id": row.get::<_, i64>(1)?,
+                    "pickup_branch_id": row.get::<_, i64>(2)?,
+                    "item_id": row.get::<_, Option<i64>>(3)?,
+                    "status": row.get::<_, String>(4)?,
+                }))
+            },
+        )
+        .optional()
+    }
+
+    fn loan_snapshot(conn: &Connection, borrowed_id: i64) -> SqliteResult<Option<Value>> {
+        conn.query_row(
+            "SELECT user_id, book_id, item_id, due_date, renewals FROM borrowed WHERE id = ?1",
+            [borrowed_id],
+            |row| {
+                Ok(json!({
+                    "user_id": row.get::<_, i64>(0)?,
+                    "book_id": row.get::<_, i64>(1)?,
+                    "item_id": row.get::<_, i64>(2)?,
+                    "due_date": row.get::<_, String>(3)?,
+                    "renewals": row.get::<_, i64>(4)?,
+                }))
+            },
+        )
+        .optional()
+    }
+
+    fn user_snapshot(conn: &Connection, user_id: i64) -> SqliteResult<Option<Value>> {
+        conn.query_row(
+            "SELECT username, role, card_number, branch_id, email, full_name, phone, address,
+                date_of_birth, membership_expires_at, registration_status
+             FROM users WHERE id = ?1",
+            [user_id],
+            |row| {
+                Ok(json!({
+                    "username": row.get::<_, String>(0)?,
+                    "role": row.get::<_, String>(1)?,
+                    "card_number": row.get::<_, Option<String>>(2)?,
+                    "branch_id": row.get::<_, Option<i64>>(3)?,
+                    "email": row.get::<_, Option<String>>(4)?,
+                    "full_name": row.get::<_, Option<String>>(5)?,
+                    "phone": row.get::<_, Option<String>>(6)?,
+                    "address": row.get::<_, Option<String>>(7)?,
+                    "date_of_birth": row.get::<_, Option<String>>(8)?,
+                    "membership_expires_at": row.get::<_, Option<String>>(9)?,
+                    "registration_status": row.get::<_, String>(10)?,
+                }))
+            },
+        )
+        .optional()
+    }
+
+    // Barcodes default to the zero-padded id the new row is about to receive (or
+    // the next free number after it) and copies without a branch go to the first one.
+    fn insert_item(
+        conn: &Connection,
+        book_id: i64,
+        barcode: Option<&str>,
+        shelf_location: &str,
+        condition: &str,
+        branch_id: Option<i64>,
+    ) -> SqliteResult<i64> {
+        let barcode = match barcode {
+            Some(barcode) => barcode.to_string(),
+            None => Self::free_barcode(conn)?,
+        };
+        conn.execute(
+            "INSERT INTO items (book_id, barcode, shelf_location, condition, branch_id)
+             VALUES (
+                ?1,
+                ?2,
+                ?3,
+                ?4,
+                COALESCE(?5, (SELECT MIN(id) FROM branches))
+             )",
+            params![book_id, barcode, shelf_location, condition, branch_id],
+        )?;
+        let item_id = conn.last_insert_rowid();
+        events::emit(conn, &DomainEvent::ItemAdded { item_id, book_id })?;
+        Ok(item_id)
+    }
+
+    // Hand-typed barcodes can look like generated ones, so skip past any that are
+    // already on a copy.
+    fn free_barcode(conn: &Connection) -> SqliteResult<String> {
+        let mut next: i64 = conn.query_row(
+            "SELECT IFNULL((SELECT seq FROM sqlite_sequence WHERE name = 'items'), 0) + 1",
+            [],
+            |row| row.get(0),
+        )?;
+        loop {
+            let candidate = format!("{next:08}");
+            let taken: bool = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM items WHERE barcode = ?1)",
+                [&candidate],
+                |row| row.get(0),
+            )?;
+            if !taken {
+                return Ok(candidate);
+            }
+            next += 1;
+        }
+    }
+
+    // Self-registration. `status` is where the account starts out, see
+    // registration::Mode. Returns the new user's id, or None if the username is taken.
+    pub fn create_user(
+        &self,
+        username: &str,
+        password: &str,
+        email: Option<&str>,
+        status: &str,
+        actor: &Actor,
+    ) -> SqliteResult<Option<i64>> {
+        let conn = self.connection.lock().unwrap();
+        Self::insert_user(&conn, username, password, "user", email, status, actor)
+    }
+
+    // Returns the new user's id, or None if the username is taken.
+    pub fn create_user_with_role(
+        &self,
+        username: &str,
+        password: &str,
+        role: &str,
+        actor: &Actor,
+    ) -> SqliteResult<Option<i64>> {
+        let conn = self.connection.lock().unwrap();
+        Self::insert_user(
+            &conn,
+            username,
+            password,
+            role,
+            None,
+            registration::APPROVED,
+            actor,
+        )
+    }
+
+    // Only works while there is no active admin, so it can't be used to take
+    // over a library that is already set up.
+    pub fn create_first_admin(
+        &self,
+        username: &str,
+        password: &str,
+        actor: &Actor,
+    ) -> SqliteResult<AdminBootstrap> {
+        let conn = self.connection.lock().unwrap();
+
+        if Self::has_admin(&conn)? {
+            return Ok(AdminBootstrap::AdminExists);
+        }
+        match Self::insert_user(
+            &conn,
+            username,
+            password,
+            "admin",
+            None,
+            registration::APPROVED,
+            actor,
+        )? {
+            Some(user_id) => Ok(AdminBootstrap::Created(user_id)),
+            None => Ok(AdminBootstrap::UsernameTaken),
+        }
+    }
+
+    pub fn admin_exists(&self) -> SqliteResult<bool> {
         let conn = self.connection.lock().unwrap();
+        Self::has_admin(&conn)
+    }
+
+    fn has_admin(conn: &Connection) -> SqliteResult<bool> {
+        conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM users WHERE role = 'admin' AND active = 1)",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    // A random card number no one else has.
+    fn new_card_number(conn: &Connection) -> SqliteResult<String> {
+        let mut rng = rand::thread_rng();
+        loop {
+            let digits: String = (0..11)
+                .map(|_| char::from(b'0' + rng.gen_range(0..10u8)))
+                .collect();
+            let body = format!("{CARD_NUMBER_PREFIX}{digits}");
+            let card_number = format!("{body}{}", luhn_check_digit(&body));
+            let taken: bool = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM users WHERE card_number = ?1)",
+                [&card_number],
+                |row| row.get(0),
+            )?;
+            if !taken {
+                return Ok(card_number);
+            }
+        }
+    }
 
+    // Returns the new user's id, or None if the username is taken.
+    fn insert_user(
+        conn: &Connection,
+        username: &str,
+        password: &str,
+        role: &str,
+        email: Option<&str>,
+        status: &str,
+        actor: &Actor,
+    ) -> SqliteResult<Option<i64>> {
         let mut stmt = conn.prepare("SELECT COUNT(*) FROM users WHERE username = ?1")?;
         let count: i32 = stmt.query_row([username], |row| row.get(0))?;
 
         if count > 0 {
-            return Ok(false);
+            return Ok(None);
         }
 
         let hashed_password = match hash(password, DEFAULT_COST) {
             Ok(pwd) => pwd,
-            Err(_) => return Ok(false), // You may choose to return an error instead
+            Err(_) => return Ok(None), // You may choose to return an error instead
         };
 
-        conn.execute(
-            "INSERT INTO users (username, password) VALUES (?1, ?2)",
-            [username, &hashed_password],
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO users (username, password, role, email, registration_status)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![username, hashed_password, role, email, status],
+        )?;
+        let user_id = tx.last_insert_rowid();
+        let card_number = Self::new_card_number(&tx)?;
+        tx.execute(
+            "UPDATE users SET card_number = ?1,
+                membership_expires_at = CASE WHEN role = 'user'
+                    THEN date('now', '+' || ?2 || ' days') END
+             WHERE id = ?3",
+            params![card_number, MEMBERSHIP_DAYS, user_id],
         )?;
+        events::emit(
+            &tx,
+            &DomainEvent::UserRegistered {
+                user_id,
+                username: username.to_string(),
+            },
+        )?;
+        let after = Self::user_snapshot(&tx, user_id)?;
+        audit::record(
+            &tx,
+            actor,
+            "user.create",
+            "user",
+            user_id,
+            None,
+            after.as_ref(),
+        )?;
+        tx.commit()?;
 
-        Ok(true)
+        Ok(Some(user_id))
     }
 
+    // Checks a password, returning the user's id, username and role.
     pub fn verify_user(
         &self,
         username: &str,
         password: &str,
-    ) -> SqliteResult<Option<(i32, String, String, String)>> {
+    ) -> SqliteResult<Option<(i64, String, String, bool)>> {
         let conn = self.connection.lock().unwrap();
-        let mut stmt =
-            conn.prepare("SELECT id, username, password, role FROM users WHERE username = ?1")?;
-
-        match stmt.query_row(params![username], |row| {
-            let user_id: i32 = row.get(0)?;
-            let stored_username: String = row.get(1)?;
-            let stored_password_hash: String = row.get(2)?;
-            let role: String = row.get(3)?;
-
-            let password_matches =
-                verify(password, &stored_password_hash).map_err(|_| RusqliteError::InvalidQuery)?;
-
-            if password_matches {
-                let expiration = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs()
-                    + 3600; // 1 hour expiration
-
-                let claims = Claims {
-                    sub: stored_username.clone(),
-                    role: role.clone(),
-                    exp: expiration as usize,
-                };
 
-                let jwt_secret = env::var("JWT_SECRET").map_err(|_| RusqliteError::InvalidQuery)?;
-                let token = encode(
-                    &Header::default(),
-                    &claims,
-                    &EncodingKey::from_secret(jwt_secret.as_bytes()),
-                )
-                .map_err(|_| RusqliteError::InvalidQuery)?;
+        let user: Option<(i64, String, String, String, bool)> = conn
+            .query_row(
+                "SELECT id, username, password, role, active FROM users WHERE username = ?1",
+                [username],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                    ))
+                },
+            )
+            .optional()?;
 
-                Ok(Some((user_id, stored_username, role, token)))
-            } else {
+        match user {
+            Some((user_id, username, password_hash, role, active))
+                if verify(password, &password_hash).unwrap_or(false) =>
+            {
+                Ok(Some((user_id, username, role, active)))
+            }
+            Some(_) => Ok(None),
+            None => {
+                // Hash anyway so an unknown username takes as long as a wrong password.
+                let _ = verify(password, DUMMY_PASSWORD_HASH);
                 Ok(None)
             }
-        }) {
-            Ok(result) => Ok(result),
-            Err(RusqliteError::QueryReturnedNoRows) => Ok(None),
-            Err(e) => Err(e),
         }
     }
 
-    pub fn add_book(
-        &self,
-        title: &str,
-        author: &str,
-        isbn: &str,
-        publication_year: &str,
-        genre: &str,
-        number_of_copies: i32,
-    ) -> SqliteResult<bool> {
+    fn login_failure(
+        conn: &Connection,
+        scope: &str,
+        key: &str,
+    ) -> SqliteResult<Option<(i64, NaiveDateTime, Option<NaiveDateTime>)>> {
+        let row: Option<(i64, String, Option<String>)> = conn
+            .query_row(
+                "SELECT failures, last_failure_at, locked_until FROM login_failures
+                 WHERE scope = ?1 AND key = ?2",
+                params![scope, key],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+
+        Ok(row.and_then(|(failures, last_failure_at, locked_until)| {
+            let last_failure_at =
+                NaiveDateTime::parse_from_str(&last_failure_at, DATETIME_FORMAT).ok()?;
+            let locked_until = locked_until
+                .and_then(|until| NaiveDateTime::parse_from_str(&until, DATETIME_FORMAT).ok());
+            Some((failures, last_failure_at, locked_until))
+        }))
+    }
+
+    // Seconds until this username may be tried again from this IP, or None if it
+    // can be tried now.
+    pub fn login_retry_after(&self, username: &str, ip: &str) -> SqliteResult<Option<i64>> {
         let conn = self.connection.lock().unwrap();
+        Self::retry_after(&conn, &LOGIN_LIMITS, [username, ip])
+    }
 
-        let mut stmt = conn.prepare("SELECT COUNT(*) FROM books WHERE isbn = ?1")?;
-        let count: i32 = stmt.query_row([isbn], |row| row.get(0))?;
+    fn retry_after(
+        conn: &Connection,
+        limits: &[LoginLimit; 2],
+        keys: [&str; 2],
+    ) -> SqliteResult<Option<i64>> {
+        let now = Utc::now().naive_utc();
+        let window = Duration::minutes(LOGIN_FAILURE_WINDOW_MINUTES);
 
-        if count > 0 {
-            return Ok(false);
-        }
+        let mut blocked_until = now;
+        for (limit, key) in limits.iter().zip(keys) {
+            let (failures, last_failure_at, locked_until) =
+                match Self::login_failure(conn, limit.scope, key)? {
+                    Some(failure) => failure,
+                    None => continue,
+                };
 
-        conn.execute(
-            "INSERT INTO books (title, author, isbn, publication_year, genre, number_of_copies) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            (title, author, isbn, publication_year, genre, number_of_copies),
-        )?;
+            if let Some(locked_until) = locked_until
+                && locked_until > now
+            {
+                blocked_until = blocked_until.max(locked_until);
+            } else if failures >= limit.free_attempts && last_failure_at > now - window {
+                let delay =
+                    (1i64 << (failures - limit.free_attempts).min(6)).min(MAX_LOGIN_DELAY_SECONDS);
+                blocked_until = blocked_until.max(last_failure_at + Duration::seconds(delay));
+            }
+        }
 
-        Ok(true)
+        Ok((blocked_until > now).then(|| (blocked_until - now).num_seconds().max(1)))
     }
 
-    pub fn fetch_books(&self) -> Result<Vec<Book>, String> {
+    // Counts a failed login against the username and the IP, locking either out
+    // once it passes its threshold.
+    pub fn record_login_failure(
+        &self,
+        username: &str,
+        ip: &str,
+        actor: &Actor,
+    ) -> SqliteResult<()> {
         let conn = self.connection.lock().unwrap();
+        Self::count_login_failure(&conn, &LOGIN_LIMITS, [username, ip], actor)
+    }
 
-        let mut stmt = match conn.prepare(
-            "SELECT id, title, author, isbn, publication_year, genre, number_of_copies FROM books",
-        ) {
-            Ok(stmt) => stmt,
-            Err(_) => return Err("Failed to prepare statement".to_string()),
-        };
+    fn count_login_failure(
+        conn: &Connection,
+        limits: &[LoginLimit; 2],
+        keys: [&str; 2],
+        actor: &Actor,
+    ) -> SqliteResult<()> {
+        let now = Utc::now().naive_utc();
+        let window = Duration::minutes(LOGIN_FAILURE_WINDOW_MINUTES);
 
-        let rows = match stmt.query_map([], |row| {
-            Ok(Book {
-                id: row.get(0)?,
-                title: row.get(1)?,
-                author: row.get(2)?,
-                isbn: row.get(3)?,
-                publication_year: row.get(4)?,
-                genre: row.get(5)?,
-                number_of_copies: row.get(6)?,
-            })
-        }) {
-            Ok(rows) => rows,
-            Err(_) => return Err("Failed to query books".to_string()),
-        };
+        let tx = conn.unchecked_transaction()?;
+        for (limit, key) in limits.iter().zip(keys) {
+            let failures = match Self::login_failure(&tx, limit.scope, key)? {
+                Some((failures, last_failure_at, _)) if last_failure_at > now - window => {
+                    failures + 1
+                }
+                _ => 1,
+            };
+            let locked_until = (failures >= limit.lockout_after)
+                .then(|| now + Duration::minutes(LOGIN_LOCKOUT_MINUTES));
 
-        let mut books = Vec::new();
-        for book_result in rows {
-            match book_result {
-                Ok(book) => books.push(book),
-                Err(_) => return Err("Error reading book row".to_string()),
+            tx.execute(
+                "INSERT INTO login_failures (scope, key, failures, last_failure_at, locked_until)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT(scope, key) DO UPDATE SET failures = ?3, last_failure_at = ?4,
+                    locked_until = ?5",
+                params![
+                    limit.scope,
+                    key,
+                    failures,
+                    now.format(DATETIME_FORMAT).to_string(),
+                    locked_until.map(|until| until.format(DATETIME_FORMAT).to_string())
+                ],
+            )?;
+
+            if locked_until.is_some() {
+                eprintln!(
+                    "Login lockout: {} '{key}' locked for {LOGIN_LOCKOUT_MINUTES} minutes after {failures} failed attempts (last from {})",
+                    limit.scope, actor.source_ip
+                );
+                let user_column = match limit.scope {
+                    "account" => Some("username"),
+                    "card" => Some("card_number"),
+                    _ => None,
+                };
+                let user_id: Option<i64> = match user_column {
+                    Some(column) => tx
+                        .query_row(
+                            &format!("SELECT id FROM users WHERE {column} = ?1"),
+                            [key],
+                            |row| row.get(0),
+                        )
+                        .optional()?,
+                    None => None,
+                };
+                if let Some(user_id) = user_id {
+                    audit::record(
+                        &tx,
+                        actor,
+                        "user.lockout",
+                        "user",
+                        user_id,
+                        None,
+                        Some(&json!({ "failures": failures, "minutes": LOGIN_LOCKOUT_MINUTES })),
+                    )?;
+                }
             }
         }
+        tx.commit()?;
 
-        Ok(books)
+        Ok(())
     }
 
-    pub fn fetch_book(&self, book_id: i64) -> Result<Option<Book>, String> {
+    // A successful login wipes the account's failures. The IP's are left to expire,
+    // or one valid account would let a guesser reset them.
+    pub fn clear_login_failures(&self, username: &str) -> SqliteResult<()> {
+        let conn = self.connection.lock().unwrap();
+        conn.execute(
+            "DELETE FROM login_failures WHERE scope = 'account' AND key = ?1",
+            [username],
+        )?;
+        Ok(())
+    }
+
+    // Usernames and IPs that are currently being slowed down or locked out.
+    pub fn fetch_login_lockouts(&self) -> SqliteResult<Vec<LoginLockout>> {
         let conn = self.connection.lock().unwrap();
+        let now = Utc::now().naive_utc();
 
-        let mut stmt = match conn.prepare(
-            "SELECT id, title, author, isbn, publication_year, genre, number_of_copies, FROM books WHERE id = ?1",
-        ) {
-            Ok(stmt) => stmt,
-            Err(_) => return Err("Failed to prepare statement".to_string()),
+        let mut stmt = conn.prepare(
+            "SELECT scope, key, failures, last_failure_at, locked_until FROM login_failures
+             WHERE locked_until > ?1 OR last_failure_at > ?2
+             ORDER BY last_failure_at DESC",
+        )?;
+
+        let lockouts = stmt
+            .query_map(
+                params![
+                    now.format(DATETIME_FORMAT).to_string(),
+                    (now - Duration::minutes(LOGIN_FAILURE_WINDOW_MINUTES))
+                        .format(DATETIME_FORMAT)
+                        .to_string()
+                ],
+                |row| {
+                    Ok(LoginLockout {
+                        scope: row.get(0)?,
+                        key: row.get(1)?,
+                        failures: row.get(2)?,
+                        last_failure_at: row.get(3)?,
+                        locked_until: row.get(4)?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(lockouts)
+    }
+
+    // Clears a user's failed logins and any lockout. None if the user doesn't
+    // exist, otherwise whether there was anything to clear.
+    pub fn unlock_user(&self, user_id: i64, actor: &Actor) -> SqliteResult<Option<bool>> {
+        let conn = self.connection.lock().unwrap();
+
+        let user: Option<(String, String)> = conn
+            .query_row(
+                "SELECT username, card_number FROM users WHERE id = ?1",
+                [user_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let (username, card_number) = match user {
+            Some(user) => user,
+            None => return Ok(None),
         };
 
-        let rows = match stmt.query_map([book_id], |row| {
-            Ok(Book {
-                id: row.get(0)?,
-                title: row.get(1)?,
-                author: row.get(2)?,
-                isbn: row.get(3)?,
-                publication_year: row.get(4)?,
-                genre: row.get(5)?,
-                number_of_copies: row.get(6)?,
+        let tx = conn.unchecked_transaction()?;
+        let before = match Self::login_failure(&tx, "account", &username)? {
+            Some(failure) => Some(failure),
+            None => Self::login_failure(&tx, "card", &card_number)?,
+        };
+        tx.execute(
+            "DELETE FROM login_failures
+             WHERE (scope = 'account' AND key = ?1) OR (scope = 'card' AND key = ?2)",
+            params![username, card_number],
+        )?;
+        if let Some((failures, _, locked_until)) = before {
+            audit::record(
+                &tx,
+                actor,
+                "user.unlock",
+                "user",
+                user_id,
+                Some(&json!({
+                    "failures": failures,
+                    "locked_until": locked_until.map(|until| until.format(DATETIME_FORMAT).to_string()),
+                })),
+                None,
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(Some(before.is_some()))
+    }
+
+    // Moves a user to another role. Their sessions are revoked so the next token
+    // they get carries the new role's permissions.
+    pub fn set_user_role(
+        &self,
+        user_id: i64,
+        role: &str,
+        actor: &Actor,
+    ) -> SqliteResult<UserChange> {
+        let conn = self.connection.lock().unwrap();
+
+        let current: Option<String> = conn
+            .query_row("SELECT role FROM users WHERE id = ?1", [user_id], |row| {
+                row.get(0)
             })
-        }) {
-            Ok(rows) => rows,
-            Err(_) => return Err("Failed to query books".to_string()),
+            .optional()?;
+        let current = match current {
+            Some(current) if current == role => return Ok(UserChange::Unchanged),
+            Some(current) => current,
+            None => return Ok(UserChange::NotFound),
         };
 
-        for book_result in rows {
-            match book_result {
-                Ok(book) => return Ok(Some(book)),
-                Err(_) => return Err("Error reading book row".to_string()),
-            }
+        if Self::is_last_admin(&conn, user_id)? {
+            return Ok(UserChange::LastAdmin);
         }
 
-        Ok(None)
+        let tx = conn.unchecked_transaction()?;
+        // Staff moved to the patron role start a membership if they have none
+        tx.execute(
+            "UPDATE users SET role = ?1,
+                membership_expires_at = CASE WHEN ?1 = 'user' AND membership_expires_at IS NULL
+                    THEN date('now', '+' || ?3 || ' days') ELSE membership_expires_at END
+             WHERE id = ?2",
+            params![role, user_id, MEMBERSHIP_DAYS],
+        )?;
+        tx.execute(
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP, revoked_reason = 'role_changed'
+             WHERE revoked_at IS NULL AND user_id = ?1",
+            [user_id],
+        )?;
+        audit::record(
+            &tx,
+            actor,
+            "user.set_role",
+            "user",
+            user_id,
+            Some(&json!({ "role": current })),
+            Some(&json!({ "role": role })),
+        )?;
+        tx.commit()?;
+
+        Ok(UserChange::Changed)
     }
 
-    pub fn edit_book(&self, book_id: i64, updated_fields: &Value) -> SqliteResult<bool> {
-        let mut query = String::from("UPDATE books SET ");
-        let mut sets = Vec::new();
-        let mut values: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+    // Whether the user is the only active admin left.
+    fn is_last_admin(conn: &Connection, user_id: i64) -> SqliteResult<bool> {
+        conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM users WHERE id = ?1 AND role = 'admin' AND active = 1)
+                AND (SELECT COUNT(*) FROM users WHERE role = 'admin' AND active = 1) <= 1",
+            [user_id],
+            |row| row.get(0),
+        )
+    }
 
-        if let Some(title) = updated_fields.get("title").and_then(|v| v.as_str()) {
-            sets.push("title = ?");
-            values.push(Box::new(title.to_string()));
-        }
-        if let Some(author) = updated_fields.get("author").and_then(|v| v.as_str()) {
-            sets.push("author = ?");
-            values.push(Box::new(author.to_string()));
-        }
-        if let Some(isbn) = updated_fields.get("isbn").and_then(|v| v.as_str()) {
-            sets.push("isbn = ?");
-            values.push(Box::new(isbn.to_string()));
+    // Deactivating signs the user out everywhere, kiosks included. Their loans,
+    // holds and ledger are kept, and reactivating restores access.
+    pub fn set_user_active(
+        &self,
+        user_id: i64,
+        active: bool,
+        reason: &str,
+        actor: &Actor,
+    ) -> SqliteResult<UserChange> {
+        let conn = self.connection.lock().unwrap();
+
+        let current: Option<bool> = conn
+            .query_row("SELECT active FROM users WHERE id = ?1", [user_id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        match current {
+            None => return Ok(UserChange::NotFound),
+            Some(current) if current == active => return Ok(UserChange::Unchanged),
+            Some(_) => {}
         }
-        if let Some(year) = updated_fields
-            .get("publication_year")
-            .and_then(|v| v.as_str())
-        {
-            sets.push("publication_year = ?");
-            values.push(Box::new(year.to_string()));
+        if !active && Self::is_last_admin(&conn, user_id)? {
+            return Ok(UserChange::LastAdmin);
         }
-        if let Some(genre) = updated_fields.get("genre").and_then(|v| v.as_str()) {
-            sets.push("genre = ?");
-            values.push(Box::new(genre.to_string()));
+
+        let tx = conn.unchecked_transaction()?;
+        if active {
+            tx.execute(
+                "UPDATE users SET active = 1, deactivated_at = NULL, deactivated_reason = NULL
+                 WHERE id = ?1",
+                [user_id],
+            )?;
+        } else {
+            tx.execute(
+                "UPDATE users SET active = 0, deactivated_at = CURRENT_TIMESTAMP,
+                    deactivated_reason = ?1
+                 WHERE id = ?2",
+                params![reason, user_id],
+            )?;
+            tx.execute(
+                "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP, revoked_reason = 'deactivated'
+                 WHERE revoked_at IS NULL AND user_id = ?1",
+                [user_id],
+            )?;
+            tx.execute(
+                "UPDATE kiosk_sessions SET ended_at = CURRENT_TIMESTAMP
+                 WHERE ended_at IS NULL AND user_id = ?1",
+                [user_id],
+            )?;
+            tx.execute("DELETE FROM login_challenges WHERE user_id = ?1", [user_id])?;
         }
-        if let Some(copies) = updated_fields
-            .get("number_of_copies")
-            .and_then(|v| v.as_i64())
-        {
-            sets.push("number_of_copies = ?");
-            values.push(Box::new(copies));
+        audit::record(
+            &tx,
+            actor,
+            if active {
+                "user.reactivate"
+            } else {
+                "user.deactivate"
+            },
+            "user",
+            user_id,
+            Some(&json!({ "active": !active })),
+            Some(&if active {
+                json!({ "active": true })
+            } else {
+                json!({ "active": false, "reason": reason })
+            }),
+        )?;
+        tx.commit()?;
+
+        Ok(UserChange::Changed)
+    }
+
+    // Removes an account for good. Refused while loans are out or the ledger
+    // isn't settled; deactivate the account instead to keep its history.
+    pub fn delete_user(&self, user_id: i64, actor: &Actor) -> SqliteResult<UserDeletion> {
+        let conn = self.connection.lock().unwrap();
+
+        let user = conn
+            .query_row(
+                &format!("SELECT {USER_SUMMARY_COLUMNS} FROM users u WHERE u.id = ?1"),
+                [user_id],
+                Self::user_summary_from_row,
+            )
+            .optional()?;
+        let user = match user {
+            Some(user) => user,
+            None => return Ok(UserDeletion::NotFound),
+        };
+        if user.active_loans > 0 || user.balance_cents != 0 {
+            return Ok(UserDeletion::Outstanding {
+                active_loans: user.active_loans,
+                balance_cents: user.balance_cents,
+            });
         }
-        if let Some(available) = updated_fields.get("available").and_then(|v| v.as_bool()) {
-            sets.push("available = ?");
-            values.push(Box::new(available));
+        if Self::is_last_admin(&conn, user_id)? {
+            return Ok(UserDeletion::LastAdmin);
         }
 
-        if sets.is_empty() {
-            return Ok(false); // Nothing to update
-        }
+        let tx = conn.unchecked_transaction()?;
+        let before = Self::user_snapshot(&tx, user_id)?;
 
-        query.push_str(&sets.join(", "));
-        query.push_str(" WHERE id = ?");
-        values.push(Box::new(book_id));
+        // Copies waiting on the hold shelf for this user go to the next hold
+        let held_items: Vec<i64> = tx
+            .prepare(
+                "SELECT item_id FROM holds
+                 WHERE user_id = ?1 AND status = 'ready' AND item_id IS NOT NULL",
+            )?
+            .query_map([user_id], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        let open_holds: Vec<i64> = tx
+            .prepare(
+                "UPDATE holds SET status = 'cancelled'
+                 WHERE user_id = ?1 AND status IN ('waiting', 'in_transit', 'ready')
+                 RETURNING id",
+            )?
+            .query_map([user_id], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        for hold_id in open_holds {
+            Self::emit_hold_status(&tx, hold_id)?;
+        }
+        for item_id in held_items {
+            Self::allocate_item(&tx, item_id)?;
+        }
 
-        let conn = self.connection.lock().unwrap();
-        let mut stmt = conn.prepare(&query)?;
-        stmt.execute(rusqlite::params_from_iter(values))?;
+        tx.execute("DELETE FROM users WHERE id = ?1", [user_id])?;
+        audit::record(
+            &tx,
+            actor,
+            "user.delete",
+            "user",
+            user_id,
+            before.as_ref(),
+            None,
+        )?;
+        tx.commit()?;
 
-        Ok(true)
+        Ok(UserDeletion::Deleted)
     }
 
-    pub fn delete_book(&self, book_id: i64) -> SqliteResult<bool> {
+    // Opens a session for a successful login. Returns the session id and its
+    // first refresh token, which is only ever stored hashed.
+    pub fn start_session(
+        &self,
+        user_id: i64,
+        user_agent: &str,
+        ip: &str,
+    ) -> SqliteResult<(i64, String)> {
         let conn = self.connection.lock().unwrap();
 
-        let mut stmt = conn.prepare("SELECT 1 FROM borrowed WHERE book_id = ?")?;
-        let mut rows = stmt.query(params![book_id])?;
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO sessions (user_id, expires_at, user_agent, ip) VALUES (?1, ?2, ?3, ?4)",
+            params![user_id, Self::session_expiry(), user_agent, ip],
+        )?;
+        let session_id = tx.last_insert_rowid();
+        let refresh_token = Self::issue_refresh_token(&tx, session_id)?;
+        tx.commit()?;
 
-        if rows.next()?.is_some() {
-            return Ok(false);
-        }
+        Ok((session_id, refresh_token))
+    }
 
-        let affected_row = conn.execute("DELETE FROM books WHERE id=?", params![book_id])?;
+    fn session_expiry() -> String {
+        (Utc::now().naive_utc() + Duration::days(REFRESH_TOKEN_DAYS))
+            .format(DATETIME_FORMAT)
+            .to_string()
+    }
 
-        Ok(affected_row > 0)
+    fn issue_refresh_token(conn: &Connection, session_id: i64) -> SqliteResult<String> {
+        let refresh_token = generate_token();
+        conn.execute(
+            "INSERT INTO refresh_tokens (token_hash, session_id) VALUES (?1, ?2)",
+            params![hash_token(&refresh_token), session_id],
+        )?;
+        Ok(refresh_token)
     }
 
-    pub fn fetch_users(&self) -> Result<Vec<Value>, String> {
+    // Trades a refresh token for a new one. Each token works once: presenting one
+    // that was already used means it was copied, so the whole session is revoked.
+    pub fn refresh_session(&self, refresh_token: &str) -> SqliteResult<RefreshOutcome> {
         let conn = self.connection.lock().unwrap();
 
-        let mut stmt = match conn.prepare("SELECT username FROM users WHERE role='user'") {
-            Ok(stmt) => stmt,
-            Err(_) => return Err("Failed to prepare statement".to_string()),
-        };
+        let token: Option<(i64, bool, bool, String, String)> = conn
+            .query_row(
+                "SELECT t.session_id, t.used_at IS NOT NULL,
+                    s.revoked_at IS NULL AND s.expires_at > ?2, u.username, u.role
+                 FROM refresh_tokens t
+                 JOIN sessions s ON t.session_id = s.id
+                 JOIN users u ON s.user_id = u.id
+                 WHERE t.token_hash = ?1",
+                params![
+                    hash_token(refresh_token),
+                    Utc::now().naive_utc().format(DATETIME_FORMAT).to_string()
+                ],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                    ))
+                },
+            )
+            .optional()?;
 
-        let users_iter = match stmt.query_map([], |row| {
-            let username: String = row.get(0)?;
-            Ok(json!({ "username": username }))
-        }) {
-            Ok(rows) => rows,
-            Err(_) => return Err("Failed to query users".to_string()),
+        let (session_id, used, active, username, role) = match token {
+            Some(token) => token,
+            None => return Ok(RefreshOutcome::Invalid),
         };
 
-        let mut users = Vec::new();
-        for result in users_iter {
-            match result {
-                Ok(user) => users.push(user),
-                Err(_) => return Err("Error reading user row".to_string()),
+        if used {
+            if active {
+                eprintln!(
+                    "Refresh token reused for session {session_id} ({username}); revoking it"
+                );
+                Self::revoke(&conn, session_id, "refresh_token_reused")?;
             }
+            return Ok(RefreshOutcome::Reused);
+        }
+        if !active {
+            return Ok(RefreshOutcome::Invalid);
         }
 
-        Ok(users)
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE refresh_tokens SET used_at = CURRENT_TIMESTAMP WHERE token_hash = ?1",
+            [hash_token(refresh_token)],
+        )?;
+        tx.execute(
+            "UPDATE sessions SET expires_at = ?1, last_seen_at = CURRENT_TIMESTAMP WHERE id = ?2",
+            params![Self::session_expiry(), session_id],
+        )?;
+        let refresh_token = Self::issue_refresh_token(&tx, session_id)?;
+        tx.commit()?;
+
+        Ok(RefreshOutcome::Rotated {
+            session_id,
+            username,
+            role,
+            refresh_token,
+        })
+    }
+
+    // Access tokens are only honoured while their session is open.
+    pub fn session_active(&self, session_id: i64) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+        conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sessions
+             WHERE id = ?1 AND revoked_at IS NULL AND expires_at > ?2)",
+            params![
+                session_id,
+                Utc::now().naive_utc().format(DATETIME_FORMAT).to_string()
+            ],
+            |row| row.get(0),
+        )
     }
 
-    pub fn borrow_book(&self, user_id: i64, book_id: i64) -> SqliteResult<bool> {
+    // Like session_active, but also records that the session was just used.
+    pub fn touch_session(&self, session_id: i64) -> SqliteResult<bool> {
         let conn = self.connection.lock().unwrap();
+        let now = Utc::now().naive_utc();
 
-        let user_exists: bool = conn.query_row(
-            "SELECT EXISTS(SELECT 1 FROM users WHERE id = ?1)",
-            [user_id],
+        let active: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sessions
+             WHERE id = ?1 AND revoked_at IS NULL AND expires_at > ?2)",
+            params![session_id, now.format(DATETIME_FORMAT).to_string()],
             |row| row.get(0),
         )?;
-
-        if !user_exists {
-            return Ok(false);
+        if active {
+            conn.execute(
+                "UPDATE sessions SET last_seen_at = ?1 WHERE id = ?2 AND last_seen_at < ?3",
+                params![
+                    now.format(DATETIME_FORMAT).to_string(),
+                    session_id,
+                    (now - Duration::seconds(SESSION_TOUCH_SECONDS))
+                        .format(DATETIME_FORMAT)
+                        .to_string()
+                ],
+            )?;
         }
 
-        let number_of_copies: i32 = conn
-            .query_row(
-                "SELECT number_of_copies FROM books WHERE id = ?1",
-                [book_id],
-                |row| row.get(0),
-            )
-            .or_else(|err| {
-                if let rusqlite::Error::QueryReturnedNoRows = err {
-                    Ok(0)
-                } else {
-                    Err(err)
-                }
-            })?;
+        Ok(active)
+    }
 
-        if number_of_copies < 1 {
-            return Ok(false);
-        }
+    // A user's open sessions, most recently used first.
+    pub fn fetch_sessions(
+        &self,
+        user_id: i64,
+        current_session_id: Option<i64>,
+    ) -> SqliteResult<Vec<Session>> {
+        let conn = self.connection.lock().unwrap();
 
-        conn.execute(
-            "INSERT INTO borrowed (user_id, book_id) VALUES (?1, ?2)",
-            [user_id, book_id],
+        let mut stmt = conn.prepare(
+            "SELECT id, user_agent, ip, created_at, last_seen_at, expires_at FROM sessions
+             WHERE user_id = ?1 AND revoked_at IS NULL AND expires_at > ?2
+             ORDER BY last_seen_at DESC, id DESC",
         )?;
 
-        conn.execute(
-            "UPDATE books SET number_of_copies = number_of_copies - 1 WHERE id = ?1",
-            [book_id],
-        )?;
+        let sessions = stmt
+            .query_map(
+                params![
+                    user_id,
+                    Utc::now().naive_utc().format(DATETIME_FORMAT).to_string()
+                ],
+                |row| {
+                    let id: i64 = row.get(0)?;
+                    Ok(Session {
+                        id,
+                        user_agent: row.get(1)?,
+                        ip: row.get(2)?,
+                        created_at: row.get(3)?,
+                        last_seen_at: row.get(4)?,
+                        expires_at: row.get(5)?,
+                        current: Some(id) == current_session_id,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
 
-        Ok(true)
+        Ok(sessions)
     }
 
-    pub fn fetch_borrowed_books(&self, user_id: i64) -> SqliteResult<Vec<BorrowedBook>> {
+    fn revoke(conn: &Connection, session_id: i64, reason: &str) -> SqliteResult<bool> {
+        let revoked = conn.execute(
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP, revoked_reason = ?1
+             WHERE id = ?2 AND revoked_at IS NULL",
+            params![reason, session_id],
+        )?;
+        Ok(revoked > 0)
+    }
+
+    pub fn revoke_session(&self, session_id: i64, reason: &str) -> SqliteResult<bool> {
         let conn = self.connection.lock().unwrap();
+        Self::revoke(&conn, session_id, reason)
+    }
 
-        let mut stmt = conn.prepare(
-            "SELECT 
-            br.id, 
-            br.due_date, 
-            br.user_id,
-            u.username,
-            b.id, b.title, b.author, b.isbn, b.publication_year, b.genre, b.number_of_copies
-         FROM 
-            borrowed br
-         JOIN 
-            books b ON br.book_id = b.id
-         JOIN 
-            users u ON br.user_id = u.id
-         WHERE 
-            br.user_id = ?1",
+    // Revokes one of a user's sessions; false if it isn't theirs or already ended.
+    pub fn revoke_user_session(
+        &self,
+        user_id: i64,
+        session_id: i64,
+        reason: &str,
+    ) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+        let revoked = conn.execute(
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP, revoked_reason = ?1
+             WHERE id = ?2 AND user_id = ?3 AND revoked_at IS NULL",
+            params![reason, session_id, user_id],
         )?;
+        Ok(revoked > 0)
+    }
 
-        let borrowed_books = stmt
-            .query_map([user_id], |row| {
-                Ok(BorrowedBook {
-                    borrowed_id: row.get(0)?,
-                    due_date: row.get(1)?,
-                    user_id: row.get(2)?,
-                    username: row.get(3)?,
-                    book: Book {
-                        id: row.get(4)?,
-                        title: row.get(5)?,
-                        author: row.get(6)?,
-                        isbn: row.get(7)?,
-                        publication_year: row.get(8)?,
-                        genre: row.get(9)?,
-                        number_of_copies: row.get(10)?,
-                    },
-                })
-            })?
-            .collect::<Result<Vec<_>, _>>()?;
+    // Returns how many open sessions were revoked.
+    pub fn revoke_user_sessions(&self, user_id: i64, reason: &str) -> SqliteResult<usize> {
+        let conn = self.connection.lock().unwrap();
+        conn.execute(
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP, revoked_reason = ?1
+             WHERE revoked_at IS NULL AND user_id = ?2",
+            params![reason, user_id],
+        )
+    }
 
-        Ok(borrowed_books)
+    // Changes a password after checking the current one. Every other session is
+    // signed out; the one making the change stays open. False when the current
+    // password is wrong.
+    pub fn change_password(
+        &self,
+        user_id: i64,
+        current_password: &str,
+        new_password: &str,
+        session_id: i64,
+        actor: &Actor,
+    ) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let password_hash: Option<String> = conn
+            .query_row(
+                "SELECT password FROM users WHERE id = ?1",
+                [user_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match password_hash {
+            Some(password_hash) if verify(current_password, &password_hash).unwrap_or(false) => {}
+            _ => return Ok(false),
+        }
+
+        let new_hash = match hash(new_password, DEFAULT_COST) {
+            Ok(new_hash) => new_hash,
+            Err(_) => return Ok(false),
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE users SET password = ?1 WHERE id = ?2",
+            params![new_hash, user_id],
+        )?;
+        tx.execute(
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP, revoked_reason = 'password_changed'
+             WHERE user_id = ?1 AND id != ?2 AND revoked_at IS NULL",
+            params![user_id, session_id],
+        )?;
+        tx.execute(
+            "UPDATE password_resets SET used_at = CURRENT_TIMESTAMP
+             WHERE user_id = ?1 AND used_at IS NULL",
+            [user_id],
+        )?;
+        // Like PINs, passwords never reach the audit log, only the fact they changed.
+        audit::record(
+            &tx,
+            actor,
+            "user.change_password",
+            "user",
+            user_id,
+            None,
+            None,
+        )?;
+        tx.commit()?;
+
+        Ok(true)
     }
 
-    pub fn return_book(&self, borrowed_id: i64, book_id: i64) -> SqliteResult<bool> {
+    // Emails a reset code to the user, replacing any code sent earlier. Returns
+    // false when there is no such user or they have no email address; callers
+    // shouldn't reveal which.
+    pub fn request_password_reset(
+        &self,
+        username: &str,
+        link_base: &str,
+        actor: &Actor,
+    ) -> SqliteResult<bool> {
         let conn = self.connection.lock().unwrap();
 
-        let mut stmt = conn.prepare("SELECT 1 FROM borrowed WHERE id=?")?;
-        let mut rows = stmt.query(params![borrowed_id])?;
+        let user_id: Option<i64> = conn
+            .query_row(
+                "SELECT id FROM users WHERE username = ?1 AND email IS NOT NULL",
+                [username],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let user_id = match user_id {
+            Some(user_id) => user_id,
+            None => return Ok(false),
+        };
+
+        let token = generate_token();
+        let expires_at = (Utc::now().naive_utc() + Duration::minutes(PASSWORD_RESET_MINUTES))
+            .format(DATETIME_FORMAT)
+            .to_string();
 
-        if rows.next()?.is_none() {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE password_resets SET used_at = CURRENT_TIMESTAMP
+             WHERE user_id = ?1 AND used_at IS NULL",
+            [user_id],
+        )?;
+        tx.execute(
+            "INSERT INTO password_resets (token_hash, user_id, expires_at) VALUES (?1, ?2, ?3)",
+            params![hash_token(&token), user_id, expires_at],
+        )?;
+        let link = format!("{link_base}/reset_password.html#token={token}");
+        let queued = Self::queue_email(
+            &tx,
+            user_id,
+            "password_reset",
+            &[
+                ("token", &token),
+                ("link", &link),
+                ("minutes", &PASSWORD_RESET_MINUTES.to_string()),
+            ],
+        )?;
+        if !queued {
             return Ok(false);
         }
-
-        conn.execute(
-            "UPDATE books SET number_of_copies = number_of_copies + 1 WHERE id = ?",
-            params![book_id],
+        audit::record(
+            &tx,
+            actor,
+            "user.request_password_reset",
+            "user",
+            user_id,
+            None,
+            None,
         )?;
+        tx.commit()?;
 
-        let affected_row = conn.execute("DELETE FROM borrowed WHERE id=?", params![borrowed_id])?;
-        Ok(affected_row > 0)
+        Ok(true)
     }
 
-    pub fn fetch_all_borrowed_books(&self) -> SqliteResult<Vec<BorrowedBook>> {
+    // Sets a new password with a reset code. The code is spent, and every session
+    // the user has is revoked since whoever held the old password may be signed in.
+    // False when the code is unknown, used or expired.
+    pub fn reset_password(
+        &self,
+        token: &str,
+        new_password: &str,
+        actor: &Actor,
+    ) -> SqliteResult<bool> {
         let conn = self.connection.lock().unwrap();
 
-        let mut stmt = conn.prepare(
-            "SELECT 
-            br.id, 
-            br.due_date, 
-            br.user_id,
-            u.username,
-            b.id, b.title, b.author, b.isbn, b.publication_year, b.genre, b.number_of_copies
-         FROM 
-            borrowed br
-         JOIN 
-            books b ON br.book_id = b.id
-         JOIN 
-            users u ON br.user_id = u.id",
+        let user_id: Option<i64> = conn
+            .query_row(
+                "SELECT user_id FROM password_resets
+                 WHERE token_hash = ?1 AND used_at IS NULL AND expires_at > ?2",
+                params![
+                    hash_token(token),
+                    Utc::now().naive_utc().format(DATETIME_FORMAT).to_string()
+                ],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let user_id = match user_id {
+            Some(user_id) => user_id,
+            None => return Ok(false),
+        };
+
+        let new_hash = match hash(new_password, DEFAULT_COST) {
+            Ok(new_hash) => new_hash,
+            Err(_) => return Ok(false),
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE users SET password = ?1 WHERE id = ?2",
+            params![new_hash, user_id],
+        )?;
+        tx.execute(
+            "UPDATE password_resets SET used_at = CURRENT_TIMESTAMP
+             WHERE user_id = ?1 AND used_at IS NULL",
+            [user_id],
+        )?;
+        tx.execute(
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP, revoked_reason = 'password_reset'
+             WHERE user_id = ?1 AND revoked_at IS NULL",
+            [user_id],
+        )?;
+        audit::record(
+            &tx,
+            actor,
+            "user.reset_password",
+            "user",
+            user_id,
+            None,
+            None,
         )?;
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    // Sets a password without the old one or a reset code, for administrators.
+    // Outstanding reset codes are spent and every session is revoked.
+    pub fn set_password(
+        &self,
+        user_id: i64,
+        new_password: &str,
+        actor: &Actor,
+    ) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let new_hash = match hash(new_password, DEFAULT_COST) {
+            Ok(new_hash) => new_hash,
+            Err(_) => return Ok(false),
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        let affected_row = tx.execute(
+            "UPDATE users SET password = ?1 WHERE id = ?2",
+            params![new_hash, user_id],
+        )?;
+        if affected_row == 0 {
+            return Ok(false);
+        }
+        tx.execute(
+            "UPDATE password_resets SET used_at = CURRENT_TIMESTAMP
+             WHERE user_id = ?1 AND used_at IS NULL",
+            [user_id],
+        )?;
+        tx.execute(
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP, revoked_reason = 'password_set'
+             WHERE user_id = ?1 AND revoked_at IS NULL",
+            [user_id],
+        )?;
+        audit::record(&tx, actor, "user.set_password", "user", user_id, None, None)?;
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    // Whether the user has two-factor login on, and how many recovery codes are unused.
+    pub fn totp_status(&self, user_id: i64) -> SqliteResult<(bool, i64)> {
+        let conn = self.connection.lock().unwrap();
+        conn.query_row(
+            "SELECT totp_enabled,
+                (SELECT COUNT(*) FROM recovery_codes WHERE user_id = ?1 AND used_at IS NULL)
+             FROM users WHERE id = ?1",
+            [user_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    }
+
+    // Issues the partial token a password login gets when a second factor is due.
+    pub fn create_login_challenge(
+        &self,
+        user_id: i64,
+        user_agent: &str,
+        ip: &str,
+    ) -> SqliteResult<String> {
+        let conn = self.connection.lock().unwrap();
+
+        let token = generate_token();
+        conn.execute(
+            "INSERT INTO login_challenges (token_hash, user_id, user_agent, ip, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                hash_token(&token),
+                user_id,
+                user_agent,
+                ip,
+                (Utc::now().naive_utc() + Duration::minutes(LOGIN_CHALLENGE_MINUTES))
+                    .format(DATETIME_FORMAT)
+                    .to_string()
+            ],
+        )?;
+
+        Ok(token)
+    }
+
+    pub fn fetch_login_challenge(&self, token: &str) -> SqliteResult<Option<LoginChallenge>> {
+        let conn = self.connection.lock().unwrap();
+
+        conn.query_row(
+            "SELECT c.user_id, u.username, u.role, c.user_agent, c.ip, u.totp_enabled
+             FROM login_challenges c JOIN users u ON c.user_id = u.id
+             WHERE c.token_hash = ?1 AND c.expires_at > ?2",
+            params![
+                hash_token(token),
+                Utc::now().naive_utc().format(DATETIME_FORMAT).to_string()
+            ],
+            |row| {
+                Ok(LoginChallenge {
+                    user_id: row.get(0)?,
+                    username: row.get(1)?,
+                    role: row.get(2)?,
+                    user_agent: row.get(3)?,
+                    ip: row.get(4)?,
+                    totp_enabled: row.get(5)?,
+                })
+            },
+        )
+        .optional()
+    }
+
+    pub fn end_login_challenge(&self, token: &str) -> SqliteResult<()> {
+        let conn = self.connection.lock().unwrap();
+        conn.execute(
+            "DELETE FROM login_challenges WHERE token_hash = ?1",
+            [hash_token(token)],
+        )?;
+        Ok(())
+    }
+
+    // Starts (or restarts) enrolment with a fresh secret. None once two-factor
+    // login is already on; it has to be turned off first.
+    pub fn start_totp_enrolment(&self, user_id: i64) -> SqliteResult<Option<String>> {
+        let conn = self.connection.lock().unwrap();
+
+        let secret = totp::generate_secret();
+        let updated = conn.execute(
+            "UPDATE users SET totp_secret = ?1 WHERE id = ?2 AND totp_enabled = 0",
+            params![secret, user_id],
+        )?;
+
+        Ok((updated > 0).then_some(secret))
+    }
+
+    fn store_recovery_codes(conn: &Connection, user_id: i64) -> SqliteResult<Vec<String>> {
+        conn.execute("DELETE FROM recovery_codes WHERE user_id = ?1", [user_id])?;
+        let codes = totp::generate_recovery_codes();
+        for code in &codes {
+            conn.execute(
+                "INSERT INTO recovery_codes (user_id, code_hash) VALUES (?1, ?2)",
+                params![user_id, hash_token(&totp::normalize_recovery_code(code))],
+            )?;
+        }
+        Ok(codes)
+    }
+
+    // Checks a TOTP code against the stored secret, refusing codes from a step
+    // that has already been used. Marks the step used on success.
+    fn check_totp_code(conn: &Connection, user_id: i64, code: &str) -> SqliteResult<bool> {
+        let secret: Option<(Option<String>, i64)> = conn
+            .query_row(
+                "SELECT totp_secret, totp_last_step FROM users WHERE id = ?1",
+                [user_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        let step = match secret {
+            Some((Some(secret), last_step)) => {
+                totp::verify(&secret, code, totp::current_step()).filter(|step| *step > last_step)
+            }
+            _ => None,
+        };
+        if let Some(step) = step {
+            conn.execute(
+                "UPDATE users SET totp_last_step = ?1 WHERE id = ?2",
+                params![step, user_id],
+            )?;
+        }
+
+        Ok(step.is_some())
+    }
+
+    // Turns two-factor login on with the first code from the new secret. Returns
+    // the recovery codes, which are only ever shown this once.
+    pub fn confirm_totp(
+        &self,
+        user_id: i64,
+        code: &str,
+        actor: &Actor,
+    ) -> SqliteResult<Option<Vec<String>>> {
+        let conn = self.connection.lock().unwrap();
+
+        let pending: bool = conn.query_row(
+            "SELECT totp_secret IS NOT NULL AND totp_enabled = 0 FROM users WHERE id = ?1",
+            [user_id],
+            |row| row.get(0),
+        )?;
+        if !pending {
+            return Ok(None);
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        if !Self::check_totp_code(&tx, user_id, code)? {
+            return Ok(None);
+        }
+        tx.execute("UPDATE users SET totp_enabled = 1 WHERE id = ?1", [user_id])?;
+        let codes = Self::store_recovery_codes(&tx, user_id)?;
+        audit::record(&tx, actor, "user.totp_enable", "user", user_id, None, None)?;
+        tx.commit()?;
+
+        Ok(Some(codes))
+    }
+
+    // The second step of a login: a current TOTP code, or one of the recovery
+    // codes, each of which works once.
+    pub fn verify_second_factor(&self, user_id: i64, code: &str) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        if code.trim().chars().all(|c| c.is_ascii_digit()) {
+            return Self::check_totp_code(&conn, user_id, code);
+        }
+
+        let used = conn.execute(
+            "UPDATE recovery_codes SET used_at = CURRENT_TIMESTAMP
+             WHERE user_id = ?1 AND code_hash = ?2 AND used_at IS NULL",
+            params![user_id, hash_token(&totp::normalize_recovery_code(code))],
+        )?;
+        if used > 0 {
+            eprintln!("Recovery code used for user {user_id}");
+        }
+
+        Ok(used > 0)
+    }
+
+    // Checks a current TOTP code only; recovery codes aren't accepted here.
+    pub fn verify_totp_code(&self, user_id: i64, code: &str) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+        Self::check_totp_code(&conn, user_id, code)
+    }
+
+    // Replaces every recovery code with a new set.
+    pub fn regenerate_recovery_codes(
+        &self,
+        user_id: i64,
+        actor: &Actor,
+    ) -> SqliteResult<Vec<String>> {
+        let conn = self.connection.lock().unwrap();
+
+        let tx = conn.unchecked_transaction()?;
+        let codes = Self::store_recovery_codes(&tx, user_id)?;
+        audit::record(
+            &tx,
+            actor,
+            "user.totp_recovery_codes",
+            "user",
+            user_id,
+            None,
+            None,
+        )?;
+        tx.commit()?;
+
+        Ok(codes)
+    }
+
+    // Turns two-factor login off and forgets the secret and recovery codes.
+    // `action` tells the audit log whether the user or staff did it. False if
+    // there's no such user.
+    pub fn remove_totp(&self, user_id: i64, action: &str, actor: &Actor) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let tx = conn.unchecked_transaction()?;
+        let updated = tx.execute(
+            "UPDATE users SET totp_secret = NULL, totp_enabled = 0 WHERE id = ?1",
+            [user_id],
+        )?;
+        if updated > 0 {
+            tx.execute("DELETE FROM recovery_codes WHERE user_id = ?1", [user_id])?;
+            tx.execute("DELETE FROM login_challenges WHERE user_id = ?1", [user_id])?;
+            audit::record(&tx, actor, action, "user", user_id, None, None)?;
+        }
+        tx.commit()?;
+
+        Ok(updated > 0)
+    }
+
+    // Sessions are kept a while after they end so reused refresh tokens are still
+    // recognised, then dropped along with their tokens.
+    pub fn prune_sessions(conn: &Connection, before: NaiveDateTime) -> SqliteResult<i64> {
+        let before = before.format(DATETIME_FORMAT).to_string();
+        let deleted = conn.execute(
+            "DELETE FROM sessions WHERE expires_at < ?1 OR revoked_at < ?1",
+            [&before],
+        )?;
+        let resets = conn.execute(
+            "DELETE FROM password_resets WHERE expires_at < ?1",
+            [&before],
+        )?;
+        let failures = conn.execute(
+            "DELETE FROM login_failures
+             WHERE last_failure_at < ?1 AND (locked_until IS NULL OR locked_until < ?1)",
+            [&before],
+        )?;
+        let challenges = conn.execute(
+            "DELETE FROM login_challenges WHERE expires_at < ?1",
+            [&before],
+        )?;
+        Ok((deleted + resets + failures + challenges) as i64)
+    }
+
+    pub fn add_book(&self, book: &NewBook, actor: &Actor) -> SqliteResult<bool> {
+        let NewBook {
+            title,
+            author,
+            isbn,
+            publication_year,
+            genre,
+            number_of_copies,
+        } = *book;
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare("SELECT COUNT(*) FROM books WHERE isbn = ?1")?;
+        let count: i32 = stmt.query_row([isbn], |row| row.get(0))?;
+
+        if count > 0 {
+            return Ok(false);
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO books (title, author, isbn, publication_year, genre) VALUES (?1, ?2, ?3, ?4, ?5)",
+            (title, author, isbn, publication_year, genre),
+        )?;
+
+        let book_id = tx.last_insert_rowid();
+        for _ in 0..number_of_copies {
+            Self::insert_item(&tx, book_id, None, "", "good", None)?;
+        }
+        events::emit(
+            &tx,
+            &DomainEvent::BookAdded {
+                book_id,
+                title: title.to_string(),
+                author: author.to_string(),
+                isbn: isbn.to_string(),
+                copies: number_of_copies.into(),
+            },
+        )?;
+        let after = Self::book_snapshot(&tx, book_id)?;
+        audit::record(
+            &tx,
+            actor,
+            "book.add",
+            "book",
+            book_id,
+            None,
+            after.as_ref(),
+        )?;
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    pub fn fetch_books(&self) -> Result<Vec<Book>, String> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = match conn.prepare(&format!("SELECT {BOOK_COLUMNS} FROM books b")) {
+            Ok(stmt) => stmt,
+            Err(_) => return Err("Failed to prepare statement".to_string()),
+        };
+
+        let rows = match stmt.query_map([], |row| Self::book_from_row(row, 0)) {
+            Ok(rows) => rows,
+            Err(_) => return Err("Failed to query books".to_string()),
+        };
+
+        let mut books = Vec::new();
+        for book_result in rows {
+            match book_result {
+                Ok(book) => books.push(book),
+                Err(_) => return Err("Error reading book row".to_string()),
+            }
+        }
+
+        Ok(books)
+    }
+
+    pub fn fetch_book(&self, book_id: i64) -> Result<Option<Book>, String> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = match conn.prepare(&format!(
+            "SELECT {BOOK_COLUMNS} FROM books b WHERE b.id = ?1"
+        )) {
+            Ok(stmt) => stmt,
+            Err(_) => return Err("Failed to prepare statement".to_string()),
+        };
+
+        match stmt
+            .query_row([book_id], |row| Self::book_from_row(row, 0))
+            .optional()
+        {
+            Ok(book) => Ok(book),
+            Err(_) => Err("Error reading book row".to_string()),
+        }
+    }
+
+    // Every search term has to match the start of a word in the title, author,
+    // ISBN or genre. Best matches come first.
+    pub fn search_books(&self, terms: &str) -> SqliteResult<Vec<Book>> {
+        let query = terms
+            .split_whitespace()
+            .map(|term| format!("\"{}\"*", term.replace('"', "")))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let conn = self.connection.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {BOOK_COLUMNS} FROM book_search s JOIN books b ON b.id = s.rowid
+             WHERE book_search MATCH ?1 ORDER BY s.rank LIMIT 50"
+        ))?;
+
+        let books = stmt
+            .query_map([query], |row| Self::book_from_row(row, 0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(books)
+    }
+
+    pub fn index_book(conn: &Connection, book_id: i64) -> SqliteResult<()> {
+        Self::unindex_book(conn, book_id)?;
+        conn.execute(
+            "INSERT INTO book_search (rowid, title, author, isbn, genre)
+             SELECT id, title, author, isbn, genre FROM books WHERE id = ?1",
+            [book_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn unindex_book(conn: &Connection, book_id: i64) -> SqliteResult<()> {
+        conn.execute("DELETE FROM book_search WHERE rowid = ?1", [book_id])?;
+        Ok(())
+    }
+
+    pub fn edit_book(
+        &self,
+        book_id: i64,
+        updated_fields: &Value,
+        actor: &Actor,
+    ) -> SqliteResult<bool> {
+        let mut query = String::from("UPDATE books SET ");
+        let mut sets = Vec::new();
+        let mut values: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+
+        if let Some(title) = updated_fields.get("title").and_then(|v| v.as_str()) {
+            sets.push("title = ?");
+            values.push(Box::new(title.to_string()));
+        }
+        if let Some(author) = updated_fields.get("author").and_then(|v| v.as_str()) {
+            sets.push("author = ?");
+            values.push(Box::new(author.to_string()));
+        }
+        if let Some(isbn) = updated_fields.get("isbn").and_then(|v| v.as_str()) {
+            sets.push("isbn = ?");
+            values.push(Box::new(isbn.to_string()));
+        }
+        if let Some(year) = updated_fields
+            .get("publication_year")
+            .and_then(|v| v.as_str())
+        {
+            sets.push("publication_year = ?");
+            values.push(Box::new(year.to_string()));
+        }
+        if let Some(genre) = updated_fields.get("genre").and_then(|v| v.as_str()) {
+            sets.push("genre = ?");
+            values.push(Box::new(genre.to_string()));
+        }
+        if let Some(available) = updated_fields.get("available").and_then(|v| v.as_bool()) {
+            sets.push("available = ?");
+            values.push(Box::new(available));
+        }
+        let copies = updated_fields
+            .get("number_of_copies")
+            .and_then(|v| v.as_i64());
+
+        if sets.is_empty() && copies.is_none() {
+            return Ok(false); // Nothing to update
+        }
+
+        let conn = self.connection.lock().unwrap();
+        let book_exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM books WHERE id = ?1)",
+            [book_id],
+            |row| row.get(0),
+        )?;
+
+        if !book_exists {
+            return Ok(false);
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        let before = Self::book_snapshot(&tx, book_id)?;
+        if !sets.is_empty() {
+            query.push_str(&sets.join(", "));
+            query.push_str(" WHERE id = ?");
+            values.push(Box::new(book_id));
+
+            let mut stmt = tx.prepare(&query)?;
+            stmt.execute(rusqlite::params_from_iter(values))?;
+        }
+
+        // The copy count from the edit form sets how many copies are on the shelf:
+        // extra copies are generated, surplus available ones are withdrawn.
+        if let Some(copies) = copies {
+            let available: i64 = tx.query_row(
+                "SELECT COUNT(*) FROM items WHERE book_id = ?1 AND status = 'available'",
+                [book_id],
+                |row| row.get(0),
+            )?;
+
+            for _ in available..copies {
+                Self::insert_item(&tx, book_id, None, "", "good", None)?;
+            }
+            if copies < available {
+                let surplus: Vec<i64> = tx
+                    .prepare(
+                        "SELECT id FROM items WHERE book_id = ?1 AND status = 'available'
+                         ORDER BY id DESC LIMIT ?2",
+                    )?
+                    .query_map(params![book_id, available - copies.max(0)], |row| {
+                        row.get(0)
+                    })?
+                    .collect::<Result<_, _>>()?;
+                for item_id in surplus {
+                    Self::set_item_status(&tx, item_id, "withdrawn")?;
+                }
+            }
+        }
+        let fields = [
+            "title",
+            "author",
+            "isbn",
+            "publication_year",
+            "genre",
+            "available",
+            "number_of_copies",
+        ]
+        .into_iter()
+        .filter(|field| updated_fields.get(field).is_some())
+        .map(str::to_string)
+        .collect();
+        events::emit(&tx, &DomainEvent::BookEdited { book_id, fields })?;
+        if let (Some(before), Some(after)) = (before, Self::book_snapshot(&tx, book_id)?) {
+            let (before, after) = audit::changes(&before, &after);
+            audit::record(
+                &tx,
+                actor,
+                "book.edit",
+                "book",
+                book_id,
+                Some(&before),
+                Some(&after),
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    pub fn delete_book(&self, book_id: i64, actor: &Actor) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare("SELECT 1 FROM borrowed WHERE book_id = ?")?;
+        let mut rows = stmt.query(params![book_id])?;
+
+        if rows.next()?.is_some() {
+            return Ok(false);
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        let before = Self::book_snapshot(&tx, book_id)?;
+        let affected_row = tx.execute("DELETE FROM books WHERE id=?", params![book_id])?;
+        if affected_row > 0 {
+            events::emit(&tx, &DomainEvent::BookDeleted { book_id })?;
+            audit::record(
+                &tx,
+                actor,
+                "book.delete",
+                "book",
+                book_id,
+                before.as_ref(),
+                None,
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(affected_row > 0)
+    }
+
+    fn user_summary_from_row(row: &Row) -> SqliteResult<UserSummary> {
+        Ok(UserSummary {
+            id: row.get(0)?,
+            username: row.get(1)?,
+            role: row.get(2)?,
+            email: row.get(3)?,
+            card_number: row.get(4)?,
+            branch_id: row.get(5)?,
+            created_at: row.get(6)?,
+            active: row.get(7)?,
+            deactivated_at: row.get(8)?,
+            deactivated_reason: row.get(9)?,
+            active_loans: row.get(10)?,
+            balance_cents: row.get(11)?,
+            full_name: row.get(12)?,
+            phone: row.get(13)?,
+            address: row.get(14)?,
+            date_of_birth: row.get(15)?,
+            membership_expires_at: row.get(16)?,
+            membership_expired: row.get(17)?,
+            registration_status: row.get(18)?,
+            registration_reason: row.get(19)?,
+            email_verified_at: row.get(20)?,
+        })
+    }
+
+    pub fn fetch_users(&self) -> SqliteResult<Vec<UserSummary>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {USER_SUMMARY_COLUMNS} FROM users u ORDER BY u.username"
+        ))?;
+        let users = stmt
+            .query_map([], Self::user_summary_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(users)
+    }
+
+    pub fn fetch_user(&self, user_id: i64) -> SqliteResult<Option<UserSummary>> {
+        let conn = self.connection.lock().unwrap();
+
+        conn.query_row(
+            &format!("SELECT {USER_SUMMARY_COLUMNS} FROM users u WHERE u.id = ?1"),
+            [user_id],
+            Self::user_summary_from_row,
+        )
+        .optional()
+    }
+
+    // Staff edits to a user's username, email and home branch. A new username
+    // invalidates the user's tokens, so their sessions are revoked.
+    pub fn update_user(
+        &self,
+        user_id: i64,
+        username: Option<&str>,
+        email: Option<Option<&str>>,
+        branch_id: Option<Option<i64>>,
+        actor: &Actor,
+    ) -> SqliteResult<UserUpdate> {
+        let conn = self.connection.lock().unwrap();
+
+        let current: Option<String> = conn
+            .query_row(
+                "SELECT username FROM users WHERE id = ?1",
+                [user_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let current = match current {
+            Some(current) => current,
+            None => return Ok(UserUpdate::NotFound),
+        };
+        let renamed = username.filter(|username| *username != current);
+
+        if let Some(username) = renamed {
+            let taken: bool = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM users WHERE username = ?1)",
+                [username],
+                |row| row.get(0),
+            )?;
+            if taken {
+                return Ok(UserUpdate::UsernameTaken);
+            }
+        }
+        if let Some(Some(branch_id)) = branch_id {
+            let branch_exists: bool = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM branches WHERE id = ?1)",
+                [branch_id],
+                |row| row.get(0),
+            )?;
+            if !branch_exists {
+                return Ok(UserUpdate::UnknownBranch);
+            }
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        let before = Self::user_snapshot(&tx, user_id)?;
+        if let Some(username) = renamed {
+            tx.execute(
+                "UPDATE users SET username = ?1 WHERE id = ?2",
+                params![username, user_id],
+            )?;
+            tx.execute(
+                "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP, revoked_reason = 'username_changed'
+                 WHERE revoked_at IS NULL AND user_id = ?1",
+                [user_id],
+            )?;
+        }
+        if let Some(email) = email {
+            tx.execute(
+                "UPDATE users SET email = ?1,
+                email_verified_at = CASE WHEN email IS ?1 THEN email_verified_at END
+             WHERE id = ?2",
+                params![email, user_id],
+            )?;
+        }
+        if let Some(branch_id) = branch_id {
+            tx.execute(
+                "UPDATE users SET branch_id = ?1 WHERE id = ?2",
+                params![branch_id, user_id],
+            )?;
+        }
+        if let (Some(before), Some(after)) = (before, Self::user_snapshot(&tx, user_id)?) {
+            let (before, after) = audit::changes(&before, &after);
+            audit::record(
+                &tx,
+                actor,
+                "user.edit",
+                "user",
+                user_id,
+                Some(&before),
+                Some(&after),
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(UserUpdate::Updated)
+    }
+
+    // Returns false when there is no such user.
+    pub fn update_profile(
+        &self,
+        user_id: i64,
+        profile: &ProfileUpdate,
+        actor: &Actor,
+    ) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let tx = conn.unchecked_transaction()?;
+        let before = match Self::user_snapshot(&tx, user_id)? {
+            Some(before) => before,
+            None => return Ok(false),
+        };
+        let fields = [
+            ("full_name", profile.full_name),
+            ("phone", profile.phone),
+            ("address", profile.address),
+            ("date_of_birth", profile.date_of_birth),
+        ];
+        for (column, value) in fields {
+            if let Some(value) = value {
+                tx.execute(
+                    &format!("UPDATE users SET {column} = ?1 WHERE id = ?2"),
+                    params![value, user_id],
+                )?;
+            }
+        }
+        if let Some(after) = Self::user_snapshot(&tx, user_id)? {
+            let (before, after) = audit::changes(&before, &after);
+            audit::record(
+                &tx,
+                actor,
+                "user.edit_profile",
+                "user",
+                user_id,
+                Some(&before),
+                Some(&after),
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    // Extends the membership by `days` from its expiry, or from today if it has
+    // already lapsed or was never set. Returns the new expiry date.
+    pub fn renew_membership(
+        &self,
+        user_id: i64,
+        days: i64,
+        actor: &Actor,
+    ) -> SqliteResult<Option<String>> {
+        let conn = self.connection.lock().unwrap();
+
+        let current: Option<Option<String>> = conn
+            .query_row(
+                "SELECT membership_expires_at FROM users WHERE id = ?1",
+                [user_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let current = match current {
+            Some(current) => current,
+            None => return Ok(None),
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        let expires_at: String = tx.query_row(
+            "UPDATE users SET membership_expires_at = date(
+                max(COALESCE(membership_expires_at, date('now')), date('now')),
+                '+' || ?1 || ' days')
+             WHERE id = ?2
+             RETURNING membership_expires_at",
+            params![days, user_id],
+            |row| row.get(0),
+        )?;
+        audit::record(
+            &tx,
+            actor,
+            "user.renew_membership",
+            "user",
+            user_id,
+            Some(&json!({ "membership_expires_at": current })),
+            Some(&json!({ "membership_expires_at": expires_at })),
+        )?;
+        tx.commit()?;
+
+        Ok(Some(expires_at))
+    }
+
+    // A replacement for a lost or damaged card. The old number stops working at
+    // the desk and kiosks straight away. Returns the new number.
+    pub fn reissue_card(&self, user_id: i64, actor: &Actor) -> SqliteResult<Option<String>> {
+        let conn = self.connection.lock().unwrap();
+
+        let current: Option<Option<String>> = conn
+            .query_row(
+                "SELECT card_number FROM users WHERE id = ?1",
+                [user_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let current = match current {
+            Some(current) => current,
+            None => return Ok(None),
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        let card_number = Self::new_card_number(&tx)?;
+        tx.execute(
+            "UPDATE users SET card_number = ?1 WHERE id = ?2",
+            params![card_number, user_id],
+        )?;
+        tx.execute(
+            "UPDATE kiosk_sessions SET ended_at = CURRENT_TIMESTAMP
+             WHERE user_id = ?1 AND ended_at IS NULL",
+            [user_id],
+        )?;
+        audit::record(
+            &tx,
+            actor,
+            "user.reissue_card",
+            "user",
+            user_id,
+            Some(&json!({ "card_number": current })),
+            Some(&json!({ "card_number": card_number })),
+        )?;
+        tx.commit()?;
+
+        Ok(Some(card_number))
+    }
+
+    // Where a self-registered account stands, and the reason staff gave if they
+    // reviewed it.
+    pub fn registration_status(
+        &self,
+        user_id: i64,
+    ) -> SqliteResult<Option<(String, Option<String>)>> {
+        let conn = self.connection.lock().unwrap();
+
+        conn.query_row(
+            "SELECT registration_status, registration_reason FROM users WHERE id = ?1",
+            [user_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+    }
+
+    // Queues the email with the link that confirms the address. Returns false
+    // when the user has no email address.
+    pub fn queue_verification_email(&self, user_id: i64, link: &str) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+        Self::queue_email(
+            &conn,
+            user_id,
+            "verify_email",
+            &[
+                ("link", link),
+                ("hours", &registration::VERIFICATION_HOURS.to_string()),
+            ],
+        )
+    }
+
+    // The id and email of an account still waiting to confirm its address.
+    pub fn pending_verification(&self, username: &str) -> SqliteResult<Option<(i64, String)>> {
+        let conn = self.connection.lock().unwrap();
+
+        conn.query_row(
+            "SELECT id, email FROM users
+             WHERE username = ?1 AND registration_status = ?2 AND email IS NOT NULL",
+            params![username, registration::PENDING_VERIFICATION],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+    }
+
+    // Marks the address as confirmed, which approves an account that was waiting
+    // on it. The email must still be the one the token was issued for.
+    pub fn verify_email(
+        &self,
+        user_id: i64,
+        email: &str,
+        actor: &Actor,
+    ) -> SqliteResult<EmailVerification> {
+        let conn = self.connection.lock().unwrap();
+
+        let current: Option<(Option<String>, Option<String>, String)> = conn
+            .query_row(
+                "SELECT email, email_verified_at, registration_status FROM users WHERE id = ?1",
+                [user_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        let status = match current {
+            Some((Some(current), verified_at, status)) if current == email => {
+                if verified_at.is_some() && status != registration::PENDING_VERIFICATION {
+                    return Ok(EmailVerification::AlreadyVerified);
+                }
+                status
+            }
+            _ => return Ok(EmailVerification::Invalid),
+        };
+        let new_status = if status == registration::PENDING_VERIFICATION {
+            registration::APPROVED
+        } else {
+            &status
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE users SET email_verified_at = CURRENT_TIMESTAMP, registration_status = ?1
+             WHERE id = ?2",
+            params![new_status, user_id],
+        )?;
+        audit::record(
+            &tx,
+            actor,
+            "user.verify_email",
+            "user",
+            user_id,
+            Some(&json!({ "registration_status": status })),
+            Some(&json!({ "email": email, "registration_status": new_status })),
+        )?;
+        tx.commit()?;
+
+        Ok(EmailVerification::Verified)
+    }
+
+    // Registrations waiting for staff, oldest first.
+    pub fn fetch_pending_registrations(&self) -> SqliteResult<Vec<UserSummary>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {USER_SUMMARY_COLUMNS} FROM users u
+             WHERE u.registration_status = ?1 ORDER BY u.created_at, u.id"
+        ))?;
+        let users = stmt
+            .query_map(
+                [registration::PENDING_APPROVAL],
+                Self::user_summary_from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(users)
+    }
+
+    // Approves or rejects a registration waiting for staff and emails the
+    // applicant. Rejected accounts keep their row so the applicant is told why
+    // at login; deleting the user frees the username.
+    pub fn review_registration(
+        &self,
+        user_id: i64,
+        approve: bool,
+        reason: &str,
+        actor: &Actor,
+    ) -> SqliteResult<RegistrationReview> {
+        let conn = self.connection.lock().unwrap();
+
+        let status: Option<String> = conn
+            .query_row(
+                "SELECT registration_status FROM users WHERE id = ?1",
+                [user_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match status.as_deref() {
+            Some(registration::PENDING_APPROVAL) => {}
+            Some(_) => return Ok(RegistrationReview::NotPending),
+            None => return Ok(RegistrationReview::NotFound),
+        }
+
+        let (new_status, action, subject, message) = if approve {
+            (
+                registration::APPROVED,
+                "user.approve_registration",
+                "Your library registration was approved",
+                "Your registration has been approved. You can now log in.".to_string(),
+            )
+        } else {
+            (
+                registration::REJECTED,
+                "user.reject_registration",
+                "Your library registration was not approved",
+                format!(
+                    "Your registration was not approved.
+
+Reason: {reason}"
+                ),
+            )
+        };
+        let reason = Some(reason).filter(|reason| !reason.is_empty());
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE users SET registration_status = ?1, registration_reason = ?2,
+                registration_reviewed_at = CURRENT_TIMESTAMP
+             WHERE id = ?3",
+            params![new_status, reason, user_id],
+        )?;
+        audit::record(
+            &tx,
+            actor,
+            action,
+            "user",
+            user_id,
+            Some(&json!({ "registration_status": registration::PENDING_APPROVAL })),
+            Some(&json!({ "registration_status": new_status, "reason": reason })),
+        )?;
+        Self::queue_email(
+            &tx,
+            user_id,
+            "account",
+            &[("subject", subject), ("message", &message)],
+        )?;
+        tx.commit()?;
+
+        Ok(RegistrationReview::Reviewed)
+    }
+
+    pub fn fetch_items(&self, book_id: i64) -> SqliteResult<Vec<Item>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {ITEM_COLUMNS} FROM items WHERE book_id = ?1 ORDER BY id"
+        ))?;
+
+        let items = stmt
+            .query_map([book_id], Self::item_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(items)
+    }
+
+    pub fn add_item(
+        &self,
+        book_id: i64,
+        barcode: Option<&str>,
+        shelf_location: &str,
+        condition: &str,
+        branch_id: Option<i64>,
+        actor: &Actor,
+    ) -> SqliteResult<Option<Item>> {
+        let conn = self.connection.lock().unwrap();
+
+        let book_exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM books WHERE id = ?1)",
+            [book_id],
+            |row| row.get(0),
+        )?;
+        let barcode_taken: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM items WHERE barcode = ?1)",
+            [barcode.unwrap_or("")],
+            |row| row.get(0),
+        )?;
+        let branch_exists: bool = conn.query_row(
+            "SELECT ?1 IS NULL OR EXISTS(SELECT 1 FROM branches WHERE id = ?1)",
+            [branch_id],
+            |row| row.get(0),
+        )?;
+
+        if !book_exists || barcode_taken || !branch_exists {
+            return Ok(None);
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        let item_id =
+            Self::insert_item(&tx, book_id, barcode, shelf_location, condition, branch_id)?;
+        let item = tx
+            .query_row(
+                &format!("SELECT {ITEM_COLUMNS} FROM items WHERE id = ?1"),
+                [item_id],
+                Self::item_from_row,
+            )
+            .optional()?;
+        audit::record(
+            &tx,
+            actor,
+            "item.add",
+            "item",
+            item_id,
+            None,
+            Some(&json!(item)),
+        )?;
+        tx.commit()?;
+
+        Ok(item)
+    }
+
+    // Loans, holds and transfers move items through the circulation statuses; this
+    // only edits shelf data and the statuses staff set by hand.
+    pub fn update_item(
+        &self,
+        item_id: i64,
+        updated_fields: &Value,
+        actor: &Actor,
+    ) -> SqliteResult<ItemUpdate> {
+        let conn = self.connection.lock().unwrap();
+
+        let current_status: Option<String> = conn
+            .query_row("SELECT status FROM items WHERE id = ?1", [item_id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+
+        let current_status = match current_status {
+            Some(status) => status,
+            None => return Ok(ItemUpdate::Refused),
+        };
+
+        let mut sets = Vec::new();
+        let mut values: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+
+        if let Some(barcode) = updated_fields.get("barcode").and_then(|v| v.as_str()) {
+            let taken: bool = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM items WHERE barcode = ?1 AND id != ?2)",
+                params![barcode, item_id],
+                |row| row.get(0),
+            )?;
+            if taken {
+                return Ok(ItemUpdate::BarcodeTaken);
+            }
+            sets.push("barcode = ?");
+            values.push(Box::new(barcode.to_string()));
+        }
+        if let Some(shelf) = updated_fields
+            .get("shelf_location")
+            .and_then(|v| v.as_str())
+        {
+            sets.push("shelf_location = ?");
+            values.push(Box::new(shelf.to_string()));
+        }
+        if let Some(condition) = updated_fields.get("condition").and_then(|v| v.as_str()) {
+            sets.push("condition = ?");
+            values.push(Box::new(condition.to_string()));
+        }
+        let status = updated_fields.get("status").and_then(|v| v.as_str());
+        if let Some(status) = status
+            && (CIRCULATION_STATUSES.contains(&status)
+                || CIRCULATION_STATUSES.contains(&current_status.as_str()))
+        {
+            return Ok(ItemUpdate::Refused);
+        }
+
+        if sets.is_empty() && status.is_none() {
+            return Ok(ItemUpdate::Refused);
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        let before = Self::item_snapshot(&tx, item_id)?;
+        if !sets.is_empty() {
+            let query = format!("UPDATE items SET {} WHERE id = ?", sets.join(", "));
+            values.push(Box::new(item_id));
+            tx.prepare(&query)?
+                .execute(rusqlite::params_from_iter(values))?;
+        }
+        if let Some(status) = status {
+            Self::set_item_status(&tx, item_id, status)?;
+        }
+        let after = Self::item_snapshot(&tx, item_id)?;
+        let (before, after) = audit::changes(&before, &after);
+        audit::record(
+            &tx,
+            actor,
+            "item.edit",
+            "item",
+            item_id,
+            Some(&before),
+            Some(&after),
+        )?;
+        tx.commit()?;
+
+        Ok(ItemUpdate::Updated)
+    }
+
+    pub fn borrow_book(
+        &self,
+        user_id: i64,
+        book_id: i64,
+        branch_id: Option<i64>,
+        actor: &Actor,
+    ) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+        Ok(Self::lend_book(&conn, user_id, book_id, branch_id, actor)?.is_some())
+    }
+
+    // A copy held for the patron is used first; otherwise any available copy,
+    // preferring the requested branch.
+    fn lend_book(
+        conn: &Connection,
+        user_id: i64,
+        book_id: i64,
+        branch_id: Option<i64>,
+        actor: &Actor,
+    ) -> SqliteResult<Option<Checkout>> {
+        if !Self::can_borrow(conn, user_id)? {
+            return Ok(None);
+        }
+
+        let held: Option<(i64, i64)> = conn
+            .query_row(
+                "SELECT id, item_id FROM holds
+                 WHERE user_id = ?1 AND book_id = ?2 AND status = 'ready'
+                 ORDER BY id LIMIT 1",
+                [user_id, book_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        let (hold_id, item_id) = match held {
+            Some((hold_id, item_id)) => (Some(hold_id), Some(item_id)),
+            None => (
+                None,
+                conn.query_row(
+                    "SELECT id FROM items WHERE book_id = ?1 AND status = 'available'
+                     ORDER BY branch_id IS ?2 DESC, id LIMIT 1",
+                    params![book_id, branch_id],
+                    |row| row.get(0),
+                )
+                .optional()?,
+            ),
+        };
+
+        match item_id {
+            Some(item_id) => Ok(Some(Self::lend_item(
+                conn, user_id, item_id, hold_id, actor, None,
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    // Only existing, active and approved accounts with a current membership can
+    // borrow or place holds. Memberships run to the end of their expiry date.
+    fn can_borrow(conn: &Connection, user_id: i64) -> SqliteResult<bool> {
+        conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM users WHERE id = ?1 AND active = 1
+                AND registration_status = 'approved'
+                AND (membership_expires_at IS NULL OR membership_expires_at >= date('now')))",
+            [user_id],
+            |row| row.get(0),
+        )
+    }
+
+    // Kiosk checkouts pass their session so the kiosk_activity row is written with
+    // the loan.
+    fn lend_item(
+        conn: &Connection,
+        user_id: i64,
+        item_id: i64,
+        hold_id: Option<i64>,
+        actor: &Actor,
+        kiosk: Option<&KioskSession>,
+    ) -> SqliteResult<Checkout> {
+        let (book_id, barcode, title): (i64, String, String) = conn.query_row(
+            "SELECT i.book_id, i.barcode, b.title FROM items i JOIN books b ON i.book_id = b.id WHERE i.id = ?1",
+            [item_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+
+        let now = Utc::now().naive_utc();
+        let due_date = Self::load_calendar(conn)?
+            .roll_forward(now + Duration::days(LOAN_PERIOD_DAYS))
+            .format(DATETIME_FORMAT)
+            .to_string();
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO borrowed (user_id, book_id, item_id, created_at, due_date) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                user_id,
+                book_id,
+                item_id,
+                now.format(DATETIME_FORMAT).to_string(),
+                due_date
+            ],
+        )?;
+        let borrowed_id = tx.last_insert_rowid();
+
+        Self::set_item_status(&tx, item_id, "on_loan")?;
+        if let Some(hold_id) = hold_id {
+            tx.execute(
+                "UPDATE holds SET status = 'fulfilled' WHERE id = ?1",
+                [hold_id],
+            )?;
+            Self::emit_hold_status(&tx, hold_id)?;
+        }
+        events::emit(
+            &tx,
+            &DomainEvent::BookBorrowed {
+                borrowed_id,
+                user_id,
+                book_id,
+                item_id,
+                barcode: barcode.clone(),
+                title: title.clone(),
+                due_date: due_date.clone(),
+            },
+        )?;
+        let after = Self::loan_snapshot(&tx, borrowed_id)?;
+        audit::record(
+            &tx,
+            actor,
+            "loan.borrow",
+            "loan",
+            borrowed_id,
+            None,
+            after.as_ref(),
+        )?;
+        if let Some(session) = kiosk {
+            Self::record_kiosk_activity(&tx, session, "checkout", &barcode, borrowed_id)?;
+        }
+        tx.commit()?;
+
+        Ok(Checkout {
+            borrowed_id,
+            barcode,
+            title,
+            due_date,
+        })
+    }
+
+    pub fn fetch_borrowed_books(&self, user_id: i64) -> SqliteResult<Vec<BorrowedBook>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT 
+            br.id, 
+            br.due_date, 
+            br.renewals,
+            br.user_id,
+            u.username,
+            i.id,
+            i.barcode,
+            {BOOK_COLUMNS}
+         FROM 
+            borrowed br
+         JOIN 
+            books b ON br.book_id = b.id
+         JOIN 
+            items i ON br.item_id = i.id
+         JOIN 
+            users u ON br.user_id = u.id
+         WHERE 
+            br.user_id = ?1"
+        ))?;
+
+        let borrowed_books = stmt
+            .query_map([user_id], Self::borrowed_book_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self::with_fines(&conn, borrowed_books)
+    }
+
+    pub fn return_book(&self, borrowed_id: i64, book_id: i64, actor: &Actor) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let loan: Option<(i64, i64)> = conn
+            .query_row(
+                "SELECT user_id, item_id FROM borrowed WHERE id = ?1 AND book_id = ?2",
+                params![borrowed_id, book_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        let (user_id, item_id) = match loan {
+            Some(loan) => loan,
+            None => return Ok(false),
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        let before = Self::loan_snapshot(&tx, borrowed_id)?;
+        let closed = Self::close_loan(&tx, borrowed_id)?;
+        Self::allocate_item(&tx, item_id)?;
+        audit::record(
+            &tx,
+            actor,
+            "loan.return",
+            "loan",
+            borrowed_id,
+            before.as_ref(),
+            None,
+        )?;
+        events::emit(
+            &tx,
+            &DomainEvent::BookReturned {
+                borrowed_id,
+                user_id,
+                book_id,
+                item_id,
+            },
+        )?;
+        tx.commit()?;
+        Ok(closed.is_some())
+    }
+
+    pub fn fetch_all_borrowed_books(&self) -> SqliteResult<Vec<BorrowedBook>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT 
+            br.id, 
+            br.due_date, 
+            br.renewals,
+            br.user_id,
+            u.username,
+            i.id,
+            i.barcode,
+            {BOOK_COLUMNS}
+         FROM 
+            borrowed br
+         JOIN 
+            books b ON br.book_id = b.id
+         JOIN 
+            items i ON br.item_id = i.id
+         JOIN 
+            users u ON br.user_id = u.id"
+        ))?;
 
         let borrowed_books = stmt
+            .query_map([], Self::borrowed_book_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::with_fines(&conn, borrowed_books)
+    }
+
+    fn borrowed_book_from_row(row: &Row) -> SqliteResult<BorrowedBook> {
+        Ok(BorrowedBook {
+            borrowed_id: row.get(0)?,
+            due_date: row.get(1)?,
+            renewals: row.get(2)?,
+            overdue_days: 0,
+            fine_cents: 0,
+            user_id: row.get(3)?,
+            username: row.get(4)?,
+            item_id: row.get(5)?,
+            barcode: row.get(6)?,
+            book: Self::book_from_row(row, 7)?,
+        })
+    }
+
+    fn with_fines(
+        conn: &Connection,
+        mut borrowed_books: Vec<BorrowedBook>,
+    ) -> SqliteResult<Vec<BorrowedBook>> {
+        let calendar = Self::load_calendar(conn)?;
+        let today = Utc::now().date_naive();
+
+        for borrowed in &mut borrowed_books {
+            if let Ok(due) = NaiveDateTime::parse_from_str(&borrowed.due_date, DATETIME_FORMAT)
+                && due.date() < today
+            {
+                borrowed.overdue_days = calendar.open_days_between(due.date(), today);
+                borrowed.fine_cents = borrowed.overdue_days * DAILY_FINE_CENTS;
+            }
+        }
+
+        Ok(borrowed_books)
+    }
+
+    // Renewing restarts the loan period from today; overdue loans must be returned.
+    pub fn renew_book(&self, borrowed_id: i64, actor: &Actor) -> SqliteResult<Renewal> {
+        let conn = self.connection.lock().unwrap();
+
+        let loan: Option<(i64, i64, String, i64)> = conn
+            .query_row(
+                "SELECT user_id, book_id, due_date, renewals FROM borrowed WHERE id = ?1",
+                [borrowed_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()?;
+
+        let (user_id, book_id, due_date, renewals) = match loan {
+            Some(loan) => loan,
+            None => return Ok(Renewal::Refused),
+        };
+
+        if !Self::can_borrow(&conn, user_id)? {
+            return Ok(Renewal::CannotBorrow);
+        }
+
+        let now = Utc::now().naive_utc();
+        let overdue = NaiveDateTime::parse_from_str(&due_date, DATETIME_FORMAT)
+            .map(|due| due < now)
+            .unwrap_or(true);
+
+        if overdue || renewals >= MAX_RENEWALS {
+            return Ok(Renewal::Refused);
+        }
+
+        let hold_waiting: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM holds WHERE book_id = ?1 AND status = 'waiting')",
+            [book_id],
+            |row| row.get(0),
+        )?;
+        if hold_waiting {
+            return Ok(Renewal::HoldWaiting);
+        }
+
+        let new_due_date = Self::load_calendar(&conn)?
+            .roll_forward(now + Duration::days(LOAN_PERIOD_DAYS))
+            .format(DATETIME_FORMAT)
+            .to_string();
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE borrowed SET due_date = ?1, renewals = renewals + 1 WHERE id = ?2",
+            params![new_due_date, borrowed_id],
+        )?;
+        events::emit(
+            &tx,
+            &DomainEvent::BookRenewed {
+                borrowed_id,
+                user_id,
+                book_id,
+                due_date: new_due_date.clone(),
+            },
+        )?;
+        audit::record(
+            &tx,
+            actor,
+            "loan.renew",
+            "loan",
+            borrowed_id,
+            Some(&json!({ "due_date": due_date, "renewals": renewals })),
+            Some(&json!({ "due_date": new_due_date, "renewals": renewals + 1 })),
+        )?;
+        tx.commit()?;
+
+        Ok(Renewal::Renewed(new_due_date))
+    }
+
+    fn load_calendar(conn: &Connection) -> SqliteResult<Calendar> {
+        let hours = Self::query_opening_hours(conn)?;
+        let closures = Self::query_closures(conn)?;
+        Ok(Calendar::new(&hours, &closures))
+    }
+
+    fn query_opening_hours(conn: &Connection) -> SqliteResult<Vec<OpeningHours>> {
+        let mut stmt = conn
+            .prepare("SELECT weekday, opens, closes, closed FROM opening_hours ORDER BY weekday")?;
+
+        let hours = stmt
+            .query_map([], |row| {
+                Ok(OpeningHours {
+                    weekday: row.get(0)?,
+                    opens: row.get(1)?,
+                    closes: row.get(2)?,
+                    closed: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(hours)
+    }
+
+    fn query_closures(conn: &Connection) -> SqliteResult<Vec<Closure>> {
+        let mut stmt = conn.prepare("SELECT date, reason FROM closures ORDER BY date")?;
+
+        let closures = stmt
+            .query_map([], |row| {
+                Ok(Closure {
+                    date: row.get(0)?,
+                    reason: row.get(1)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(closures)
+    }
+
+    pub fn fetch_calendar(&self) -> SqliteResult<(Vec<OpeningHours>, Vec<Closure>)> {
+        let conn = self.connection.lock().unwrap();
+        Ok((
+            Self::query_opening_hours(&conn)?,
+            Self::query_closures(&conn)?,
+        ))
+    }
+
+    pub fn set_opening_hours(
+        &self,
+        weekday: u32,
+        opens: &str,
+        closes: &str,
+        closed: bool,
+        actor: &Actor,
+    ) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let tx = conn.unchecked_transaction()?;
+        let snapshot = |conn: &Connection| {
+            conn.query_row(
+                "SELECT opens, closes, closed FROM opening_hours WHERE weekday = ?1",
+                [weekday],
+                |row| {
+                    Ok(json!({
+                        "opens": row.get::<_, String>(0)?,
+                        "closes": row.get::<_, String>(1)?,
+                        "closed": row.get::<_, bool>(2)?,
+                    }))
+                },
+            )
+            .optional()
+        };
+        let before = snapshot(&tx)?;
+        let affected_row = tx.execute(
+            "UPDATE opening_hours SET opens = ?1, closes = ?2, closed = ?3 WHERE weekday = ?4",
+            params![opens, closes, closed, weekday],
+        )?;
+        if let (Some(before), Some(after)) = (before, snapshot(&tx)?) {
+            let (before, after) = audit::changes(&before, &after);
+            audit::record(
+                &tx,
+                actor,
+                "calendar.set_hours",
+                "opening_hours",
+                weekday as i64,
+                Some(&before),
+                Some(&after),
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(affected_row > 0)
+    }
+
+    // Closures are audited under their rowid, with the date in the entry.
+    pub fn add_closure(&self, date: &str, reason: &str, actor: &Actor) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let tx = conn.unchecked_transaction()?;
+        let affected_row = tx.execute(
+            "INSERT OR IGNORE INTO closures (date, reason) VALUES (?1, ?2)",
+            params![date, reason],
+        )?;
+        if affected_row > 0 {
+            audit::record(
+                &tx,
+                actor,
+                "calendar.add_closure",
+                "closure",
+                tx.last_insert_rowid(),
+                None,
+                Some(&json!({ "date": date, "reason": reason })),
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(affected_row > 0)
+    }
+
+    pub fn delete_closure(&self, date: &str, actor: &Actor) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let tx = conn.unchecked_transaction()?;
+        let closure: Option<(i64, String)> = tx
+            .query_row(
+                "DELETE FROM closures WHERE date = ?1 RETURNING rowid, reason",
+                params![date],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        if let Some((closure_id, reason)) = &closure {
+            audit::record(
+                &tx,
+                actor,
+                "calendar.delete_closure",
+                "closure",
+                *closure_id,
+                Some(&json!({ "date": date, "reason": reason })),
+                None,
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(closure.is_some())
+    }
+
+    // Every change of a copy's status goes through here, so the live feeds hear
+    // about each one.
+    fn set_item_status(conn: &Connection, item_id: i64, status: &str) -> SqliteResult<()> {
+        let book_id: Option<i64> = conn
+            .query_row(
+                "UPDATE items SET status = ?1 WHERE id = ?2 AND status IS NOT ?1 RETURNING book_id",
+                params![status, item_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(book_id) = book_id {
+            events::emit(
+                conn,
+                &DomainEvent::ItemStatusChanged {
+                    item_id,
+                    book_id,
+                    status: status.to_string(),
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    // Call after changing a hold's status.
+    fn emit_hold_status(conn: &Connection, hold_id: i64) -> SqliteResult<()> {
+        let (user_id, book_id, status): (i64, i64, String) = conn.query_row(
+            "SELECT user_id, book_id, status FROM holds WHERE id = ?1",
+            [hold_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        events::emit(
+            conn,
+            &DomainEvent::HoldStatusChanged {
+                hold_id,
+                user_id,
+                book_id,
+                status,
+            },
+        )
+    }
+
+    // Hands a copy that just came free to the oldest waiting hold on its book, or
+    // puts it back on the shelf when nobody is waiting.
+    fn allocate_item(conn: &Connection, item_id: i64) -> SqliteResult<()> {
+        let (book_id, branch_id): (i64, i64) = conn.query_row(
+            "SELECT book_id, branch_id FROM items WHERE id = ?1",
+            [item_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        let hold: Option<(i64, i64)> = conn
+            .query_row(
+                "SELECT id, pickup_branch_id FROM holds
+                 WHERE book_id = ?1 AND status = 'waiting'
+                 ORDER BY created_at, id LIMIT 1",
+                [book_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        match hold {
+            Some((hold_id, pickup_branch_id)) => {
+                Self::assign_hold_item(conn, hold_id, item_id, branch_id, pickup_branch_id)
+            }
+            None => Self::set_item_status(conn, item_id, "available"),
+        }
+    }
+
+    // Copies already at the pickup branch wait on the hold shelf; others are sent there.
+    fn assign_hold_item(
+        conn: &Connection,
+        hold_id: i64,
+        item_id: i64,
+        item_branch_id: i64,
+        pickup_branch_id: i64,
+    ) -> SqliteResult<()> {
+        if item_branch_id == pickup_branch_id {
+            Self::set_item_status(conn, item_id, "on_hold")?;
+            conn.execute(
+                "UPDATE holds SET item_id = ?1, status = 'ready' WHERE id = ?2",
+                [item_id, hold_id],
+            )?;
+            Self::emit_hold_status(conn, hold_id)?;
+            Self::emit_hold_ready(conn, hold_id)?;
+        } else {
+            Self::set_item_status(conn, item_id, "in_transit")?;
+            conn.execute(
+                "INSERT INTO transfers (item_id, from_branch_id, to_branch_id, hold_id) VALUES (?1, ?2, ?3, ?4)",
+                [item_id, item_branch_id, pickup_branch_id, hold_id],
+            )?;
+            conn.execute(
+                "UPDATE holds SET item_id = ?1, status = 'in_transit' WHERE id = ?2",
+                [item_id, hold_id],
+            )?;
+            Self::emit_hold_status(conn, hold_id)?;
+        }
+        Ok(())
+    }
+
+    fn emit_hold_ready(conn: &Connection, hold_id: i64) -> SqliteResult<()> {
+        let (user_id, book_id, title, branch): (i64, i64, String, String) = conn.query_row(
+            "SELECT h.user_id, h.book_id, b.title, br.name FROM holds h
+             JOIN books b ON h.book_id = b.id
+             JOIN branches br ON h.pickup_branch_id = br.id
+             WHERE h.id = ?1",
+            [hold_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?;
+
+        events::emit(
+            conn,
+            &DomainEvent::HoldReady {
+                hold_id,
+                user_id,
+                book_id,
+                title,
+                branch,
+            },
+        )
+    }
+
+    pub fn fetch_branches(&self) -> SqliteResult<Vec<Branch>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare("SELECT id, name, address FROM branches ORDER BY id")?;
+
+        let branches = stmt
+            .query_map([], |row| {
+                Ok(Branch {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    address: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(branches)
+    }
+
+    pub fn add_branch(&self, name: &str, address: &str, actor: &Actor) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let tx = conn.unchecked_transaction()?;
+        let affected_row = tx.execute(
+            "INSERT OR IGNORE INTO branches (name, address) VALUES (?1, ?2)",
+            params![name, address],
+        )?;
+        if affected_row > 0 {
+            audit::record(
+                &tx,
+                actor,
+                "branch.add",
+                "branch",
+                tx.last_insert_rowid(),
+                None,
+                Some(&json!({ "name": name, "address": address })),
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(affected_row > 0)
+    }
+
+    pub fn set_user_branch(
+        &self,
+        user_id: i64,
+        branch_id: i64,
+        actor: &Actor,
+    ) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let branch_exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM branches WHERE id = ?1)",
+            [branch_id],
+            |row| row.get(0),
+        )?;
+
+        if !branch_exists {
+            return Ok(false);
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        let before = Self::user_snapshot(&tx, user_id)?;
+        let affected_row = tx.execute(
+            "UPDATE users SET branch_id = ?1 WHERE id = ?2",
+            [branch_id, user_id],
+        )?;
+        if let (Some(before), Some(after)) = (before, Self::user_snapshot(&tx, user_id)?) {
+            let (before, after) = audit::changes(&before, &after);
+            audit::record(
+                &tx,
+                actor,
+                "user.edit",
+                "user",
+                user_id,
+                Some(&before),
+                Some(&after),
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(affected_row > 0)
+    }
+
+    pub fn fetch_branch_availability(&self, book_id: i64) -> SqliteResult<Vec<BranchAvailability>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT br.id, br.name,
+                COUNT(CASE WHEN i.status = 'available' THEN 1 END),
+                COUNT(CASE WHEN i.status NOT IN ('lost', 'withdrawn') THEN 1 END)
+             FROM branches br
+             JOIN items i ON i.branch_id = br.id AND i.book_id = ?1
+             GROUP BY br.id
+             ORDER BY br.id",
+        )?;
+
+        let availability = stmt
+            .query_map([book_id], |row| {
+                Ok(BranchAvailability {
+                    branch_id: row.get(0)?,
+                    name: row.get(1)?,
+                    available: row.get(2)?,
+                    total: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(availability)
+    }
+
+    pub fn place_hold(
+        &self,
+        user_id: i64,
+        book_id: i64,
+        pickup_branch_id: i64,
+        actor: &Actor,
+    ) -> SqliteResult<Option<i64>> {
+        let conn = self.connection.lock().unwrap();
+
+        if !Self::can_borrow(&conn, user_id)? {
+            return Ok(None);
+        }
+        let valid: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM books WHERE id = ?2)
+                AND EXISTS(SELECT 1 FROM branches WHERE id = ?3)
+                AND NOT EXISTS(
+                    SELECT 1 FROM holds WHERE user_id = ?1 AND book_id = ?2
+                    AND status IN ('waiting', 'in_transit', 'ready')
+                )",
+            [user_id, book_id, pickup_branch_id],
+            |row| row.get(0),
+        )?;
+
+        if !valid {
+            return Ok(None);
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO holds (user_id, book_id, pickup_branch_id) VALUES (?1, ?2, ?3)",
+            [user_id, book_id, pickup_branch_id],
+        )?;
+        let hold_id = tx.last_insert_rowid();
+
+        let available: Option<(i64, i64)> = tx
+            .query_row(
+                "SELECT id, branch_id FROM items WHERE book_id = ?1 AND status = 'available'
+                 ORDER BY branch_id = ?2 DESC, id LIMIT 1",
+                [book_id, pickup_branch_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        if let Some((item_id, branch_id)) = available {
+            Self::assign_hold_item(&tx, hold_id, item_id, branch_id, pickup_branch_id)?;
+        }
+        let after = Self::hold_snapshot(&tx, hold_id)?;
+        audit::record(
+            &tx,
+            actor,
+            "hold.place",
+            "hold",
+            hold_id,
+            None,
+            after.as_ref(),
+        )?;
+        tx.commit()?;
+
+        Ok(Some(hold_id))
+    }
+
+    pub fn fetch_holds(&self, user_id: Option<i64>) -> SqliteResult<Vec<Hold>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT h.id, h.user_id, u.username, h.book_id, b.title,
+                h.pickup_branch_id, br.name, h.item_id, h.status, h.created_at
+             FROM holds h
+             JOIN users u ON h.user_id = u.id
+             JOIN books b ON h.book_id = b.id
+             JOIN branches br ON h.pickup_branch_id = br.id
+             WHERE h.status IN ('waiting', 'in_transit', 'ready')
+                AND (?1 IS NULL OR h.user_id = ?1)
+             ORDER BY h.created_at, h.id",
+        )?;
+
+        let holds = stmt
+            .query_map([user_id], |row| {
+                Ok(Hold {
+                    id: row.get(0)?,
+                    user_id: row.get(1)?,
+                    username: row.get(2)?,
+                    book_id: row.get(3)?,
+                    title: row.get(4)?,
+                    pickup_branch_id: row.get(5)?,
+                    pickup_branch: row.get(6)?,
+                    item_id: row.get(7)?,
+                    status: row.get(8)?,
+                    created_at: row.get(9)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(holds)
+    }
+
+    // Who a hold belongs to, so patrons can only cancel their own.
+    pub fn hold_owner(&self, hold_id: i64) -> SqliteResult<Option<i64>> {
+        let conn = self.connection.lock().unwrap();
+
+        conn.query_row(
+            "SELECT user_id FROM holds WHERE id = ?1",
+            [hold_id],
+            |row| row.get(0),
+        )
+        .optional()
+    }
+
+    // A copy waiting on the hold shelf moves on to the next hold; one still in
+    // transit is dealt with when the transfer is received.
+    pub fn cancel_hold(&self, hold_id: i64, actor: &Actor) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let hold: Option<(String, Option<i64>)> = conn
+            .query_row(
+                "SELECT status, item_id FROM holds WHERE id = ?1",
+                [hold_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        let (status, item_id) = match hold {
+            Some(hold) => hold,
+            None => return Ok(false),
+        };
+
+        if !["waiting", "in_transit", "ready"].contains(&status.as_str()) {
+            return Ok(false);
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        let before = Self::hold_snapshot(&tx, hold_id)?;
+        tx.execute(
+            "UPDATE holds SET status = 'cancelled' WHERE id = ?1",
+            [hold_id],
+        )?;
+        Self::emit_hold_status(&tx, hold_id)?;
+        if let (Some(item_id), "ready") = (item_id, status.as_str()) {
+            Self::allocate_item(&tx, item_id)?;
+        }
+        if let (Some(before), Some(after)) = (before, Self::hold_snapshot(&tx, hold_id)?) {
+            let (before, after) = audit::changes(&before, &after);
+            audit::record(
+                &tx,
+                actor,
+                "hold.cancel",
+                "hold",
+                hold_id,
+                Some(&before),
+                Some(&after),
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    pub fn start_transfer(
+        &self,
+        item_id: i64,
+        to_branch_id: i64,
+        actor: &Actor,
+    ) -> SqliteResult<Option<i64>> {
+        let conn = self.connection.lock().unwrap();
+
+        let from_branch_id: Option<i64> = conn
+            .query_row(
+                "SELECT branch_id FROM items WHERE id = ?1 AND status = 'available'",
+                [item_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let branch_exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM branches WHERE id = ?1)",
+            [to_branch_id],
+            |row| row.get(0),
+        )?;
+
+        let from_branch_id = match from_branch_id {
+            Some(id) if branch_exists && id != to_branch_id => id,
+            _ => return Ok(None),
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        Self::set_item_status(&tx, item_id, "in_transit")?;
+        tx.execute(
+            "INSERT INTO transfers (item_id, from_branch_id, to_branch_id) VALUES (?1, ?2, ?3)",
+            [item_id, from_branch_id, to_branch_id],
+        )?;
+        let transfer_id = tx.last_insert_rowid();
+        audit::record(
+            &tx,
+            actor,
+            "transfer.start",
+            "transfer",
+            transfer_id,
+            None,
+            Some(&json!({
+                "item_id": item_id,
+                "from_branch_id": from_branch_id,
+                "to_branch_id": to_branch_id,
+            })),
+        )?;
+        tx.commit()?;
+
+        Ok(Some(transfer_id))
+    }
+
+    pub fn fetch_transfers(&self) -> SqliteResult<Vec<Transfer>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT t.id, t.item_id, i.barcode, t.from_branch_id, t.to_branch_id,
+                t.hold_id, t.sent_at, t.received_at
+             FROM transfers t
+             JOIN items i ON t.item_id = i.id
+             WHERE t.received_at IS NULL
+             ORDER BY t.sent_at, t.id",
+        )?;
+
+        let transfers = stmt
+            .query_map([], |row| {
+                Ok(Transfer {
+                    id: row.get(0)?,
+                    item_id: row.get(1)?,
+                    barcode: row.get(2)?,
+                    from_branch_id: row.get(3)?,
+                    to_branch_id: row.get(4)?,
+                    hold_id: row.get(5)?,
+                    sent_at: row.get(6)?,
+                    received_at: row.get(7)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(transfers)
+    }
+
+    // The copy now belongs to the receiving branch. If it was sent for a hold that
+    // is still open it goes on the hold shelf, otherwise it is allocated afresh.
+    pub fn receive_transfer(&self, transfer_id: i64, actor: &Actor) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let transfer: Option<(i64, i64, Option<i64>)> = conn
+            .query_row(
+                "SELECT item_id, to_branch_id, hold_id FROM transfers
+                 WHERE id = ?1 AND received_at IS NULL",
+                [transfer_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+
+        let (item_id, to_branch_id, hold_id) = match transfer {
+            Some(transfer) => transfer,
+            None => return Ok(false),
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE transfers SET received_at = CURRENT_TIMESTAMP WHERE id = ?1",
+            [transfer_id],
+        )?;
+        tx.execute(
+            "UPDATE items SET branch_id = ?1 WHERE id = ?2",
+            [to_branch_id, item_id],
+        )?;
+
+        let hold_waiting: bool = tx.query_row(
+            "SELECT EXISTS(SELECT 1 FROM holds WHERE id = ?1 AND status = 'in_transit')",
+            [hold_id],
+            |row| row.get(0),
+        )?;
+
+        match hold_id {
+            Some(hold_id) if hold_waiting => {
+                Self::assign_hold_item(&tx, hold_id, item_id, to_branch_id, to_branch_id)?
+            }
+            _ => Self::allocate_item(&tx, item_id)?,
+        }
+        let item_status: String =
+            tx.query_row("SELECT status FROM items WHERE id = ?1", [item_id], |row| {
+                row.get(0)
+            })?;
+        audit::record(
+            &tx,
+            actor,
+            "transfer.receive",
+            "transfer",
+            transfer_id,
+            None,
+            Some(&json!({
+                "item_id": item_id,
+                "branch_id": to_branch_id,
+                "hold_id": hold_id,
+                "item_status": item_status,
+            })),
+        )?;
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    // Ends a loan and posts any overdue fine to the patron's ledger, without
+    // returning the copy to circulation. Returns the patron, book and copy so the
+    // caller can charge and re-status it.
+    fn close_loan(conn: &Connection, borrowed_id: i64) -> SqliteResult<Option<(i64, i64, i64)>> {
+        let loan: Option<(i64, i64, i64, String)> = conn
+            .query_row(
+                "SELECT user_id, book_id, item_id, due_date FROM borrowed WHERE id = ?1",
+                [borrowed_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()?;
+
+        let (user_id, book_id, item_id, due_date) = match loan {
+            Some(loan) => loan,
+            None => return Ok(None),
+        };
+
+        let today = Utc::now().date_naive();
+        if let Ok(due) = NaiveDateTime::parse_from_str(&due_date, DATETIME_FORMAT)
+            && due.date() < today
+        {
+            let overdue_days = Self::load_calendar(conn)?.open_days_between(due.date(), today);
+            if overdue_days > 0 {
+                Self::charge(
+                    conn,
+                    user_id,
+                    item_id,
+                    "overdue_fine",
+                    overdue_days * DAILY_FINE_CENTS,
+                    &format!("Overdue fine, {overdue_days} open day(s) late"),
+                )?;
+            }
+        }
+
+        conn.execute("DELETE FROM borrowed WHERE id = ?1", [borrowed_id])?;
+
+        Ok(Some((user_id, book_id, item_id)))
+    }
+
+    fn charge(
+        conn: &Connection,
+        user_id: i64,
+        item_id: i64,
+        kind: &str,
+        amount_cents: i64,
+        note: &str,
+    ) -> SqliteResult<()> {
+        conn.execute(
+            "INSERT INTO ledger (user_id, item_id, kind, amount_cents, note) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![user_id, item_id, kind, amount_cents, note],
+        )?;
+        Ok(())
+    }
+
+    pub fn declare_lost(
+        &self,
+        borrowed_id: i64,
+        fee_cents: i64,
+        actor: &Actor,
+    ) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let tx = conn.unchecked_transaction()?;
+        let before = Self::loan_snapshot(&tx, borrowed_id)?;
+        let (user_id, book_id, item_id) = match Self::close_loan(&tx, borrowed_id)? {
+            Some(loan) => loan,
+            None => return Ok(false),
+        };
+
+        Self::set_item_status(&tx, item_id, "lost")?;
+        Self::charge(
+            &tx,
+            user_id,
+            item_id,
+            "lost_replacement",
+            fee_cents,
+            "Replacement fee for lost item",
+        )?;
+        events::emit(
+            &tx,
+            &DomainEvent::ItemLost {
+                borrowed_id,
+                user_id,
+                book_id,
+                item_id,
+            },
+        )?;
+        audit::record(
+            &tx,
+            actor,
+            "loan.lost",
+            "loan",
+            borrowed_id,
+            before.as_ref(),
+            Some(&json!({ "fee_cents": fee_cents })),
+        )?;
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    pub fn return_damaged(
+        &self,
+        borrowed_id: i64,
+        fee_cents: i64,
+        condition: &str,
+        actor: &Actor,
+    ) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let tx = conn.unchecked_transaction()?;
+        let before = Self::loan_snapshot(&tx, borrowed_id)?;
+        let (user_id, book_id, item_id) = match Self::close_loan(&tx, borrowed_id)? {
+            Some(loan) => loan,
+            None => return Ok(false),
+        };
+
+        tx.execute(
+            "UPDATE items SET condition = ?1 WHERE id = ?2",
+            params![condition, item_id],
+        )?;
+        Self::set_item_status(&tx, item_id, "in_repair")?;
+        Self::charge(
+            &tx,
+            user_id,
+            item_id,
+            "damage",
+            fee_cents,
+            "Item returned damaged",
+        )?;
+        events::emit(
+            &tx,
+            &DomainEvent::BookReturned {
+                borrowed_id,
+                user_id,
+                book_id,
+                item_id,
+            },
+        )?;
+        audit::record(
+            &tx,
+            actor,
+            "loan.return_damaged",
+            "loan",
+            borrowed_id,
+            before.as_ref(),
+            Some(&json!({ "fee_cents": fee_cents, "condition": condition })),
+        )?;
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    // Puts a lost copy back into circulation and refunds the replacement fee that
+    // was charged for it, if it hasn't been refunded already.
+    pub fn mark_found(&self, item_id: i64, actor: &Actor) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let is_lost: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM items WHERE id = ?1 AND status = 'lost')",
+            [item_id],
+            |row| row.get(0),
+        )?;
+
+        if !is_lost {
+            return Ok(false);
+        }
+
+        let charge: Option<(i64, i64, i64)> = conn
+            .query_row(
+                "SELECT l.id, l.user_id, l.amount_cents FROM ledger l
+                 WHERE l.item_id = ?1 AND l.kind = 'lost_replacement'
+                    AND NOT EXISTS(SELECT 1 FROM ledger r WHERE r.reverses_id = l.id)
+                 ORDER BY l.id DESC LIMIT 1",
+                [item_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+
+        let tx = conn.unchecked_transaction()?;
+        let before = Self::item_snapshot(&tx, item_id)?;
+        if let Some((charge_id, user_id, amount_cents)) = charge {
+            tx.execute(
+                "INSERT INTO ledger (user_id, item_id, kind, amount_cents, note, reverses_id)
+                 VALUES (?1, ?2, 'lost_reversal', ?3, 'Lost item found', ?4)",
+                params![user_id, item_id, -amount_cents, charge_id],
+            )?;
+        }
+        Self::allocate_item(&tx, item_id)?;
+        let after = Self::item_snapshot(&tx, item_id)?;
+        let (before, after) = audit::changes(&before, &after);
+        audit::record(
+            &tx,
+            actor,
+            "item.found",
+            "item",
+            item_id,
+            Some(&before),
+            Some(&after),
+        )?;
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    pub fn fetch_ledger(&self, user_id: i64) -> SqliteResult<(Vec<LedgerEntry>, i64)> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, item_id, kind, amount_cents, note, reverses_id, created_at
+             FROM ledger WHERE user_id = ?1 ORDER BY id",
+        )?;
+
+        let entries = stmt
+            .query_map([user_id], |row| {
+                Ok(LedgerEntry {
+                    id: row.get(0)?,
+                    user_id: row.get(1)?,
+                    item_id: row.get(2)?,
+                    kind: row.get(3)?,
+                    amount_cents: row.get(4)?,
+                    note: row.get(5)?,
+                    reverses_id: row.get(6)?,
+                    created_at: row.get(7)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let balance = entries.iter().map(|entry| entry.amount_cents).sum();
+
+        Ok((entries, balance))
+    }
+
+    fn patron_from_row(row: &Row) -> SqliteResult<Patron> {
+        Ok(Patron {
+            id: row.get(0)?,
+            username: row.get(1)?,
+            role: row.get(2)?,
+            card_number: row.get(3)?,
+            active: row.get(4)?,
+            membership_expires_at: row.get(5)?,
+            membership_expired: row.get(6)?,
+        })
+    }
+
+    // Desk staff scan either the library card or type the username.
+    // The desk takes a card number or a username. Card numbers are tried first, so
+    // an account named after someone else's card can't stand in for them.
+    pub fn find_patron(&self, identifier: &str) -> SqliteResult<Option<Patron>> {
+        let conn = self.connection.lock().unwrap();
+
+        let by_card = conn
+            .query_row(
+                &format!("SELECT {PATRON_COLUMNS} FROM users WHERE card_number = ?1"),
+                [identifier],
+                Self::patron_from_row,
+            )
+            .optional()?;
+        if by_card.is_some() {
+            return Ok(by_card);
+        }
+
+        Self::query_patron_by_username(&conn, identifier)
+    }
+
+    pub fn fetch_patron_by_username(&self, username: &str) -> SqliteResult<Option<Patron>> {
+        let conn = self.connection.lock().unwrap();
+        Self::query_patron_by_username(&conn, username)
+    }
+
+    fn query_patron_by_username(conn: &Connection, username: &str) -> SqliteResult<Option<Patron>> {
+        conn.query_row(
+            &format!("SELECT {PATRON_COLUMNS} FROM users WHERE username = ?1"),
+            [username],
+            Self::patron_from_row,
+        )
+        .optional()
+    }
+
+    // Who a loan belongs to, so patrons can only renew and return their own.
+    pub fn loan_owner(&self, borrowed_id: i64) -> SqliteResult<Option<i64>> {
+        let conn = self.connection.lock().unwrap();
+
+        conn.query_row(
+            "SELECT user_id FROM borrowed WHERE id = ?1",
+            [borrowed_id],
+            |row| row.get(0),
+        )
+        .optional()
+    }
+
+    pub fn fetch_patron(&self, user_id: i64) -> SqliteResult<Option<Patron>> {
+        let conn = self.connection.lock().unwrap();
+
+        conn.query_row(
+            &format!("SELECT {PATRON_COLUMNS} FROM users WHERE id = ?1"),
+            [user_id],
+            Self::patron_from_row,
+        )
+        .optional()
+    }
+
+    pub fn checkout_by_isbn(
+        &self,
+        user_id: i64,
+        isbn: &str,
+        actor: &Actor,
+    ) -> SqliteResult<Option<Checkout>> {
+        let conn = self.connection.lock().unwrap();
+
+        let book_id: Option<i64> = conn
+            .query_row("SELECT id FROM books WHERE isbn = ?1", [isbn], |row| {
+                row.get(0)
+            })
+            .optional()?;
+
+        match book_id {
+            Some(book_id) => Self::lend_book(&conn, user_id, book_id, None, actor),
+            None => Ok(None),
+        }
+    }
+
+    // The scanned copy must be on the shelf, or on the hold shelf for this patron.
+    pub fn checkout_by_barcode(
+        &self,
+        user_id: i64,
+        barcode: &str,
+        actor: &Actor,
+        kiosk: Option<&KioskSession>,
+    ) -> SqliteResult<Option<Checkout>> {
+        let conn = self.connection.lock().unwrap();
+
+        if !Self::can_borrow(&conn, user_id)? {
+            return Ok(None);
+        }
+
+        let item: Option<(i64, String, Option<i64>)> = conn
+            .query_row(
+                "SELECT i.id, i.status,
+                    (SELECT h.id FROM holds h
+                     WHERE h.item_id = i.id AND h.user_id = ?2 AND h.status = 'ready')
+                 FROM items i WHERE i.barcode = ?1",
+                params![barcode, user_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+
+        match item {
+            Some((item_id, status, None)) if status == "available" => Ok(Some(Self::lend_item(
+                &conn, user_id, item_id, None, actor, kiosk,
+            )?)),
+            Some((item_id, status, Some(hold_id))) if status == "on_hold" => Ok(Some(
+                Self::lend_item(&conn, user_id, item_id, Some(hold_id), actor, kiosk)?,
+            )),
+            _ => Ok(None),
+        }
+    }
+
+    // Finds the open loan from the scanned copy, so staff don't need loan ids.
+    pub fn checkin_by_barcode(
+        &self,
+        barcode: &str,
+        actor: &Actor,
+        kiosk: Option<&KioskSession>,
+    ) -> SqliteResult<Option<CheckIn>> {
+        let conn = self.connection.lock().unwrap();
+
+        let loan: Option<(i64, i64, i64, String, String, i64)> = conn
+            .query_row(
+                "SELECT br.id, br.item_id, br.user_id, u.username, b.title, br.book_id
+                 FROM borrowed br
+                 JOIN items i ON br.item_id = i.id
+                 JOIN users u ON br.user_id = u.id
+                 JOIN books b ON br.book_id = b.id
+                 WHERE i.barcode = ?1",
+                [barcode],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                        row.get(5)?,
+                    ))
+                },
+            )
+            .optional()?;
+
+        let (borrowed_id, item_id, user_id, username, title, book_id) = match loan {
+            Some(loan) => loan,
+            None => return Ok(None),
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        let before = Self::loan_snapshot(&tx, borrowed_id)?;
+        Self::close_loan(&tx, borrowed_id)?;
+        Self::allocate_item(&tx, item_id)?;
+        audit::record(
+            &tx,
+            actor,
+            "loan.return",
+            "loan",
+            borrowed_id,
+            before.as_ref(),
+            None,
+        )?;
+        events::emit(
+            &tx,
+            &DomainEvent::BookReturned {
+                borrowed_id,
+                user_id,
+                book_id,
+                item_id,
+            },
+        )?;
+        if let Some(session) = kiosk {
+            Self::record_kiosk_activity(&tx, session, "checkin", barcode, borrowed_id)?;
+        }
+        let item_status: String =
+            tx.query_row("SELECT status FROM items WHERE id = ?1", [item_id], |row| {
+                row.get(0)
+            })?;
+        tx.commit()?;
+
+        Ok(Some(CheckIn {
+            borrowed_id,
+            user_id,
+            username,
+            barcode: barcode.to_string(),
+            title,
+            item_status,
+        }))
+    }
+
+    pub fn set_pin(&self, username: &str, pin: &str, actor: &Actor) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let pin_hash = match hash(pin, DEFAULT_COST) {
+            Ok(pin_hash) => pin_hash,
+            Err(_) => return Ok(false),
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        let affected_row = tx.execute(
+            "UPDATE users SET pin_hash = ?1 WHERE username = ?2",
+            params![pin_hash, username],
+        )?;
+        // The PIN itself is never logged, only that it changed.
+        if affected_row > 0 {
+            let user_id: i64 = tx.query_row(
+                "SELECT id FROM users WHERE username = ?1",
+                [username],
+                |row| row.get(0),
+            )?;
+            audit::record(&tx, actor, "user.set_pin", "user", user_id, None, None)?;
+        }
+        tx.commit()?;
+
+        Ok(affected_row > 0)
+    }
+
+    // Returns the kiosk id and the device credential, which is only shown this once.
+    pub fn register_kiosk(
+        &self,
+        name: &str,
+        branch_id: Option<i64>,
+        actor: &Actor,
+    ) -> SqliteResult<Option<(i64, String)>> {
+        let conn = self.connection.lock().unwrap();
+
+        let branch_exists: bool = conn.query_row(
+            "SELECT ?1 IS NULL OR EXISTS(SELECT 1 FROM branches WHERE id = ?1)",
+            [branch_id],
+            |row| row.get(0),
+        )?;
+
+        if !branch_exists {
+            return Ok(None);
+        }
+
+        let secret = generate_token();
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO kiosks (name, secret_hash, branch_id) VALUES (?1, ?2, ?3)",
+            params![name, hash_token(&secret), branch_id],
+        )?;
+        let kiosk_id = tx.last_insert_rowid();
+        audit::record(
+            &tx,
+            actor,
+            "kiosk.register",
+            "kiosk",
+            kiosk_id,
+            None,
+            Some(&json!({ "name": name, "branch_id": branch_id })),
+        )?;
+        tx.commit()?;
+
+        Ok(Some((kiosk_id, format!("{kiosk_id}.{secret}"))))
+    }
+
+    pub fn fetch_kiosks(&self) -> SqliteResult<Vec<Kiosk>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt =
+            conn.prepare("SELECT id, name, branch_id, active, created_at FROM kiosks ORDER BY id")?;
+
+        let kiosks = stmt
+            .query_map([], |row| {
+                Ok(Kiosk {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    branch_id: row.get(2)?,
+                    active: row.get(3)?,
+                    created_at: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(kiosks)
+    }
+
+    // Deactivating a kiosk also ends whatever session is open on it.
+    pub fn deactivate_kiosk(&self, kiosk_id: i64, actor: &Actor) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let tx = conn.unchecked_transaction()?;
+        let affected_row = tx.execute(
+            "UPDATE kiosks SET active = 0 WHERE id = ?1 AND active = 1",
+            [kiosk_id],
+        )?;
+        tx.execute(
+            "UPDATE kiosk_sessions SET ended_at = CURRENT_TIMESTAMP
+             WHERE kiosk_id = ?1 AND ended_at IS NULL",
+            [kiosk_id],
+        )?;
+        if affected_row > 0 {
+            audit::record(
+                &tx,
+                actor,
+                "kiosk.deactivate",
+                "kiosk",
+                kiosk_id,
+                Some(&json!({ "active": true })),
+                Some(&json!({ "active": false })),
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(affected_row > 0)
+    }
+
+    fn verify_kiosk(conn: &Connection, credential: &str) -> SqliteResult<Option<i64>> {
+        let (kiosk_id, secret) = match credential.split_once('.') {
+            Some((id, secret)) => match id.parse::<i64>() {
+                Ok(id) => (id, secret),
+                Err(_) => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+
+        conn.query_row(
+            "SELECT id FROM kiosks WHERE id = ?1 AND secret_hash = ?2 AND active = 1",
+            params![kiosk_id, hash_token(secret)],
+            |row| row.get(0),
+        )
+        .optional()
+    }
+
+    // Starts a patron session on a kiosk, ending any session still open on it.
+    // Wrong PINs are throttled like passwords, per card and per kiosk.
+    pub fn start_kiosk_session(
+        &self,
+        credential: &str,
+        card_number: &str,
+        pin: &str,
+        actor: &Actor,
+    ) -> SqliteResult<KioskLogin> {
+        let conn = self.connection.lock().unwrap();
+
+        let kiosk_id = match Self::verify_kiosk(&conn, credential)? {
+            Some(id) => id,
+            None => return Ok(KioskLogin::Invalid),
+        };
+
+        let keys = [card_number, &kiosk_id.to_string()];
+        if let Some(seconds) = Self::retry_after(&conn, &KIOSK_LOGIN_LIMITS, keys)? {
+            return Ok(KioskLogin::RetryAfter(seconds));
+        }
+
+        let patron: Option<(i64, Option<String>)> = conn
+            .query_row(
+                "SELECT id, pin_hash FROM users WHERE card_number = ?1 AND active = 1",
+                [card_number],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        // Hash anyway so an unknown card takes as long as a wrong PIN.
+        let pin_hash = match &patron {
+            Some((_, Some(pin_hash))) => pin_hash.as_str(),
+            _ => DUMMY_PASSWORD_HASH,
+        };
+        let pin_matches = verify(pin, pin_hash).unwrap_or(false);
+        let user_id = match patron {
+            Some((user_id, Some(_))) if pin_matches => user_id,
+            _ => {
+                Self::count_login_failure(
+                    &conn,
+                    &KIOSK_LOGIN_LIMITS,
+                    keys,
+                    &actor.kiosk(kiosk_id),
+                )?;
+                return Ok(KioskLogin::Invalid);
+            }
+        };
+
+        let token = generate_token();
+        let expires_at = (Utc::now().naive_utc() + Duration::minutes(KIOSK_SESSION_MINUTES))
+            .format(DATETIME_FORMAT)
+            .to_string();
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE kiosk_sessions SET ended_at = CURRENT_TIMESTAMP
+             WHERE kiosk_id = ?1 AND ended_at IS NULL",
+            [kiosk_id],
+        )?;
+        tx.execute(
+            "INSERT INTO kiosk_sessions (kiosk_id, user_id, token_hash, expires_at) VALUES (?1, ?2, ?3, ?4)",
+            params![kiosk_id, user_id, hash_token(&token), expires_at],
+        )?;
+        tx.execute(
+            "DELETE FROM login_failures WHERE scope = 'card' AND key = ?1",
+            [card_number],
+        )?;
+        tx.commit()?;
+
+        Ok(KioskLogin::Started { token, expires_at })
+    }
+
+    // Both the device credential and the session token must match an open,
+    // unexpired session; a successful check slides the expiry forward.
+    pub fn kiosk_session(
+        &self,
+        credential: &str,
+        token: &str,
+    ) -> SqliteResult<Option<KioskSession>> {
+        let conn = self.connection.lock().unwrap();
+
+        let kiosk_id = match Self::verify_kiosk(&conn, credential)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        let now = Utc::now().naive_utc();
+        let session: Option<(i64, i64)> = conn
+            .query_row(
+                "SELECT id, user_id FROM kiosk_sessions
+                 WHERE kiosk_id = ?1 AND token_hash = ?2 AND ended_at IS NULL AND expires_at > ?3",
+                params![
+                    kiosk_id,
+                    hash_token(token),
+                    now.format(DATETIME_FORMAT).to_string()
+                ],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        let (session_id, user_id) = match session {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+
+        conn.execute(
+            "UPDATE kiosk_sessions SET expires_at = ?1 WHERE id = ?2",
+            params![
+                (now + Duration::minutes(KIOSK_SESSION_MINUTES))
+                    .format(DATETIME_FORMAT)
+                    .to_string(),
+                session_id
+            ],
+        )?;
+
+        Ok(Some(KioskSession {
+            session_id,
+            kiosk_id,
+            user_id,
+        }))
+    }
+
+    pub fn end_kiosk_session(&self, session_id: i64) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let affected_row = conn.execute(
+            "UPDATE kiosk_sessions SET ended_at = CURRENT_TIMESTAMP WHERE id = ?1 AND ended_at IS NULL",
+            [session_id],
+        )?;
+
+        Ok(affected_row > 0)
+    }
+
+    fn record_kiosk_activity(
+        conn: &Connection,
+        session: &KioskSession,
+        action: &str,
+        barcode: &str,
+        borrowed_id: i64,
+    ) -> SqliteResult<()> {
+        conn.execute(
+            "INSERT INTO kiosk_activity (kiosk_id, session_id, user_id, action, barcode, borrowed_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                session.kiosk_id,
+                session.session_id,
+                session.user_id,
+                action,
+                barcode,
+                borrowed_id
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn loan_owner_by_barcode(&self, barcode: &str) -> SqliteResult<Option<i64>> {
+        let conn = self.connection.lock().unwrap();
+
+        conn.query_row(
+            "SELECT br.user_id FROM borrowed br JOIN items i ON br.item_id = i.id WHERE i.barcode = ?1",
+            [barcode],
+            |row| row.get(0),
+        )
+        .optional()
+    }
+
+    // Runs `work` at most once per (job, run_key). The claim and the work share a
+    // transaction, so a crash part way through leaves the run unclaimed.
+    // Returns None when the run already happened.
+    pub fn run_job_once<F>(&self, job: &str, run_key: &str, work: F) -> SqliteResult<Option<i64>>
+    where
+        F: FnOnce(&Connection) -> SqliteResult<i64>,
+    {
+        let conn = self.connection.lock().unwrap();
+
+        let tx = conn.unchecked_transaction()?;
+        let claimed = tx.execute(
+            "INSERT OR IGNORE INTO job_runs (job, run_key) VALUES (?1, ?2)",
+            params![job, run_key],
+        )?;
+
+        if claimed == 0 {
+            return Ok(None);
+        }
+
+        let processed = work(&tx)?;
+        tx.execute(
+            "UPDATE job_runs SET processed = ?1, finished_at = CURRENT_TIMESTAMP
+             WHERE job = ?2 AND run_key = ?3",
+            params![processed, job, run_key],
+        )?;
+        tx.commit()?;
+
+        Ok(Some(processed))
+    }
+
+    pub fn fetch_job_runs(&self) -> SqliteResult<Vec<JobRun>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT job, run_key, processed, started_at, finished_at FROM job_runs
+             ORDER BY started_at DESC, job LIMIT 100",
+        )?;
+
+        let runs = stmt
+            .query_map([], |row| {
+                Ok(JobRun {
+                    job: row.get(0)?,
+                    run_key: row.get(1)?,
+                    processed: row.get(2)?,
+                    started_at: row.get(3)?,
+                    finished_at: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(runs)
+    }
+
+    // Queues a due_soon notice for loans due within `days_before` days, and an
+    // overdue notice at the highest escalation level each late loan has reached.
+    // Returns how many new notices were queued.
+    pub fn enqueue_loan_notices(
+        conn: &Connection,
+        now: NaiveDateTime,
+        days_before: i64,
+    ) -> SqliteResult<i64> {
+        let mut stmt = conn.prepare("SELECT id, user_id, book_id, due_date FROM borrowed")?;
+        let loans = stmt
             .query_map([], |row| {
-                Ok(BorrowedBook {
-                    borrowed_id: row.get(0)?,
-                    due_date: row.get(1)?,
-                    user_id: row.get(2)?,
-                    username: row.get(3)?,
-                    book: Book {
-                        id: row.get(4)?,
-                        title: row.get(5)?,
-                        author: row.get(6)?,
-                        isbn: row.get(7)?,
-                        publication_year: row.get(8)?,
-                        genre: row.get(9)?,
-                        number_of_copies: row.get(10)?,
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut queued = 0;
+        for (borrowed_id, user_id, book_id, due_date) in loans {
+            let due = match NaiveDateTime::parse_from_str(&due_date, DATETIME_FORMAT) {
+                Ok(due) => due,
+                Err(_) => continue,
+            };
+
+            let notice = if due >= now {
+                (due - now <= Duration::days(days_before)).then_some(("due_soon", 0))
+            } else {
+                let days_late = (now - due).num_days();
+                OVERDUE_ESCALATION_DAYS
+                    .iter()
+                    .rposition(|threshold| days_late >= *threshold)
+                    .map(|index| ("overdue", index as i64 + 1))
+            };
+
+            if let Some((kind, level)) = notice {
+                let inserted = conn.execute(
+                    "INSERT OR IGNORE INTO notices (user_id, borrowed_id, book_id, kind, level, due_date)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![user_id, borrowed_id, book_id, kind, level, due_date],
+                )?;
+                if inserted == 0 {
+                    continue;
+                }
+                queued += 1;
+
+                let notice_id = conn.last_insert_rowid();
+                let title: String =
+                    conn.query_row("SELECT title FROM books WHERE id = ?1", [book_id], |row| {
+                        row.get(0)
+                    })?;
+                if kind == "overdue" {
+                    Self::notify(
+                        conn,
+                        user_id,
+                        "overdue",
+                        &format!("Overdue: {title}"),
+                        &format!("This loan was due on {due_date}. Please return it."),
+                    )?;
+                }
+                let emailed = Self::queue_email(
+                    conn,
+                    user_id,
+                    kind,
+                    &[
+                        ("title", &title),
+                        ("due_date", &due_date),
+                        ("level", &level.to_string()),
+                    ],
+                )?;
+                conn.execute(
+                    "UPDATE notices SET status = ?1 WHERE id = ?2",
+                    params![if emailed { "emailed" } else { "skipped" }, notice_id],
+                )?;
+            }
+        }
+
+        Ok(queued)
+    }
+
+    pub fn fetch_notices(&self, status: Option<&str>) -> SqliteResult<Vec<Notice>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT n.id, n.user_id, u.username, n.borrowed_id, COALESCE(b.title, ''), n.kind,
+                    n.level, n.due_date, n.status, n.created_at
+             FROM notices n
+             JOIN users u ON n.user_id = u.id
+             LEFT JOIN books b ON n.book_id = b.id
+             WHERE ?1 IS NULL OR n.status = ?1
+             ORDER BY n.id DESC",
+        )?;
+
+        let notices = stmt
+            .query_map([status], |row| {
+                Ok(Notice {
+                    id: row.get(0)?,
+                    user_id: row.get(1)?,
+                    username: row.get(2)?,
+                    borrowed_id: row.get(3)?,
+                    title: row.get(4)?,
+                    kind: row.get(5)?,
+                    level: row.get(6)?,
+                    due_date: row.get(7)?,
+                    status: row.get(8)?,
+                    created_at: row.get(9)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(notices)
+    }
+
+    // Renders a template into the outbox. Returns false without queueing anything
+    // when the patron has no email address or has opted out of the category.
+    pub fn queue_email(
+        conn: &Connection,
+        user_id: i64,
+        category: &str,
+        vars: &[(&str, &str)],
+    ) -> SqliteResult<bool> {
+        let template = match mail::template(category) {
+            Some(template) => template,
+            None => return Ok(false),
+        };
+
+        let recipient: Option<(String, Option<String>)> = conn
+            .query_row(
+                "SELECT username, email FROM users u WHERE id = ?1
+                 AND NOT EXISTS(SELECT 1 FROM email_opt_outs o WHERE o.user_id = u.id AND o.category = ?2)",
+                params![user_id, category],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        let (username, to_address) = match recipient {
+            Some((username, Some(email))) => (username, email),
+            _ => return Ok(false),
+        };
+
+        let mut vars = vars.to_vec();
+        vars.push(("username", &username));
+
+        conn.execute(
+            "INSERT INTO outbox (user_id, to_address, category, subject, body) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                user_id,
+                to_address,
+                category,
+                mail::render(template.subject, &vars),
+                mail::render(template.body, &vars)
+            ],
+        )?;
+
+        Ok(true)
+    }
+
+    pub fn set_email(
+        &self,
+        username: &str,
+        email: Option<&str>,
+        actor: &Actor,
+    ) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let user_id: Option<i64> = conn
+            .query_row(
+                "SELECT id FROM users WHERE username = ?1",
+                [username],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let user_id = match user_id {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        let before = Self::user_snapshot(&tx, user_id)?;
+        tx.execute(
+            "UPDATE users SET email = ?1,
+                email_verified_at = CASE WHEN email IS ?1 THEN email_verified_at END
+             WHERE id = ?2",
+            params![email, user_id],
+        )?;
+        if let (Some(before), Some(after)) = (before, Self::user_snapshot(&tx, user_id)?) {
+            let (before, after) = audit::changes(&before, &after);
+            audit::record(
+                &tx,
+                actor,
+                "user.edit",
+                "user",
+                user_id,
+                Some(&before),
+                Some(&after),
+            )?;
+        }
+        Self::queue_email(
+            &tx,
+            user_id,
+            "account",
+            &[
+                ("subject", "Email address updated"),
+                (
+                    "message",
+                    "Library notices will be sent to this address from now on.",
+                ),
+            ],
+        )?;
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    pub fn fetch_email_preferences(
+        &self,
+        username: &str,
+    ) -> SqliteResult<Option<EmailPreferences>> {
+        let conn = self.connection.lock().unwrap();
+
+        let user: Option<(i64, Option<String>)> = conn
+            .query_row(
+                "SELECT id, email FROM users WHERE username = ?1",
+                [username],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        let (user_id, email) = match user {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        let mut categories = BTreeMap::new();
+        for category in OPTIONAL_EMAIL_CATEGORIES {
+            let opted_out: bool = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM email_opt_outs WHERE user_id = ?1 AND category = ?2)",
+                params![user_id, category],
+                |row| row.get(0),
+            )?;
+            categories.insert(category, !opted_out);
+        }
+
+        Ok(Some(EmailPreferences { email, categories }))
+    }
+
+    pub fn set_email_preference(
+        &self,
+        username: &str,
+        category: &str,
+        enabled: bool,
+    ) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let user_id: Option<i64> = conn
+            .query_row(
+                "SELECT id FROM users WHERE username = ?1",
+                [username],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let user_id = match user_id {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+
+        if enabled {
+            conn.execute(
+                "DELETE FROM email_opt_outs WHERE user_id = ?1 AND category = ?2",
+                params![user_id, category],
+            )?;
+        } else {
+            conn.execute(
+                "INSERT OR IGNORE INTO email_opt_outs (user_id, category) VALUES (?1, ?2)",
+                params![user_id, category],
+            )?;
+        }
+
+        Ok(true)
+    }
+
+    fn outbox_email_from_row(row: &Row) -> SqliteResult<OutboxEmail> {
+        Ok(OutboxEmail {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            to_address: row.get(2)?,
+            category: row.get(3)?,
+            subject: row.get(4)?,
+            body: row.get(5)?,
+            status: row.get(6)?,
+            attempts: row.get(7)?,
+            next_attempt_at: row.get(8)?,
+            last_error: row.get(9)?,
+            created_at: row.get(10)?,
+            sent_at: row.get(11)?,
+        })
+    }
+
+    pub fn fetch_due_emails(&self, limit: i64) -> SqliteResult<Vec<OutboxEmail>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, to_address, category, subject, body, status, attempts,
+                    next_attempt_at, last_error, created_at, sent_at
+             FROM outbox WHERE status = 'pending' AND next_attempt_at <= ?1
+             ORDER BY id LIMIT ?2",
+        )?;
+
+        let emails = stmt
+            .query_map(
+                params![
+                    Utc::now().naive_utc().format(DATETIME_FORMAT).to_string(),
+                    limit
+                ],
+                Self::outbox_email_from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(emails)
+    }
+
+    pub fn fetch_outbox(&self, status: Option<&str>) -> SqliteResult<Vec<OutboxEmail>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, to_address, category, subject,
+                    CASE WHEN category IN ('password_reset', 'verify_email') THEN '[redacted]' ELSE body END,
+                    status, attempts, next_attempt_at, last_error, created_at, sent_at
+             FROM outbox WHERE ?1 IS NULL OR status = ?1
+             ORDER BY id DESC LIMIT 200",
+        )?;
+
+        let emails = stmt
+            .query_map([status], Self::outbox_email_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(emails)
+    }
+
+    pub fn mark_email_sent(&self, email_id: i64) -> SqliteResult<()> {
+        let conn = self.connection.lock().unwrap();
+
+        conn.execute(
+            "UPDATE outbox SET status = 'sent', attempts = attempts + 1,
+                    sent_at = CURRENT_TIMESTAMP, last_error = NULL,
+                    body = CASE WHEN category IN ('password_reset', 'verify_email') THEN '[redacted]' ELSE body END
+             WHERE id = ?1",
+            [email_id],
+        )?;
+
+        Ok(())
+    }
+
+    // Schedules the next attempt with exponential backoff, or gives up once
+    // MAX_EMAIL_ATTEMPTS is reached.
+    pub fn mark_email_failed(&self, email_id: i64, error: &str) -> SqliteResult<()> {
+        let conn = self.connection.lock().unwrap();
+
+        let attempts: i64 = conn.query_row(
+            "SELECT attempts + 1 FROM outbox WHERE id = ?1",
+            [email_id],
+            |row| row.get(0),
+        )?;
+
+        let status = if attempts >= MAX_EMAIL_ATTEMPTS {
+            "failed"
+        } else {
+            "pending"
+        };
+        let next_attempt_at = (Utc::now().naive_utc() + Duration::minutes(1 << (attempts - 1)))
+            .format(DATETIME_FORMAT)
+            .to_string();
+
+        // A reset or verification link that will never be delivered is cleared
+        // like one that was.
+        conn.execute(
+            "UPDATE outbox SET status = ?1, attempts = ?2, next_attempt_at = ?3, last_error = ?4,
+                    body = CASE WHEN ?1 = 'failed' AND category IN ('password_reset', 'verify_email')
+                           THEN '[redacted]' ELSE body END
+             WHERE id = ?5",
+            params![status, attempts, next_attempt_at, error, email_id],
+        )?;
+
+        Ok(())
+    }
+
+    // Puts a failed email back in the queue for immediate delivery. Reset and
+    // verification emails have lost their link by then, so the patron has to ask
+    // for a new one instead.
+    pub fn retry_email(&self, email_id: i64) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let affected_row = conn.execute(
+            "UPDATE outbox SET status = 'pending', attempts = 0, next_attempt_at = ?1
+             WHERE id = ?2 AND status = 'failed'
+               AND category NOT IN ('password_reset', 'verify_email')",
+            params![
+                Utc::now().naive_utc().format(DATETIME_FORMAT).to_string(),
+                email_id
+            ],
+        )?;
+
+        Ok(affected_row > 0)
+    }
+
+    pub fn notify(
+        conn: &Connection,
+        user_id: i64,
+        kind: &str,
+        title: &str,
+        message: &str,
+    ) -> SqliteResult<()> {
+        conn.execute(
+            "INSERT INTO notifications (user_id, kind, title, message) VALUES (?1, ?2, ?3, ?4)",
+            params![user_id, kind, title, message],
+        )?;
+        events::emit(
+            conn,
+            &DomainEvent::UserNotified {
+                notification_id: conn.last_insert_rowid(),
+                user_id,
+                kind: kind.to_string(),
+                title: title.to_string(),
+                message: message.to_string(),
+            },
+        )
+    }
+
+    // Newest first, with the number still unread.
+    pub fn fetch_notifications(
+        &self,
+        username: &str,
+        unread_only: bool,
+    ) -> SqliteResult<(Vec<Notification>, i64)> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT id, kind, title, message, read_at IS NOT NULL, created_at FROM notifications
+             WHERE user_id = (SELECT id FROM users WHERE username = ?1)
+             AND (?2 = 0 OR read_at IS NULL)
+             ORDER BY id DESC LIMIT 100",
+        )?;
+
+        let notifications = stmt
+            .query_map(params![username, unread_only], |row| {
+                Ok(Notification {
+                    id: row.get(0)?,
+                    kind: row.get(1)?,
+                    title: row.get(2)?,
+                    message: row.get(3)?,
+                    read: row.get(4)?,
+                    created_at: row.get(5)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let unread = Self::count_unread(&conn, username)?;
+
+        Ok((notifications, unread))
+    }
+
+    fn count_unread(conn: &Connection, username: &str) -> SqliteResult<i64> {
+        conn.query_row(
+            "SELECT COUNT(*) FROM notifications
+             WHERE user_id = (SELECT id FROM users WHERE username = ?1) AND read_at IS NULL",
+            [username],
+            |row| row.get(0),
+        )
+    }
+
+    pub fn unread_notifications(&self, username: &str) -> SqliteResult<i64> {
+        let conn = self.connection.lock().unwrap();
+        Self::count_unread(&conn, username)
+    }
+
+    // With no id, marks every notification read. Only touches the user's own.
+    pub fn mark_notifications_read(
+        &self,
+        username: &str,
+        notification_id: Option<i64>,
+    ) -> SqliteResult<usize> {
+        let conn = self.connection.lock().unwrap();
+
+        conn.execute(
+            "UPDATE notifications SET read_at = CURRENT_TIMESTAMP
+             WHERE user_id = (SELECT id FROM users WHERE username = ?1)
+             AND (?2 IS NULL OR id = ?2) AND read_at IS NULL",
+            params![username, notification_id],
+        )
+    }
+
+    pub fn delete_notification(&self, username: &str, notification_id: i64) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let affected_row = conn.execute(
+            "DELETE FROM notifications
+             WHERE id = ?1 AND user_id = (SELECT id FROM users WHERE username = ?2)",
+            params![notification_id, username],
+        )?;
+
+        Ok(affected_row > 0)
+    }
+
+    pub fn latest_event_id(&self) -> SqliteResult<i64> {
+        let conn = self.connection.lock().unwrap();
+        conn.query_row(
+            "SELECT COALESCE(MAX(id), 0) FROM domain_events",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    // Catalog events plus, when a user is given, that user's own events. Also
+    // returns the id to resume after, which moves past events the stream doesn't
+    // show so a quiet stream still keeps up.
+    pub fn fetch_events_after(
+        &self,
+        last_event_id: i64,
+        user_id: Option<i64>,
+    ) -> SqliteResult<(Vec<Event>, i64)> {
+        self.live_events_after(last_event_id, |event| {
+            event.user_id.is_none() || event.user_id == user_id
+        })
+    }
+
+    // Every event, including other users' loans and holds; for staff views only.
+    pub fn fetch_all_events_after(&self, last_event_id: i64) -> SqliteResult<(Vec<Event>, i64)> {
+        self.live_events_after(last_event_id, |_| true)
+    }
+
+    fn live_events_after(
+        &self,
+        last_event_id: i64,
+        visible: impl Fn(&Event) -> bool,
+    ) -> SqliteResult<(Vec<Event>, i64)> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn
+            .prepare("SELECT id, payload FROM domain_events WHERE id > ?1 ORDER BY id LIMIT 500")?;
+        let rows = stmt
+            .query_map([last_event_id], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut cursor = last_event_id;
+        let mut live = Vec::new();
+        for (event_id, payload) in rows {
+            cursor = event_id;
+            // Unreadable events are skipped, as the subscribers skip them
+            if let Ok(event) = serde_json::from_str::<DomainEvent>(&payload) {
+                live.extend(
+                    Self::live_events(&conn, event_id, &event)?
+                        .into_iter()
+                        .filter(|event| visible(event)),
+                );
+            }
+        }
+
+        Ok((live, cursor))
+    }
+
+    // How a domain event shows on the live feeds. Book details and copy counts are
+    // read as they stand now, so a client catching up sees the latest state.
+    fn live_events(
+        conn: &Connection,
+        event_id: i64,
+        event: &DomainEvent,
+    ) -> SqliteResult<Vec<Event>> {
+        let live = |user_id: Option<i64>, kind: &str, data: Value| Event {
+            id: event_id,
+            user_id,
+            kind: kind.to_string(),
+            data: data.to_string(),
+        };
+        let loans_changed = |user_id: i64, data: Value| live(Some(user_id), "loans_changed", data);
+
+        let events = match event {
+            DomainEvent::BookAdded { book_id, title, .. } => vec![live(
+                None,
+                "book_added",
+                json!({ "book_id": book_id, "title": title }),
+            )],
+            DomainEvent::BookEdited { book_id, .. } => conn
+                .query_row(
+                    "SELECT title, author, isbn, publication_year, genre FROM books WHERE id = ?1",
+                    [book_id],
+                    |row| {
+                        Ok(json!({
+                            "book_id": book_id,
+                            "title": row.get::<_, String>(0)?,
+                            "author": row.get::<_, String>(1)?,
+                            "isbn": row.get::<_, String>(2)?,
+                            "publication_year": row.get::<_, String>(3)?,
+                            "genre": row.get::<_, String>(4)?,
+                        }))
                     },
+                )
+                .optional()?
+                .map(|data| live(None, "book_edited", data))
+                .into_iter()
+                .collect(),
+            DomainEvent::BookDeleted { book_id } => {
+                vec![live(None, "book_deleted", json!({ "book_id": book_id }))]
+            }
+            DomainEvent::ItemAdded { book_id, .. }
+            | DomainEvent::ItemStatusChanged { book_id, .. } => conn
+                .query_row(
+                    "SELECT available, total FROM book_copy_counts WHERE book_id = ?1",
+                    [book_id],
+                    |row| {
+                        Ok(json!({
+                            "book_id": book_id,
+                            "available": row.get::<_, i64>(0)?,
+                            "total": row.get::<_, i64>(1)?,
+                        }))
+                    },
+                )
+                .optional()?
+                .map(|data| live(None, "copies_changed", data))
+                .into_iter()
+                .collect(),
+            DomainEvent::BookBorrowed {
+                borrowed_id,
+                user_id,
+                book_id,
+                due_date,
+                ..
+            } => vec![loans_changed(
+                *user_id,
+                json!({ "action": "borrowed", "borrowed_id": borrowed_id, "book_id": book_id, "due_date": due_date }),
+            )],
+            DomainEvent::BookRenewed {
+                borrowed_id,
+                user_id,
+                book_id,
+                due_date,
+            } => vec![loans_changed(
+                *user_id,
+                json!({ "action": "renewed", "borrowed_id": borrowed_id, "book_id": book_id, "due_date": due_date }),
+            )],
+            DomainEvent::BookReturned {
+                borrowed_id,
+                user_id,
+                book_id,
+                ..
+            } => vec![loans_changed(
+                *user_id,
+                json!({ "action": "returned", "borrowed_id": borrowed_id, "book_id": book_id }),
+            )],
+            DomainEvent::ItemLost {
+                borrowed_id,
+                user_id,
+                book_id,
+                ..
+            } => vec![loans_changed(
+                *user_id,
+                json!({ "action": "lost", "borrowed_id": borrowed_id, "book_id": book_id }),
+            )],
+            DomainEvent::HoldStatusChanged {
+                hold_id,
+                user_id,
+                book_id,
+                status,
+            } => vec![live(
+                Some(*user_id),
+                "hold_updated",
+                json!({ "hold_id": hold_id, "book_id": book_id, "status": status }),
+            )],
+            DomainEvent::UserNotified {
+                notification_id,
+                user_id,
+                kind,
+                title,
+                message,
+            } => vec![live(
+                Some(*user_id),
+                "notification",
+                json!({ "id": notification_id, "kind": kind, "title": title, "message": message }),
+            )],
+            // HoldReady is also reported as a HoldStatusChanged
+            DomainEvent::UserRegistered { .. } | DomainEvent::HoldReady { .. } => Vec::new(),
+        };
+
+        Ok(events)
+    }
+
+    // Events are only kept long enough for live clients to reconnect and catch up,
+    // and until every subscriber has seen them.
+    pub fn prune_events(conn: &Connection, before: NaiveDateTime) -> SqliteResult<i64> {
+        let before = before.format(DATETIME_FORMAT).to_string();
+        let deleted = conn.execute(
+            "DELETE FROM domain_events WHERE created_at < ?1
+             AND id <= (SELECT IFNULL(MIN(last_event_id), 0) FROM event_cursors)",
+            [&before],
+        )?;
+        Ok(deleted as i64)
+    }
+
+    // Feeds up to `limit` events past the subscriber's cursor to `handle`, one
+    // transaction per event. A failing event is retried on the next call and
+    // skipped once it has failed MAX_SUBSCRIBER_ATTEMPTS times, so one bad event
+    // can't stall the subscriber. Returns how many events were handled.
+    pub fn dispatch_domain_events(
+        &self,
+        subscriber: &str,
+        limit: i64,
+        handle: fn(&Connection, i64, &DomainEvent) -> SqliteResult<()>,
+    ) -> SqliteResult<usize> {
+        let conn = self.connection.lock().unwrap();
+
+        conn.execute(
+            "INSERT OR IGNORE INTO event_cursors (subscriber) VALUES (?1)",
+            [subscriber],
+        )?;
+        let mut stmt = conn.prepare(
+            "SELECT id, payload FROM domain_events
+             WHERE id > (SELECT last_event_id FROM event_cursors WHERE subscriber = ?1)
+             ORDER BY id LIMIT ?2",
+        )?;
+        let pending = stmt
+            .query_map(params![subscriber, limit], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut handled = 0;
+        for (event_id, payload) in pending {
+            let tx = conn.unchecked_transaction()?;
+            let result = match serde_json::from_str::<DomainEvent>(&payload) {
+                Ok(event) => handle(&tx, event_id, &event),
+                Err(e) => {
+                    eprintln!("Subscriber {subscriber} skipped unreadable event {event_id}: {e}");
+                    Ok(())
+                }
+            };
+
+            match result {
+                Ok(()) => {
+                    tx.execute(
+                        "UPDATE event_cursors SET last_event_id = ?1, failures = 0, last_error = NULL,
+                         updated_at = CURRENT_TIMESTAMP WHERE subscriber = ?2",
+                        params![event_id, subscriber],
+                    )?;
+                    tx.commit()?;
+                    handled += 1;
+                }
+                Err(e) => {
+                    drop(tx);
+                    let failures: i64 = conn.query_row(
+                        "UPDATE event_cursors SET failures = failures + 1, last_error = ?1,
+                         updated_at = CURRENT_TIMESTAMP WHERE subscriber = ?2 RETURNING failures",
+                        params![e.to_string(), subscriber],
+                        |row| row.get(0),
+                    )?;
+                    if failures >= events::MAX_SUBSCRIBER_ATTEMPTS {
+                        eprintln!("Subscriber {subscriber} gave up on event {event_id}: {e}");
+                        conn.execute(
+                            "UPDATE event_cursors SET last_event_id = ?1, failures = 0
+                             WHERE subscriber = ?2",
+                            params![event_id, subscriber],
+                        )?;
+                    }
+                    break;
+                }
+            }
+        }
+
+        Ok(handled)
+    }
+
+    // Newest first.
+    pub fn fetch_audit_log(&self, filter: &AuditFilter) -> SqliteResult<Vec<AuditEntry>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT id, actor, actor_id, source_ip, action, target_type, target_id, before, after, created_at
+             FROM audit_log
+             WHERE (?1 IS NULL OR actor = ?1)
+                AND (?2 IS NULL OR action = ?2)
+                AND (?3 IS NULL OR target_type = ?3)
+                AND (?4 IS NULL OR target_id = ?4)
+                AND (?5 IS NULL OR created_at >= ?5)
+                AND (?6 IS NULL OR created_at < date(?6, '+1 day'))
+             ORDER BY id DESC LIMIT 200",
+        )?;
+
+        let entries = stmt
+            .query_map(
+                params![
+                    filter.actor,
+                    filter.action,
+                    filter.target_type,
+                    filter.target_id,
+                    filter.from,
+                    filter.to
+                ],
+                |row| {
+                    let before: Option<String> = row.get(7)?;
+                    let after: Option<String> = row.get(8)?;
+                    Ok(AuditEntry {
+                        id: row.get(0)?,
+                        actor: row.get(1)?,
+                        actor_id: row.get(2)?,
+                        source_ip: row.get(3)?,
+                        action: row.get(4)?,
+                        target_type: row.get(5)?,
+                        target_id: row.get(6)?,
+                        before: before.and_then(|json| serde_json::from_str(&json).ok()),
+                        after: after.and_then(|json| serde_json::from_str(&json).ok()),
+                        created_at: row.get(9)?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(entries)
+    }
+
+    pub fn fetch_event_subscribers(&self) -> SqliteResult<Vec<EventSubscriber>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT c.subscriber, c.last_event_id,
+                (SELECT COUNT(*) FROM domain_events e WHERE e.id > c.last_event_id),
+                c.failures, c.last_error, c.updated_at
+             FROM event_cursors c ORDER BY c.subscriber",
+        )?;
+
+        let subscribers = stmt
+            .query_map([], |row| {
+                Ok(EventSubscriber {
+                    subscriber: row.get(0)?,
+                    last_event_id: row.get(1)?,
+                    pending: row.get(2)?,
+                    failures: row.get(3)?,
+                    last_error: row.get(4)?,
+                    updated_at: row.get(5)?,
                 })
             })?
             .collect::<Result<Vec<_>, _>>()?;
-        Ok(borrowed_books)
+
+        Ok(subscribers)
+    }
+
+    // `events` is a comma-separated list of webhook event types. Returns the
+    // webhook id and its signing secret, which is only shown this once.
+    // The secret is left out of the audit entry.
+    pub fn add_webhook(
+        &self,
+        url: &str,
+        events: &[&str],
+        actor: &Actor,
+    ) -> SqliteResult<(i64, String)> {
+        let conn = self.connection.lock().unwrap();
+
+        let secret = generate_token();
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO webhooks (url, secret, events) VALUES (?1, ?2, ?3)",
+            params![url, secret, events.join(",")],
+        )?;
+        let webhook_id = tx.last_insert_rowid();
+        audit::record(
+            &tx,
+            actor,
+            "webhook.add",
+            "webhook",
+            webhook_id,
+            None,
+            Some(&json!({ "url": url, "events": events })),
+        )?;
+        tx.commit()?;
+
+        Ok((webhook_id, secret))
+    }
+
+    pub fn fetch_webhooks(&self) -> SqliteResult<Vec<Webhook>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt =
+            conn.prepare("SELECT id, url, events, active, created_at FROM webhooks ORDER BY id")?;
+
+        let webhooks = stmt
+            .query_map([], |row| {
+                let events: String = row.get(2)?;
+                Ok(Webhook {
+                    id: row.get(0)?,
+                    url: row.get(1)?,
+                    events: events
+                        .split(',')
+                        .filter(|event| !event.is_empty())
+                        .map(str::to_string)
+                        .collect(),
+                    active: row.get(3)?,
+                    created_at: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(webhooks)
+    }
+
+    // Deactivates rather than deletes so the delivery log is kept.
+    pub fn deactivate_webhook(&self, webhook_id: i64, actor: &Actor) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let tx = conn.unchecked_transaction()?;
+        let affected_row = tx.execute(
+            "UPDATE webhooks SET active = 0 WHERE id = ?1 AND active = 1",
+            [webhook_id],
+        )?;
+        if affected_row > 0 {
+            audit::record(
+                &tx,
+                actor,
+                "webhook.deactivate",
+                "webhook",
+                webhook_id,
+                Some(&json!({ "active": true })),
+                Some(&json!({ "active": false })),
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(affected_row > 0)
+    }
+
+    // Called by the webhooks subscriber in its cursor transaction, so each domain
+    // event is queued once per subscribed webhook.
+    pub fn queue_webhook_deliveries(
+        conn: &Connection,
+        event_id: i64,
+        event_type: &str,
+        event: &DomainEvent,
+    ) -> SqliteResult<()> {
+        let created_at: String = conn.query_row(
+            "SELECT created_at FROM domain_events WHERE id = ?1",
+            [event_id],
+            |row| row.get(0),
+        )?;
+        let mut event = serde_json::to_value(event)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        let payload = json!({
+            "id": event_id,
+            "type": event_type,
+            "created_at": created_at,
+            "data": event["data"].take(),
+        })
+        .to_string();
+
+        conn.execute(
+            "INSERT INTO webhook_deliveries (webhook_id, event_id, event_type, payload)
+             SELECT id, ?1, ?2, ?3 FROM webhooks
+             WHERE active = 1 AND instr(',' || events || ',', ',' || ?2 || ',') > 0",
+            params![event_id, event_type, payload],
+        )?;
+
+        Ok(())
+    }
+
+    fn webhook_delivery_from_row(row: &Row) -> SqliteResult<WebhookDelivery> {
+        Ok(WebhookDelivery {
+            id: row.get(0)?,
+            webhook_id: row.get(1)?,
+            url: row.get(2)?,
+            secret: row.get(3)?,
+            event_id: row.get(4)?,
+            event_type: row.get(5)?,
+            payload: row.get(6)?,
+            status: row.get(7)?,
+            attempts: row.get(8)?,
+            next_attempt_at: row.get(9)?,
+            response_status: row.get(10)?,
+            last_error: row.get(11)?,
+            created_at: row.get(12)?,
+            delivered_at: row.get(13)?,
+        })
+    }
+
+    pub fn fetch_due_webhook_deliveries(&self, limit: i64) -> SqliteResult<Vec<WebhookDelivery>> {
+        let conn = self.connection.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT d.id, d.webhook_id, w.url, w.secret, d.event_id, d.event_type, d.payload,
+                    d.status, d.attempts, d.next_attempt_at, d.response_status, d.last_error,
+                    d.created_at, d.delivered_at
+             FROM webhook_deliveries d JOIN webhooks w ON d.webhook_id = w.id
+             WHERE w.active = 1 AND d.status = 'pending' AND d.next_attempt_at <= ?1
+             ORDER BY d.id LIMIT ?2",
+        )?;
+
+        let deliveries = stmt
+            .query_map(
+                params![
+                    Utc::now().naive_utc().format(DATETIME_FORMAT).to_string(),
+                    limit
+                ],
+                Self::webhook_delivery_from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(deliveries)
+    }
+
+    // Returns None when the webhook doesn't exist.
+    pub fn fetch_webhook_deliveries(
+        &self,
+        webhook_id: i64,
+    ) -> SqliteResult<Option<Vec<WebhookDelivery>>> {
+        let conn = self.connection.lock().unwrap();
+
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM webhooks WHERE id = ?1)",
+            [webhook_id],
+            |row| row.get(0),
+        )?;
+
+        if !exists {
+            return Ok(None);
+        }
+
+        let mut stmt = conn.prepare(
+            "SELECT d.id, d.webhook_id, w.url, w.secret, d.event_id, d.event_type, d.payload,
+                    d.status, d.attempts, d.next_attempt_at, d.response_status, d.last_error,
+                    d.created_at, d.delivered_at
+             FROM webhook_deliveries d JOIN webhooks w ON d.webhook_id = w.id
+             WHERE d.webhook_id = ?1
+             ORDER BY d.id DESC LIMIT 200",
+        )?;
+
+        let deliveries = stmt
+            .query_map([webhook_id], Self::webhook_delivery_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Some(deliveries))
+    }
+
+    pub fn mark_webhook_delivered(&self, delivery_id: i64, status: u16) -> SqliteResult<()> {
+        let conn = self.connection.lock().unwrap();
+
+        conn.execute(
+            "UPDATE webhook_deliveries SET status = 'delivered', attempts = attempts + 1,
+                    response_status = ?1, last_error = NULL, delivered_at = CURRENT_TIMESTAMP
+             WHERE id = ?2",
+            params![status, delivery_id],
+        )?;
+
+        Ok(())
+    }
+
+    // Same backoff as the email outbox: 1, 2, 4, 8... minutes, then failed.
+    pub fn mark_webhook_failed(
+        &self,
+        delivery_id: i64,
+        response_status: Option<u16>,
+        error: &str,
+    ) -> SqliteResult<()> {
+        let conn = self.connection.lock().unwrap();
+
+        let attempts: i64 = conn.query_row(
+            "SELECT attempts + 1 FROM webhook_deliveries WHERE id = ?1",
+            [delivery_id],
+            |row| row.get(0),
+        )?;
+
+        let status = if attempts >= MAX_WEBHOOK_ATTEMPTS {
+            "failed"
+        } else {
+            "pending"
+        };
+        let next_attempt_at = (Utc::now().naive_utc() + Duration::minutes(1 << (attempts - 1)))
+            .format(DATETIME_FORMAT)
+            .to_string();
+
+        conn.execute(
+            "UPDATE webhook_deliveries SET status = ?1, attempts = ?2, next_attempt_at = ?3,
+                    response_status = ?4, last_error = ?5
+             WHERE id = ?6",
+            params![
+                status,
+                attempts,
+                next_attempt_at,
+                response_status,
+                error,
+                delivery_id
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    // Queues any delivery, failed or already delivered, to be sent again right away.
+    pub fn redeliver_webhook(&self, delivery_id: i64) -> SqliteResult<bool> {
+        let conn = self.connection.lock().unwrap();
+
+        let affected_row = conn.execute(
+            "UPDATE webhook_deliveries SET status = 'pending', attempts = 0, next_attempt_at = ?1
+             WHERE id = ?2",
+            params![
+                Utc::now().naive_utc().format(DATETIME_FORMAT).to_string(),
+                delivery_id
+            ],
+        )?;
+
+        Ok(affected_row > 0)
+    }
+}
+
+// Usernames made only of digits could be mistaken for a library card number at
+// the desk, so new and renamed accounts can't use them.
+pub fn looks_like_card_number(username: &str) -> bool {
+    !username.is_empty() && username.chars().all(|c| c.is_ascii_digit())
+}
+
+// The Luhn check digit for a string of digits, as used on library cards.
+fn luhn_check_digit(digits: &str) -> u32 {
+    let sum: u32 = digits
+        .chars()
+        .rev()
+        .filter_map(|c| c.to_digit(10))
+        .enumerate()
+        .map(|(i, digit)| match (i % 2 == 0, digit * 2) {
+            (true, doubled) if doubled > 9 => doubled - 9,
+            (true, doubled) => doubled,
+            (false, _) => digit,
+        })
+        .sum();
+    (10 - sum % 10) % 10
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database_with_user() -> (Database, i64) {
+        let db = Database::new(":memory:").unwrap();
+        let user_id = db
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "INSERT INTO users (username, password) VALUES ('reader', 'x') RETURNING id",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        (db, user_id)
+    }
+
+    fn enrol(db: &Database, user_id: i64) -> Vec<String> {
+        let secret = db.start_totp_enrolment(user_id).unwrap().unwrap();
+        let code = totp::code_at(&totp::base32_decode(&secret).unwrap(), totp::current_step());
+        db.confirm_totp(user_id, &code, &Actor::cli())
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn recovery_code_works_once() {
+        let (db, user_id) = database_with_user();
+        let codes = enrol(&db, user_id);
+
+        assert!(db.verify_second_factor(user_id, &codes[0]).unwrap());
+        assert!(!db.verify_second_factor(user_id, &codes[0]).unwrap());
+        // Retyping it differently doesn't make it new again
+        let retyped = codes[0].to_uppercase().replace('-', "");
+        assert!(!db.verify_second_factor(user_id, &retyped).unwrap());
+
+        let retyped = codes[1].to_uppercase().replace('-', "");
+        assert!(db.verify_second_factor(user_id, &retyped).unwrap());
+        assert!(!db.verify_second_factor(user_id, "zzzz-zzzz").unwrap());
+    }
+
+    #[test]
+    fn regenerating_recovery_codes_retires_the_old_ones() {
+        let (db, user_id) = database_with_user();
+        let old = enrol(&db, user_id);
+        let new = db
+            .regenerate_recovery_codes(user_id, &Actor::cli())
+            .unwrap();
+
+        assert!(!db.verify_second_factor(user_id, &old[0]).unwrap());
+        assert!(db.verify_second_factor(user_id, &new[0]).unwrap());
+    }
+
+    #[test]
+    fn totp_code_is_not_accepted_twice() {
+        let (db, user_id) = database_with_user();
+        enrol(&db, user_id);
+
+        // Enrolment used the current step, so its code can't log in again
+        let secret: String = db
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT totp_secret FROM users WHERE id = ?1",
+                [user_id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let secret = totp::base32_decode(&secret).unwrap();
+        let step: i64 = db
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT totp_last_step FROM users WHERE id = ?1",
+                [user_id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(
+            !db.verify_second_factor(user_id, &totp::code_at(&secret, step))
+                .unwrap()
+        );
     }
 }
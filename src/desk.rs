@@ -0,0 +1,174 @@
+use crate::auth::{self, Claims};
+use crate::db::Database;
+use crate::handlers;
+use crate::websocket::{
+    self, CLOSE_NORMAL, CLOSE_POLICY, CLOSE_UNSUPPORTED, Message, MessageReader, ReadError,
+};
+use serde_json::{Value, json};
+use std::{
+    net::{Shutdown, TcpStream},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+// How long to wait for client messages before checking for new events.
+const POLL_MILLIS: u64 = 500;
+// The server pings this often and gives up on a client that misses a whole interval.
+const PING_SECONDS: u64 = 30;
+
+// Live circulation desk feed over WebSocket. Every live event is pushed as
+// {"type": "event", ...}; the client may send {"type": "lookup", "patron": "..."}
+// to get the same answer as GET /api/desk/patrons/{identifier}.
+//
+// The socket lives as long as the login session behind the token, not the token
+// itself. Lookups use the latest token the client sent with
+// {"type": "authenticate", "token": "..."}, so it sends one after each refresh.
+//
+// A reader thread turns frames into messages so this thread is the only writer
+// and can interleave pushes, pongs and replies safely.
+pub fn serve(
+    mut stream: TcpStream,
+    db: Database,
+    claims: Claims,
+    mut authorization: String,
+    last_event_id: Option<i64>,
+) {
+    let mut last_event_id = match last_event_id {
+        Some(id) => id,
+        None => match db.latest_event_id() {
+            Ok(id) => id,
+            Err(_) => return,
+        },
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let read_half = match stream.try_clone() {
+        Ok(read_half) => read_half,
+        Err(_) => return,
+    };
+    thread::spawn(move || {
+        let mut reader = MessageReader::new(read_half);
+        loop {
+            let message = reader.read_message();
+            let finished = !matches!(
+                message,
+                Ok(Message::Text(_) | Message::Binary(_) | Message::Ping(_) | Message::Pong(_))
+            );
+            if sender.send(message).is_err() || finished {
+                break;
+            }
+        }
+    });
+
+    let mut last_ping = Instant::now();
+    let mut awaiting_pong = false;
+
+    let result = loop {
+        match receiver.recv_timeout(Duration::from_millis(POLL_MILLIS)) {
+            Ok(Ok(Message::Text(text))) => {
+                let reply = handle_command(&text, &mut authorization, claims.sid, &db);
+                if let Err(e) = websocket::send_text(&mut stream, &reply.to_string()) {
+                    break Err(e);
+                }
+            }
+            Ok(Ok(Message::Binary(_))) => {
+                break websocket::send_close(
+                    &mut stream,
+                    CLOSE_UNSUPPORTED,
+                    "binary messages are not supported",
+                );
+            }
+            Ok(Ok(Message::Ping(payload))) => {
+                if let Err(e) = websocket::send_pong(&mut stream, &payload) {
+                    break Err(e);
+                }
+            }
+            Ok(Ok(Message::Pong(_))) => awaiting_pong = false,
+            // Echo the close code back to finish the closing handshake
+            Ok(Ok(Message::Close(code, _))) => {
+                break websocket::send_close(&mut stream, code.unwrap_or(CLOSE_NORMAL), "");
+            }
+            Ok(Err(ReadError::Protocol(code, reason))) => {
+                break websocket::send_close(&mut stream, code, reason);
+            }
+            Ok(Err(ReadError::Io(_))) | Err(RecvTimeoutError::Disconnected) => break Ok(()),
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+
+        if last_ping.elapsed() >= Duration::from_secs(PING_SECONDS) {
+            if !db.session_active(claims.sid).unwrap_or(false) {
+                break websocket::send_close(&mut stream, CLOSE_POLICY, "session ended");
+            }
+            if awaiting_pong {
+                break websocket::send_close(&mut stream, CLOSE_POLICY, "ping timeout");
+            }
+            if let Err(e) = websocket::send_ping(&mut stream, b"desk") {
+                break Err(e);
+            }
+            awaiting_pong = true;
+            last_ping = Instant::now();
+        }
+
+        let (events, cursor) = match db.fetch_all_events_after(last_event_id) {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                eprintln!("Desk feed stopped: {e}");
+                break Ok(());
+            }
+        };
+        let mut sent = Ok(());
+        for event in events {
+            let data: Value = serde_json::from_str(&event.data).unwrap_or(Value::Null);
+            let message =
+                json!({ "type": "event", "id": event.id, "kind": event.kind, "data": data });
+            sent = websocket::send_text(&mut stream, &message.to_string());
+            if sent.is_err() {
+                break;
+            }
+        }
+        if let Err(e) = sent {
+            break Err(e);
+        }
+        last_event_id = cursor;
+    };
+
+    if let Err(e) = result {
+        eprintln!("Desk socket closed: {e}");
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+fn handle_command(text: &str, authorization: &mut String, session_id: i64, db: &Database) -> Value {
+    let command: Value = match serde_json::from_str(text) {
+        Ok(command) => command,
+        Err(_) => return json!({ "type": "error", "message": "Invalid JSON" }),
+    };
+
+    match (
+        command.get("type").and_then(|v| v.as_str()),
+        command.get("patron").and_then(|v| v.as_str()),
+    ) {
+        (Some("authenticate"), _) => {
+            let token = command.get("token").and_then(|v| v.as_str()).unwrap_or("");
+            let fresh = format!("Bearer {token}");
+            // Only a token for the same session may take over the socket.
+            match auth::decode_claims(Some(&fresh)) {
+                Some(claims) if claims.sid == session_id => {
+                    *authorization = fresh;
+                    json!({ "type": "authenticated", "expires_at": claims.exp })
+                }
+                _ => json!({ "type": "error", "message": "Invalid token for this session" }),
+            }
+        }
+        (Some("lookup"), Some(patron)) => {
+            let (status, body) = handlers::handle_desk_lookup(patron, Some(authorization), db);
+            json!({
+                "type": "lookup",
+                "status": status.trim_start_matches("HTTP/1.1 "),
+                "result": serde_json::from_str::<Value>(&body).unwrap_or(Value::Null),
+            })
+        }
+        _ => json!({ "type": "error", "message": "Unknown command" }),
+    }
+}
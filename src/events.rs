@@ -0,0 +1,238 @@
+use crate::db::Database;
+use crate::webhooks;
+use rusqlite::{Connection, Result as SqliteResult};
+use serde::{Deserialize, Serialize};
+use std::{thread, time::Duration};
+
+// How often the dispatcher looks for new domain events.
+pub const POLL_MILLIS: u64 = 500;
+// Events handed to each subscriber per poll.
+pub const BATCH_SIZE: i64 = 100;
+// A subscriber that keeps failing on the same event skips it after this many tries.
+pub const MAX_SUBSCRIBER_ATTEMPTS: i64 = 5;
+
+// What happened, as opposed to which rows changed. Mutations write these to the
+// domain_events outbox in the same transaction as the change itself, so an
+// event exists if and only if its change was committed.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum DomainEvent {
+    UserRegistered {
+        user_id: i64,
+        username: String,
+    },
+    BookAdded {
+        book_id: i64,
+        title: String,
+        author: String,
+        isbn: String,
+        copies: i64,
+    },
+    BookEdited {
+        book_id: i64,
+        fields: Vec<String>,
+    },
+    BookDeleted {
+        book_id: i64,
+    },
+    BookBorrowed {
+        borrowed_id: i64,
+        user_id: i64,
+        book_id: i64,
+        item_id: i64,
+        barcode: String,
+        title: String,
+        due_date: String,
+    },
+    BookRenewed {
+        borrowed_id: i64,
+        user_id: i64,
+        // Renewals recorded before the live feeds read this outbox carry no book.
+        #[serde(default)]
+        book_id: i64,
+        due_date: String,
+    },
+    BookReturned {
+        borrowed_id: i64,
+        user_id: i64,
+        book_id: i64,
+        item_id: i64,
+    },
+    ItemLost {
+        borrowed_id: i64,
+        user_id: i64,
+        book_id: i64,
+        item_id: i64,
+    },
+    HoldReady {
+        hold_id: i64,
+        user_id: i64,
+        book_id: i64,
+        title: String,
+        branch: String,
+    },
+    // Any move of a hold to another status, including the one HoldReady reports.
+    HoldStatusChanged {
+        hold_id: i64,
+        user_id: i64,
+        book_id: i64,
+        status: String,
+    },
+    ItemAdded {
+        item_id: i64,
+        book_id: i64,
+    },
+    ItemStatusChanged {
+        item_id: i64,
+        book_id: i64,
+        status: String,
+    },
+    UserNotified {
+        notification_id: i64,
+        user_id: i64,
+        kind: String,
+        title: String,
+        message: String,
+    },
+}
+
+impl DomainEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DomainEvent::UserRegistered { .. } => "user_registered",
+            DomainEvent::BookAdded { .. } => "book_added",
+            DomainEvent::BookEdited { .. } => "book_edited",
+            DomainEvent::BookDeleted { .. } => "book_deleted",
+            DomainEvent::BookBorrowed { .. } => "book_borrowed",
+            DomainEvent::BookRenewed { .. } => "book_renewed",
+            DomainEvent::BookReturned { .. } => "book_returned",
+            DomainEvent::ItemLost { .. } => "item_lost",
+            DomainEvent::HoldReady { .. } => "hold_ready",
+            DomainEvent::HoldStatusChanged { .. } => "hold_status_changed",
+            DomainEvent::ItemAdded { .. } => "item_added",
+            DomainEvent::ItemStatusChanged { .. } => "item_status_changed",
+            DomainEvent::UserNotified { .. } => "user_notified",
+        }
+    }
+}
+
+// Appends an event to the outbox. Call it on the transaction that makes the change.
+pub fn emit(conn: &Connection, event: &DomainEvent) -> SqliteResult<()> {
+    let payload = serde_json::to_string(event)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    conn.execute(
+        "INSERT INTO domain_events (event_type, payload) VALUES (?1, ?2)",
+        [event.name(), &payload],
+    )?;
+    Ok(())
+}
+
+pub struct Subscriber {
+    pub name: &'static str,
+    // Runs inside the transaction that advances the subscriber's cursor, so an
+    // event is handled exactly once unless the handler fails. Gets the event's
+    // id in domain_events along with the event.
+    pub handle: fn(&Connection, i64, &DomainEvent) -> SqliteResult<()>,
+}
+
+// The audit log isn't a subscriber: its entries need the actor and before/after
+// snapshots, and must commit or roll back with the change itself.
+pub const SUBSCRIBERS: &[Subscriber] = &[
+    Subscriber {
+        name: "notifications",
+        handle: notifications,
+    },
+    Subscriber {
+        name: "search_index",
+        handle: search_index,
+    },
+    Subscriber {
+        name: "webhooks",
+        handle: webhook_deliveries,
+    },
+];
+
+// In-app notices and emails for the patron a circulation event concerns.
+fn notifications(conn: &Connection, _event_id: i64, event: &DomainEvent) -> SqliteResult<()> {
+    match event {
+        DomainEvent::BookBorrowed {
+            user_id,
+            barcode,
+            title,
+            due_date,
+            ..
+        } => Database::notify(
+            conn,
+            *user_id,
+            "borrowed",
+            &format!("Borrowed: {title}"),
+            &format!("Copy {barcode} is due back on {due_date}."),
+        ),
+        DomainEvent::HoldReady {
+            user_id,
+            title,
+            branch,
+            ..
+        } => {
+            Database::notify(
+                conn,
+                *user_id,
+                "hold_ready",
+                &format!("Hold ready: {title}"),
+                &format!("Your copy is waiting at {branch}."),
+            )?;
+            Database::queue_email(
+                conn,
+                *user_id,
+                "hold_ready",
+                &[("title", title), ("branch", branch)],
+            )?;
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Keeps the full-text catalog index in step with the books table.
+fn search_index(conn: &Connection, _event_id: i64, event: &DomainEvent) -> SqliteResult<()> {
+    match event {
+        DomainEvent::BookAdded { book_id, .. } | DomainEvent::BookEdited { book_id, .. } => {
+            Database::index_book(conn, *book_id)
+        }
+        DomainEvent::BookDeleted { book_id } => Database::unindex_book(conn, *book_id),
+        _ => Ok(()),
+    }
+}
+
+// Queues a delivery for each active webhook subscribed to the event's public type.
+// The scheduler sends them.
+fn webhook_deliveries(conn: &Connection, event_id: i64, event: &DomainEvent) -> SqliteResult<()> {
+    match webhooks::event_type(event) {
+        Some(event_type) => Database::queue_webhook_deliveries(conn, event_id, event_type, event),
+        None => Ok(()),
+    }
+}
+
+// Hands new events to every subscriber. Returns how many deliveries were made.
+pub fn dispatch_pending(db: &Database) -> usize {
+    let mut handled = 0;
+
+    for subscriber in SUBSCRIBERS {
+        match db.dispatch_domain_events(subscriber.name, BATCH_SIZE, subscriber.handle) {
+            Ok(count) => handled += count,
+            Err(e) => eprintln!("Subscriber {} failed: {e}", subscriber.name),
+        }
+    }
+
+    handled
+}
+
+// Background thread that delivers domain events for the life of the server.
+pub fn start(db: Database) {
+    thread::spawn(move || {
+        loop {
+            dispatch_pending(&db);
+            thread::sleep(Duration::from_millis(POLL_MILLIS));
+        }
+    });
+}
@@ -1,8 +1,25 @@
-use crate::db::Database;
+use crate::audit::Actor;
+use crate::auth::{self, Claims};
+use crate::calendar::DATE_FORMAT;
+use crate::db::{
+    self, AuditFilter, BookDetail, DEFAULT_REPLACEMENT_FEE_CENTS, Database, EmailVerification,
+    ITEM_STATUSES, ItemUpdate, KioskLogin, KioskSession, LOGIN_CHALLENGE_MINUTES, LoginChallenge,
+    MEMBERSHIP_DAYS, NewBook, OPTIONAL_EMAIL_CATEGORIES, Patron, ProfileUpdate, RefreshOutcome,
+    RegistrationReview, Renewal, UserChange, UserDeletion, UserUpdate,
+};
+use crate::registration::{self, Mode};
+use crate::roles;
+use crate::scheduler;
+use crate::totp;
+use crate::webhooks::{self, WEBHOOK_EVENTS};
+use crate::websocket;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{NaiveDate, NaiveTime, Utc};
 use serde_json;
-use serde_json::Value;
+use serde_json::{Value, json};
+use std::{collections::HashMap, env};
 
-pub fn handle_signup(request_body: &str, db: &Database) -> (&'static str, String) {
+pub fn handle_signup(request_body: &str, actor: &Actor, db: &Database) -> (&'static str, String) {
     // Parse JSON
     let signup_data: serde_json::Value = match serde_json::from_str(request_body) {
         Ok(data) => data,
@@ -27,6 +44,9 @@ pub fn handle_signup(request_body: &str, db: &Database) -> (&'static str, String
             r#"{"success": false, "message": "Username must be between 3 and 50 characters"}"#;
         return ("HTTP/1.1 400 Bad Request", response.to_string());
     }
+    if db::looks_like_card_number(username.trim()) {
+        return username_like_card_number();
+    }
 
     // Validate password length
     if password.len() < 6 {
@@ -35,16 +55,59 @@ pub fn handle_signup(request_body: &str, db: &Database) -> (&'static str, String
         return ("HTTP/1.1 400 Bad Request", response.to_string());
     }
 
+    // Email is optional unless it has to be confirmed or checked against the
+    // allowed domains
+    let mode = registration::mode();
+    let allowed_domains = registration::allowed_domains();
+    let email = match signup_data.get("email") {
+        None | Some(Value::Null) => None,
+        Some(Value::String(email)) if email.trim().is_empty() => None,
+        Some(Value::String(email)) if is_valid_email(email) => Some(email.trim()),
+        Some(_) => {
+            let response = r#"{"success": false, "message": "Invalid email"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+    if email.is_none() && (mode == Mode::EmailVerified || !allowed_domains.is_empty()) {
+        let response = r#"{"success": false, "message": "Please provide an email address"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+    if let Some(email) = email
+        && !allowed_domains.is_empty()
+        && !registration::domain_allowed(email, &allowed_domains)
+    {
+        let response = json!({
+            "success": false,
+            "message": format!(
+                "Registration is limited to email addresses at {}",
+                allowed_domains.join(", ")
+            ),
+        });
+        return ("HTTP/1.1 403 Forbidden", response.to_string());
+    }
+
     // Try to create user
-    match db.create_user(username, password) {
-        Ok(true) => {
-            let response = format!(
-                r#"{{"success": true, "message": "User '{}' created successfully"}}"#,
-                username
-            );
-            ("HTTP/1.1 201 Created", response)
+    match db.create_user(username, password, email, mode.initial_status(), actor) {
+        Ok(Some(user_id)) => {
+            let message = match mode {
+                Mode::Open => format!("User '{username}' created successfully"),
+                Mode::EmailVerified => {
+                    send_verification_email(user_id, email.unwrap_or_default(), db);
+                    "Check your email and follow the link to confirm your address before logging in"
+                        .to_string()
+                }
+                Mode::StaffApproved => {
+                    "Your registration is waiting for approval by library staff".to_string()
+                }
+            };
+            let response = json!({
+                "success": true,
+                "message": message,
+                "registration_status": mode.initial_status(),
+            });
+            ("HTTP/1.1 201 Created", response.to_string())
         }
-        Ok(false) => {
+        Ok(None) => {
             let response = r#"{"success": false, "message": "Username already exists"}"#;
             ("HTTP/1.1 409 Conflict", response.to_string())
         }
@@ -56,48 +119,3570 @@ pub fn handle_signup(request_body: &str, db: &Database) -> (&'static str, String
     }
 }
 
-pub fn handle_login(request_body: &str, db: &Database) -> (&'static str, String) {
-    // Parse JSON
-    let login_data: serde_json::Value = match serde_json::from_str(request_body) {
-        Ok(data) => data,
-        Err(_) => {
-            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
-            return ("HTTP/1.1 400 Bad Request", response.to_string());
-        }
+// Failures are only logged; the applicant can ask for the email again.
+fn send_verification_email(user_id: i64, email: &str, db: &Database) {
+    let token = match auth::issue_verification_token(
+        user_id,
+        email,
+        registration::VERIFICATION_HOURS * 3600,
+    ) {
+        Some(token) => token,
+        None => {
+            eprintln!("Could not sign a verification token; is JWT_SECRET set?");
+            return;
+        }
+    };
+    let link_base = env::var("APP_URL").unwrap_or_else(|_| "http://localhost:7878".to_string());
+    let link = format!(
+        "{}/verify_email.html#token={token}",
+        link_base.trim_end_matches('/')
+    );
+    if let Err(e) = db.queue_verification_email(user_id, &link) {
+        eprintln!("Could not queue verification email: {e}");
+    }
+}
+
+// What the signup page needs to know: the mode and any email domain restriction.
+pub fn handle_fetch_registration_settings() -> (&'static str, String) {
+    let response = json!({
+        "mode": registration::mode().name(),
+        "email_domains": registration::allowed_domains(),
+    });
+    ("HTTP/1.1 200 OK", response.to_string())
+}
+
+pub fn handle_verify_email(
+    request_body: &str,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let claims = match parsed["token"]
+        .as_str()
+        .and_then(auth::decode_verification_token)
+    {
+        Some(claims) => claims,
+        None => {
+            let response = r#"{"success": false, "message": "This verification link is invalid or has expired"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    match db.verify_email(claims.sub, &claims.email, actor) {
+        Ok(EmailVerification::Verified) | Ok(EmailVerification::AlreadyVerified) => {
+            let response = r#"{"success": true, "message": "Email address confirmed"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(EmailVerification::Invalid) => {
+            let response = r#"{"success": false, "message": "This verification link is invalid or has expired"}"#;
+            ("HTTP/1.1 400 Bad Request", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Answers the same whether or not the account exists or is waiting, so it can't
+// be used to find usernames.
+pub fn handle_resend_verification(request_body: &str, db: &Database) -> (&'static str, String) {
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let username = parsed["username"].as_str().unwrap_or("").trim();
+    if username.is_empty() {
+        let response = r#"{"success": false, "message": "Please provide a username"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    match db.pending_verification(username) {
+        Ok(Some((user_id, email))) => send_verification_email(user_id, &email, db),
+        Ok(None) => {}
+        Err(e) => eprintln!("Could not look up pending verification: {e}"),
+    }
+
+    let response = r#"{"success": true, "message": "If that account is waiting for email confirmation, a new link is on its way"}"#;
+    ("HTTP/1.1 200 OK", response.to_string())
+}
+
+pub fn handle_login(
+    request_body: &str,
+    user_agent: &str,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    // Parse JSON
+    let login_data: serde_json::Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let username = login_data["username"].as_str().unwrap_or("");
+    let password = login_data["password"].as_str().unwrap_or("");
+
+    // Validate input
+    if username.trim().is_empty() || password.trim().is_empty() {
+        let response = r#"{"success": false, "message": "Please provide username and password"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    // Throttled attempts are turned away before the password is even checked
+    if let Err(response) = check_login_throttle(username, &actor.source_ip, db) {
+        return response;
+    }
+
+    // Verify user credentials
+    let (user_id, username, role, active) = match db.verify_user(username, password) {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            if let Err(e) = db.record_login_failure(username, &actor.source_ip, actor) {
+                eprintln!("Could not record failed login: {e}");
+            }
+            let response = r#"{"success": false, "message": "Invalid username or password"}"#;
+            return ("HTTP/1.1 401 Unauthorized", response.to_string());
+        }
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            let response = r#"{"success": false, "message": "Internal server error"}"#;
+            return ("HTTP/1.1 500 Internal Server Error", response.to_string());
+        }
+    };
+
+    if !active {
+        let response = r#"{"success": false, "message": "This account has been deactivated"}"#;
+        return ("HTTP/1.1 403 Forbidden", response.to_string());
+    }
+    if let Err(response) = require_approved_registration(user_id, db) {
+        return response;
+    }
+
+    // Accounts with two-factor login, or whose role requires it, get a partial
+    // token to trade for a session once they supply a code. Failed logins are
+    // only cleared after that, so a known password doesn't reset the count.
+    let totp_enabled = match db.totp_status(user_id) {
+        Ok((enabled, _)) => enabled,
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            let response = r#"{"success": false, "message": "Internal server error"}"#;
+            return ("HTTP/1.1 500 Internal Server Error", response.to_string());
+        }
+    };
+    if totp_enabled || totp::required_for(&role) {
+        return match db.create_login_challenge(user_id, user_agent, &actor.source_ip) {
+            Ok(mfa_token) => {
+                let message = if totp_enabled {
+                    "Enter the code from your authenticator app"
+                } else {
+                    "Two-factor authentication must be set up for this account"
+                };
+                let response = json!({
+                    "success": true,
+                    "message": message,
+                    "mfa_required": true,
+                    "mfa_token": mfa_token,
+                    "enrolment_required": !totp_enabled,
+                    "expires_in": LOGIN_CHALLENGE_MINUTES * 60,
+                });
+                ("HTTP/1.1 200 OK", response.to_string())
+            }
+            Err(_) => {
+                let response = r#"{"success": false, "message": "Internal server error"}"#;
+                ("HTTP/1.1 500 Internal Server Error", response.to_string())
+            }
+        };
+    }
+
+    open_login_session(
+        user_id,
+        &username,
+        &role,
+        user_agent,
+        &actor.source_ip,
+        None,
+        db,
+    )
+}
+
+// Self-registered accounts may still be waiting on their email or on staff.
+fn require_approved_registration(
+    user_id: i64,
+    db: &Database,
+) -> Result<(), (&'static str, String)> {
+    let (status, reason) = match db.registration_status(user_id) {
+        Ok(Some(registration)) => registration,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            let response = r#"{"success": false, "message": "Internal server error"}"#;
+            return Err(("HTTP/1.1 500 Internal Server Error", response.to_string()));
+        }
+    };
+
+    let message = match status.as_str() {
+        registration::APPROVED => return Ok(()),
+        registration::PENDING_VERIFICATION => {
+            "Confirm your email address before logging in; check your inbox for the link"
+                .to_string()
+        }
+        registration::PENDING_APPROVAL => {
+            "Your registration is waiting for approval by library staff".to_string()
+        }
+        _ => match reason {
+            Some(reason) => format!("Your registration was not approved: {reason}"),
+            None => "Your registration was not approved".to_string(),
+        },
+    };
+    let response = json!({
+        "success": false,
+        "message": message,
+        "registration_status": status,
+    });
+    Err(("HTTP/1.1 403 Forbidden", response.to_string()))
+}
+
+fn username_like_card_number() -> (&'static str, String) {
+    let response = r#"{"success": false, "message": "Usernames can't be all digits, so they aren't mistaken for library card numbers"}"#;
+    ("HTTP/1.1 400 Bad Request", response.to_string())
+}
+
+fn check_login_throttle(
+    username: &str,
+    source_ip: &str,
+    db: &Database,
+) -> Result<(), (&'static str, String)> {
+    match db.login_retry_after(username, source_ip) {
+        Ok(None) => Ok(()),
+        Ok(Some(seconds)) => Err(too_many_login_attempts(seconds)),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            let response = r#"{"success": false, "message": "Internal server error"}"#;
+            Err(("HTTP/1.1 500 Internal Server Error", response.to_string()))
+        }
+    }
+}
+
+fn too_many_login_attempts(seconds: i64) -> (&'static str, String) {
+    let response = json!({
+        "success": false,
+        "message": format!(
+            "Too many failed login attempts. Try again in {seconds} second{}",
+            if seconds == 1 { "" } else { "s" }
+        ),
+        "retry_after": seconds,
+    });
+    ("HTTP/1.1 429 Too Many Requests", response.to_string())
+}
+
+// Finishes a login: clears failed attempts and opens a session. Recovery codes
+// are included when the login also completed two-factor enrolment.
+fn open_login_session(
+    user_id: i64,
+    username: &str,
+    role: &str,
+    user_agent: &str,
+    source_ip: &str,
+    recovery_codes: Option<Vec<String>>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(e) = db.clear_login_failures(username) {
+        eprintln!("Could not clear failed logins: {e}");
+    }
+
+    let session = db
+        .start_session(user_id, user_agent, source_ip)
+        .ok()
+        .and_then(|(session_id, refresh_token)| {
+            auth::issue_access_token(username, role, session_id).map(|jwt| (jwt, refresh_token))
+        });
+    match session {
+        Some((jwt, refresh_token)) => {
+            let mut response = json!({
+                "success": true,
+                "message": "Login successful",
+                "userId": user_id.to_string(),
+                "username": username,
+                "role": role,
+                "permissions": roles::permissions_for(role),
+                "jwt": jwt,
+                "refresh_token": refresh_token,
+                "expires_in": auth::ACCESS_TOKEN_SECONDS,
+            });
+            if let Some(recovery_codes) = recovery_codes {
+                response["recovery_codes"] = json!(recovery_codes);
+            }
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        None => {
+            let response = r#"{"success": false, "message": "Internal server error"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Second step of a two-factor login: trades the partial token and a code for a
+// session. During enrolment the code confirms the new secret instead.
+pub fn handle_login_totp(request_body: &str, db: &Database) -> (&'static str, String) {
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let mfa_token = parsed["mfa_token"].as_str().unwrap_or("");
+    let code = parsed["code"].as_str().unwrap_or("");
+
+    let challenge = match fetch_login_challenge(mfa_token, db) {
+        Ok(challenge) => challenge,
+        Err(response) => return response,
+    };
+
+    if let Err(response) = check_login_throttle(&challenge.username, &challenge.ip, db) {
+        return response;
+    }
+
+    let actor = Actor {
+        username: Some(challenge.username.clone()),
+        source_ip: challenge.ip.clone(),
+    };
+    let verified = if challenge.totp_enabled {
+        db.verify_second_factor(challenge.user_id, code)
+            .map(|verified| verified.then_some(None))
+    } else {
+        db.confirm_totp(challenge.user_id, code, &actor)
+            .map(|codes| codes.map(Some))
+    };
+
+    match verified {
+        Ok(Some(recovery_codes)) => {
+            if let Err(e) = db.end_login_challenge(mfa_token) {
+                eprintln!("Could not end login challenge: {e}");
+            }
+            open_login_session(
+                challenge.user_id,
+                &challenge.username,
+                &challenge.role,
+                &challenge.user_agent,
+                &challenge.ip,
+                recovery_codes,
+                db,
+            )
+        }
+        Ok(None) => {
+            if let Err(e) = db.record_login_failure(&challenge.username, &challenge.ip, &actor) {
+                eprintln!("Could not record failed login: {e}");
+            }
+            let response = r#"{"success": false, "message": "Invalid code"}"#;
+            ("HTTP/1.1 401 Unauthorized", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Starts enrolment for an account whose role requires two-factor login but
+// which hasn't set it up yet, using the partial token from the password step.
+pub fn handle_login_totp_enroll(request_body: &str, db: &Database) -> (&'static str, String) {
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let challenge = match fetch_login_challenge(parsed["mfa_token"].as_str().unwrap_or(""), db) {
+        Ok(challenge) => challenge,
+        Err(response) => return response,
+    };
+
+    totp_enrolment_response(challenge.user_id, &challenge.username, db)
+}
+
+fn fetch_login_challenge(
+    mfa_token: &str,
+    db: &Database,
+) -> Result<LoginChallenge, (&'static str, String)> {
+    match db.fetch_login_challenge(mfa_token) {
+        Ok(Some(challenge)) => Ok(challenge),
+        Ok(None) => Err((
+            "HTTP/1.1 401 Unauthorized",
+            r#"{"success": false, "message": "Login expired, please sign in again"}"#.to_string(),
+        )),
+        Err(_) => Err((
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Database error occurred"}"#.to_string(),
+        )),
+    }
+}
+
+fn totp_enrolment_response(user_id: i64, username: &str, db: &Database) -> (&'static str, String) {
+    match db.start_totp_enrolment(user_id) {
+        Ok(Some(secret)) => (
+            "HTTP/1.1 200 OK",
+            json!({
+                "success": true,
+                "message": "Add this key to your authenticator app, then confirm with a code",
+                "otpauth_uri": totp::otpauth_uri(username, &secret),
+                "secret": secret,
+            })
+            .to_string(),
+        ),
+        Ok(None) => {
+            let response =
+                r#"{"success": false, "message": "Two-factor authentication is already on"}"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_fetch_totp(authorization: Option<&str>, db: &Database) -> (&'static str, String) {
+    let (claims, account) = match require_account(authorization, db) {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    match db.totp_status(account.id) {
+        Ok((enabled, recovery_codes_left)) => (
+            "HTTP/1.1 200 OK",
+            json!({
+                "enabled": enabled,
+                "required": totp::required_for(&claims.role),
+                "recovery_codes_left": recovery_codes_left,
+            })
+            .to_string(),
+        ),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_enroll_totp(authorization: Option<&str>, db: &Database) -> (&'static str, String) {
+    let (_, account) = match require_account(authorization, db) {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    totp_enrolment_response(account.id, &account.username, db)
+}
+
+pub fn handle_confirm_totp(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let (_, account) = match require_account(authorization, db) {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    match db.confirm_totp(account.id, parsed["code"].as_str().unwrap_or(""), actor) {
+        Ok(Some(recovery_codes)) => (
+            "HTTP/1.1 200 OK",
+            json!({
+                "success": true,
+                "message": "Two-factor authentication is on. Keep these recovery codes somewhere safe",
+                "recovery_codes": recovery_codes,
+            })
+            .to_string(),
+        ),
+        Ok(None) => {
+            let response = r#"{"success": false, "message": "Invalid code, or enrolment not started"}"#;
+            ("HTTP/1.1 400 Bad Request", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Checks the `code` field of a request against the caller's authenticator.
+fn require_totp_code(
+    request_body: &str,
+    user_id: i64,
+    db: &Database,
+) -> Result<(), (&'static str, String)> {
+    let parsed: Value = serde_json::from_str(request_body).map_err(|_| {
+        (
+            "HTTP/1.1 400 Bad Request",
+            r#"{"success": false, "message": "Invalid JSON"}"#.to_string(),
+        )
+    })?;
+
+    match db.verify_totp_code(user_id, parsed["code"].as_str().unwrap_or("")) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((
+            "HTTP/1.1 403 Forbidden",
+            r#"{"success": false, "message": "Invalid code"}"#.to_string(),
+        )),
+        Err(_) => Err((
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Database error occurred"}"#.to_string(),
+        )),
+    }
+}
+
+pub fn handle_disable_totp(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let (claims, account) = match require_account(authorization, db) {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    if totp::required_for(&claims.role) {
+        let response = r#"{"success": false, "message": "Two-factor authentication is required for your role"}"#;
+        return ("HTTP/1.1 403 Forbidden", response.to_string());
+    }
+    if let Err(response) = require_totp_code(request_body, account.id, db) {
+        return response;
+    }
+
+    match db.remove_totp(account.id, "user.totp_disable", actor) {
+        Ok(_) => {
+            let response = r#"{"success": true, "message": "Two-factor authentication is off"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_regenerate_recovery_codes(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let (_, account) = match require_account(authorization, db) {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    if let Err(response) = require_totp_code(request_body, account.id, db) {
+        return response;
+    }
+
+    match db.regenerate_recovery_codes(account.id, actor) {
+        Ok(recovery_codes) => (
+            "HTTP/1.1 200 OK",
+            json!({ "success": true, "recovery_codes": recovery_codes }).to_string(),
+        ),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// For a user who has lost their authenticator and recovery codes. If their role
+// requires two-factor login they enrol again at their next login.
+pub fn handle_reset_user_totp(
+    user_id: i64,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "users.manage") {
+        return response;
+    }
+
+    match db.remove_totp(user_id, "user.totp_reset", actor) {
+        Ok(true) => {
+            let response = r#"{"success": true, "message": "Two-factor authentication reset"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(false) => {
+            let response = r#"{"success": false, "message": "User not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Exchanges a refresh token for a new access token and refresh token.
+pub fn handle_refresh_token(request_body: &str, db: &Database) -> (&'static str, String) {
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let refresh_token = match parsed.get("refresh_token").and_then(|v| v.as_str()) {
+        Some(token) => token,
+        None => {
+            let response = r#"{"success": false, "message": "Missing refresh_token"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    match db.refresh_session(refresh_token) {
+        Ok(RefreshOutcome::Rotated {
+            session_id,
+            username,
+            role,
+            refresh_token,
+        }) => match auth::issue_access_token(&username, &role, session_id) {
+            Some(jwt) => (
+                "HTTP/1.1 200 OK",
+                json!({
+                    "success": true,
+                    "jwt": jwt,
+                    "refresh_token": refresh_token,
+                    "expires_in": auth::ACCESS_TOKEN_SECONDS,
+                })
+                .to_string(),
+            ),
+            None => {
+                let response = r#"{"success": false, "message": "Internal server error"}"#;
+                ("HTTP/1.1 500 Internal Server Error", response.to_string())
+            }
+        },
+        Ok(RefreshOutcome::Reused) => {
+            let response = r#"{"success": false, "message": "Refresh token was already used; the session has been ended"}"#;
+            ("HTTP/1.1 401 Unauthorized", response.to_string())
+        }
+        Ok(RefreshOutcome::Invalid) => {
+            let response = r#"{"success": false, "message": "Invalid or expired refresh token"}"#;
+            ("HTTP/1.1 401 Unauthorized", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Ends the session the access token belongs to.
+pub fn handle_logout(authorization: Option<&str>, db: &Database) -> (&'static str, String) {
+    let claims = match require_login(authorization) {
+        Ok(claims) => claims,
+        Err(response) => return response,
+    };
+
+    match db.revoke_session(claims.sid, "logout") {
+        Ok(_) => {
+            let response = r#"{"success": true, "message": "Logged out"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Ends every session the user has open, this one included.
+pub fn handle_logout_all(authorization: Option<&str>, db: &Database) -> (&'static str, String) {
+    let (_, account) = match require_account(authorization, db) {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    match db.revoke_user_sessions(account.id, "logout_all") {
+        Ok(revoked) => (
+            "HTTP/1.1 200 OK",
+            json!({ "success": true, "message": "Logged out everywhere", "revoked": revoked })
+                .to_string(),
+        ),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// The caller's open sessions, with the one making this request marked `current`.
+pub fn handle_fetch_sessions(authorization: Option<&str>, db: &Database) -> (&'static str, String) {
+    let (claims, account) = match require_account(authorization, db) {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    match db.fetch_sessions(account.id, Some(claims.sid)) {
+        Ok(sessions) => ("HTTP/1.1 200 OK", json!(sessions).to_string()),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Could not fetch sessions"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_revoke_session(
+    session_id: i64,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    let (_, account) = match require_account(authorization, db) {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    match db.revoke_user_session(account.id, session_id, "revoked_by_user") {
+        Ok(true) => {
+            let response = r#"{"success": true, "message": "Session revoked"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(false) => {
+            let response = r#"{"success": false, "message": "Session not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_fetch_user_sessions(
+    user_id: i64,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "users.manage") {
+        return response;
+    }
+
+    match db.fetch_patron(user_id) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            let response = r#"{"success": false, "message": "User not found"}"#;
+            return ("HTTP/1.1 404 Not Found", response.to_string());
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            return ("HTTP/1.1 500 Internal Server Error", response.to_string());
+        }
+    }
+
+    match db.fetch_sessions(user_id, None) {
+        Ok(sessions) => ("HTTP/1.1 200 OK", json!(sessions).to_string()),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Could not fetch sessions"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_fetch_login_lockouts(
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "users.manage") {
+        return response;
+    }
+
+    match db.fetch_login_lockouts() {
+        Ok(lockouts) => ("HTTP/1.1 200 OK", json!(lockouts).to_string()),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Could not fetch lockouts"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_unlock_user(
+    user_id: i64,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "users.manage") {
+        return response;
+    }
+
+    match db.unlock_user(user_id, actor) {
+        Ok(Some(unlocked)) => (
+            "HTTP/1.1 200 OK",
+            json!({ "success": true, "message": "Failed logins cleared", "unlocked": unlocked })
+                .to_string(),
+        ),
+        Ok(None) => {
+            let response = r#"{"success": false, "message": "User not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Revokes one of a user's sessions, or all of them when no session id is given.
+pub fn handle_revoke_user_sessions(
+    user_id: i64,
+    session_id: Option<i64>,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "users.manage") {
+        return response;
+    }
+
+    let revoked = match session_id {
+        Some(session_id) => db
+            .revoke_user_session(user_id, session_id, "revoked_by_admin")
+            .map(usize::from),
+        None => db.revoke_user_sessions(user_id, "revoked_by_admin"),
+    };
+
+    match revoked {
+        Ok(0) if session_id.is_some() => {
+            let response = r#"{"success": false, "message": "Session not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Ok(revoked) => (
+            "HTTP/1.1 200 OK",
+            json!({ "success": true, "message": "Sessions revoked", "revoked": revoked })
+                .to_string(),
+        ),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// The roles users can be given and what each one allows.
+pub fn handle_fetch_roles(authorization: Option<&str>) -> (&'static str, String) {
+    if let Err(response) = require_login(authorization) {
+        return response;
+    }
+
+    (
+        "HTTP/1.1 200 OK",
+        json!({ "roles": roles::ROLES, "permissions": roles::PERMISSIONS }).to_string(),
+    )
+}
+
+pub fn handle_set_user_role(
+    user_id: i64,
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "roles.assign") {
+        return response;
+    }
+
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let role = match parsed["role"].as_str().and_then(roles::find) {
+        Some(role) => role,
+        None => {
+            let names: Vec<&str> = roles::ROLES.iter().map(|role| role.name).collect();
+            let response = json!({
+                "success": false,
+                "message": format!("Role must be one of: {}", names.join(", ")),
+            });
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    match db.set_user_role(user_id, role.name, actor) {
+        Ok(UserChange::Changed) => {
+            let response = json!({
+                "success": true,
+                "message": "Role updated; the user has been signed out",
+                "role": role.name,
+                "permissions": role.permissions,
+            });
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(UserChange::Unchanged) => {
+            let response = json!({
+                "success": true,
+                "message": "User already has this role",
+                "role": role.name,
+                "permissions": role.permissions,
+            });
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(UserChange::NotFound) => {
+            let response = r#"{"success": false, "message": "User not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Ok(UserChange::LastAdmin) => {
+            let response =
+                r#"{"success": false, "message": "The last admin cannot be given another role"}"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Passes a bearer token through only while its session is still open, so logged
+// out and revoked tokens stop working before they expire.
+pub fn authenticate<'a>(
+    authorization: Option<&'a str>,
+    db: &Database,
+) -> Result<Option<&'a str>, (&'static str, String)> {
+    if authorization.is_none() {
+        return Ok(None);
+    }
+
+    let claims = require_login(authorization)?;
+    match db.touch_session(claims.sid) {
+        Ok(true) => Ok(authorization),
+        Ok(false) => Err((
+            "HTTP/1.1 401 Unauthorized",
+            r#"{"success": false, "message": "Session has ended"}"#.to_string(),
+        )),
+        Err(_) => Err((
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Database error occurred"}"#.to_string(),
+        )),
+    }
+}
+
+pub fn handle_add_book(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "catalog.edit") {
+        return response;
+    }
+
+    let book_data: serde_json::Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let title = book_data["title"].as_str().unwrap_or("");
+    let author = book_data["author"].as_str().unwrap_or("");
+    let isbn = book_data["isbn"].as_str().unwrap_or("");
+    let publication_year_str = book_data["publication_year"].as_str().unwrap_or("");
+    let genre = book_data["genre"].as_str().unwrap_or("");
+    let number_of_copies = book_data["number_of_copies"].as_i64().unwrap_or(-1);
+
+    if title.trim().is_empty()
+        || author.trim().is_empty()
+        || isbn.trim().is_empty()
+        || publication_year_str.trim().is_empty()
+        || genre.trim().is_empty()
+        || number_of_copies <= 0
+    {
+        let response = r#"{"success": false, "message": "Please provide all the fields"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    // Validate ISBN is exactly 13 digits
+    if isbn.len() != 13 || !isbn.chars().all(|c| c.is_ascii_digit()) {
+        let response = r#"{"success": false, "message": "ISBN must be exactly 13 digits"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    // Validate publication year
+    let publication_year: u16 = match publication_year_str.parse() {
+        Ok(year) if (1500..=2024).contains(&year) => year,
+        _ => {
+            let response = r#"{"success": false, "message": "Publication year must be a valid year between 1500 and 2024"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    // Validate number of copies
+    if number_of_copies <= 0 || number_of_copies > i32::MAX as i64 {
+        let response =
+            r#"{"success": false, "message": "Number of copies must be a positive integer"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    let book = NewBook {
+        title,
+        author,
+        isbn,
+        publication_year: &publication_year.to_string(),
+        genre,
+        number_of_copies: number_of_copies as i32,
+    };
+    match db.add_book(&book, actor) {
+        Ok(true) => {
+            let response = r#"{"success": true, "message": "Book added successfully"}"#;
+            ("HTTP/1.1 201 Created", response.to_string())
+        }
+        Ok(false) => {
+            let response = r#"{"success": false, "message": "Book with this ISBN already exists"}"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_fetch_books(db: &Database) -> (&'static str, String) {
+    match db.fetch_books() {
+        Ok(books) => {
+            let json = serde_json::to_string(&books).unwrap_or("[]".to_string());
+            ("HTTP/1.1 200 OK", json)
+        }
+        Err(_) => {
+            let error = r#"{"success": false, "message": "Could not fetch books"}"#;
+            ("HTTP/1.1 500 Internal Server Error", error.to_string())
+        }
+    }
+}
+
+pub fn handle_search_books(terms: &str, db: &Database) -> (&'static str, String) {
+    match db.search_books(terms) {
+        Ok(books) => ("HTTP/1.1 200 OK", json!(books).to_string()),
+        Err(_) => {
+            let error = r#"{"success": false, "message": "Could not search books"}"#;
+            ("HTTP/1.1 500 Internal Server Error", error.to_string())
+        }
+    }
+}
+
+pub fn handle_fetch_book(book_id: i64, db: &Database) -> (&'static str, String) {
+    let branches = match db.fetch_branch_availability(book_id) {
+        Ok(branches) => branches,
+        Err(_) => {
+            let error = r#"{"success": false, "message": "Could not fetch book"}"#;
+            return ("HTTP/1.1 500 Internal Server Error", error.to_string());
+        }
+    };
+
+    match db.fetch_book(book_id) {
+        Ok(Some(book)) => match serde_json::to_string(&BookDetail { book, branches }) {
+            Ok(json) => ("HTTP/1.1 200 OK", json),
+            Err(_) => (
+                "HTTP/1.1 500 Internal Server Error",
+                r#"{"success": false, "message": "Failed to serialize book"}"#.to_string(),
+            ),
+        },
+        Ok(None) => (
+            "HTTP/1.1 404 Not Found",
+            r#"{"success": false, "message": "Book not found"}"#.to_string(),
+        ),
+        Err(_) => (
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Could not fetch book"}"#.to_string(),
+        ),
+    }
+}
+
+pub fn handle_edit_book(
+    id: i64,
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "catalog.edit") {
+        return response;
+    }
+
+    let updated_fields: Value = match serde_json::from_str::<Value>(request_body) {
+        Ok(val) if val.is_object() => val,
+        _ => {
+            let error = r#"{ "success": false, "message": "Invalid JSON body" }"#;
+            return ("HTTP/1.1 400 Bad Request", error.to_string());
+        }
+    };
+
+    // Ensure at least one editable field is present
+    let allowed_keys = [
+        "title",
+        "author",
+        "isbn",
+        "publication_year",
+        "genre",
+        "number_of_copies",
+        "available",
+    ];
+    let has_valid_keys = updated_fields
+        .as_object()
+        .unwrap()
+        .keys()
+        .any(|k| allowed_keys.contains(&k.as_str()));
+
+    if !has_valid_keys {
+        let error = r#"{ "success": false, "message": "No valid fields provided to update" }"#;
+        return ("HTTP/1.1 400 Bad Request", error.to_string());
+    }
+
+    // Call DB update function
+    match db.edit_book(id, &updated_fields, actor) {
+        Ok(true) => {
+            let response = r#"{ "success": true, "message": "Book updated successfully" }"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(false) => {
+            let response = r#"{ "success": false, "message": "Book not found or not updated" }"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{ "success": false, "message": "Database error during update" }"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_delete_book(
+    id: i64,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "catalog.edit") {
+        return response;
+    }
+
+    match db.delete_book(id, actor) {
+        Ok(true) => (
+            "HTTP/1.1 200 OK",
+            r#"{ "success": true, "message": "Book deleted successfully" }"#.to_string(),
+        ),
+        Ok(false) => (
+            "HTTP/1.1 404 Not Found",
+            r#"{ "success": false, "message": "Book not found" }"#.to_string(),
+        ),
+        Err(_) => (
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{ "success": false, "message": "Database error" }"#.to_string(),
+        ),
+    }
+}
+
+pub fn handle_fetch_users(authorization: Option<&str>, db: &Database) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "users.view") {
+        return response;
+    }
+
+    match db.fetch_users() {
+        Ok(users) => ("HTTP/1.1 200 OK", json!(users).to_string()),
+        Err(_) => {
+            let error = r#"{"success": false, "message": "Could not fetch users"}"#;
+            ("HTTP/1.1 500 Internal Server Error", error.to_string())
+        }
+    }
+}
+
+// A user's profile with their current loans, holds and balance. Users can see
+// their own.
+pub fn handle_fetch_user(
+    user_id: i64,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_self_or(Some(user_id), "users.view", authorization, db) {
+        return response;
+    }
+
+    let user = match db.fetch_user(user_id) {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            let response = r#"{"success": false, "message": "User not found"}"#;
+            return ("HTTP/1.1 404 Not Found", response.to_string());
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error"}"#;
+            return ("HTTP/1.1 500 Internal Server Error", response.to_string());
+        }
+    };
+
+    match (
+        db.fetch_borrowed_books(user_id),
+        db.fetch_holds(Some(user_id)),
+    ) {
+        (Ok(loans), Ok(holds)) => {
+            let mut profile = json!(user);
+            profile["loans"] = json!(loans);
+            profile["holds"] = json!(holds);
+            ("HTTP/1.1 200 OK", profile.to_string())
+        }
+        _ => {
+            let response = r#"{"success": false, "message": "Could not fetch user details"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Edits username, email and home branch. Roles are changed with PUT .../role.
+pub fn handle_update_user(
+    user_id: i64,
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "users.manage") {
+        return response;
+    }
+
+    let parsed: Value = match serde_json::from_str::<Value>(request_body) {
+        Ok(val) if val.is_object() => val,
+        _ => {
+            let response = r#"{"success": false, "message": "Invalid JSON body"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let username = match parsed.get("username") {
+        None => None,
+        Some(Value::String(username)) if db::looks_like_card_number(username.trim()) => {
+            return username_like_card_number();
+        }
+        Some(Value::String(username)) if (3..=50).contains(&username.trim().len()) => {
+            Some(username.trim())
+        }
+        Some(_) => {
+            let response =
+                r#"{"success": false, "message": "Username must be between 3 and 50 characters"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+    // null clears the email address or home branch
+    let email = match parsed.get("email") {
+        None => None,
+        Some(Value::Null) => Some(None),
+        Some(Value::String(email)) if is_valid_email(email) => Some(Some(email.trim())),
+        Some(_) => {
+            let response = r#"{"success": false, "message": "Invalid email"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+    let branch_id = match parsed.get("branch_id") {
+        None => None,
+        Some(Value::Null) => Some(None),
+        Some(branch_id) if branch_id.is_i64() => Some(branch_id.as_i64()),
+        Some(_) => {
+            let response = r#"{"success": false, "message": "Invalid branch_id"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    if parsed.get("role").is_some() {
+        let response =
+            r#"{"success": false, "message": "Roles are changed with PUT /api/users/{id}/role"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+    if username.is_none() && email.is_none() && branch_id.is_none() {
+        let response = r#"{"success": false, "message": "No valid fields provided to update"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    match db.update_user(user_id, username, email, branch_id, actor) {
+        Ok(UserUpdate::Updated) => {
+            let response = r#"{"success": true, "message": "User updated"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(UserUpdate::NotFound) => {
+            let response = r#"{"success": false, "message": "User not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Ok(UserUpdate::UsernameTaken) => {
+            let response = r#"{"success": false, "message": "Username already exists"}"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Ok(UserUpdate::UnknownBranch) => {
+            let response = r#"{"success": false, "message": "Branch not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// The queue of registrations waiting for staff in staff_approved mode.
+pub fn handle_fetch_registrations(
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "users.manage") {
+        return response;
+    }
+
+    match db.fetch_pending_registrations() {
+        Ok(registrations) => ("HTTP/1.1 200 OK", json!(registrations).to_string()),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Rejecting needs a reason, which the applicant is emailed and shown at login.
+pub fn handle_review_registration(
+    user_id: i64,
+    approve: bool,
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "users.manage") {
+        return response;
+    }
+
+    // An empty body is fine when approving
+    let parsed: Value = serde_json::from_str(request_body).unwrap_or(json!({}));
+    let reason = parsed["reason"].as_str().unwrap_or("").trim();
+    if !approve && reason.is_empty() {
+        let response = r#"{"success": false, "message": "Please give a reason for rejecting"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    match db.review_registration(user_id, approve, reason, actor) {
+        Ok(RegistrationReview::Reviewed) => {
+            let message = if approve {
+                "Registration approved"
+            } else {
+                "Registration rejected"
+            };
+            let response = json!({ "success": true, "message": message });
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(RegistrationReview::NotFound) => {
+            let response = r#"{"success": false, "message": "User not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Ok(RegistrationReview::NotPending) => {
+            let response =
+                r#"{"success": false, "message": "This registration is not waiting for approval"}"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Patrons edit their own contact details; staff with users.manage can edit anyone's.
+// Email has its own endpoint since changing it sends a confirmation.
+pub fn handle_update_profile(
+    user_id: i64,
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_self_or(Some(user_id), "users.manage", authorization, db) {
+        return response;
+    }
+
+    let parsed: Value = match serde_json::from_str::<Value>(request_body) {
+        Ok(val) if val.is_object() => val,
+        _ => {
+            let response = r#"{"success": false, "message": "Invalid JSON body"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let full_name = match profile_field(&parsed, "full_name", 100) {
+        Ok(full_name) => full_name,
+        Err(response) => return response,
+    };
+    let address = match profile_field(&parsed, "address", 200) {
+        Ok(address) => address,
+        Err(response) => return response,
+    };
+    let phone = match profile_field(&parsed, "phone", 20) {
+        Ok(Some(Some(phone)))
+            if phone.chars().filter(|c| c.is_ascii_digit()).count() < 5
+                || !phone
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || " +-().".contains(c)) =>
+        {
+            let response = r#"{"success": false, "message": "Invalid phone number"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+        Ok(phone) => phone,
+        Err(response) => return response,
+    };
+    let date_of_birth = match profile_field(&parsed, "date_of_birth", 10) {
+        Ok(Some(Some(date)))
+            if !NaiveDate::parse_from_str(date, DATE_FORMAT).is_ok_and(|date| {
+                date < Utc::now().date_naive()
+                    && date > NaiveDate::from_ymd_opt(1900, 1, 1).unwrap()
+            }) =>
+        {
+            let response = r#"{"success": false, "message": "date_of_birth must be a YYYY-MM-DD date in the past"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+        Ok(date_of_birth) => date_of_birth,
+        Err(response) => return response,
+    };
+
+    if parsed.get("email").is_some() {
+        let response =
+            r#"{"success": false, "message": "Email is changed with PUT /api/account/email"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+    if full_name.is_none() && phone.is_none() && address.is_none() && date_of_birth.is_none() {
+        let response = r#"{"success": false, "message": "No valid fields provided to update"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    let profile = ProfileUpdate {
+        full_name,
+        phone,
+        address,
+        date_of_birth,
+    };
+    match db.update_profile(user_id, &profile, actor) {
+        Ok(true) => {
+            let response = r#"{"success": true, "message": "Profile updated"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(false) => {
+            let response = r#"{"success": false, "message": "User not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// An optional text field of a profile edit: missing leaves it alone, and null or
+// an empty string clears it.
+fn profile_field<'a>(
+    parsed: &'a Value,
+    field: &str,
+    max_len: usize,
+) -> Result<Option<Option<&'a str>>, (&'static str, String)> {
+    match parsed.get(field) {
+        None => Ok(None),
+        Some(Value::Null) => Ok(Some(None)),
+        Some(Value::String(text)) if text.trim().is_empty() => Ok(Some(None)),
+        Some(Value::String(text)) if text.trim().chars().count() <= max_len => {
+            Ok(Some(Some(text.trim())))
+        }
+        Some(_) => Err((
+            "HTTP/1.1 400 Bad Request",
+            json!({
+                "success": false,
+                "message": format!("{field} must be text of at most {max_len} characters"),
+            })
+            .to_string(),
+        )),
+    }
+}
+
+// Extends a membership by MEMBERSHIP_DAYS, or by "days" from the body.
+pub fn handle_renew_membership(
+    user_id: i64,
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "users.manage") {
+        return response;
+    }
+
+    // An empty body renews for the standard period
+    let parsed: Value = serde_json::from_str(request_body).unwrap_or(json!({}));
+    let days = match parsed.get("days") {
+        None => MEMBERSHIP_DAYS,
+        Some(days) => match days.as_i64() {
+            Some(days) if (1..=3650).contains(&days) => days,
+            _ => {
+                let response =
+                    r#"{"success": false, "message": "days must be between 1 and 3650"}"#;
+                return ("HTTP/1.1 400 Bad Request", response.to_string());
+            }
+        },
+    };
+
+    match db.renew_membership(user_id, days, actor) {
+        Ok(Some(expires_at)) => (
+            "HTTP/1.1 200 OK",
+            json!({
+                "success": true,
+                "message": "Membership renewed",
+                "membership_expires_at": expires_at,
+            })
+            .to_string(),
+        ),
+        Ok(None) => {
+            let response = r#"{"success": false, "message": "User not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Replaces a lost card; the old number stops working immediately.
+pub fn handle_reissue_card(
+    user_id: i64,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "users.manage") {
+        return response;
+    }
+
+    match db.reissue_card(user_id, actor) {
+        Ok(Some(card_number)) => (
+            "HTTP/1.1 200 OK",
+            json!({
+                "success": true,
+                "message": "New card issued",
+                "card_number": card_number,
+            })
+            .to_string(),
+        ),
+        Ok(None) => {
+            let response = r#"{"success": false, "message": "User not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Deactivated users can't sign in, borrow or place holds until reactivated.
+pub fn handle_set_user_active(
+    user_id: i64,
+    active: bool,
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let account = match require_user_manager(authorization, db) {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+    if !active && account.id == user_id {
+        let response = r#"{"success": false, "message": "You cannot deactivate your own account"}"#;
+        return ("HTTP/1.1 409 Conflict", response.to_string());
+    }
+
+    // An empty body is fine; the reason is optional
+    let parsed: Value = serde_json::from_str(request_body).unwrap_or(json!({}));
+    let reason = parsed["reason"].as_str().unwrap_or("").trim();
+
+    match db.set_user_active(user_id, active, reason, actor) {
+        Ok(UserChange::Changed) | Ok(UserChange::Unchanged) => {
+            let message = if active {
+                "User reactivated"
+            } else {
+                "User deactivated"
+            };
+            (
+                "HTTP/1.1 200 OK",
+                json!({ "success": true, "message": message, "active": active }).to_string(),
+            )
+        }
+        Ok(UserChange::NotFound) => {
+            let response = r#"{"success": false, "message": "User not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Ok(UserChange::LastAdmin) => {
+            let response =
+                r#"{"success": false, "message": "The last active admin cannot be deactivated"}"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_delete_user(
+    user_id: i64,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let account = match require_user_manager(authorization, db) {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+    if account.id == user_id {
+        let response = r#"{"success": false, "message": "You cannot delete your own account"}"#;
+        return ("HTTP/1.1 409 Conflict", response.to_string());
+    }
+
+    match db.delete_user(user_id, actor) {
+        Ok(UserDeletion::Deleted) => {
+            let response = r#"{"success": true, "message": "User deleted"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(UserDeletion::NotFound) => {
+            let response = r#"{"success": false, "message": "User not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Ok(UserDeletion::LastAdmin) => {
+            let response =
+                r#"{"success": false, "message": "The last active admin cannot be deleted"}"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Ok(UserDeletion::Outstanding {
+            active_loans,
+            balance_cents,
+        }) => (
+            "HTTP/1.1 409 Conflict",
+            json!({
+                "success": false,
+                "message": "Return or declare lost every loan and settle the balance first, or deactivate the user instead",
+                "active_loans": active_loans,
+                "balance_cents": balance_cents,
+            })
+            .to_string(),
+        ),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_borrow_book(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let parsed: serde_json::Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let user_id = match parsed.get("user_id").and_then(|v| v.as_i64()) {
+        Some(id) => id,
+        None => {
+            let response = r#"{"success": false, "message": "Missing or invalid user_id"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let book_id = match parsed.get("book_id").and_then(|v| v.as_i64()) {
+        Some(id) => id,
+        None => {
+            let response = r#"{"success": false, "message": "Missing or invalid book_id"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+    let branch_id = parsed.get("branch_id").and_then(|v| v.as_i64());
+
+    if let Err(response) = require_self_or(Some(user_id), "circulation.checkout", authorization, db)
+    {
+        return response;
+    }
+    if let Err(response) = require_active_patron(user_id, db) {
+        return response;
+    }
+
+    match db.borrow_book(user_id, book_id, branch_id, actor) {
+        Ok(true) => {
+            let response = r#"{"success": true, "message": "Book borrowed successfully"}"#;
+            ("HTTP/1.1 201 Created", response.to_string())
+        }
+        Ok(false) => {
+            let response = r#"{"success": false, "message": "Book or User doesn't exist"}"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_fetch_borrowed_books(
+    user_id: i64,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_self_or(Some(user_id), "circulation.checkout", authorization, db)
+    {
+        return response;
+    }
+
+    match db.fetch_borrowed_books(user_id) {
+        Ok(books) => {
+            let json = serde_json::to_string(&books).unwrap_or("[]".to_string());
+            ("HTTP/1.1 200 OK", json)
+        }
+        Err(_) => {
+            let error = r#"{"success": false, "message": "Could not fetch borrowed books"}"#;
+            ("HTTP/1.1 500 Internal Server Error", error.to_string())
+        }
+    }
+}
+
+pub fn handle_return_book(
+    borrowed_id: i64,
+    book_id: i64,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_loan_access(borrowed_id, authorization, db) {
+        return response;
+    }
+
+    match db.return_book(borrowed_id, book_id, actor) {
+        Ok(true) => (
+            "HTTP/1.1 200 OK",
+            r#"{"success": true, "message":"Book returned successfully"}"#.to_string(),
+        ),
+        Ok(false) => (
+            "HTTP/1.1 404 NOT FOUND",
+            r#"{"success": false, "message": "Borrow details not found"}"#.to_string(),
+        ),
+        Err(_) => (
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Database error"}"#.to_string(),
+        ),
+    }
+}
+
+pub fn handle_fetch_all_borrowed_books(
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "circulation.checkout") {
+        return response;
+    }
+
+    match db.fetch_all_borrowed_books() {
+        Ok(books) => {
+            let json = serde_json::to_string(&books).unwrap_or("[]".to_string());
+            ("HTTP/1.1 200 OK", json)
+        }
+        Err(_) => {
+            let error = r#"{"success": false, "message": "Could not fetch borrowed books"}"#;
+            ("HTTP/1.1 500 Internal Server Error", error.to_string())
+        }
+    }
+}
+
+pub fn handle_fetch_items(book_id: i64, db: &Database) -> (&'static str, String) {
+    match db.fetch_items(book_id) {
+        Ok(items) => {
+            let json = serde_json::to_string(&items).unwrap_or("[]".to_string());
+            ("HTTP/1.1 200 OK", json)
+        }
+        Err(_) => {
+            let error = r#"{"success": false, "message": "Could not fetch items"}"#;
+            ("HTTP/1.1 500 Internal Server Error", error.to_string())
+        }
+    }
+}
+
+pub fn handle_add_item(
+    book_id: i64,
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "catalog.edit") {
+        return response;
+    }
+
+    let item_data: Value = match serde_json::from_str::<Value>(request_body) {
+        Ok(val) if val.is_object() => val,
+        _ => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let barcode = item_data["barcode"].as_str().map(str::trim);
+    let shelf_location = item_data["shelf_location"].as_str().unwrap_or("");
+    let condition = item_data["condition"].as_str().unwrap_or("good");
+    let branch_id = item_data["branch_id"].as_i64();
+
+    if barcode.is_some_and(str::is_empty) {
+        let response = r#"{"success": false, "message": "Barcode cannot be empty"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    match db.add_item(
+        book_id,
+        barcode,
+        shelf_location,
+        condition,
+        branch_id,
+        actor,
+    ) {
+        Ok(Some(item)) => {
+            let json = serde_json::to_string(&item).unwrap_or("{}".to_string());
+            ("HTTP/1.1 201 Created", json)
+        }
+        Ok(None) => {
+            let response = r#"{"success": false, "message": "Book or branch not found, or barcode already exists"}"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_update_item(
+    item_id: i64,
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "catalog.edit") {
+        return response;
+    }
+
+    let updated_fields: Value = match serde_json::from_str::<Value>(request_body) {
+        Ok(val) if val.is_object() => val,
+        _ => {
+            let error = r#"{ "success": false, "message": "Invalid JSON body" }"#;
+            return ("HTTP/1.1 400 Bad Request", error.to_string());
+        }
+    };
+
+    if let Some(status) = updated_fields.get("status").and_then(|v| v.as_str())
+        && !ITEM_STATUSES.contains(&status)
+    {
+        let error = r#"{ "success": false, "message": "Unknown item status" }"#;
+        return ("HTTP/1.1 400 Bad Request", error.to_string());
+    }
+
+    if let Some(barcode) = updated_fields.get("barcode").and_then(|v| v.as_str())
+        && barcode.trim().is_empty()
+    {
+        let error = r#"{ "success": false, "message": "Barcode cannot be empty" }"#;
+        return ("HTTP/1.1 400 Bad Request", error.to_string());
+    }
+
+    match db.update_item(item_id, &updated_fields, actor) {
+        Ok(ItemUpdate::Updated) => {
+            let response = r#"{ "success": true, "message": "Item updated successfully" }"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(ItemUpdate::BarcodeTaken) => {
+            let response = r#"{ "success": false, "message": "Barcode already exists" }"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Ok(ItemUpdate::Refused) => {
+            let response = r#"{ "success": false, "message": "Item not found, on loan, or nothing to update" }"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{ "success": false, "message": "Database error during update" }"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_renew_book(
+    borrowed_id: i64,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_loan_access(borrowed_id, authorization, db) {
+        return response;
+    }
+
+    match db.renew_book(borrowed_id, actor) {
+        Ok(Renewal::Renewed(due_date)) => (
+            "HTTP/1.1 200 OK",
+            json!({ "success": true, "message": "Loan renewed", "due_date": due_date }).to_string(),
+        ),
+        Ok(Renewal::HoldWaiting) => (
+            "HTTP/1.1 409 Conflict",
+            r#"{"success": false, "message": "Another patron has a hold on this book, so the loan can't be renewed"}"#
+                .to_string(),
+        ),
+        Ok(Renewal::CannotBorrow) => (
+            "HTTP/1.1 403 Forbidden",
+            r#"{"success": false, "message": "The borrower's account is deactivated, awaiting approval, or has an expired membership"}"#
+                .to_string(),
+        ),
+        Ok(Renewal::Refused) => (
+            "HTTP/1.1 409 Conflict",
+            r#"{"success": false, "message": "Loan not found, overdue, or renewal limit reached"}"#
+                .to_string(),
+        ),
+        Err(_) => (
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Database error"}"#.to_string(),
+        ),
+    }
+}
+
+pub fn handle_fetch_calendar(db: &Database) -> (&'static str, String) {
+    match db.fetch_calendar() {
+        Ok((opening_hours, closures)) => (
+            "HTTP/1.1 200 OK",
+            json!({ "opening_hours": opening_hours, "closures": closures }).to_string(),
+        ),
+        Err(_) => {
+            let error = r#"{"success": false, "message": "Could not fetch calendar"}"#;
+            ("HTTP/1.1 500 Internal Server Error", error.to_string())
+        }
+    }
+}
+
+pub fn handle_set_opening_hours(
+    weekday: u32,
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
+    }
+
+    let hours_data: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let opens = hours_data["opens"].as_str().unwrap_or("09:00");
+    let closes = hours_data["closes"].as_str().unwrap_or("17:00");
+    let closed = hours_data["closed"].as_bool().unwrap_or(false);
+
+    let valid_time = |time: &str| NaiveTime::parse_from_str(time, "%H:%M").is_ok();
+    if weekday > 6 || !valid_time(opens) || !valid_time(closes) {
+        let response =
+            r#"{"success": false, "message": "Weekday must be 0-6 and times must be HH:MM"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    match db.set_opening_hours(weekday, opens, closes, closed, actor) {
+        Ok(true) => {
+            let response = r#"{"success": true, "message": "Opening hours updated"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(false) => {
+            let response = r#"{"success": false, "message": "Weekday not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_add_closure(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
+    }
+
+    let closure_data: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let date = closure_data["date"].as_str().unwrap_or("");
+    let reason = closure_data["reason"].as_str().unwrap_or("");
+
+    if NaiveDate::parse_from_str(date, DATE_FORMAT).is_err() {
+        let response = r#"{"success": false, "message": "Date must be YYYY-MM-DD"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    match db.add_closure(date, reason, actor) {
+        Ok(true) => {
+            let response = r#"{"success": true, "message": "Closure added"}"#;
+            ("HTTP/1.1 201 Created", response.to_string())
+        }
+        Ok(false) => {
+            let response = r#"{"success": false, "message": "Closure already exists"}"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_delete_closure(
+    date: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
+    }
+
+    match db.delete_closure(date, actor) {
+        Ok(true) => (
+            "HTTP/1.1 200 OK",
+            r#"{"success": true, "message": "Closure removed"}"#.to_string(),
+        ),
+        Ok(false) => (
+            "HTTP/1.1 404 Not Found",
+            r#"{"success": false, "message": "Closure not found"}"#.to_string(),
+        ),
+        Err(_) => (
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Database error"}"#.to_string(),
+        ),
+    }
+}
+
+pub fn handle_fetch_branches(db: &Database) -> (&'static str, String) {
+    match db.fetch_branches() {
+        Ok(branches) => {
+            let json = serde_json::to_string(&branches).unwrap_or("[]".to_string());
+            ("HTTP/1.1 200 OK", json)
+        }
+        Err(_) => {
+            let error = r#"{"success": false, "message": "Could not fetch branches"}"#;
+            ("HTTP/1.1 500 Internal Server Error", error.to_string())
+        }
+    }
+}
+
+pub fn handle_add_branch(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
+    }
+
+    let branch_data: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let name = branch_data["name"].as_str().unwrap_or("");
+    let address = branch_data["address"].as_str().unwrap_or("");
+
+    if name.trim().is_empty() {
+        let response = r#"{"success": false, "message": "Please provide a branch name"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    match db.add_branch(name.trim(), address, actor) {
+        Ok(true) => {
+            let response = r#"{"success": true, "message": "Branch added successfully"}"#;
+            ("HTTP/1.1 201 Created", response.to_string())
+        }
+        Ok(false) => {
+            let response =
+                r#"{"success": false, "message": "Branch with this name already exists"}"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_set_user_branch(
+    user_id: i64,
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_self_or(Some(user_id), "users.manage", authorization, db) {
+        return response;
+    }
+
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let branch_id = match parsed.get("branch_id").and_then(|v| v.as_i64()) {
+        Some(id) => id,
+        None => {
+            let response = r#"{"success": false, "message": "Missing or invalid branch_id"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    match db.set_user_branch(user_id, branch_id, actor) {
+        Ok(true) => {
+            let response = r#"{"success": true, "message": "User branch updated"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(false) => {
+            let response = r#"{"success": false, "message": "User or branch not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_place_hold(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let (user_id, book_id, pickup_branch_id) = match (
+        parsed.get("user_id").and_then(|v| v.as_i64()),
+        parsed.get("book_id").and_then(|v| v.as_i64()),
+        parsed.get("pickup_branch_id").and_then(|v| v.as_i64()),
+    ) {
+        (Some(user_id), Some(book_id), Some(branch_id)) => (user_id, book_id, branch_id),
+        _ => {
+            let response = r#"{"success": false, "message": "Please provide user_id, book_id and pickup_branch_id"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    if let Err(response) = require_self_or(Some(user_id), "circulation.checkout", authorization, db)
+    {
+        return response;
+    }
+    if let Err(response) = require_active_patron(user_id, db) {
+        return response;
+    }
+
+    match db.place_hold(user_id, book_id, pickup_branch_id, actor) {
+        Ok(Some(hold_id)) => (
+            "HTTP/1.1 201 Created",
+            json!({ "success": true, "message": "Hold placed", "hold_id": hold_id }).to_string(),
+        ),
+        Ok(None) => {
+            let response = r#"{"success": false, "message": "User, book or branch doesn't exist, or a hold is already active"}"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Without a user id this is every hold in the system, which is for staff.
+pub fn handle_fetch_holds(
+    user_id: Option<i64>,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_self_or(user_id, "circulation.checkout", authorization, db) {
+        return response;
+    }
+
+    match db.fetch_holds(user_id) {
+        Ok(holds) => {
+            let json = serde_json::to_string(&holds).unwrap_or("[]".to_string());
+            ("HTTP/1.1 200 OK", json)
+        }
+        Err(_) => {
+            let error = r#"{"success": false, "message": "Could not fetch holds"}"#;
+            ("HTTP/1.1 500 Internal Server Error", error.to_string())
+        }
+    }
+}
+
+pub fn handle_cancel_hold(
+    hold_id: i64,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let owner_id = match db.hold_owner(hold_id) {
+        Ok(owner_id) => owner_id,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error"}"#;
+            return ("HTTP/1.1 500 Internal Server Error", response.to_string());
+        }
+    };
+    if let Err(response) = require_self_or(owner_id, "circulation.checkout", authorization, db) {
+        return response;
+    }
+
+    match db.cancel_hold(hold_id, actor) {
+        Ok(true) => (
+            "HTTP/1.1 200 OK",
+            r#"{"success": true, "message": "Hold cancelled"}"#.to_string(),
+        ),
+        Ok(false) => (
+            "HTTP/1.1 404 Not Found",
+            r#"{"success": false, "message": "Active hold not found"}"#.to_string(),
+        ),
+        Err(_) => (
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Database error"}"#.to_string(),
+        ),
+    }
+}
+
+pub fn handle_start_transfer(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "circulation.checkout") {
+        return response;
+    }
+
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let (item_id, to_branch_id) = match (
+        parsed.get("item_id").and_then(|v| v.as_i64()),
+        parsed.get("to_branch_id").and_then(|v| v.as_i64()),
+    ) {
+        (Some(item_id), Some(to_branch_id)) => (item_id, to_branch_id),
+        _ => {
+            let response =
+                r#"{"success": false, "message": "Please provide item_id and to_branch_id"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    match db.start_transfer(item_id, to_branch_id, actor) {
+        Ok(Some(transfer_id)) => (
+            "HTTP/1.1 201 Created",
+            json!({ "success": true, "message": "Transfer started", "transfer_id": transfer_id })
+                .to_string(),
+        ),
+        Ok(None) => {
+            let response =
+                r#"{"success": false, "message": "Item is not available or branch is invalid"}"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_fetch_transfers(
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "circulation.checkout") {
+        return response;
+    }
+
+    match db.fetch_transfers() {
+        Ok(transfers) => {
+            let json = serde_json::to_string(&transfers).unwrap_or("[]".to_string());
+            ("HTTP/1.1 200 OK", json)
+        }
+        Err(_) => {
+            let error = r#"{"success": false, "message": "Could not fetch transfers"}"#;
+            ("HTTP/1.1 500 Internal Server Error", error.to_string())
+        }
+    }
+}
+
+pub fn handle_receive_transfer(
+    transfer_id: i64,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "circulation.checkout") {
+        return response;
+    }
+
+    match db.receive_transfer(transfer_id, actor) {
+        Ok(true) => (
+            "HTTP/1.1 200 OK",
+            r#"{"success": true, "message": "Transfer received"}"#.to_string(),
+        ),
+        Ok(false) => (
+            "HTTP/1.1 404 Not Found",
+            r#"{"success": false, "message": "Transfer not found or already received"}"#
+                .to_string(),
+        ),
+        Err(_) => (
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Database error"}"#.to_string(),
+        ),
+    }
+}
+
+pub fn handle_declare_lost(
+    borrowed_id: i64,
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "circulation.checkout") {
+        return response;
+    }
+
+    // An empty body charges the default replacement fee
+    let parsed: Value = serde_json::from_str(request_body).unwrap_or(json!({}));
+    let fee_cents = parsed["fee_cents"]
+        .as_i64()
+        .unwrap_or(DEFAULT_REPLACEMENT_FEE_CENTS);
+
+    if fee_cents < 0 {
+        let response = r#"{"success": false, "message": "Fee cannot be negative"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    match db.declare_lost(borrowed_id, fee_cents, actor) {
+        Ok(true) => (
+            "HTTP/1.1 200 OK",
+            r#"{"success": true, "message": "Loan declared lost"}"#.to_string(),
+        ),
+        Ok(false) => (
+            "HTTP/1.1 404 Not Found",
+            r#"{"success": false, "message": "Borrow details not found"}"#.to_string(),
+        ),
+        Err(_) => (
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Database error"}"#.to_string(),
+        ),
+    }
+}
+
+pub fn handle_return_damaged(
+    borrowed_id: i64,
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "circulation.checkout") {
+        return response;
+    }
+
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let fee_cents = parsed["fee_cents"].as_i64().unwrap_or(-1);
+    let condition = parsed["condition"].as_str().unwrap_or("damaged");
+
+    if fee_cents < 0 {
+        let response =
+            r#"{"success": false, "message": "Please provide a non-negative fee_cents"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    match db.return_damaged(borrowed_id, fee_cents, condition, actor) {
+        Ok(true) => (
+            "HTTP/1.1 200 OK",
+            r#"{"success": true, "message": "Damaged return recorded"}"#.to_string(),
+        ),
+        Ok(false) => (
+            "HTTP/1.1 404 Not Found",
+            r#"{"success": false, "message": "Borrow details not found"}"#.to_string(),
+        ),
+        Err(_) => (
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Database error"}"#.to_string(),
+        ),
+    }
+}
+
+pub fn handle_mark_found(
+    item_id: i64,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "circulation.checkout") {
+        return response;
+    }
+
+    match db.mark_found(item_id, actor) {
+        Ok(true) => (
+            "HTTP/1.1 200 OK",
+            r#"{"success": true, "message": "Item marked as found"}"#.to_string(),
+        ),
+        Ok(false) => (
+            "HTTP/1.1 404 Not Found",
+            r#"{"success": false, "message": "Lost item not found"}"#.to_string(),
+        ),
+        Err(_) => (
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Database error"}"#.to_string(),
+        ),
+    }
+}
+
+pub fn handle_fetch_ledger(
+    user_id: i64,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_self_or(Some(user_id), "circulation.checkout", authorization, db)
+    {
+        return response;
+    }
+
+    match db.fetch_ledger(user_id) {
+        Ok((entries, balance)) => (
+            "HTTP/1.1 200 OK",
+            json!({ "entries": entries, "balance_cents": balance }).to_string(),
+        ),
+        Err(_) => {
+            let error = r#"{"success": false, "message": "Could not fetch ledger"}"#;
+            ("HTTP/1.1 500 Internal Server Error", error.to_string())
+        }
+    }
+}
+
+fn require_login(authorization: Option<&str>) -> Result<Claims, (&'static str, String)> {
+    auth::decode_claims(authorization).ok_or((
+        "HTTP/1.1 401 Unauthorized",
+        r#"{"success": false, "message": "Missing or invalid token"}"#.to_string(),
+    ))
+}
+
+// The signed-in user's own account record, alongside the token's claims.
+fn require_account(
+    authorization: Option<&str>,
+    db: &Database,
+) -> Result<(Claims, Patron), (&'static str, String)> {
+    let claims = require_login(authorization)?;
+    match db.fetch_patron_by_username(&claims.sub) {
+        Ok(Some(patron)) => Ok((claims, patron)),
+        Ok(None) => Err((
+            "HTTP/1.1 401 Unauthorized",
+            r#"{"success": false, "message": "Missing or invalid token"}"#.to_string(),
+        )),
+        Err(_) => Err((
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Database error occurred"}"#.to_string(),
+        )),
+    }
+}
+
+// Staff endpoints name the permission they need; roles.rs says which roles have it.
+fn require_permission(
+    authorization: Option<&str>,
+    permission: &str,
+) -> Result<Claims, (&'static str, String)> {
+    let claims = require_login(authorization)?;
+    if roles::has_permission(&claims.role, permission) {
+        Ok(claims)
+    } else {
+        Err(missing_permission(permission))
+    }
+}
+
+// Patrons may act on their own records; anyone else needs the permission. An
+// unknown owner (a loan or hold that doesn't exist) also needs it, so patrons
+// can't probe for other people's records.
+fn require_self_or(
+    owner_id: Option<i64>,
+    permission: &str,
+    authorization: Option<&str>,
+    db: &Database,
+) -> Result<Claims, (&'static str, String)> {
+    let (claims, account) = require_account(authorization, db)?;
+    if owner_id == Some(account.id) || roles::has_permission(&claims.role, permission) {
+        Ok(claims)
+    } else {
+        Err(missing_permission(permission))
+    }
+}
+
+// Renewing or returning a loan: the borrower's own, or anyone's with circulation.checkout.
+fn require_loan_access(
+    borrowed_id: i64,
+    authorization: Option<&str>,
+    db: &Database,
+) -> Result<Claims, (&'static str, String)> {
+    let owner_id = db.loan_owner(borrowed_id).map_err(|_| {
+        (
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Database error"}"#.to_string(),
+        )
+    })?;
+    require_self_or(owner_id, "circulation.checkout", authorization, db)
+}
+
+// Deactivating and deleting need users.manage. Returns the caller's own account
+// so handlers can stop staff from locking themselves out.
+fn require_user_manager(
+    authorization: Option<&str>,
+    db: &Database,
+) -> Result<Patron, (&'static str, String)> {
+    let (claims, account) = require_account(authorization, db)?;
+    if roles::has_permission(&claims.role, "users.manage") {
+        Ok(account)
+    } else {
+        Err(missing_permission("users.manage"))
+    }
+}
+
+// Borrowing for a deactivated account or an expired membership is refused with
+// a clear reason; unknown users fall through to the usual not-found responses.
+fn require_active_patron(user_id: i64, db: &Database) -> Result<(), (&'static str, String)> {
+    match db.fetch_patron(user_id) {
+        Ok(Some(patron)) if !patron.active => Err((
+            "HTTP/1.1 403 Forbidden",
+            r#"{"success": false, "message": "This account has been deactivated"}"#.to_string(),
+        )),
+        Ok(Some(patron)) if patron.membership_expired => Err((
+            "HTTP/1.1 403 Forbidden",
+            json!({
+                "success": false,
+                "message": format!(
+                    "Membership expired on {}; renew it to borrow",
+                    patron.membership_expires_at.unwrap_or_default()
+                ),
+            })
+            .to_string(),
+        )),
+        Ok(_) => Ok(()),
+        Err(_) => Err((
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Database error occurred"}"#.to_string(),
+        )),
+    }
+}
+
+fn missing_permission(permission: &str) -> (&'static str, String) {
+    (
+        "HTTP/1.1 403 Forbidden",
+        json!({
+            "success": false,
+            "message": format!("This requires the {permission} permission"),
+        })
+        .to_string(),
+    )
+}
+
+// Circulation desk endpoints are for staff; patrons keep using /api/borrow.
+pub fn handle_desk_lookup(
+    identifier: &str,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "circulation.checkout") {
+        return response;
+    }
+
+    let patron = match db.find_patron(identifier) {
+        Ok(Some(patron)) => patron,
+        Ok(None) => {
+            let response = r#"{"success": false, "message": "Patron not found"}"#;
+            return ("HTTP/1.1 404 Not Found", response.to_string());
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error"}"#;
+            return ("HTTP/1.1 500 Internal Server Error", response.to_string());
+        }
+    };
+
+    match (
+        db.fetch_borrowed_books(patron.id),
+        db.fetch_holds(Some(patron.id)),
+        db.fetch_ledger(patron.id),
+    ) {
+        (Ok(loans), Ok(holds), Ok((_, balance))) => (
+            "HTTP/1.1 200 OK",
+            json!({
+                "patron": patron,
+                "loans": loans,
+                "holds": holds,
+                "balance_cents": balance,
+            })
+            .to_string(),
+        ),
+        _ => {
+            let response = r#"{"success": false, "message": "Could not fetch patron details"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_desk_checkout(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "circulation.checkout") {
+        return response;
+    }
+
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    // The patron is identified by id, or by the card number / username the desk scanned
+    let user_id = match (
+        parsed.get("user_id").and_then(|v| v.as_i64()),
+        parsed.get("patron").and_then(|v| v.as_str()),
+    ) {
+        (Some(user_id), _) => user_id,
+        (None, Some(identifier)) => match db.find_patron(identifier) {
+            Ok(Some(patron)) => patron.id,
+            Ok(None) => {
+                let response = r#"{"success": false, "message": "Patron not found"}"#;
+                return ("HTTP/1.1 404 Not Found", response.to_string());
+            }
+            Err(_) => {
+                let response = r#"{"success": false, "message": "Database error"}"#;
+                return ("HTTP/1.1 500 Internal Server Error", response.to_string());
+            }
+        },
+        (None, None) => {
+            let response = r#"{"success": false, "message": "Please provide user_id or patron"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    if let Err(response) = require_active_patron(user_id, db) {
+        return response;
+    }
+
+    let checkout = match (
+        parsed.get("barcode").and_then(|v| v.as_str()),
+        parsed.get("isbn").and_then(|v| v.as_str()),
+    ) {
+        (Some(barcode), _) => db.checkout_by_barcode(user_id, barcode, actor, None),
+        (None, Some(isbn)) => db.checkout_by_isbn(user_id, isbn, actor),
+        (None, None) => {
+            let response = r#"{"success": false, "message": "Please provide barcode or isbn"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    match checkout {
+        Ok(Some(checkout)) => (
+            "HTTP/1.1 201 Created",
+            json!({ "success": true, "message": "Checked out", "loan": checkout }).to_string(),
+        ),
+        Ok(None) => {
+            let response = r#"{"success": false, "message": "Patron or copy not found, or copy not available"}"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_desk_checkin(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "circulation.checkout") {
+        return response;
+    }
+
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let barcode = match parsed.get("barcode").and_then(|v| v.as_str()) {
+        Some(barcode) => barcode,
+        None => {
+            let response = r#"{"success": false, "message": "Missing or invalid barcode"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    match db.checkin_by_barcode(barcode, actor, None) {
+        Ok(Some(checkin)) => (
+            "HTTP/1.1 200 OK",
+            json!({ "success": true, "message": "Checked in", "checkin": checkin }).to_string(),
+        ),
+        Ok(None) => {
+            let response = r#"{"success": false, "message": "No open loan for this barcode"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Plain text sized for a receipt printer, listing everything the patron has out.
+pub fn handle_desk_receipt(
+    user_id: i64,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "circulation.checkout") {
+        return response;
+    }
+
+    let (patron, loans) = match (db.fetch_patron(user_id), db.fetch_borrowed_books(user_id)) {
+        (Ok(Some(patron)), Ok(loans)) => (patron, loans),
+        (Ok(None), _) => return ("HTTP/1.1 404 Not Found", "Patron not found".to_string()),
+        _ => {
+            return (
+                "HTTP/1.1 500 Internal Server Error",
+                "Could not fetch loans".to_string(),
+            );
+        }
+    };
+
+    let mut receipt = String::from("LIBRARY LOAN RECEIPT\n");
+    receipt.push_str(&format!(
+        "Patron: {} (card {})\n",
+        patron.username, patron.card_number
+    ));
+    receipt.push_str(&format!(
+        "Printed: {}\n\n",
+        Utc::now().format("%Y-%m-%d %H:%M UTC")
+    ));
+    receipt.push_str(&format!("{:<28} {:<14} {}\n", "Title", "Barcode", "Due"));
+
+    for loan in &loans {
+        let title: String = loan.book.title.chars().take(28).collect();
+        let due = loan.due_date.get(..10).unwrap_or(&loan.due_date);
+        receipt.push_str(&format!("{:<28} {:<14} {}\n", title, loan.barcode, due));
+    }
+    receipt.push_str(&format!("\nItems on loan: {}\n", loans.len()));
+
+    ("HTTP/1.1 200 OK", receipt)
+}
+
+pub fn handle_set_pin(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let claims = match require_login(authorization) {
+        Ok(claims) => claims,
+        Err(response) => return response,
+    };
+
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let pin = match parsed.get("pin").and_then(|v| v.as_str()) {
+        Some(pin) if (4..=6).contains(&pin.len()) && pin.chars().all(|c| c.is_ascii_digit()) => pin,
+        _ => {
+            let response = r#"{"success": false, "message": "PIN must be 4 to 6 digits"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    match db.set_pin(&claims.sub, pin, actor) {
+        Ok(true) => {
+            let response = r#"{"success": true, "message": "PIN updated"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(false) => {
+            let response = r#"{"success": false, "message": "User not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_change_password(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let (claims, account) = match require_account(authorization, db) {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let current_password = parsed["current_password"].as_str().unwrap_or("");
+    let new_password = parsed["new_password"].as_str().unwrap_or("");
+
+    if new_password.len() < 6 {
+        let response =
+            r#"{"success": false, "message": "Password must be at least 6 characters long"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    match db.change_password(
+        account.id,
+        current_password,
+        new_password,
+        claims.sid,
+        actor,
+    ) {
+        Ok(true) => {
+            let response = r#"{"success": true, "message": "Password changed"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(false) => {
+            let response = r#"{"success": false, "message": "Current password is incorrect"}"#;
+            ("HTTP/1.1 403 Forbidden", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Always answers the same way, so it can't be used to find out which usernames
+// exist or have an email address.
+pub fn handle_forgot_password(
+    request_body: &str,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let username = parsed["username"].as_str().unwrap_or("").trim();
+    if username.is_empty() {
+        let response = r#"{"success": false, "message": "Please provide a username"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    let link_base = env::var("APP_URL").unwrap_or_else(|_| "http://localhost:7878".to_string());
+    if let Err(e) = db.request_password_reset(username, link_base.trim_end_matches('/'), actor) {
+        eprintln!("Password reset request failed: {e}");
+    }
+
+    let response = r#"{"success": true, "message": "If that account has an email address, a reset code is on its way"}"#;
+    ("HTTP/1.1 200 OK", response.to_string())
+}
+
+pub fn handle_reset_password(
+    request_body: &str,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let token = parsed["token"].as_str().unwrap_or("").trim();
+    let new_password = parsed["new_password"].as_str().unwrap_or("");
+
+    if new_password.len() < 6 {
+        let response =
+            r#"{"success": false, "message": "Password must be at least 6 characters long"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    match db.reset_password(token, new_password, actor) {
+        Ok(true) => {
+            let response = r#"{"success": true, "message": "Password reset, please log in again"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(false) => {
+            let response = r#"{"success": false, "message": "Invalid or expired reset code"}"#;
+            ("HTTP/1.1 400 Bad Request", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_register_kiosk(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
+    }
+
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let name = match parsed.get("name").and_then(|v| v.as_str()) {
+        Some(name) if !name.trim().is_empty() => name.trim(),
+        _ => {
+            let response = r#"{"success": false, "message": "Missing or invalid name"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+    let branch_id = parsed.get("branch_id").and_then(|v| v.as_i64());
+
+    match db.register_kiosk(name, branch_id, actor) {
+        Ok(Some((kiosk_id, credential))) => (
+            "HTTP/1.1 201 Created",
+            json!({
+                "success": true,
+                "message": "Kiosk registered; store the credential now, it is not shown again",
+                "kiosk_id": kiosk_id,
+                "credential": credential,
+            })
+            .to_string(),
+        ),
+        Ok(None) => {
+            let response = r#"{"success": false, "message": "Branch not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_fetch_kiosks(authorization: Option<&str>, db: &Database) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
+    }
+
+    match db.fetch_kiosks() {
+        Ok(kiosks) => ("HTTP/1.1 200 OK", json!(kiosks).to_string()),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Could not fetch kiosks"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_deactivate_kiosk(
+    kiosk_id: i64,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
+    }
+
+    match db.deactivate_kiosk(kiosk_id, actor) {
+        Ok(true) => {
+            let response = r#"{"success": true, "message": "Kiosk deactivated"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(false) => {
+            let response =
+                r#"{"success": false, "message": "Kiosk not found or already inactive"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_kiosk_login(
+    request_body: &str,
+    credential: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let credential = match credential {
+        Some(credential) => credential,
+        None => {
+            let response = r#"{"success": false, "message": "Missing kiosk credential"}"#;
+            return ("HTTP/1.1 401 Unauthorized", response.to_string());
+        }
+    };
+
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let (card_number, pin) = match (
+        parsed.get("card_number").and_then(|v| v.as_str()),
+        parsed.get("pin").and_then(|v| v.as_str()),
+    ) {
+        (Some(card_number), Some(pin)) => (card_number, pin),
+        _ => {
+            let response = r#"{"success": false, "message": "Please provide card_number and pin"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    // A bad device credential, unknown card and wrong PIN all look the same
+    match db.start_kiosk_session(credential, card_number, pin, actor) {
+        Ok(KioskLogin::Started { token, expires_at }) => (
+            "HTTP/1.1 200 OK",
+            json!({ "success": true, "session": token, "expires_at": expires_at }).to_string(),
+        ),
+        Ok(KioskLogin::RetryAfter(seconds)) => too_many_login_attempts(seconds),
+        Ok(KioskLogin::Invalid) => {
+            let response = r#"{"success": false, "message": "Invalid kiosk, card number or PIN"}"#;
+            ("HTTP/1.1 401 Unauthorized", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+fn require_kiosk_session(
+    credential: Option<&str>,
+    session: Option<&str>,
+    db: &Database,
+) -> Result<KioskSession, (&'static str, String)> {
+    let (credential, session) = match (credential, session) {
+        (Some(credential), Some(session)) => (credential, session),
+        _ => {
+            return Err((
+                "HTTP/1.1 401 Unauthorized",
+                r#"{"success": false, "message": "Missing kiosk credential or session"}"#
+                    .to_string(),
+            ));
+        }
+    };
+
+    match db.kiosk_session(credential, session) {
+        Ok(Some(session)) => Ok(session),
+        Ok(None) => Err((
+            "HTTP/1.1 401 Unauthorized",
+            r#"{"success": false, "message": "Kiosk session expired or invalid"}"#.to_string(),
+        )),
+        Err(_) => Err((
+            "HTTP/1.1 500 Internal Server Error",
+            r#"{"success": false, "message": "Database error occurred"}"#.to_string(),
+        )),
+    }
+}
+
+pub fn handle_kiosk_account(
+    credential: Option<&str>,
+    session: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    let session = match require_kiosk_session(credential, session, db) {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+
+    match (
+        db.fetch_patron(session.user_id),
+        db.fetch_borrowed_books(session.user_id),
+        db.fetch_ledger(session.user_id),
+    ) {
+        (Ok(Some(patron)), Ok(loans), Ok((_, balance_cents))) => (
+            "HTTP/1.1 200 OK",
+            json!({
+                "patron": patron,
+                "loans": loans,
+                "balance_cents": balance_cents,
+            })
+            .to_string(),
+        ),
+        (Ok(None), _, _) => {
+            let response = r#"{"success": false, "message": "Patron not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        _ => {
+            let response = r#"{"success": false, "message": "Could not fetch account"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_kiosk_checkout(
+    request_body: &str,
+    credential: Option<&str>,
+    session: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let session = match require_kiosk_session(credential, session, db) {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let barcode = match parsed.get("barcode").and_then(|v| v.as_str()) {
+        Some(barcode) => barcode,
+        None => {
+            let response = r#"{"success": false, "message": "Missing or invalid barcode"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    if let Err(response) = require_active_patron(session.user_id, db) {
+        return response;
+    }
+
+    // The kiosk_activity row is written with the loan, so a failed write fails the checkout
+    match db.checkout_by_barcode(
+        session.user_id,
+        barcode,
+        &actor.kiosk(session.kiosk_id),
+        Some(&session),
+    ) {
+        Ok(Some(checkout)) => (
+            "HTTP/1.1 201 Created",
+            json!({ "success": true, "message": "Checked out", "loan": checkout }).to_string(),
+        ),
+        Ok(None) => {
+            let response = r#"{"success": false, "message": "Copy not found or not available"}"#;
+            ("HTTP/1.1 409 Conflict", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Kiosks only take back copies the signed-in patron has on loan.
+pub fn handle_kiosk_checkin(
+    request_body: &str,
+    credential: Option<&str>,
+    session: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let session = match require_kiosk_session(credential, session, db) {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    let barcode = match parsed.get("barcode").and_then(|v| v.as_str()) {
+        Some(barcode) => barcode,
+        None => {
+            let response = r#"{"success": false, "message": "Missing or invalid barcode"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    match db.loan_owner_by_barcode(barcode) {
+        Ok(Some(user_id)) if user_id == session.user_id => {}
+        Ok(_) => {
+            let response = r#"{"success": false, "message": "This copy is not on loan to you"}"#;
+            return ("HTTP/1.1 404 Not Found", response.to_string());
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            return ("HTTP/1.1 500 Internal Server Error", response.to_string());
+        }
+    }
+
+    match db.checkin_by_barcode(barcode, &actor.kiosk(session.kiosk_id), Some(&session)) {
+        Ok(Some(checkin)) => (
+            "HTTP/1.1 200 OK",
+            json!({ "success": true, "message": "Checked in", "checkin": checkin }).to_string(),
+        ),
+        Ok(None) => {
+            let response = r#"{"success": false, "message": "No open loan for this barcode"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_kiosk_logout(
+    credential: Option<&str>,
+    session: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    let session = match require_kiosk_session(credential, session, db) {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+
+    match db.end_kiosk_session(session.session_id) {
+        Ok(_) => {
+            let response = r#"{"success": true, "message": "Signed out"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_fetch_notices(
+    status: Option<&str>,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "reports.view") {
+        return response;
+    }
+
+    match db.fetch_notices(status) {
+        Ok(notices) => ("HTTP/1.1 200 OK", json!(notices).to_string()),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Could not fetch notices"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_fetch_job_runs(authorization: Option<&str>, db: &Database) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "reports.view") {
+        return response;
+    }
+
+    match db.fetch_job_runs() {
+        Ok(runs) => ("HTTP/1.1 200 OK", json!(runs).to_string()),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Could not fetch job runs"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Runs the scheduler now instead of waiting for the next tick. Jobs that already
+// ran for their current period are reported with "ran": false.
+pub fn handle_run_scheduler(authorization: Option<&str>, db: &Database) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
+    }
+
+    let jobs: Vec<Value> = scheduler::run_pending(db)
+        .into_iter()
+        .map(|(job, run_key, processed)| {
+            json!({
+                "job": job,
+                "run_key": run_key,
+                "ran": processed.is_some(),
+                "processed": processed.unwrap_or(0),
+            })
+        })
+        .collect();
+
+    (
+        "HTTP/1.1 200 OK",
+        json!({ "success": true, "jobs": jobs }).to_string(),
+    )
+}
+
+// `target` is a record type ("book") or a type and id ("book:5"); `from` and `to`
+// are inclusive dates.
+pub fn handle_fetch_audit_log(
+    actor: Option<&str>,
+    action: Option<&str>,
+    target: Option<&str>,
+    from: Option<&str>,
+    to: Option<&str>,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "audit.view") {
+        return response;
+    }
+
+    let (target_type, target_id) = match target.map(|target| target.split_once(':')) {
+        None => (None, None),
+        Some(None) => (target, None),
+        Some(Some((target_type, id))) => match id.parse::<i64>() {
+            Ok(id) => (Some(target_type), Some(id)),
+            Err(_) => {
+                let response = r#"{"success": false, "message": "Invalid target"}"#;
+                return ("HTTP/1.1 400 Bad Request", response.to_string());
+            }
+        },
+    };
+
+    let valid_dates = [from, to]
+        .into_iter()
+        .flatten()
+        .all(|date| NaiveDate::parse_from_str(date, DATE_FORMAT).is_ok());
+    if !valid_dates {
+        let response = r#"{"success": false, "message": "Dates must be YYYY-MM-DD"}"#;
+        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    }
+
+    let filter = AuditFilter {
+        actor,
+        action,
+        target_type,
+        target_id,
+        from,
+        to,
+    };
+    match db.fetch_audit_log(&filter) {
+        Ok(entries) => ("HTTP/1.1 200 OK", json!(entries).to_string()),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Could not fetch audit log"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Where each domain event subscriber has got to and how far it is behind.
+pub fn handle_fetch_event_subscribers(
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
+    }
+
+    match db.fetch_event_subscribers() {
+        Ok(subscribers) => ("HTTP/1.1 200 OK", json!(subscribers).to_string()),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Could not fetch subscribers"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_set_email(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    let claims = match require_login(authorization) {
+        Ok(claims) => claims,
+        Err(response) => return response,
+    };
+
+    let parsed: Value = match serde_json::from_str(request_body) {
+        Ok(data) => data,
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Invalid JSON"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    // null removes the address and with it every email
+    let email = match parsed.get("email") {
+        Some(Value::Null) => None,
+        Some(Value::String(email)) if is_valid_email(email) => Some(email.trim()),
+        _ => {
+            let response = r#"{"success": false, "message": "Missing or invalid email"}"#;
+            return ("HTTP/1.1 400 Bad Request", response.to_string());
+        }
+    };
+
+    match db.set_email(&claims.sub, email, actor) {
+        Ok(true) => {
+            let response = r#"{"success": true, "message": "Email updated"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(false) => {
+            let response = r#"{"success": false, "message": "User not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+// Just enough checking to keep header-breaking input out of the outbox.
+fn is_valid_email(email: &str) -> bool {
+    let email = email.trim();
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.contains('@')
+                && !email.contains(|c: char| c.is_whitespace() || c == '<' || c == '>')
+        }
+        None => false,
+    }
+}
+
+pub fn handle_fetch_email_preferences(
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    let claims = match require_login(authorization) {
+        Ok(claims) => claims,
+        Err(response) => return response,
     };
 
-    let username = login_data["username"].as_str().unwrap_or("");
-    let password = login_data["password"].as_str().unwrap_or("");
-
-    // Validate input
-    if username.trim().is_empty() || password.trim().is_empty() {
-        let response = r#"{"success": false, "message": "Please provide username and password"}"#;
-        return ("HTTP/1.1 400 Bad Request", response.to_string());
-    }
-
-    // Verify user credentials
-    match db.verify_user(username, password) {
-        Ok(Some((user_id, username, role, jwt))) => {
-            let response = format!(
-                r#"{{"success": true, "message": "Login successful", "userId": "{}", "username": "{}", "role": "{}", "jwt": "{}"}}"#,
-                user_id, username, role, jwt
-            );
-            ("HTTP/1.1 200 OK", response)
-        }
+    match db.fetch_email_preferences(&claims.sub) {
+        Ok(Some(preferences)) => ("HTTP/1.1 200 OK", json!(preferences).to_string()),
         Ok(None) => {
-            let response = r#"{"success": false, "message": "Invalid username or password"}"#;
-            ("HTTP/1.1 401 Unauthorized", response.to_string())
+            let response = r#"{"success": false, "message": "User not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
         }
-        Err(e) => {
-            eprintln!("Database error: {}", e);
-            let response = r#"{"success": false, "message": "Internal server error"}"#;
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
             ("HTTP/1.1 500 Internal Server Error", response.to_string())
         }
     }
 }
 
-pub fn handle_add_book(request_body: &str, db: &Database) -> (&'static str, String) {
-    let book_data: serde_json::Value = match serde_json::from_str(request_body) {
+// Body is a map of category to true/false, e.g. {"due_soon": false}.
+pub fn handle_set_email_preferences(
+    request_body: &str,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    let claims = match require_login(authorization) {
+        Ok(claims) => claims,
+        Err(response) => return response,
+    };
+
+    let parsed: serde_json::Map<String, Value> = match serde_json::from_str(request_body) {
         Ok(data) => data,
         Err(_) => {
             let response = r#"{"success": false, "message": "Invalid JSON"}"#;
@@ -105,61 +3690,78 @@ pub fn handle_add_book(request_body: &str, db: &Database) -> (&'static str, Stri
         }
     };
 
-    let title = book_data["title"].as_str().unwrap_or("");
-    let author = book_data["author"].as_str().unwrap_or("");
-    let isbn = book_data["isbn"].as_str().unwrap_or("");
-    let publication_year_str = book_data["publication_year"].as_str().unwrap_or("");
-    let genre = book_data["genre"].as_str().unwrap_or("");
-    let number_of_copies = book_data["number_of_copies"].as_i64().unwrap_or(-1);
+    let mut changes = Vec::new();
+    for (category, enabled) in &parsed {
+        match enabled.as_bool() {
+            Some(enabled) if OPTIONAL_EMAIL_CATEGORIES.contains(&category.as_str()) => {
+                changes.push((category.as_str(), enabled))
+            }
+            _ => {
+                let response = json!({
+                    "success": false,
+                    "message": format!(
+                        "Categories must be one of {} and set to true or false",
+                        OPTIONAL_EMAIL_CATEGORIES.join(", ")
+                    ),
+                });
+                return ("HTTP/1.1 400 Bad Request", response.to_string());
+            }
+        }
+    }
 
-    if title.trim().is_empty()
-        || author.trim().is_empty()
-        || isbn.trim().is_empty()
-        || publication_year_str.trim().is_empty()
-        || genre.trim().is_empty()
-        || number_of_copies <= 0
-    {
-        let response = r#"{"success": false, "message": "Please provide all the fields"}"#;
-        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    for (category, enabled) in changes {
+        match db.set_email_preference(&claims.sub, category, enabled) {
+            Ok(true) => {}
+            Ok(false) => {
+                let response = r#"{"success": false, "message": "User not found"}"#;
+                return ("HTTP/1.1 404 Not Found", response.to_string());
+            }
+            Err(_) => {
+                let response = r#"{"success": false, "message": "Database error occurred"}"#;
+                return ("HTTP/1.1 500 Internal Server Error", response.to_string());
+            }
+        }
     }
 
-    // Validate ISBN is exactly 13 digits
-    if isbn.len() != 13 || !isbn.chars().all(|c| c.is_ascii_digit()) {
-        let response = r#"{"success": false, "message": "ISBN must be exactly 13 digits"}"#;
-        return ("HTTP/1.1 400 Bad Request", response.to_string());
+    let response = r#"{"success": true, "message": "Preferences updated"}"#;
+    ("HTTP/1.1 200 OK", response.to_string())
+}
+
+pub fn handle_fetch_outbox(
+    status: Option<&str>,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
     }
 
-    // Validate publication year
-    let publication_year: u16 = match publication_year_str.parse() {
-        Ok(year) if year >= 1500 && year <= 2024 => year,
-        _ => {
-            let response = r#"{"success": false, "message": "Publication year must be a valid year between 1500 and 2024"}"#;
-            return ("HTTP/1.1 400 Bad Request", response.to_string());
+    match db.fetch_outbox(status) {
+        Ok(emails) => ("HTTP/1.1 200 OK", json!(emails).to_string()),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Could not fetch outbox"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
         }
-    };
+    }
+}
 
-    // Validate number of copies
-    if number_of_copies <= 0 || number_of_copies > i32::MAX as i64 {
-        let response =
-            r#"{"success": false, "message": "Number of copies must be a positive integer"}"#;
-        return ("HTTP/1.1 400 Bad Request", response.to_string());
+pub fn handle_retry_email(
+    email_id: i64,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
     }
 
-    match db.add_book(
-        title,
-        author,
-        isbn,
-        &publication_year.to_string(),
-        genre,
-        number_of_copies as i32,
-    ) {
+    match db.retry_email(email_id) {
         Ok(true) => {
-            let response = r#"{"success": true, "message": "Book added successfully"}"#;
-            ("HTTP/1.1 201 Created", response.to_string())
+            let response = r#"{"success": true, "message": "Email queued for another attempt"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
         }
         Ok(false) => {
-            let response = r#"{"success": false, "message": "Book with this ISBN already exists"}"#;
-            ("HTTP/1.1 409 Conflict", response.to_string())
+            let response = r#"{"success": false, "message": "No failed email with that ID that can be sent again"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
         }
         Err(_) => {
             let response = r#"{"success": false, "message": "Database error occurred"}"#;
@@ -168,118 +3770,175 @@ pub fn handle_add_book(request_body: &str, db: &Database) -> (&'static str, Stri
     }
 }
 
-pub fn handle_fetch_books(db: &Database) -> (&'static str, String) {
-    match db.fetch_books() {
-        Ok(books) => {
-            let json = serde_json::to_string(&books).unwrap_or("[]".to_string());
-            ("HTTP/1.1 200 OK", json)
-        }
+pub fn handle_fetch_notifications(
+    unread_only: bool,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    let claims = match require_login(authorization) {
+        Ok(claims) => claims,
+        Err(response) => return response,
+    };
+
+    match db.fetch_notifications(&claims.sub, unread_only) {
+        Ok((notifications, unread)) => (
+            "HTTP/1.1 200 OK",
+            json!({ "unread": unread, "notifications": notifications }).to_string(),
+        ),
         Err(_) => {
-            let error = r#"{"success": false, "message": "Could not fetch books"}"#;
-            ("HTTP/1.1 500 Internal Server Error", error.to_string())
+            let response = r#"{"success": false, "message": "Could not fetch notifications"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
         }
     }
 }
 
-pub fn handle_fetch_book(book_id: i64, db: &Database) -> (&'static str, String) {
-    match db.fetch_book(book_id) {
-        Ok(Some(book)) => match serde_json::to_string(&book) {
-            Ok(json) => ("HTTP/1.1 200 OK", json),
-            Err(_) => (
-                "HTTP/1.1 500 Internal Server Error",
-                r#"{"success": false, "message": "Failed to serialize book"}"#.to_string(),
-            ),
-        },
-        Ok(None) => (
-            "HTTP/1.1 404 Not Found",
-            r#"{"success": false, "message": "Book not found"}"#.to_string(),
-        ),
-        Err(_) => (
-            "HTTP/1.1 500 Internal Server Error",
-            r#"{"success": false, "message": "Could not fetch book"}"#.to_string(),
-        ),
+pub fn handle_unread_notifications(
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    let claims = match require_login(authorization) {
+        Ok(claims) => claims,
+        Err(response) => return response,
+    };
+
+    match db.unread_notifications(&claims.sub) {
+        Ok(unread) => ("HTTP/1.1 200 OK", json!({ "unread": unread }).to_string()),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Could not count notifications"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
     }
 }
 
-pub fn handle_edit_book(id: i64, request_body: &str, db: &Database) -> (&'static str, String) {
-    let updated_fields: Value = match serde_json::from_str::<Value>(request_body) {
-        Ok(val) if val.is_object() => val,
-        _ => {
-            let error = r#"{ "success": false, "message": "Invalid JSON body" }"#;
-            return ("HTTP/1.1 400 Bad Request", error.to_string());
-        }
+// With no id every unread notification is marked read.
+pub fn handle_mark_notifications_read(
+    notification_id: Option<i64>,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    let claims = match require_login(authorization) {
+        Ok(claims) => claims,
+        Err(response) => return response,
     };
 
-    // Ensure at least one editable field is present
-    let allowed_keys = [
-        "title",
-        "author",
-        "isbn",
-        "publication_year",
-        "genre",
-        "number_of_copies",
-        "available",
-    ];
-    let has_valid_keys = updated_fields
-        .as_object()
-        .unwrap()
-        .keys()
-        .any(|k| allowed_keys.contains(&k.as_str()));
-
-    if !has_valid_keys {
-        let error = r#"{ "success": false, "message": "No valid fields provided to update" }"#;
-        return ("HTTP/1.1 400 Bad Request", error.to_string());
+    match db.mark_notifications_read(&claims.sub, notification_id) {
+        Ok(0) if notification_id.is_some() => {
+            let response =
+                r#"{"success": false, "message": "Notification not found or already read"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
+        }
+        Ok(marked) => (
+            "HTTP/1.1 200 OK",
+            json!({ "success": true, "message": "Marked as read", "marked": marked }).to_string(),
+        ),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
     }
+}
 
-    // Call DB update function
-    match db.edit_book(id, &updated_fields) {
+pub fn handle_delete_notification(
+    notification_id: i64,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    let claims = match require_login(authorization) {
+        Ok(claims) => claims,
+        Err(response) => return response,
+    };
+
+    match db.delete_notification(&claims.sub, notification_id) {
         Ok(true) => {
-            let response = r#"{ "success": true, "message": "Book updated successfully" }"#;
+            let response = r#"{"success": true, "message": "Notification deleted"}"#;
             ("HTTP/1.1 200 OK", response.to_string())
         }
         Ok(false) => {
-            let response = r#"{ "success": false, "message": "Book not found or not updated" }"#;
+            let response = r#"{"success": false, "message": "Notification not found"}"#;
             ("HTTP/1.1 404 Not Found", response.to_string())
         }
         Err(_) => {
-            let response = r#"{ "success": false, "message": "Database error during update" }"#;
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
             ("HTTP/1.1 500 Internal Server Error", response.to_string())
         }
     }
 }
 
-pub fn handle_delete_book(id: i64, db: &Database) -> (&'static str, String) {
-    match db.delete_book(id) {
-        Ok(true) => (
-            "HTTP/1.1 200 OK",
-            r#"{ "success": true, "message": "Book deleted successfully" }"#.to_string(),
-        ),
-        Ok(false) => (
-            "HTTP/1.1 404 Not Found",
-            r#"{ "success": false, "message": "Book not found" }"#.to_string(),
-        ),
-        Err(_) => (
-            "HTTP/1.1 500 Internal Server Error",
-            r#"{ "success": false, "message": "Database error" }"#.to_string(),
-        ),
+// Anonymous streams get catalog events only; with a valid token the user's own
+// events are included too. Returns the user and session ids for signed-in streams.
+pub fn event_stream_user(
+    authorization: Option<&str>,
+    db: &Database,
+) -> Result<Option<(i64, i64)>, (&'static str, String)> {
+    if authorization.is_none() {
+        return Ok(None);
     }
+
+    let (claims, patron) = require_account(authorization, db)?;
+    Ok(Some((patron.id, claims.sid)))
 }
 
-pub fn handle_fetch_users(db: &Database) -> (&'static str, String) {
-    match db.fetch_users() {
-        Ok(users) => {
-            let json = serde_json::to_string(&users).unwrap_or("[]".to_string());
-            ("HTTP/1.1 200 OK", json)
+// Validates a WebSocket upgrade for the live desk feed and returns the staff
+// claims plus the 101 response to send.
+pub fn desk_socket_upgrade(
+    headers: &HashMap<String, String>,
+    authorization: Option<&str>,
+) -> Result<(Claims, String), (&'static str, String)> {
+    let header_has = |name: &str, token: &str| {
+        headers.get(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|part| part.trim().eq_ignore_ascii_case(token))
+        })
+    };
+
+    if !header_has("upgrade", "websocket") || !header_has("connection", "upgrade") {
+        return Err((
+            "HTTP/1.1 400 Bad Request",
+            r#"{"success": false, "message": "Expected a WebSocket upgrade"}"#.to_string(),
+        ));
+    }
+    if headers.get("sec-websocket-version").map(String::as_str) != Some("13") {
+        return Err((
+            "HTTP/1.1 426 Upgrade Required",
+            r#"{"success": false, "message": "Only WebSocket version 13 is supported"}"#
+                .to_string(),
+        ));
+    }
+
+    let key = match headers.get("sec-websocket-key") {
+        Some(key)
+            if STANDARD
+                .decode(key.trim())
+                .is_ok_and(|bytes| bytes.len() == 16) =>
+        {
+            key
         }
-        Err(_) => {
-            let error = r#"{"success": false, "message": "Could not fetch books"}"#;
-            ("HTTP/1.1 500 Internal Server Error", error.to_string())
+        _ => {
+            return Err((
+                "HTTP/1.1 400 Bad Request",
+                r#"{"success": false, "message": "Missing or invalid Sec-WebSocket-Key"}"#
+                    .to_string(),
+            ));
         }
-    }
+    };
+
+    let claims = require_permission(authorization, "circulation.checkout")?;
+
+    Ok((claims, websocket::handshake_response(key)))
 }
 
-pub fn handle_borrow_book(request_body: &str, db: &Database) -> (&'static str, String) {
-    let parsed: serde_json::Value = match serde_json::from_str(request_body) {
+pub fn handle_add_webhook(
+    request_body: &str,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
+    }
+
+    let parsed: Value = match serde_json::from_str(request_body) {
         Ok(data) => data,
         Err(_) => {
             let response = r#"{"success": false, "message": "Invalid JSON"}"#;
@@ -287,29 +3946,86 @@ pub fn handle_borrow_book(request_body: &str, db: &Database) -> (&'static str, S
         }
     };
 
-    let user_id = match parsed.get("user_id").and_then(|v| v.as_i64()) {
-        Some(id) => id,
-        None => {
-            let response = r#"{"success": false, "message": "Missing or invalid user_id"}"#;
+    let url = match parsed.get("url").and_then(|v| v.as_str()) {
+        Some(url) if webhooks::parse_url(url).is_some() => url,
+        _ => {
+            let response = r#"{"success": false, "message": "url must be an http:// URL"}"#;
             return ("HTTP/1.1 400 Bad Request", response.to_string());
         }
     };
 
-    let book_id = match parsed.get("book_id").and_then(|v| v.as_i64()) {
-        Some(id) => id,
-        None => {
-            let response = r#"{"success": false, "message": "Missing or invalid book_id"}"#;
+    // Without an event list the webhook receives every event type
+    let events: Vec<&str> = match parsed.get("events") {
+        None => WEBHOOK_EVENTS.to_vec(),
+        Some(Value::Array(events)) => {
+            let events: Vec<&str> = events.iter().filter_map(|v| v.as_str()).collect();
+            if events.is_empty() || events.iter().any(|event| !WEBHOOK_EVENTS.contains(event)) {
+                let response = json!({
+                    "success": false,
+                    "message": format!("events must be a list drawn from {}", WEBHOOK_EVENTS.join(", ")),
+                });
+                return ("HTTP/1.1 400 Bad Request", response.to_string());
+            }
+            events
+        }
+        Some(_) => {
+            let response = r#"{"success": false, "message": "events must be a list"}"#;
             return ("HTTP/1.1 400 Bad Request", response.to_string());
         }
     };
-    match db.borrow_book(user_id, book_id) {
+
+    match db.add_webhook(url, &events, actor) {
+        Ok((webhook_id, secret)) => (
+            "HTTP/1.1 201 Created",
+            json!({
+                "success": true,
+                "message": "Webhook added; store the secret now, it is not shown again",
+                "webhook_id": webhook_id,
+                "secret": secret,
+                "events": events,
+            })
+            .to_string(),
+        ),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_fetch_webhooks(authorization: Option<&str>, db: &Database) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
+    }
+
+    match db.fetch_webhooks() {
+        Ok(webhooks) => ("HTTP/1.1 200 OK", json!(webhooks).to_string()),
+        Err(_) => {
+            let response = r#"{"success": false, "message": "Could not fetch webhooks"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
+        }
+    }
+}
+
+pub fn handle_deactivate_webhook(
+    webhook_id: i64,
+    authorization: Option<&str>,
+    actor: &Actor,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
+    }
+
+    match db.deactivate_webhook(webhook_id, actor) {
         Ok(true) => {
-            let response = r#"{"success": true, "message": "Book borrowed successfully"}"#;
-            ("HTTP/1.1 201 Created", response.to_string())
+            let response = r#"{"success": true, "message": "Webhook deactivated"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
         }
         Ok(false) => {
-            let response = r#"{"success": false, "message": "Book or User doesn't exist"}"#;
-            ("HTTP/1.1 409 Conflict", response.to_string())
+            let response =
+                r#"{"success": false, "message": "Webhook not found or already inactive"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
         }
         Err(_) => {
             let response = r#"{"success": false, "message": "Database error occurred"}"#;
@@ -318,45 +4034,49 @@ pub fn handle_borrow_book(request_body: &str, db: &Database) -> (&'static str, S
     }
 }
 
-pub fn handle_fetch_borrowed_books(user_id: i64, db: &Database) -> (&'static str, String) {
-    match db.fetch_borrowed_books(user_id) {
-        Ok(books) => {
-            let json = serde_json::to_string(&books).unwrap_or("[]".to_string());
-            return ("HTTP/1.1 200 OK", json);
+pub fn handle_fetch_webhook_deliveries(
+    webhook_id: i64,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
+    }
+
+    match db.fetch_webhook_deliveries(webhook_id) {
+        Ok(Some(deliveries)) => ("HTTP/1.1 200 OK", json!(deliveries).to_string()),
+        Ok(None) => {
+            let response = r#"{"success": false, "message": "Webhook not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
         }
         Err(_) => {
-            let error = r#"{"success": false, "message": "Could not fetch borrowed books"}"#;
-            ("HTTP/1.1 500 Internal Server Error", error.to_string())
+            let response = r#"{"success": false, "message": "Could not fetch deliveries"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
         }
     }
 }
 
-pub fn handle_return_book(borrowed_id: i64, book_id: i64, db: &Database) -> (&'static str, String) {
-    match db.return_book(borrowed_id, book_id) {
-        Ok(true) => (
-            "HTTP/1.1 200 OK",
-            r#"{"success": true, "message":"Book returned successfully"}"#.to_string(),
-        ),
-        Ok(false) => (
-            "HTTP/1.1 404 NOT FOUND",
-            r#"{"success": false, "message": "Borrow details not found"}"#.to_string(),
-        ),
-        Err(_) => (
-            "HTTP/1.1 500 Internal Server Error",
-            r#"{"success": false, "message": "Database error"}"#.to_string(),
-        ),
+pub fn handle_redeliver_webhook(
+    delivery_id: i64,
+    authorization: Option<&str>,
+    db: &Database,
+) -> (&'static str, String) {
+    if let Err(response) = require_permission(authorization, "system.manage") {
+        return response;
     }
-}
 
-pub fn handle_fetch_all_borrowed_books(db: &Database) -> (&'static str, String) {
-    match db.fetch_all_borrowed_books() {
-        Ok(books) => {
-            let json = serde_json::to_string(&books).unwrap_or("[]".to_string());
-            return ("HTTP/1.1 200 OK", json);
+    match db.redeliver_webhook(delivery_id) {
+        Ok(true) => {
+            let response = r#"{"success": true, "message": "Delivery queued"}"#;
+            ("HTTP/1.1 200 OK", response.to_string())
+        }
+        Ok(false) => {
+            let response = r#"{"success": false, "message": "Delivery not found"}"#;
+            ("HTTP/1.1 404 Not Found", response.to_string())
         }
         Err(_) => {
-            let error = r#"{"success": false, "message": "Could not fetch borrowed books"}"#;
-            ("HTTP/1.1 500 Internal Server Error", error.to_string())
+            let response = r#"{"success": false, "message": "Database error occurred"}"#;
+            ("HTTP/1.1 500 Internal Server Error", response.to_string())
         }
     }
 }
@@ -0,0 +1,215 @@
+use crate::db::{Database, OutboxEmail};
+use std::{
+    env,
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+pub struct Template {
+    pub subject: &'static str,
+    pub body: &'static str,
+}
+
+// Message templates per email category; {{name}} placeholders are filled by `render`.
+pub fn template(category: &str) -> Option<Template> {
+    let template = match category {
+        "hold_ready" => Template {
+            subject: "Your hold is ready: {{title}}",
+            body: "Hello {{username}},\n\n\"{{title}}\" is waiting for you at {{branch}}.\n\nPlease pick it up at the desk or a self-checkout kiosk.\n",
+        },
+        "due_soon" => Template {
+            subject: "Due soon: {{title}}",
+            body: "Hello {{username}},\n\n\"{{title}}\" is due on {{due_date}}.\n\nYou can renew it from your dashboard if nobody is waiting for it.\n",
+        },
+        "overdue" => Template {
+            subject: "Overdue notice {{level}}: {{title}}",
+            body: "Hello {{username}},\n\n\"{{title}}\" was due on {{due_date}} and is now overdue.\nThis is notice {{level}} of 3. Fines accrue for every day the library is open.\n\nPlease return it as soon as possible.\n",
+        },
+        "account" => Template {
+            subject: "{{subject}}",
+            body: "Hello {{username}},\n\n{{message}}\n",
+        },
+        "password_reset" => Template {
+            subject: "Reset your library password",
+            body: "Hello {{username}},\n\nSomeone asked to reset the password for your library account. To choose a new one, open\n\n{{link}}\n\nor enter this code on the reset page:\n\n{{token}}\n\nThe code works once and expires in {{minutes}} minutes. If you didn't ask for this you can ignore this email; your password hasn't changed.\n",
+        },
+        "verify_email" => Template {
+            subject: "Confirm your library email address",
+            body: "Hello {{username}},\n\nPlease confirm this is your email address by opening\n\n{{link}}\n\nYou can log in once it's confirmed. The link expires in {{hours}} hours. If you didn't sign up for a library account you can ignore this email.\n",
+        },
+        _ => return None,
+    };
+    Some(template)
+}
+
+pub fn render(text: &str, vars: &[(&str, &str)]) -> String {
+    let mut rendered = text.to_string();
+    for (name, value) in vars {
+        rendered = rendered.replace(&format!("{{{{{name}}}}}"), value);
+    }
+    rendered
+}
+
+// Where outbox emails go. MAIL_TRANSPORT=file appends them to MAIL_FILE instead
+// of sending them, for development and tests.
+pub enum Transport {
+    Smtp(SmtpTransport),
+    File(FileTransport),
+}
+
+impl Transport {
+    pub fn from_env() -> Transport {
+        match env::var("MAIL_TRANSPORT").as_deref() {
+            Ok("file") => Transport::File(FileTransport {
+                path: env::var("MAIL_FILE").unwrap_or_else(|_| "mail.log".to_string()),
+            }),
+            _ => Transport::Smtp(SmtpTransport::from_env()),
+        }
+    }
+
+    pub fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        match self {
+            Transport::Smtp(transport) => transport.send(to, subject, body),
+            Transport::File(transport) => transport.send(to, subject, body),
+        }
+    }
+}
+
+pub struct FileTransport {
+    pub path: String,
+}
+
+impl FileTransport {
+    pub fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("open {}: {e}", self.path))?;
+        writeln!(file, "To: {to}\nSubject: {subject}\n\n{body}\n---").map_err(|e| e.to_string())
+    }
+}
+
+// Minimal plain SMTP client, enough for a local relay or a test sink.
+pub struct SmtpTransport {
+    pub host: String,
+    pub port: u16,
+    pub from: String,
+}
+
+impl SmtpTransport {
+    pub fn from_env() -> SmtpTransport {
+        SmtpTransport {
+            host: env::var("SMTP_HOST").unwrap_or_else(|_| "127.0.0.1".to_string()),
+            port: env::var("SMTP_PORT")
+                .ok()
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(25),
+            from: env::var("SMTP_FROM").unwrap_or_else(|_| "library@localhost".to_string()),
+        }
+    }
+
+    pub fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .map_err(|e| format!("connect: {e}"))?;
+        stream
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .map_err(|e| e.to_string())?;
+        let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+        let mut writer = stream;
+
+        expect_reply(&mut reader, 2)?;
+        command(&mut writer, &mut reader, "HELO localhost", 2)?;
+        command(
+            &mut writer,
+            &mut reader,
+            &format!("MAIL FROM:<{}>", self.from),
+            2,
+        )?;
+        command(&mut writer, &mut reader, &format!("RCPT TO:<{to}>"), 2)?;
+        command(&mut writer, &mut reader, "DATA", 3)?;
+
+        let mut message = format!(
+            "From: {}\r\nTo: {to}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+            self.from,
+            subject.replace(['\r', '\n'], " ")
+        );
+        for line in body.lines() {
+            // Dot-stuffing, so a line holding a single "." can't end the message early
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message.push_str(".\r\n");
+        writer
+            .write_all(message.as_bytes())
+            .map_err(|e| e.to_string())?;
+        expect_reply(&mut reader, 2)?;
+
+        let _ = command(&mut writer, &mut reader, "QUIT", 2);
+        Ok(())
+    }
+}
+
+fn command(
+    writer: &mut TcpStream,
+    reader: &mut BufReader<TcpStream>,
+    line: &str,
+    class: u8,
+) -> Result<(), String> {
+    writer
+        .write_all(format!("{line}\r\n").as_bytes())
+        .map_err(|e| e.to_string())?;
+    expect_reply(reader, class)
+}
+
+// Reads a (possibly multi-line) reply and checks its first digit, e.g. 2 for 250.
+fn expect_reply(reader: &mut BufReader<TcpStream>, class: u8) -> Result<(), String> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+            return Err("connection closed".to_string());
+        }
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        return match line.as_bytes().first() {
+            Some(digit) if *digit == b'0' + class => Ok(()),
+            _ => Err(line.trim_end().to_string()),
+        };
+    }
+}
+
+// Sends whatever is due in the outbox. Runs on the scheduler thread, outside the
+// database lock, so a slow mail server never blocks requests.
+pub fn deliver_pending(db: &Database, transport: &Transport) -> usize {
+    let emails: Vec<OutboxEmail> = match db.fetch_due_emails(50) {
+        Ok(emails) => emails,
+        Err(e) => {
+            eprintln!("Could not read outbox: {e}");
+            return 0;
+        }
+    };
+
+    let mut sent = 0;
+    for email in emails {
+        let result = match transport.send(&email.to_address, &email.subject, &email.body) {
+            Ok(()) => {
+                sent += 1;
+                db.mark_email_sent(email.id)
+            }
+            Err(error) => {
+                eprintln!("Email {} to {} failed: {error}", email.id, email.to_address);
+                db.mark_email_failed(email.id, &error)
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("Could not update outbox entry {}: {e}", email.id);
+        }
+    }
+    sent
+}
@@ -1,6 +1,12 @@
 use dotenv::dotenv;
-use project::{ThreadPool, db::Database, handlers};
+use project::{
+    ThreadPool, audit::Actor, cli, db::Database, desk, events, handlers, scheduler, sse,
+};
+use std::collections::HashMap;
+use std::env;
 use std::fs;
+use std::process;
+use std::thread;
 use std::{
     io::{BufReader, prelude::*},
     net::{TcpListener, TcpStream},
@@ -9,9 +15,24 @@ use std::{
 fn main() {
     //load the env file
     dotenv().ok();
+
+    // Admin commands such as create-admin run instead of the server
+    let args: Vec<String> = env::args().skip(1).collect();
+    let db_path = match cli::run(&args) {
+        cli::Invocation::Serve(db_path) => db_path,
+        cli::Invocation::Exit(code) => process::exit(code),
+    };
+
     // Initialize database
-    let db = Database::new("project.db").expect("Failed to initialize database");
+    let db = Database::new(&db_path).expect("Failed to initialize database");
     println!("Database initialized successfully");
+    if let Ok(false) = db.admin_exists() {
+        println!("No admin account yet; create one with `cargo run -- create-admin <username>`");
+    }
+
+    // Background jobs such as due-date reminders
+    scheduler::start(db.clone());
+    events::start(db.clone());
 
     let listener: TcpListener = TcpListener::bind("127.0.0.1:7878").unwrap();
     let pool = ThreadPool::new(4);
@@ -34,50 +55,902 @@ fn handle_connection(mut stream: TcpStream, db: Database) {
     let method = parts.next().unwrap();
     let path = parts.next().unwrap();
 
-    let mut content_length = 0;
+    // Header names are case-insensitive, so they are stored lowercased
+    let mut headers = HashMap::new();
     for line in buf_reader.by_ref().lines() {
         let line = line.unwrap();
         if line.is_empty() {
             break;
         }
-        if line.to_lowercase().starts_with("content-length:") {
-            content_length = line.split(':').nth(1).unwrap().trim().parse().unwrap_or(0);
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    let content_length = headers
+        .get("content-length")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    let authorization = headers.get("authorization").map(String::as_str);
+    let kiosk_credential = headers.get("x-kiosk-credential").map(String::as_str);
+    let kiosk_session = headers.get("x-kiosk-session").map(String::as_str);
+
+    let mut request_body = vec![0; content_length];
+    buf_reader.read_exact(&mut request_body).unwrap();
+    let request_body = String::from_utf8(request_body).unwrap();
+
+    // Long-lived connections (server-sent events and WebSockets) run on their own
+    // thread. EventSource and WebSocket clients can't set headers, so the JWT may
+    // also come as ?token=, and a resume point as ?last_event_id=.
+    let (route, query) = path.split_once('?').unwrap_or((path, ""));
+    if method == "GET" && (route == "/api/events" || route == "/api/ws/desk") {
+        let authorization = authorization
+            .map(str::to_string)
+            .or_else(|| query_param(query, "token").map(|token| format!("Bearer {token}")));
+        let last_event_id = headers
+            .get("last-event-id")
+            .map(String::as_str)
+            .or_else(|| query_param(query, "last_event_id"))
+            .and_then(|id| id.parse::<i64>().ok());
+
+        let rejected = match handlers::authenticate(authorization.as_deref(), &db) {
+            Err(response) => response,
+            Ok(_) if route == "/api/events" => {
+                match handlers::event_stream_user(authorization.as_deref(), &db) {
+                    Ok(viewer) => {
+                        let (user_id, session_id) = viewer.unzip();
+                        thread::spawn(move || {
+                            sse::stream(stream, db, user_id, session_id, last_event_id)
+                        });
+                        return;
+                    }
+                    Err(response) => response,
+                }
+            }
+            Ok(_) => match handlers::desk_socket_upgrade(&headers, authorization.as_deref()) {
+                Ok((claims, handshake)) => {
+                    if stream.write_all(handshake.as_bytes()).is_ok() {
+                        let authorization = authorization.unwrap_or_default();
+                        thread::spawn(move || {
+                            desk::serve(stream, db, claims, authorization, last_event_id)
+                        });
+                    }
+                    return;
+                }
+                Err(response) => response,
+            },
+        };
+
+        let (status_line, contents) = rejected;
+        let length = contents.len();
+        let response = format!(
+            "{status_line}\r\nContent-Type: application/json\r\nContent-Length: {length}\r\n\r\n{contents}"
+        );
+        stream.write_all(response.as_bytes()).unwrap();
+        return;
+    }
+
+    // Tokens whose session was logged out or revoked are treated as absent.
+    let authorization = handlers::authenticate(authorization, &db).unwrap_or(None);
+    // Mutations are audited against the caller and the address they connected from.
+    let source_ip = stream
+        .peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default();
+    let actor = Actor::from_request(authorization, &source_ip);
+
+    let (status_line, contents, content_type) = match (method, path) {
+        // API endpoints
+        //user apis
+        ("POST", "/api/signup") => {
+            let (status, body) = handlers::handle_signup(&request_body, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("GET", "/api/signup/settings") => {
+            let (status, body) = handlers::handle_fetch_registration_settings();
+            (status, body, "application/json")
+        }
+        ("POST", "/api/signup/verify") => {
+            let (status, body) = handlers::handle_verify_email(&request_body, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("POST", "/api/signup/resend") => {
+            let (status, body) = handlers::handle_resend_verification(&request_body, &db);
+            (status, body, "application/json")
+        }
+        //registrations waiting for staff approval
+        ("GET", "/api/registrations") => {
+            let (status, body) = handlers::handle_fetch_registrations(authorization, &db);
+            (status, body, "application/json")
+        }
+        ("POST", path)
+            if path.starts_with("/api/registrations/")
+                && (path.ends_with("/approve") || path.ends_with("/reject")) =>
+        {
+            let approve = path.ends_with("/approve");
+            let id_part = path
+                .trim_start_matches("/api/registrations/")
+                .trim_end_matches("/approve")
+                .trim_end_matches("/reject");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) = handlers::handle_review_registration(
+                        user_id,
+                        approve,
+                        &request_body,
+                        authorization,
+                        &actor,
+                        &db,
+                    );
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("POST", "/api/login") => {
+            let user_agent = headers.get("user-agent").map_or("", String::as_str);
+            let (status, body) = handlers::handle_login(&request_body, user_agent, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("POST", "/api/token/refresh") => {
+            let (status, body) = handlers::handle_refresh_token(&request_body, &db);
+            (status, body, "application/json")
+        }
+        ("POST", "/api/logout") => {
+            let (status, body) = handlers::handle_logout(authorization, &db);
+            (status, body, "application/json")
+        }
+        ("POST", "/api/logout/all") => {
+            let (status, body) = handlers::handle_logout_all(authorization, &db);
+            (status, body, "application/json")
+        }
+        //two-factor login
+        ("POST", "/api/login/totp") => {
+            let (status, body) = handlers::handle_login_totp(&request_body, &db);
+            (status, body, "application/json")
+        }
+        ("POST", "/api/login/totp/enroll") => {
+            let (status, body) = handlers::handle_login_totp_enroll(&request_body, &db);
+            (status, body, "application/json")
+        }
+        ("GET", "/api/account/totp") => {
+            let (status, body) = handlers::handle_fetch_totp(authorization, &db);
+            (status, body, "application/json")
+        }
+        ("POST", "/api/account/totp/enroll") => {
+            let (status, body) = handlers::handle_enroll_totp(authorization, &db);
+            (status, body, "application/json")
+        }
+        ("POST", "/api/account/totp/confirm") => {
+            let (status, body) =
+                handlers::handle_confirm_totp(&request_body, authorization, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("DELETE", "/api/account/totp") => {
+            let (status, body) =
+                handlers::handle_disable_totp(&request_body, authorization, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("POST", "/api/account/totp/recovery-codes") => {
+            let (status, body) = handlers::handle_regenerate_recovery_codes(
+                &request_body,
+                authorization,
+                &actor,
+                &db,
+            );
+            (status, body, "application/json")
+        }
+        ("DELETE", path) if path.starts_with("/api/users/") && path.ends_with("/totp") => {
+            let id_part = path
+                .trim_start_matches("/api/users/")
+                .trim_end_matches("/totp");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) =
+                        handlers::handle_reset_user_totp(user_id, authorization, &actor, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        //passwords
+        ("PUT", "/api/account/password") => {
+            let (status, body) =
+                handlers::handle_change_password(&request_body, authorization, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("POST", "/api/password/forgot") => {
+            let (status, body) = handlers::handle_forgot_password(&request_body, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("POST", "/api/password/reset") => {
+            let (status, body) = handlers::handle_reset_password(&request_body, &actor, &db);
+            (status, body, "application/json")
+        }
+        //login lockouts (staff)
+        ("GET", "/api/lockouts") => {
+            let (status, body) = handlers::handle_fetch_login_lockouts(authorization, &db);
+            (status, body, "application/json")
+        }
+        ("DELETE", path) if path.starts_with("/api/users/") && path.ends_with("/lockout") => {
+            let id_part = path
+                .trim_start_matches("/api/users/")
+                .trim_end_matches("/lockout");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) =
+                        handlers::handle_unlock_user(user_id, authorization, &actor, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        //sessions and devices
+        ("GET", "/api/account/sessions") => {
+            let (status, body) = handlers::handle_fetch_sessions(authorization, &db);
+            (status, body, "application/json")
+        }
+        ("DELETE", path) if path.starts_with("/api/account/sessions/") => {
+            let id_part = path.trim_start_matches("/api/account/sessions/");
+            match id_part.parse::<i64>() {
+                Ok(session_id) => {
+                    let (status, body) =
+                        handlers::handle_revoke_session(session_id, authorization, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("GET", path) if path.starts_with("/api/users/") && path.ends_with("/sessions") => {
+            let id_part = path
+                .trim_start_matches("/api/users/")
+                .trim_end_matches("/sessions");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) =
+                        handlers::handle_fetch_user_sessions(user_id, authorization, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("DELETE", path) if path.starts_with("/api/users/") && path.contains("/sessions") => {
+            let parts: Vec<&str> = path
+                .trim_start_matches("/api/users/")
+                .split("/sessions")
+                .collect();
+            let session_part = parts.get(1).map(|part| part.trim_start_matches('/'));
+            let session_id = match session_part {
+                Some("") | None => Ok(None),
+                Some(id) => id.parse::<i64>().map(Some),
+            };
+            match (parts[0].parse::<i64>(), session_id) {
+                (Ok(user_id), Ok(session_id)) => {
+                    let (status, body) = handlers::handle_revoke_user_sessions(
+                        user_id,
+                        session_id,
+                        authorization,
+                        &db,
+                    );
+                    (status, body, "application/json")
+                }
+                _ => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("GET", "/api/users") => {
+            let (status, body) = handlers::handle_fetch_users(authorization, &db);
+            (status, body, "application/json")
+        }
+        //roles and permissions
+        ("GET", "/api/roles") => {
+            let (status, body) = handlers::handle_fetch_roles(authorization);
+            (status, body, "application/json")
+        }
+        ("PUT", path) if path.starts_with("/api/users/") && path.ends_with("/role") => {
+            let id_part = path
+                .trim_start_matches("/api/users/")
+                .trim_end_matches("/role");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) = handlers::handle_set_user_role(
+                        user_id,
+                        &request_body,
+                        authorization,
+                        &actor,
+                        &db,
+                    );
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        //book apis CRUD operations
+        ("POST", "/api/books") => {
+            let (status, body) =
+                handlers::handle_add_book(&request_body, authorization, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("GET", "/api/books") => {
+            let (status, body) = handlers::handle_fetch_books(&db);
+            (status, body, "application/json")
+        }
+        //per-copy inventory apis
+        ("GET", _) if route == "/api/books/search" => {
+            let terms = url_decode(query_param(query, "q").unwrap_or_default());
+            let (status, body) = handlers::handle_search_books(&terms, &db);
+            (status, body, "application/json")
+        }
+        ("GET", path) if path.starts_with("/api/books/") && path.ends_with("/items") => {
+            let id_part = path
+                .trim_start_matches("/api/books/")
+                .trim_end_matches("/items");
+            match id_part.parse::<i64>() {
+                Ok(book_id) => {
+                    let (status, body) = handlers::handle_fetch_items(book_id, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("POST", path) if path.starts_with("/api/books/") && path.ends_with("/items") => {
+            let id_part = path
+                .trim_start_matches("/api/books/")
+                .trim_end_matches("/items");
+            match id_part.parse::<i64>() {
+                Ok(book_id) => {
+                    let (status, body) = handlers::handle_add_item(
+                        book_id,
+                        &request_body,
+                        authorization,
+                        &actor,
+                        &db,
+                    );
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("PATCH", path) if path.starts_with("/api/items/") => {
+            let id_part = path.trim_start_matches("/api/items/");
+            match id_part.parse::<i64>() {
+                Ok(item_id) => {
+                    let (status, body) = handlers::handle_update_item(
+                        item_id,
+                        &request_body,
+                        authorization,
+                        &actor,
+                        &db,
+                    );
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("GET", path) if path.starts_with("/api/books/") => {
+            let id_part = path.trim_start_matches("/api/books/");
+            match id_part.parse::<i64>() {
+                Ok(book_id) => {
+                    let (status, body) = handlers::handle_fetch_book(book_id, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("PATCH", path) if path.starts_with("/api/books/") => {
+            let id_part = path.trim_start_matches("/api/books/");
+            match id_part.parse::<i64>() {
+                Ok(book_id) => {
+                    let (status, body) = handlers::handle_edit_book(
+                        book_id,
+                        &request_body,
+                        authorization,
+                        &actor,
+                        &db,
+                    );
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("DELETE", path) if path.starts_with("/api/books/") => {
+            let id_part = path.trim_start_matches("/api/books/");
+            match id_part.parse::<i64>() {
+                Ok(book_id) => {
+                    let (status, body) =
+                        handlers::handle_delete_book(book_id, authorization, &actor, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        //borrow book apis
+        ("POST", "/api/borrow") => {
+            let (status, body) =
+                handlers::handle_borrow_book(&request_body, authorization, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("POST", path) if path.starts_with("/api/borrow/") && path.ends_with("/renew") => {
+            let id_part = path
+                .trim_start_matches("/api/borrow/")
+                .trim_end_matches("/renew");
+            match id_part.parse::<i64>() {
+                Ok(borrowed_id) => {
+                    let (status, body) =
+                        handlers::handle_renew_book(borrowed_id, authorization, &actor, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("POST", path) if path.starts_with("/api/borrow/") && path.ends_with("/lost") => {
+            let id_part = path
+                .trim_start_matches("/api/borrow/")
+                .trim_end_matches("/lost");
+            match id_part.parse::<i64>() {
+                Ok(borrowed_id) => {
+                    let (status, body) = handlers::handle_declare_lost(
+                        borrowed_id,
+                        &request_body,
+                        authorization,
+                        &actor,
+                        &db,
+                    );
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("POST", path) if path.starts_with("/api/borrow/") && path.ends_with("/damaged") => {
+            let id_part = path
+                .trim_start_matches("/api/borrow/")
+                .trim_end_matches("/damaged");
+            match id_part.parse::<i64>() {
+                Ok(borrowed_id) => {
+                    let (status, body) = handlers::handle_return_damaged(
+                        borrowed_id,
+                        &request_body,
+                        authorization,
+                        &actor,
+                        &db,
+                    );
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("POST", path) if path.starts_with("/api/items/") && path.ends_with("/found") => {
+            let id_part = path
+                .trim_start_matches("/api/items/")
+                .trim_end_matches("/found");
+            match id_part.parse::<i64>() {
+                Ok(item_id) => {
+                    let (status, body) =
+                        handlers::handle_mark_found(item_id, authorization, &actor, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("GET", path) if path.starts_with("/api/ledger/") => {
+            let id_part = path.trim_start_matches("/api/ledger/");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) = handlers::handle_fetch_ledger(user_id, authorization, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("GET", path) if path.starts_with("/api/borrow/") => {
+            let id_part = path.trim_start_matches("/api/borrow/");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) =
+                        handlers::handle_fetch_borrowed_books(user_id, authorization, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("GET", "/api/borrow") => {
+            let (status, body) = handlers::handle_fetch_all_borrowed_books(authorization, &db);
+            (status, body, "application/json")
+        }
+        ("DELETE", path) if path.starts_with("/api/borrow/") => {
+            let parts: Vec<&str> = path.trim_start_matches("/api/borrow/").split("/").collect();
+            if parts.len() == 2 {
+                if let (Ok(borrowed_id), Ok(book_id)) =
+                    (parts[0].parse::<i64>(), parts[1].parse::<i64>())
+                {
+                    let (status, body) = handlers::handle_return_book(
+                        borrowed_id,
+                        book_id,
+                        authorization,
+                        &actor,
+                        &db,
+                    );
+                    (status, body, "application/json")
+                } else {
+                    let error = r#"{"success":false, "message":"Invalid IDs"}"#;
+                    (
+                        "HTTP/1.1 400 Bad Request",
+                        error.to_string(),
+                        "application/json",
+                    )
+                }
+            } else {
+                let error = r#"{"success": false, "message": "Invalid path"}"#;
+                (
+                    "HTTP/1.1 400 Bad Request",
+                    error.to_string(),
+                    "application/json",
+                )
+            }
+        }
+        //branch, hold and transfer apis
+        ("GET", "/api/branches") => {
+            let (status, body) = handlers::handle_fetch_branches(&db);
+            (status, body, "application/json")
+        }
+        ("POST", "/api/branches") => {
+            let (status, body) =
+                handlers::handle_add_branch(&request_body, authorization, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("PATCH", path) if path.starts_with("/api/users/") && path.ends_with("/branch") => {
+            let id_part = path
+                .trim_start_matches("/api/users/")
+                .trim_end_matches("/branch");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) = handlers::handle_set_user_branch(
+                        user_id,
+                        &request_body,
+                        authorization,
+                        &actor,
+                        &db,
+                    );
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        //user administration; after the /api/users/{id}/... routes above
+        ("PUT", path) if path.starts_with("/api/users/") && path.ends_with("/profile") => {
+            let id_part = path
+                .trim_start_matches("/api/users/")
+                .trim_end_matches("/profile");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) = handlers::handle_update_profile(
+                        user_id,
+                        &request_body,
+                        authorization,
+                        &actor,
+                        &db,
+                    );
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("POST", path)
+            if path.starts_with("/api/users/") && path.ends_with("/membership/renew") =>
+        {
+            let id_part = path
+                .trim_start_matches("/api/users/")
+                .trim_end_matches("/membership/renew");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) = handlers::handle_renew_membership(
+                        user_id,
+                        &request_body,
+                        authorization,
+                        &actor,
+                        &db,
+                    );
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("POST", path) if path.starts_with("/api/users/") && path.ends_with("/card") => {
+            let id_part = path
+                .trim_start_matches("/api/users/")
+                .trim_end_matches("/card");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) =
+                        handlers::handle_reissue_card(user_id, authorization, &actor, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("POST", path)
+            if path.starts_with("/api/users/")
+                && (path.ends_with("/deactivate") || path.ends_with("/reactivate")) =>
+        {
+            let active = path.ends_with("/reactivate");
+            let id_part = path
+                .trim_start_matches("/api/users/")
+                .trim_end_matches("/deactivate")
+                .trim_end_matches("/reactivate");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) = handlers::handle_set_user_active(
+                        user_id,
+                        active,
+                        &request_body,
+                        authorization,
+                        &actor,
+                        &db,
+                    );
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("GET", path) if path.starts_with("/api/users/") => {
+            let id_part = path.trim_start_matches("/api/users/");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) = handlers::handle_fetch_user(user_id, authorization, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("PATCH", path) if path.starts_with("/api/users/") => {
+            let id_part = path.trim_start_matches("/api/users/");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) = handlers::handle_update_user(
+                        user_id,
+                        &request_body,
+                        authorization,
+                        &actor,
+                        &db,
+                    );
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("DELETE", path) if path.starts_with("/api/users/") => {
+            let id_part = path.trim_start_matches("/api/users/");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) =
+                        handlers::handle_delete_user(user_id, authorization, &actor, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("POST", "/api/holds") => {
+            let (status, body) =
+                handlers::handle_place_hold(&request_body, authorization, &actor, &db);
+            (status, body, "application/json")
         }
-    }
-
-    let mut request_body = vec![0; content_length];
-    buf_reader.read_exact(&mut request_body).unwrap();
-    let request_body = String::from_utf8(request_body).unwrap();
-
-    let (status_line, contents, content_type) = match (method, path) {
-        // API endpoints
-        //user apis
-        ("POST", "/api/signup") => {
-            let (status, body) = handlers::handle_signup(&request_body, &db);
+        ("GET", "/api/holds") => {
+            let (status, body) = handlers::handle_fetch_holds(None, authorization, &db);
             (status, body, "application/json")
         }
-        ("POST", "/api/login") => {
-            let (status, body) = handlers::handle_login(&request_body, &db);
+        ("GET", path) if path.starts_with("/api/holds/") => {
+            let id_part = path.trim_start_matches("/api/holds/");
+            match id_part.parse::<i64>() {
+                Ok(user_id) => {
+                    let (status, body) =
+                        handlers::handle_fetch_holds(Some(user_id), authorization, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("DELETE", path) if path.starts_with("/api/holds/") => {
+            let id_part = path.trim_start_matches("/api/holds/");
+            match id_part.parse::<i64>() {
+                Ok(hold_id) => {
+                    let (status, body) =
+                        handlers::handle_cancel_hold(hold_id, authorization, &actor, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("POST", "/api/transfers") => {
+            let (status, body) =
+                handlers::handle_start_transfer(&request_body, authorization, &actor, &db);
             (status, body, "application/json")
         }
-        ("GET", "/api/users") => {
-            let (status, body) = handlers::handle_fetch_users(&db);
+        ("GET", "/api/transfers") => {
+            let (status, body) = handlers::handle_fetch_transfers(authorization, &db);
             (status, body, "application/json")
         }
-        //book apis CRUD operations
-        ("POST", "/api/books") => {
-            let (status, body) = handlers::handle_add_book(&request_body, &db);
+        ("POST", path) if path.starts_with("/api/transfers/") && path.ends_with("/receive") => {
+            let id_part = path
+                .trim_start_matches("/api/transfers/")
+                .trim_end_matches("/receive");
+            match id_part.parse::<i64>() {
+                Ok(transfer_id) => {
+                    let (status, body) =
+                        handlers::handle_receive_transfer(transfer_id, authorization, &actor, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        //circulation desk apis (staff)
+        ("GET", path) if path.starts_with("/api/desk/patrons/") => {
+            let identifier = path.trim_start_matches("/api/desk/patrons/");
+            let (status, body) = handlers::handle_desk_lookup(identifier, authorization, &db);
             (status, body, "application/json")
         }
-        ("GET", "/api/books") => {
-            let (status, body) = handlers::handle_fetch_books(&db);
+        ("POST", "/api/desk/checkout") => {
+            let (status, body) =
+                handlers::handle_desk_checkout(&request_body, authorization, &actor, &db);
             (status, body, "application/json")
         }
-        ("GET", path) if path.starts_with("/api/books/") => {
-            let id_part = path.trim_start_matches("/api/books/");
+        ("POST", "/api/desk/checkin") => {
+            let (status, body) =
+                handlers::handle_desk_checkin(&request_body, authorization, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("GET", path) if path.starts_with("/api/desk/receipt/") => {
+            let id_part = path.trim_start_matches("/api/desk/receipt/");
             match id_part.parse::<i64>() {
-                Ok(book_id) => {
-                    let (status, body) = handlers::handle_fetch_book(book_id, &db);
+                Ok(user_id) => {
+                    let (status, body) = handlers::handle_desk_receipt(user_id, authorization, &db);
+                    (status, body, "text/plain")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        //self-checkout kiosk apis
+        ("PUT", "/api/account/pin") => {
+            let (status, body) =
+                handlers::handle_set_pin(&request_body, authorization, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("POST", "/api/kiosks") => {
+            let (status, body) =
+                handlers::handle_register_kiosk(&request_body, authorization, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("GET", "/api/kiosks") => {
+            let (status, body) = handlers::handle_fetch_kiosks(authorization, &db);
+            (status, body, "application/json")
+        }
+        ("DELETE", path) if path.starts_with("/api/kiosks/") => {
+            let id_part = path.trim_start_matches("/api/kiosks/");
+            match id_part.parse::<i64>() {
+                Ok(kiosk_id) => {
+                    let (status, body) =
+                        handlers::handle_deactivate_kiosk(kiosk_id, authorization, &actor, &db);
                     (status, body, "application/json")
                 }
                 Err(_) => (
@@ -87,11 +960,69 @@ fn handle_connection(mut stream: TcpStream, db: Database) {
                 ),
             }
         }
-        ("PATCH", path) if path.starts_with("/api/books/") => {
-            let id_part = path.trim_start_matches("/api/books/");
+        ("POST", "/api/kiosk/login") => {
+            let (status, body) =
+                handlers::handle_kiosk_login(&request_body, kiosk_credential, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("GET", "/api/kiosk/account") => {
+            let (status, body) =
+                handlers::handle_kiosk_account(kiosk_credential, kiosk_session, &db);
+            (status, body, "application/json")
+        }
+        ("POST", "/api/kiosk/checkout") => {
+            let (status, body) = handlers::handle_kiosk_checkout(
+                &request_body,
+                kiosk_credential,
+                kiosk_session,
+                &actor,
+                &db,
+            );
+            (status, body, "application/json")
+        }
+        ("POST", "/api/kiosk/checkin") => {
+            let (status, body) = handlers::handle_kiosk_checkin(
+                &request_body,
+                kiosk_credential,
+                kiosk_session,
+                &actor,
+                &db,
+            );
+            (status, body, "application/json")
+        }
+        ("POST", "/api/kiosk/logout") => {
+            let (status, body) =
+                handlers::handle_kiosk_logout(kiosk_credential, kiosk_session, &db);
+            (status, body, "application/json")
+        }
+        //in-app notification inbox
+        ("GET", "/api/notifications") => {
+            let (status, body) = handlers::handle_fetch_notifications(false, authorization, &db);
+            (status, body, "application/json")
+        }
+        ("GET", "/api/notifications/unread") => {
+            let (status, body) = handlers::handle_fetch_notifications(true, authorization, &db);
+            (status, body, "application/json")
+        }
+        ("GET", "/api/notifications/unread-count") => {
+            let (status, body) = handlers::handle_unread_notifications(authorization, &db);
+            (status, body, "application/json")
+        }
+        ("POST", "/api/notifications/read") => {
+            let (status, body) = handlers::handle_mark_notifications_read(None, authorization, &db);
+            (status, body, "application/json")
+        }
+        ("POST", path) if path.starts_with("/api/notifications/") && path.ends_with("/read") => {
+            let id_part = path
+                .trim_start_matches("/api/notifications/")
+                .trim_end_matches("/read");
             match id_part.parse::<i64>() {
-                Ok(book_id) => {
-                    let (status, body) = handlers::handle_edit_book(book_id, &request_body, &db);
+                Ok(notification_id) => {
+                    let (status, body) = handlers::handle_mark_notifications_read(
+                        Some(notification_id),
+                        authorization,
+                        &db,
+                    );
                     (status, body, "application/json")
                 }
                 Err(_) => (
@@ -101,11 +1032,12 @@ fn handle_connection(mut stream: TcpStream, db: Database) {
                 ),
             }
         }
-        ("DELETE", path) if path.starts_with("/api/books/") => {
-            let id_part = path.trim_start_matches("/api/books/");
+        ("DELETE", path) if path.starts_with("/api/notifications/") => {
+            let id_part = path.trim_start_matches("/api/notifications/");
             match id_part.parse::<i64>() {
-                Ok(book_id) => {
-                    let (status, body) = handlers::handle_delete_book(book_id, &db);
+                Ok(notification_id) => {
+                    let (status, body) =
+                        handlers::handle_delete_notification(notification_id, authorization, &db);
                     (status, body, "application/json")
                 }
                 Err(_) => (
@@ -115,16 +1047,32 @@ fn handle_connection(mut stream: TcpStream, db: Database) {
                 ),
             }
         }
-        //borrow book apis
-        ("POST", "/api/borrow") => {
-            let (status, body) = handlers::handle_borrow_book(&request_body, &db);
+        //email address, preferences and outbox
+        ("PUT", "/api/account/email") => {
+            let (status, body) =
+                handlers::handle_set_email(&request_body, authorization, &actor, &db);
             (status, body, "application/json")
         }
-        ("GET", path) if path.starts_with("/api/borrow/") => {
-            let id_part = path.trim_start_matches("/api/borrow/");
+        ("GET", "/api/account/notifications") => {
+            let (status, body) = handlers::handle_fetch_email_preferences(authorization, &db);
+            (status, body, "application/json")
+        }
+        ("PUT", "/api/account/notifications") => {
+            let (status, body) =
+                handlers::handle_set_email_preferences(&request_body, authorization, &db);
+            (status, body, "application/json")
+        }
+        ("GET", "/api/outbox") => {
+            let (status, body) = handlers::handle_fetch_outbox(None, authorization, &db);
+            (status, body, "application/json")
+        }
+        ("POST", path) if path.starts_with("/api/outbox/") && path.ends_with("/retry") => {
+            let id_part = path
+                .trim_start_matches("/api/outbox/")
+                .trim_end_matches("/retry");
             match id_part.parse::<i64>() {
-                Ok(user_id) => {
-                    let (status, body) = handlers::handle_fetch_borrowed_books(user_id, &db);
+                Ok(email_id) => {
+                    let (status, body) = handlers::handle_retry_email(email_id, authorization, &db);
                     (status, body, "application/json")
                 }
                 Err(_) => (
@@ -134,35 +1082,145 @@ fn handle_connection(mut stream: TcpStream, db: Database) {
                 ),
             }
         }
-        ("GET", "/api/borrow") => {
-            let (status, body) = handlers::handle_fetch_all_borrowed_books(&db);
+        ("GET", path) if path.starts_with("/api/outbox/") => {
+            let status_part = path.trim_start_matches("/api/outbox/");
+            let (status, body) =
+                handlers::handle_fetch_outbox(Some(status_part), authorization, &db);
             (status, body, "application/json")
         }
-        ("DELETE", path) if path.starts_with("/api/borrow/") => {
-            let parts: Vec<&str> = path.trim_start_matches("/api/borrow/").split("/").collect();
-            if parts.len() == 2 {
-                if let (Ok(borrowed_id), Ok(book_id)) =
-                    (parts[0].parse::<i64>(), parts[1].parse::<i64>())
-                {
-                    let (status, body) = handlers::handle_return_book(borrowed_id, book_id, &db);
+        //outbound webhooks (staff)
+        ("POST", "/api/webhooks") => {
+            let (status, body) =
+                handlers::handle_add_webhook(&request_body, authorization, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("GET", "/api/webhooks") => {
+            let (status, body) = handlers::handle_fetch_webhooks(authorization, &db);
+            (status, body, "application/json")
+        }
+        ("POST", path)
+            if path.starts_with("/api/webhooks/deliveries/") && path.ends_with("/redeliver") =>
+        {
+            let id_part = path
+                .trim_start_matches("/api/webhooks/deliveries/")
+                .trim_end_matches("/redeliver");
+            match id_part.parse::<i64>() {
+                Ok(delivery_id) => {
+                    let (status, body) =
+                        handlers::handle_redeliver_webhook(delivery_id, authorization, &db);
                     (status, body, "application/json")
-                } else {
-                    let error = r#"{"success":false, "message":"Invalid IDs"}"#;
-                    (
-                        "HTTP/1.1 400 Bad Request",
-                        error.to_string(),
-                        "application/json",
-                    )
                 }
-            } else {
-                let error = r#"{"success": false, "message": "Invalid path"}"#;
-                (
+                Err(_) => (
                     "HTTP/1.1 400 Bad Request",
-                    error.to_string(),
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
                     "application/json",
-                )
+                ),
+            }
+        }
+        ("GET", path) if path.starts_with("/api/webhooks/") && path.ends_with("/deliveries") => {
+            let id_part = path
+                .trim_start_matches("/api/webhooks/")
+                .trim_end_matches("/deliveries");
+            match id_part.parse::<i64>() {
+                Ok(webhook_id) => {
+                    let (status, body) =
+                        handlers::handle_fetch_webhook_deliveries(webhook_id, authorization, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        ("DELETE", path) if path.starts_with("/api/webhooks/") => {
+            let id_part = path.trim_start_matches("/api/webhooks/");
+            match id_part.parse::<i64>() {
+                Ok(webhook_id) => {
+                    let (status, body) =
+                        handlers::handle_deactivate_webhook(webhook_id, authorization, &actor, &db);
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid ID" }"#.to_string(),
+                    "application/json",
+                ),
+            }
+        }
+        //scheduled jobs and loan notices (staff)
+        ("GET", "/api/notices") => {
+            let (status, body) = handlers::handle_fetch_notices(None, authorization, &db);
+            (status, body, "application/json")
+        }
+        ("GET", path) if path.starts_with("/api/notices/") => {
+            let status_part = path.trim_start_matches("/api/notices/");
+            let (status, body) =
+                handlers::handle_fetch_notices(Some(status_part), authorization, &db);
+            (status, body, "application/json")
+        }
+        ("GET", "/api/scheduler/runs") => {
+            let (status, body) = handlers::handle_fetch_job_runs(authorization, &db);
+            (status, body, "application/json")
+        }
+        ("POST", "/api/scheduler/run") => {
+            let (status, body) = handlers::handle_run_scheduler(authorization, &db);
+            (status, body, "application/json")
+        }
+        //audit log (staff)
+        ("GET", _) if route == "/api/audit" => {
+            let param = |name| query_param(query, name).map(url_decode);
+            let (status, body) = handlers::handle_fetch_audit_log(
+                param("actor").as_deref(),
+                param("action").as_deref(),
+                param("target").as_deref(),
+                param("from").as_deref(),
+                param("to").as_deref(),
+                authorization,
+                &db,
+            );
+            (status, body, "application/json")
+        }
+        ("GET", "/api/domain-events/subscribers") => {
+            let (status, body) = handlers::handle_fetch_event_subscribers(authorization, &db);
+            (status, body, "application/json")
+        }
+        //library calendar apis
+        ("GET", "/api/calendar") => {
+            let (status, body) = handlers::handle_fetch_calendar(&db);
+            (status, body, "application/json")
+        }
+        ("PUT", path) if path.starts_with("/api/calendar/hours/") => {
+            let weekday_part = path.trim_start_matches("/api/calendar/hours/");
+            match weekday_part.parse::<u32>() {
+                Ok(weekday) => {
+                    let (status, body) = handlers::handle_set_opening_hours(
+                        weekday,
+                        &request_body,
+                        authorization,
+                        &actor,
+                        &db,
+                    );
+                    (status, body, "application/json")
+                }
+                Err(_) => (
+                    "HTTP/1.1 400 Bad Request",
+                    r#"{ "success": false, "message": "Invalid weekday" }"#.to_string(),
+                    "application/json",
+                ),
             }
         }
+        ("POST", "/api/calendar/closures") => {
+            let (status, body) =
+                handlers::handle_add_closure(&request_body, authorization, &actor, &db);
+            (status, body, "application/json")
+        }
+        ("DELETE", path) if path.starts_with("/api/calendar/closures/") => {
+            let date = path.trim_start_matches("/api/calendar/closures/");
+            let (status, body) = handlers::handle_delete_closure(date, authorization, &actor, &db);
+            (status, body, "application/json")
+        }
         // HTML pages
         //login and signup
         ("GET", "/login.html") => match fs::read_to_string("frontend/login.html") {
@@ -251,6 +1309,40 @@ fn handle_connection(mut stream: TcpStream, db: Database) {
                 "text/html",
             ),
         },
+        ("GET", "/notifications.html") => match fs::read_to_string("frontend/notifications.html") {
+            Ok(html) => ("HTTP/1.1 200 OK", html, "text/html"),
+            Err(_) => (
+                "HTTP/1.1 404 NOT FOUND",
+                "<h1>404 Page Not Found</h1>".to_string(),
+                "text/html",
+            ),
+        },
+        ("GET", "/reset_password.html") => {
+            match fs::read_to_string("frontend/reset_password.html") {
+                Ok(html) => ("HTTP/1.1 200 OK", html, "text/html"),
+                Err(_) => (
+                    "HTTP/1.1 404 NOT FOUND",
+                    "<h1>404 Page Not Found</h1>".to_string(),
+                    "text/html",
+                ),
+            }
+        }
+        ("GET", "/verify_email.html") => match fs::read_to_string("frontend/verify_email.html") {
+            Ok(html) => ("HTTP/1.1 200 OK", html, "text/html"),
+            Err(_) => (
+                "HTTP/1.1 404 NOT FOUND",
+                "<h1>404 Page Not Found</h1>".to_string(),
+                "text/html",
+            ),
+        },
+        ("GET", "/sessions.html") => match fs::read_to_string("frontend/sessions.html") {
+            Ok(html) => ("HTTP/1.1 200 OK", html, "text/html"),
+            Err(_) => (
+                "HTTP/1.1 404 NOT FOUND",
+                "<h1>404 Page Not Found</h1>".to_string(),
+                "text/html",
+            ),
+        },
         ("GET", "/borrow_details.html") => match fs::read_to_string("frontend/borrow_details.html")
         {
             Ok(html) => ("HTTP/1.1 200 OK", html, "text/html"),
@@ -330,6 +1422,42 @@ fn handle_connection(mut stream: TcpStream, db: Database) {
                 ),
             }
         }
+        ("GET", "/js/reset_password.js") => {
+            match fs::read_to_string("frontend/js/reset_password.js") {
+                Ok(js) => ("HTTP/1.1 200 OK", js, "application/javascript"),
+                Err(_) => (
+                    "HTTP/1.1 404 NOT FOUND",
+                    "console.error('JS file not found');".to_string(),
+                    "application/javascript",
+                ),
+            }
+        }
+        ("GET", "/js/verify_email.js") => match fs::read_to_string("frontend/js/verify_email.js") {
+            Ok(js) => ("HTTP/1.1 200 OK", js, "application/javascript"),
+            Err(_) => (
+                "HTTP/1.1 404 NOT FOUND",
+                "console.error('JS file not found');".to_string(),
+                "application/javascript",
+            ),
+        },
+        ("GET", "/js/sessions.js") => match fs::read_to_string("frontend/js/sessions.js") {
+            Ok(js) => ("HTTP/1.1 200 OK", js, "application/javascript"),
+            Err(_) => (
+                "HTTP/1.1 404 NOT FOUND",
+                "console.error('JS file not found');".to_string(),
+                "application/javascript",
+            ),
+        },
+        ("GET", "/js/notifications.js") => {
+            match fs::read_to_string("frontend/js/notifications.js") {
+                Ok(js) => ("HTTP/1.1 200 OK", js, "application/javascript"),
+                Err(_) => (
+                    "HTTP/1.1 404 NOT FOUND",
+                    "console.error('JS file not found');".to_string(),
+                    "application/javascript",
+                ),
+            }
+        }
         ("GET", "/js/borrow_details_admin.js") => {
             match fs::read_to_string("frontend/js/borrow_details_admin.js") {
                 Ok(js) => ("HTTP/1.1 200 OK", js, "application/javascript"),
@@ -372,3 +1500,36 @@ fn handle_connection(mut stream: TcpStream, db: Database) {
     );
     stream.write_all(response.as_bytes()).unwrap();
 }
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// Query values arrive percent-encoded, with '+' for spaces.
+fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
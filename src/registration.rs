@@ -0,0 +1,186 @@
+use std::env;
+
+// How self-registration through POST /api/signup works, from REGISTRATION_MODE.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    // Accounts can log in straight away
+    Open,
+    // The emailed verification link has to be followed before the first login
+    EmailVerified,
+    // Staff with users.manage approve or reject each registration
+    StaffApproved,
+}
+
+// Where a self-registered account stands; only approved accounts can log in.
+pub const APPROVED: &str = "approved";
+pub const PENDING_VERIFICATION: &str = "pending_verification";
+pub const PENDING_APPROVAL: &str = "pending_approval";
+pub const REJECTED: &str = "rejected";
+
+// Verification links stop working after this many hours; a new one can be requested.
+pub const VERIFICATION_HOURS: u64 = 48;
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Open => "open",
+            Mode::EmailVerified => "email_verified",
+            Mode::StaffApproved => "staff_approved",
+        }
+    }
+
+    pub fn initial_status(self) -> &'static str {
+        match self {
+            Mode::Open => APPROVED,
+            Mode::EmailVerified => PENDING_VERIFICATION,
+            Mode::StaffApproved => PENDING_APPROVAL,
+        }
+    }
+}
+
+// Defaults to open. A value that isn't recognised falls back to staff approval
+// rather than letting anyone in.
+pub fn mode() -> Mode {
+    parse_mode(env::var("REGISTRATION_MODE").ok().as_deref())
+}
+
+fn parse_mode(value: Option<&str>) -> Mode {
+    match value.map(str::trim) {
+        None | Some("") | Some("open") => Mode::Open,
+        Some("email_verified") => Mode::EmailVerified,
+        Some("staff_approved") => Mode::StaffApproved,
+        Some(other) => {
+            eprintln!("Unknown REGISTRATION_MODE '{other}'; using staff_approved");
+            Mode::StaffApproved
+        }
+    }
+}
+
+// Domains self-registered emails must belong to, from REGISTRATION_EMAIL_DOMAINS
+// (comma separated). Empty when anyone may register.
+pub fn allowed_domains() -> Vec<String> {
+    env::var("REGISTRATION_EMAIL_DOMAINS")
+        .map(|domains| parse_domains(&domains))
+        .unwrap_or_default()
+}
+
+fn parse_domains(domains: &str) -> Vec<String> {
+    domains
+        .split(',')
+        .map(|domain| domain.trim().trim_start_matches('@').to_ascii_lowercase())
+        .filter(|domain| !domain.is_empty())
+        .collect()
+}
+
+// Subdomains count, so "campus.edu" also allows "cs.campus.edu".
+pub fn domain_allowed(email: &str, allowed: &[String]) -> bool {
+    let domain = match email.trim().rsplit_once('@') {
+        Some((_, domain)) => domain.to_ascii_lowercase(),
+        None => return false,
+    };
+    allowed
+        .iter()
+        .any(|allowed| domain == *allowed || domain.ends_with(&format!(".{allowed}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mode_defaults_to_open() {
+        assert_eq!(parse_mode(None), Mode::Open);
+        assert_eq!(parse_mode(Some("")), Mode::Open);
+        assert_eq!(parse_mode(Some("  ")), Mode::Open);
+        assert_eq!(parse_mode(Some("open")), Mode::Open);
+    }
+
+    #[test]
+    fn mode_reads_each_name() {
+        for mode in [Mode::Open, Mode::EmailVerified, Mode::StaffApproved] {
+            assert_eq!(parse_mode(Some(mode.name())), mode);
+            assert_eq!(parse_mode(Some(&format!(" {} ", mode.name()))), mode);
+        }
+    }
+
+    #[test]
+    fn unknown_mode_falls_back_to_staff_approval() {
+        assert_eq!(parse_mode(Some("closed")), Mode::StaffApproved);
+        assert_eq!(parse_mode(Some("Open")), Mode::StaffApproved);
+        assert_eq!(parse_mode(Some("email-verified")), Mode::StaffApproved);
+    }
+
+    #[test]
+    fn initial_status_follows_mode() {
+        assert_eq!(Mode::Open.initial_status(), APPROVED);
+        assert_eq!(Mode::EmailVerified.initial_status(), PENDING_VERIFICATION);
+        assert_eq!(Mode::StaffApproved.initial_status(), PENDING_APPROVAL);
+    }
+
+    #[test]
+    fn domains_config_is_tidied() {
+        assert_eq!(
+            parse_domains(" @Campus.EDU, library.org ,,@"),
+            vec!["campus.edu", "library.org"]
+        );
+        assert!(parse_domains("").is_empty());
+    }
+
+    #[test]
+    fn exact_domain_is_allowed() {
+        let domains = parse_domains("campus.edu");
+        assert!(domain_allowed("reader@campus.edu", &domains));
+        assert!(domain_allowed(" reader@campus.edu ", &domains));
+        assert!(!domain_allowed("reader@college.edu", &domains));
+    }
+
+    #[test]
+    fn subdomains_are_allowed() {
+        let domains = parse_domains("campus.edu");
+        assert!(domain_allowed("reader@cs.campus.edu", &domains));
+        assert!(domain_allowed("reader@mail.cs.campus.edu", &domains));
+    }
+
+    #[test]
+    fn case_is_ignored() {
+        assert!(domain_allowed(
+            "Reader@CS.Campus.Edu",
+            &parse_domains("campus.edu")
+        ));
+        assert!(domain_allowed(
+            "reader@campus.edu",
+            &parse_domains("CAMPUS.EDU")
+        ));
+    }
+
+    #[test]
+    fn leading_at_in_config_is_ignored() {
+        let domains = parse_domains("@campus.edu");
+        assert!(domain_allowed("reader@campus.edu", &domains));
+        assert!(domain_allowed("reader@cs.campus.edu", &domains));
+    }
+
+    #[test]
+    fn look_alike_domains_are_refused() {
+        let domains = parse_domains("campus.edu");
+        for email in [
+            "reader@evilcampus.edu",
+            "reader@campus.edu.evil.com",
+            "reader@campus.education",
+            "reader@xcampus.edu",
+            "campus.edu@evil.com",
+            "reader@",
+            "campus.edu",
+        ] {
+            assert!(!domain_allowed(email, &domains), "{email}");
+        }
+    }
+
+    #[test]
+    fn any_listed_domain_will_do() {
+        let domains = parse_domains("campus.edu, library.org");
+        assert!(domain_allowed("reader@library.org", &domains));
+        assert!(domain_allowed("reader@campus.edu", &domains));
+        assert!(!domain_allowed("reader@example.com", &domains));
+    }
+}
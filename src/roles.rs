@@ -0,0 +1,60 @@
+use serde::Serialize;
+
+// What the API checks for. Patrons need none of these to use their own account
+// (loans, holds, fines, notifications); they cover acting on the catalog, on
+// other patrons' records and on the system itself.
+pub const PERMISSIONS: &[&str] = &[
+    // books and their copies
+    "catalog.edit",
+    // loans, returns, renewals, holds, transfers and fines for any patron, and the desk
+    "circulation.checkout",
+    // the patron list
+    "users.view",
+    // patrons' branches, sessions, lockouts and two-factor resets
+    "users.manage",
+    "roles.assign",
+    // notices and scheduler history
+    "reports.view",
+    "audit.view",
+    // calendar, branches, kiosks, webhooks, the email outbox, scheduler and event dispatch
+    "system.manage",
+];
+
+#[derive(Debug, Serialize)]
+pub struct Role {
+    pub name: &'static str,
+    pub permissions: &'static [&'static str],
+}
+
+pub const ROLES: &[Role] = &[
+    Role {
+        name: "user",
+        permissions: &[],
+    },
+    Role {
+        name: "librarian",
+        permissions: &[
+            "catalog.edit",
+            "circulation.checkout",
+            "users.view",
+            "reports.view",
+        ],
+    },
+    Role {
+        name: "admin",
+        permissions: PERMISSIONS,
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Role> {
+    ROLES.iter().find(|role| role.name == name)
+}
+
+// Unknown roles get no permissions.
+pub fn permissions_for(role: &str) -> &'static [&'static str] {
+    find(role).map_or(&[], |role| role.permissions)
+}
+
+pub fn has_permission(role: &str, permission: &str) -> bool {
+    permissions_for(role).contains(&permission)
+}
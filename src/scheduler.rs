@@ -0,0 +1,105 @@
+use crate::db::Database;
+use crate::mail::{self, Transport};
+use crate::webhooks;
+use chrono::{NaiveDateTime, Utc};
+use rusqlite::{Connection, Result as SqliteResult};
+use std::{env, thread, time::Duration};
+
+// How often the scheduler wakes up to look for jobs that are due.
+pub const TICK_SECONDS: u64 = 60;
+// Patrons get a due_soon notice this many days before a loan is due,
+// unless REMINDER_DAYS_BEFORE says otherwise.
+pub const DEFAULT_REMINDER_DAYS: i64 = 2;
+
+pub struct Job {
+    pub name: &'static str,
+    // A job runs at most once per key, e.g. once per calendar day.
+    pub run_key: fn(NaiveDateTime) -> String,
+    pub work: fn(&Connection, NaiveDateTime) -> SqliteResult<i64>,
+}
+
+fn daily(now: NaiveDateTime) -> String {
+    now.format("%Y-%m-%d").to_string()
+}
+
+fn reminder_days() -> i64 {
+    env::var("REMINDER_DAYS_BEFORE")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .filter(|days| *days >= 0)
+        .unwrap_or(DEFAULT_REMINDER_DAYS)
+}
+
+fn loan_notices(conn: &Connection, now: NaiveDateTime) -> SqliteResult<i64> {
+    Database::enqueue_loan_notices(conn, now, reminder_days())
+}
+
+// Live-stream clients further behind than this reload instead of resuming.
+pub const EVENT_RETENTION_DAYS: i64 = 2;
+
+fn prune_events(conn: &Connection, now: NaiveDateTime) -> SqliteResult<i64> {
+    Database::prune_events(conn, now - chrono::Duration::days(EVENT_RETENTION_DAYS))
+}
+
+// Ended sessions are kept this long so a replayed refresh token is still
+// recognised as reuse rather than as an unknown token.
+pub const SESSION_RETENTION_DAYS: i64 = 30;
+
+fn prune_sessions(conn: &Connection, now: NaiveDateTime) -> SqliteResult<i64> {
+    Database::prune_sessions(conn, now - chrono::Duration::days(SESSION_RETENTION_DAYS))
+}
+
+pub const JOBS: &[Job] = &[
+    Job {
+        name: "loan_notices",
+        run_key: daily,
+        work: loan_notices,
+    },
+    Job {
+        name: "prune_events",
+        run_key: daily,
+        work: prune_events,
+    },
+    Job {
+        name: "prune_sessions",
+        run_key: daily,
+        work: prune_sessions,
+    },
+];
+
+// Runs every job whose current run key hasn't been claimed yet.
+// Returns (job, run_key, Some(processed)) for jobs that ran and None for ones already done.
+pub fn run_pending(db: &Database) -> Vec<(&'static str, String, Option<i64>)> {
+    let now = Utc::now().naive_utc();
+    let mut results = Vec::new();
+
+    for job in JOBS {
+        let run_key = (job.run_key)(now);
+        match db.run_job_once(job.name, &run_key, |conn| (job.work)(conn, now)) {
+            Ok(processed) => {
+                if let Some(processed) = processed {
+                    println!("Job {} ({run_key}) processed {processed}", job.name);
+                }
+                results.push((job.name, run_key, processed));
+            }
+            Err(e) => eprintln!("Job {} ({run_key}) failed: {e}", job.name),
+        }
+    }
+
+    results
+}
+
+// Background thread that checks for due jobs and delivers emails and webhooks
+// once per tick for the life of the server.
+pub fn start(db: Database) -> thread::JoinHandle<()> {
+    let transport = Transport::from_env();
+
+    thread::spawn(move || {
+        loop {
+            run_pending(&db);
+            mail::deliver_pending(&db, &transport);
+            webhooks::deliver_pending(&db);
+            thread::sleep(Duration::from_secs(TICK_SECONDS));
+        }
+    })
+}
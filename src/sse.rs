@@ -0,0 +1,83 @@
+use crate::db::Database;
+use std::{
+    io::Write,
+    net::TcpStream,
+    thread,
+    time::{Duration, Instant},
+};
+
+// How often the stream checks for new domain events.
+const POLL_MILLIS: u64 = 500;
+// A comment line is sent this often so dead connections are noticed and closed.
+const KEEPALIVE_SECONDS: u64 = 15;
+
+// Streams events to one client until it disconnects. Runs on its own thread so
+// long-lived streams never tie up the request workers.
+//
+// Each event carries its domain event id as the SSE id. A reconnecting EventSource sends
+// it back as Last-Event-ID and picks up exactly where it left off; a new client
+// only sees events from now on. A signed-in stream ends when its session does.
+pub fn stream(
+    mut stream: TcpStream,
+    db: Database,
+    user_id: Option<i64>,
+    session_id: Option<i64>,
+    last_event_id: Option<i64>,
+) {
+    let mut last_event_id = match last_event_id {
+        Some(id) => id,
+        None => match db.latest_event_id() {
+            Ok(id) => id,
+            Err(_) => return,
+        },
+    };
+
+    let headers = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\n\r\nretry: 3000\n\n";
+    if stream.write_all(headers.as_bytes()).is_err() {
+        return;
+    }
+
+    let mut last_write = Instant::now();
+    let mut last_session_check = Instant::now();
+    loop {
+        if let Some(session_id) = session_id
+            && last_session_check.elapsed() >= Duration::from_secs(KEEPALIVE_SECONDS)
+        {
+            if !db.session_active(session_id).unwrap_or(false) {
+                return;
+            }
+            last_session_check = Instant::now();
+        }
+
+        let (events, cursor) = match db.fetch_events_after(last_event_id, user_id) {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                eprintln!("Event stream stopped: {e}");
+                return;
+            }
+        };
+
+        if !events.is_empty() {
+            let mut chunk = String::new();
+            for event in &events {
+                chunk.push_str(&format!(
+                    "id: {}\nevent: {}\ndata: {}\n\n",
+                    event.id, event.kind, event.data
+                ));
+            }
+            if stream.write_all(chunk.as_bytes()).is_err() {
+                return;
+            }
+            last_write = Instant::now();
+        } else if last_write.elapsed() >= Duration::from_secs(KEEPALIVE_SECONDS) {
+            if stream.write_all(b": keepalive\n\n").is_err() {
+                return;
+            }
+            last_write = Instant::now();
+        }
+
+        last_event_id = cursor;
+
+        thread::sleep(Duration::from_millis(POLL_MILLIS));
+    }
+}
@@ -0,0 +1,432 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use sha1::{Digest, Sha1};
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+};
+
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Larger messages are refused with close code 1009; nothing the desk sends comes close.
+pub const MAX_MESSAGE_BYTES: usize = 1 << 20;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_UNSUPPORTED: u16 = 1003;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_POLICY: u16 = 1008;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+#[derive(Debug)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<u16>, String),
+}
+
+// Sec-WebSocket-Accept for a client's Sec-WebSocket-Key (RFC 6455 section 4.2.2).
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(HANDSHAKE_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+pub fn handshake_response(key: &str) -> String {
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )
+}
+
+// Reads whole messages off the socket, reassembling fragmented ones. Control
+// frames may arrive between fragments and are returned as soon as they're read.
+pub struct MessageReader<R: Read> {
+    reader: R,
+    fragments: Option<(u8, Vec<u8>)>,
+}
+
+// A read either fails at the socket or because the peer broke the protocol, in
+// which case the close code to send back is given.
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    Protocol(u16, &'static str),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(error: io::Error) -> ReadError {
+        ReadError::Io(error)
+    }
+}
+
+impl<R: Read> MessageReader<R> {
+    pub fn new(reader: R) -> MessageReader<R> {
+        MessageReader {
+            reader,
+            fragments: None,
+        }
+    }
+
+    pub fn read_message(&mut self) -> Result<Message, ReadError> {
+        loop {
+            let (fin, opcode, payload) = self.read_frame()?;
+
+            match opcode {
+                OP_CLOSE => {
+                    return match payload.len() {
+                        0 => Ok(Message::Close(None, String::new())),
+                        1 => Err(ReadError::Protocol(CLOSE_PROTOCOL_ERROR, "bad close frame")),
+                        _ => {
+                            let code = u16::from_be_bytes([payload[0], payload[1]]);
+                            match String::from_utf8(payload[2..].to_vec()) {
+                                Ok(reason) => Ok(Message::Close(Some(code), reason)),
+                                Err(_) => Err(ReadError::Protocol(
+                                    CLOSE_INVALID_DATA,
+                                    "close reason is not UTF-8",
+                                )),
+                            }
+                        }
+                    };
+                }
+                OP_PING => return Ok(Message::Ping(payload)),
+                OP_PONG => return Ok(Message::Pong(payload)),
+                OP_TEXT | OP_BINARY => {
+                    if self.fragments.is_some() {
+                        return Err(ReadError::Protocol(
+                            CLOSE_PROTOCOL_ERROR,
+                            "new message before the last one finished",
+                        ));
+                    }
+                    if fin {
+                        return Self::data_message(opcode, payload);
+                    }
+                    self.fragments = Some((opcode, payload));
+                }
+                OP_CONTINUATION => {
+                    let (first_opcode, mut data) = match self.fragments.take() {
+                        Some(fragments) => fragments,
+                        None => {
+                            return Err(ReadError::Protocol(
+                                CLOSE_PROTOCOL_ERROR,
+                                "continuation without a message",
+                            ));
+                        }
+                    };
+                    if data.len() + payload.len() > MAX_MESSAGE_BYTES {
+                        return Err(ReadError::Protocol(CLOSE_TOO_BIG, "message too big"));
+                    }
+                    data.extend_from_slice(&payload);
+                    if fin {
+                        return Self::data_message(first_opcode, data);
+                    }
+                    self.fragments = Some((first_opcode, data));
+                }
+                _ => return Err(ReadError::Protocol(CLOSE_PROTOCOL_ERROR, "unknown opcode")),
+            }
+        }
+    }
+
+    fn data_message(opcode: u8, payload: Vec<u8>) -> Result<Message, ReadError> {
+        if opcode == OP_BINARY {
+            return Ok(Message::Binary(payload));
+        }
+        String::from_utf8(payload)
+            .map(Message::Text)
+            .map_err(|_| ReadError::Protocol(CLOSE_INVALID_DATA, "text is not UTF-8"))
+    }
+
+    fn read_frame(&mut self) -> Result<(bool, u8, Vec<u8>), ReadError> {
+        let mut header = [0u8; 2];
+        self.reader.read_exact(&mut header)?;
+
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0F;
+        if header[0] & 0x70 != 0 {
+            return Err(ReadError::Protocol(
+                CLOSE_PROTOCOL_ERROR,
+                "reserved bits set",
+            ));
+        }
+        // Clients must mask every frame
+        if header[1] & 0x80 == 0 {
+            return Err(ReadError::Protocol(
+                CLOSE_PROTOCOL_ERROR,
+                "unmasked client frame",
+            ));
+        }
+
+        let length = match header[1] & 0x7F {
+            126 => {
+                let mut bytes = [0u8; 2];
+                self.reader.read_exact(&mut bytes)?;
+                u16::from_be_bytes(bytes) as u64
+            }
+            127 => {
+                let mut bytes = [0u8; 8];
+                self.reader.read_exact(&mut bytes)?;
+                u64::from_be_bytes(bytes)
+            }
+            length => length as u64,
+        };
+
+        let is_control = opcode & 0x8 != 0;
+        if is_control && (!fin || length > 125) {
+            return Err(ReadError::Protocol(
+                CLOSE_PROTOCOL_ERROR,
+                "bad control frame",
+            ));
+        }
+        if length > MAX_MESSAGE_BYTES as u64 {
+            return Err(ReadError::Protocol(CLOSE_TOO_BIG, "message too big"));
+        }
+
+        let mut mask = [0u8; 4];
+        self.reader.read_exact(&mut mask)?;
+        let mut payload = vec![0u8; length as usize];
+        self.reader.read_exact(&mut payload)?;
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+
+        Ok((fin, opcode, payload))
+    }
+}
+
+// Server frames are never masked or fragmented.
+fn write_frame<W: Write>(stream: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)
+}
+
+pub fn send_text(stream: &mut TcpStream, text: &str) -> io::Result<()> {
+    write_frame(stream, OP_TEXT, text.as_bytes())
+}
+
+pub fn send_ping(stream: &mut TcpStream, payload: &[u8]) -> io::Result<()> {
+    write_frame(stream, OP_PING, payload)
+}
+
+pub fn send_pong(stream: &mut TcpStream, payload: &[u8]) -> io::Result<()> {
+    write_frame(stream, OP_PONG, payload)
+}
+
+pub fn send_close(stream: &mut TcpStream, code: u16, reason: &str) -> io::Result<()> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    write_frame(stream, OP_CLOSE, &payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    // Builds a frame the way a browser would: always masked.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            length if length < 126 => frame.push(0x80 | length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&MASK);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(index, byte)| byte ^ MASK[index % 4]),
+        );
+        frame
+    }
+
+    fn read(bytes: &[u8]) -> Result<Message, ReadError> {
+        MessageReader::new(bytes).read_message()
+    }
+
+    fn protocol_code(result: Result<Message, ReadError>) -> u16 {
+        match result {
+            Err(ReadError::Protocol(code, _)) => code,
+            other => panic!("expected a protocol error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn reads_masked_text_of_each_length_form() {
+        for length in [0, 5, 125, 126, 1000, u16::MAX as usize, 70_000] {
+            let text = "x".repeat(length);
+            match read(&client_frame(true, OP_TEXT, text.as_bytes())) {
+                Ok(Message::Text(received)) => assert_eq!(received, text, "length {length}"),
+                other => panic!("length {length}: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn unmasks_payload() {
+        let frame = client_frame(true, OP_BINARY, &[1, 2, 3, 4, 5, 6]);
+        // The bytes on the wire are not the payload itself
+        assert_ne!(&frame[6..], &[1, 2, 3, 4, 5, 6]);
+        match read(&frame) {
+            Ok(Message::Binary(payload)) => assert_eq!(payload, vec![1, 2, 3, 4, 5, 6]),
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn rejects_unmasked_frames() {
+        let frame = [0x81, 0x02, b'h', b'i'];
+        assert_eq!(protocol_code(read(&frame)), CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn rejects_reserved_bits() {
+        let mut frame = client_frame(true, OP_TEXT, b"hi");
+        frame[0] |= 0x40;
+        assert_eq!(protocol_code(read(&frame)), CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn rejects_oversized_messages_before_reading_them() {
+        let mut frame = vec![0x82, 0x80 | 127];
+        frame.extend_from_slice(&(MAX_MESSAGE_BYTES as u64 + 1).to_be_bytes());
+        assert_eq!(protocol_code(read(&frame)), CLOSE_TOO_BIG);
+    }
+
+    #[test]
+    fn reassembles_fragments_around_control_frames() {
+        let mut bytes = client_frame(false, OP_TEXT, b"hel");
+        bytes.extend(client_frame(true, OP_PING, b"p"));
+        bytes.extend(client_frame(true, OP_CONTINUATION, b"lo"));
+        let mut reader = MessageReader::new(&bytes[..]);
+
+        assert!(matches!(reader.read_message(), Ok(Message::Ping(payload)) if payload == b"p"));
+        assert!(matches!(reader.read_message(), Ok(Message::Text(text)) if text == "hello"));
+    }
+
+    #[test]
+    fn rejects_continuation_without_a_message() {
+        let frame = client_frame(true, OP_CONTINUATION, b"lo");
+        assert_eq!(protocol_code(read(&frame)), CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn rejects_new_message_inside_a_fragmented_one() {
+        let mut bytes = client_frame(false, OP_TEXT, b"hel");
+        bytes.extend(client_frame(true, OP_TEXT, b"lo"));
+        assert_eq!(protocol_code(read(&bytes)), CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn reads_ping_pong_and_close() {
+        assert!(matches!(
+            read(&client_frame(true, OP_PING, b"abc")),
+            Ok(Message::Ping(payload)) if payload == b"abc"
+        ));
+        assert!(matches!(
+            read(&client_frame(true, OP_PONG, b"")),
+            Ok(Message::Pong(payload)) if payload.is_empty()
+        ));
+        assert!(matches!(
+            read(&client_frame(true, OP_CLOSE, b"")),
+            Ok(Message::Close(None, reason)) if reason.is_empty()
+        ));
+
+        let mut payload = CLOSE_NORMAL.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+        assert!(matches!(
+            read(&client_frame(true, OP_CLOSE, &payload)),
+            Ok(Message::Close(Some(CLOSE_NORMAL), reason)) if reason == "bye"
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_control_frames() {
+        // Control frames can't be fragmented or carry more than 125 bytes
+        assert_eq!(
+            protocol_code(read(&client_frame(false, OP_PING, b"p"))),
+            CLOSE_PROTOCOL_ERROR
+        );
+        assert_eq!(
+            protocol_code(read(&client_frame(true, OP_PING, &[0; 126]))),
+            CLOSE_PROTOCOL_ERROR
+        );
+        assert_eq!(
+            protocol_code(read(&client_frame(true, OP_CLOSE, &[0x03]))),
+            CLOSE_PROTOCOL_ERROR
+        );
+        assert_eq!(
+            protocol_code(read(&client_frame(true, OP_CLOSE, &[0x03, 0xe8, 0xff]))),
+            CLOSE_INVALID_DATA
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_utf8_and_unknown_opcodes() {
+        assert_eq!(
+            protocol_code(read(&client_frame(true, OP_TEXT, &[0xff, 0xfe]))),
+            CLOSE_INVALID_DATA
+        );
+        assert_eq!(
+            protocol_code(read(&client_frame(true, 0x3, b""))),
+            CLOSE_PROTOCOL_ERROR
+        );
+    }
+
+    #[test]
+    fn writes_unmasked_frames_of_each_length_form() {
+        let cases: [(usize, &[u8]); 4] = [
+            (0, &[0x81, 0]),
+            (125, &[0x81, 125]),
+            (126, &[0x81, 126, 0x00, 126]),
+            (70_000, &[0x81, 127, 0, 0, 0, 0, 0x00, 0x01, 0x11, 0x70]),
+        ];
+        for (length, header) in cases {
+            let payload = vec![b'x'; length];
+            let mut frame = Vec::new();
+            write_frame(&mut frame, OP_TEXT, &payload).unwrap();
+            assert_eq!(&frame[..header.len()], header, "length {length}");
+            assert_eq!(&frame[header.len()..], &payload[..], "length {length}");
+        }
+
+        let mut frame = Vec::new();
+        write_frame(&mut frame, OP_TEXT, &[b'x'; 65_535]).unwrap();
+        assert_eq!(&frame[..4], &[0x81, 126, 0xff, 0xff]);
+    }
+}